@@ -22,37 +22,12 @@ use polkadot_service::polkadot_runtime as dot_rt;
 use polkadot_service::westend_runtime as westend_rt;
 use polkadot_service::Block;
 use sc_chain_spec::ChainSpec;
-use substrate_archive::{Archive, ArchiveBuilder};
+use std::sync::Arc;
+use substrate_archive::{backend::ChainDataSource, Archive, ArchiveBuilder, Worker};
 
 pub fn run_archive(config: Config) -> Result<Box<dyn Archive<Block>>> {
-    
-    let mut db_path = if let Some(p) = config.polkadot_path() {
-        p    
-    } else {
-        let path = std::env::var("CHAIN_DATA_DB").expect("CHAIN_DATA_DB must be set.");
-        std::path::PathBuf::from(path)
-    };
-
     let spec = get_spec(config.cli().chain.as_str())?;
-    
-    let last_path_part = db_path
-        .file_name()
-        .context("Polkadot path not valid")?
-        .to_str()
-        .context("could not convert path to string")?;
-
-
-    match last_path_part {
-        "polkadot" => db_path.push(format!("chains/{}/db", spec.id())),
-        "chains" => db_path.push(format!("{}/db", spec.id())),
-        _ => return Err(anyhow!("invalid path {}", db_path.as_path().display())),
-    }
-
-    let db_path = db_path
-        .as_path()
-        .to_str()
-        .context("could not convert rocksdb path to str")?
-        .to_string();
+    let db_path = chain_db_path(&config, spec.as_ref())?;
 
     match config.cli().chain.to_ascii_lowercase().as_str() {
         "kusama" | "ksm" => {
@@ -104,6 +79,136 @@ pub fn run_archive(config: Config) -> Result<Box<dyn Archive<Block>>> {
     }
 }
 
+/// Start an archive indexing the blocks of `source`.
+/// Their execution is left to standalone workers, since the source has no state.
+pub fn run_block_file(
+    config: Config,
+    source: Arc<dyn ChainDataSource<Block>>,
+) -> Result<Box<dyn Archive<Block>>> {
+    match config.cli().chain.to_ascii_lowercase().as_str() {
+        "kusama" | "ksm" => {
+            let archive =
+                ArchiveBuilder::<Block, ksm_rt::RuntimeApi, polkadot_service::KusamaExecutor> {
+                    pg_url: config.psql_conf().map(|u| u.url()),
+                    block_workers: config.block_workers(),
+                    wasm_pages: config.wasm_pages(),
+                    ..ArchiveBuilder::default()
+                }
+                .chain_data_source(source)
+                .local_execution(false)
+                .build()?;
+            Ok(Box::new(archive))
+        }
+        "westend" => {
+            let archive = ArchiveBuilder::<
+                Block,
+                westend_rt::RuntimeApi,
+                polkadot_service::WestendExecutor,
+            > {
+                pg_url: config.psql_conf().map(|u| u.url()),
+                block_workers: config.block_workers(),
+                wasm_pages: config.wasm_pages(),
+                ..ArchiveBuilder::default()
+            }
+            .chain_data_source(source)
+            .local_execution(false)
+            .build()?;
+            Ok(Box::new(archive))
+        }
+        "polkadot" | "dot" => {
+            let archive =
+                ArchiveBuilder::<Block, dot_rt::RuntimeApi, polkadot_service::PolkadotExecutor> {
+                    pg_url: config.psql_conf().map(|u| u.url()),
+                    block_workers: config.block_workers(),
+                    wasm_pages: config.wasm_pages(),
+                    ..ArchiveBuilder::default()
+                }
+                .chain_data_source(source)
+                .local_execution(false)
+                .build()?;
+            Ok(Box::new(archive))
+        }
+        c => Err(anyhow!("unknown chain {}", c)),
+    }
+}
+
+/// Start a standalone worker executing the blocks queued by an archive of the chain
+pub fn run_worker(config: Config, id: Option<String>) -> Result<Worker> {
+    let spec = get_spec(config.cli().chain.as_str())?;
+    let db_path = chain_db_path(&config, spec.as_ref())?;
+
+    match config.cli().chain.to_ascii_lowercase().as_str() {
+        "kusama" | "ksm" => {
+            let builder =
+                ArchiveBuilder::<Block, ksm_rt::RuntimeApi, polkadot_service::KusamaExecutor> {
+                    pg_url: config.psql_conf().map(|u| u.url()),
+                    cache_size: config.cache_size(),
+                    block_workers: config.block_workers(),
+                    wasm_pages: config.wasm_pages(),
+                    worker_id: id,
+                    ..ArchiveBuilder::default()
+                };
+            Ok(builder.chain_data_db(db_path).build_worker()?)
+        }
+        "westend" => {
+            let builder = ArchiveBuilder::<
+                Block,
+                westend_rt::RuntimeApi,
+                polkadot_service::WestendExecutor,
+            > {
+                pg_url: config.psql_conf().map(|u| u.url()),
+                cache_size: config.cache_size(),
+                block_workers: config.block_workers(),
+                wasm_pages: config.wasm_pages(),
+                worker_id: id,
+                ..ArchiveBuilder::default()
+            };
+            Ok(builder.chain_data_db(db_path).build_worker()?)
+        }
+        "polkadot" | "dot" => {
+            let builder =
+                ArchiveBuilder::<Block, dot_rt::RuntimeApi, polkadot_service::PolkadotExecutor> {
+                    pg_url: config.psql_conf().map(|u| u.url()),
+                    cache_size: config.cache_size(),
+                    block_workers: config.block_workers(),
+                    wasm_pages: config.wasm_pages(),
+                    worker_id: id,
+                    ..ArchiveBuilder::default()
+                };
+            Ok(builder.chain_data_db(db_path).build_worker()?)
+        }
+        c => Err(anyhow!("unknown chain {}", c)),
+    }
+}
+
+/// Path to the RocksDB database of `spec` in the polkadot data directory
+fn chain_db_path(config: &Config, spec: &dyn ChainSpec) -> Result<String> {
+    let mut db_path = if let Some(p) = config.polkadot_path() {
+        p
+    } else {
+        let path = std::env::var("CHAIN_DATA_DB").expect("CHAIN_DATA_DB must be set.");
+        std::path::PathBuf::from(path)
+    };
+
+    let last_path_part = db_path
+        .file_name()
+        .context("Polkadot path not valid")?
+        .to_str()
+        .context("could not convert path to string")?;
+
+    match last_path_part {
+        "polkadot" => db_path.push(format!("chains/{}/db", spec.id())),
+        "chains" => db_path.push(format!("{}/db", spec.id())),
+        _ => return Err(anyhow!("invalid path {}", db_path.as_path().display())),
+    }
+
+    Ok(db_path
+        .as_path()
+        .to_str()
+        .context("could not convert rocksdb path to str")?
+        .to_string())
+}
+
 fn get_spec(chain: &str) -> Result<Box<dyn ChainSpec>> {
     match chain.to_ascii_lowercase().as_str() {
         "kusama" | "ksm" => {
@@ -121,4 +226,3 @@ fn get_spec(chain: &str) -> Result<Box<dyn ChainSpec>> {
         c => Err(anyhow!("unknown chain {}", c)),
     }
 }
-
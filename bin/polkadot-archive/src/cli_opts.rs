@@ -14,7 +14,7 @@
 // You should have received a copy of the GNU General Public License
 // along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
 
-use clap::{load_yaml, App};
+use clap::{load_yaml, value_t_or_exit, App};
 use std::path::PathBuf;
 
 #[derive(Debug, Clone)]
@@ -23,6 +23,25 @@ pub struct CliOpts {
     pub log_level: log::LevelFilter,
     pub log_num: u64,
     pub chain: String,
+    pub cmd: Option<Command>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Command {
+    /// export blocks `from..=to` into `out`
+    Export { from: u32, to: u32, out: PathBuf },
+    /// import the export in `dir`
+    Import { dir: PathBuf },
+    /// index the blocks in a block `file`
+    ImportBlocks {
+        file: PathBuf,
+        schedule: Option<PathBuf>,
+        metadata: Option<PathBuf>,
+    },
+    /// execute queued blocks as a standalone worker named `id`
+    Worker { id: Option<String> },
+    /// log the space saved by the deduplicated storage layout
+    StorageSpace,
 }
 
 impl CliOpts {
@@ -43,11 +62,33 @@ impl CliOpts {
             .value_of("chain")
             .unwrap_or("polkadot");
 
+        let cmd = match matches.subcommand() {
+            ("export", Some(m)) => Some(Command::Export {
+                from: value_t_or_exit!(m, "from", u32),
+                to: value_t_or_exit!(m, "to", u32),
+                out: PathBuf::from(m.value_of("out").expect("out is required")),
+            }),
+            ("import", Some(m)) => Some(Command::Import {
+                dir: PathBuf::from(m.value_of("dir").expect("dir is required")),
+            }),
+            ("import-blocks", Some(m)) => Some(Command::ImportBlocks {
+                file: PathBuf::from(m.value_of("file").expect("file is required")),
+                schedule: m.value_of("schedule").map(PathBuf::from),
+                metadata: m.value_of("metadata").map(PathBuf::from),
+            }),
+            ("worker", Some(m)) => Some(Command::Worker {
+                id: m.value_of("id").map(String::from),
+            }),
+            ("storage-space", Some(_)) => Some(Command::StorageSpace),
+            _ => None,
+        };
+
         CliOpts {
             file: file.map(|f| PathBuf::from(f)),
             log_level,
             log_num,
             chain: chain.to_string(),
+            cmd,
         }
     }
 }
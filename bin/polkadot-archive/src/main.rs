@@ -17,16 +17,30 @@ mod archive;
 mod cli_opts;
 mod config;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use cli_opts::Command;
+use polkadot_service::Block;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use substrate_archive::{block_file, export, queries};
 
 pub fn main() -> Result<()> {
     let config = config::Config::new()?;
     substrate_archive::init_logger(config.cli().log_level, log::LevelFilter::Debug);
 
+    if let Some(cmd) = config.cli().cmd.clone() {
+        return run_command(&config, cmd);
+    }
+
     let mut archive = archive::run_archive(config.clone())?;
     archive.drive()?;
+    wait_for_ctrlc();
+    archive.boxed_shutdown()?;
+
+    Ok(())
+}
+
+fn wait_for_ctrlc() {
     let running = Arc::new(AtomicBool::new(true));
     let r = running.clone();
 
@@ -35,7 +49,56 @@ pub fn main() -> Result<()> {
     })
     .expect("Error setting Ctrl-C handler");
     while running.load(Ordering::SeqCst) {}
-    archive.boxed_shutdown()?;
+}
 
+fn run_command(config: &config::Config, cmd: Command) -> Result<()> {
+    let url = match config.psql_conf() {
+        Some(conf) => conf.url(),
+        None => std::env::var("DATABASE_URL")?,
+    };
+    match cmd {
+        Command::Export { from, to, out } => {
+            let manifest = export::export_archive(&url, out.as_path(), from, to)?;
+            log::info!("{:?}", manifest);
+        }
+        Command::Import { dir } => {
+            let manifest = export::import_archive::<Block>(&url, dir.as_path())?;
+            log::info!("{:?}", manifest);
+        }
+        Command::ImportBlocks {
+            file,
+            schedule,
+            metadata,
+        } => {
+            let metadata = metadata
+                .map(|m| block_file::metadata_from_dir(m.as_path()))
+                .transpose()?
+                .unwrap_or_default();
+            let mut import =
+                block_file::BlockFileImport::<Block>::new(url.as_str(), file).metadata(metadata);
+            if let Some(schedule) = schedule {
+                import = import.schedule(block_file::SpecSchedule::from_json(schedule.as_path())?);
+            }
+            let source = import.source()?;
+            let last = source
+                .last_number()
+                .ok_or_else(|| anyhow!("the block file is empty"))?;
+            let mut archive = archive::run_block_file(config.clone(), Arc::new(source))?;
+            archive.drive()?;
+            block_file::wait_for_block(&url, last)?;
+            archive.boxed_shutdown()?;
+            log::info!("Imported blocks up to {}", last);
+        }
+        Command::Worker { id } => {
+            let worker = archive::run_worker(config.clone(), id)?;
+            log::info!("Running worker `{}`", worker.id());
+            wait_for_ctrlc();
+            worker.shutdown()?;
+        }
+        Command::StorageSpace => {
+            let space = queries::storage_space_at(&url)?;
+            log::info!("{:?}, saved {} bytes", space, space.saved_bytes());
+        }
+    }
     Ok(())
 }
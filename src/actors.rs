@@ -18,36 +18,92 @@
 
 mod actor_pool;
 // mod generators;
+mod latency;
 pub mod msg;
 mod workers;
 
 pub use self::actor_pool::ActorPool;
-use self::workers::GetState;
-pub use self::workers::{BlocksIndexer, DatabaseActor, StorageAggregator};
+pub use self::latency::{LatencyMetrics, TipLatency};
+use self::msg::SharedStorage;
+pub(crate) use self::workers::GetState;
+pub use self::workers::{
+    BalanceIndexer, BlocksIndexer, DatabaseActor, DroppedBlocks, IndexingMode, PendingMetrics,
+    PendingMonitor, StorageAggregator, StorageDecoder,
+};
 use super::{
-    backend::{ApiAccess, Meta, ReadOnlyBackend},
-    database::{queries, Channel, Listener},
+    backend::{ApiAccess, Meta, ReadOnlyBackend, StorageSource},
+    database::{
+        cursors::{self, Stage},
+        initial_sync::{self, Phase},
+        partitions, queries,
+        queue::{self, Priority, QueueStatus},
+        Channel, Database, Insert, Listener,
+    },
+    decode::RuntimeTypes,
     error::Result,
     sql_block_builder::BlockBuilder as SqlBlockBuilder,
     tasks::Environment,
-    types::Archive,
+    types::{Archive, CachedStorageProof},
 };
-use coil::Job as _;
-use futures::FutureExt;
+use futures::{channel::mpsc::UnboundedSender, FutureExt, StreamExt};
 use hashbrown::HashSet;
+use parking_lot::Mutex;
 use sc_client_api::backend;
 use serde::de::DeserializeOwned;
 use sp_api::{ApiExt, ConstructRuntimeApi};
 use sp_block_builder::BlockBuilder as BlockBuilderApi;
 use sp_runtime::traits::{Block as BlockT, Header as _, NumberFor};
+use sp_state_machine::StorageProof;
+use sqlx::{Connection, PgPool};
 use std::marker::PhantomData;
 use std::panic::AssertUnwindSafe;
 use std::sync::Arc;
+use std::time::Instant;
 use xtra::prelude::*;
 
+/// How long the execution loop waits for new blocks before looking for jobs to retry
+const IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(3600);
+/// How long the listener uses the best block number it read from the chain data
+const BEST_BLOCK_TTL: std::time::Duration = std::time::Duration::from_secs(1);
+/// Blocks made into jobs at a time, per worker executing blocks
+const JOBS_PER_WORKER: usize = 4;
+/// Blocks of archived storage sent to be decoded at a time, see `System::catch_up_decoding`
+const DECODE_BACKLOG_BLOCKS: usize = 64;
+
 // TODO: Split this up into two objects
 // System should be a factory that produces objects that should be spawned
 
+/// Options that change what the indexing engine archives
+#[derive(Debug, Clone, Default)]
+pub struct SystemConfig {
+    /// decode storage against metadata into the `storage_decoded` table
+    pub decode_storage: bool,
+    /// index account balances into the `account_balances` table
+    pub index_balances: bool,
+    /// definitions of the types that changed between runtime versions
+    pub runtime_types: RuntimeTypes,
+    /// store the value each changed key had before the block in `storage.old_storage`
+    pub old_storage: bool,
+    /// cache storage proofs in the `storage_proofs` table
+    pub cache_proofs: bool,
+    /// ingest without the indexes of `storage` until caught up with the chain
+    pub initial_sync: bool,
+    /// store every storage value once in `storage_values`
+    pub dedup_storage: bool,
+    /// where storage changes of blocks come from
+    pub storage_source: StorageSource,
+    /// skip storage of blocks whose execution results in the wrong state root
+    pub strict_state_root: bool,
+    /// bytes of blocks the crawler loads before they are inserted
+    pub crawl_memory: usize,
+    /// whether blocks ahead of finality are indexed
+    pub indexing_mode: IndexingMode,
+    /// blocks near the tip executed for every block of a backfill, while both are waiting
+    pub tip_weight: u32,
+    /// leave queued blocks to standalone workers instead of executing them
+    pub external_execution: bool,
+}
+
 /// Context that every actor may use
 #[derive(Clone)]
 pub struct ActorContext<B: BlockT + Unpin>
@@ -58,6 +114,9 @@ where
     pg_url: String,
     meta: Meta<B>,
     workers: usize,
+    config: SystemConfig,
+    latency: TipLatency,
+    pending: PendingMonitor,
 }
 
 impl<B: BlockT + Unpin> ActorContext<B>
@@ -69,12 +128,16 @@ where
         meta: Meta<B>,
         workers: usize,
         pg_url: String,
+        config: SystemConfig,
     ) -> Self {
         Self {
             backend,
             meta,
             workers,
             pg_url,
+            config,
+            latency: TipLatency::default(),
+            pending: PendingMonitor::default(),
         }
     }
 
@@ -88,17 +151,35 @@ where
     pub fn meta(&self) -> &Meta<B> {
         &self.meta
     }
+
+    pub fn config(&self) -> &SystemConfig {
+        &self.config
+    }
+
+    /// Latency of the archive following the tip of the chain
+    pub fn latency(&self) -> &TipLatency {
+        &self.latency
+    }
+
+    /// Inserts waiting for the rows they reference to be committed
+    pub fn pending(&self) -> &PendingMonitor {
+        &self.pending
+    }
 }
 
-struct Actors<B: BlockT + Unpin>
+struct Actors<B: BlockT + Unpin + DeserializeOwned>
 where
     B::Hash: Unpin,
     NumberFor<B>: Into<u32>,
 {
     storage: Address<workers::StorageAggregator<B>>,
+    decoder: Option<Address<workers::StorageDecoder<B>>>,
+    balances: Option<Address<workers::BalanceIndexer<B>>>,
     blocks: Address<workers::BlocksIndexer<B>>,
     metadata: Address<workers::Metadata<B>>,
     db_pool: Address<ActorPool<DatabaseActor<B>>>,
+    /// phase of an initial sync when the archive started
+    initial_sync: Phase,
 }
 
 /// Control the execution of the indexing engine.
@@ -112,6 +193,9 @@ where
     start_tx: flume::Sender<()>,
     kill_tx: flume::Sender<()>,
     context: ActorContext<B>,
+    /// connections for the status of the archive, and the `storage_proofs` cache if enabled.
+    /// Opened on first use
+    pool: Mutex<Option<PgPool>>,
     /// handle to the futures runtime indexing the running chain
     handle: jod_thread::JoinHandle<Result<()>>,
     _marker: PhantomData<(B, R, C)>,
@@ -148,17 +232,20 @@ where
         backend: Arc<ReadOnlyBackend<B>>,
         workers: usize,
         pg_url: &str,
+        config: SystemConfig,
     ) -> Result<Self> {
         let context = ActorContext::new(
             backend.clone(),
             client_api.clone(),
             workers,
             pg_url.to_string(),
+            config,
         );
         let (start_tx, kill_tx, handle) = Self::start(context.clone(), client_api);
 
         Ok(Self {
             context,
+            pool: Mutex::new(None),
             start_tx,
             kill_tx,
             handle,
@@ -166,6 +253,16 @@ where
         })
     }
 
+    /// Connect to Postgres the first time the pool is needed
+    async fn pool(&self) -> Result<PgPool> {
+        let cached = self.pool.lock().clone();
+        if let Some(pool) = cached {
+            return Ok(pool);
+        }
+        let pool = PgPool::connect(self.context.pg_url()).await?;
+        Ok(self.pool.lock().get_or_insert(pool).clone())
+    }
+
     fn drive(&self) {
         self.start_tx.send(()).expect("Could not start actors");
     }
@@ -204,11 +301,23 @@ where
             .await?
             .await?
             .pool();
-        let listener = Self::init_listeners(ctx.pg_url()).await?;
+        let (queued_tx, mut queued_rx) = futures::channel::mpsc::unbounded();
+        let listener = Self::init_listeners(ctx.pg_url(), ctx.backend().clone(), queued_tx).await?;
         let mut conn = pool.acquire().await?;
-        Self::restore_missing_storage(&mut *conn).await?;
-        let env =
-            Environment::<B, R, C>::new(ctx.backend().clone(), client, actors.storage.clone());
+        let legacy = partitions::legacy_max_block(&mut *conn).await?;
+        if legacy.is_some() {
+            partitions::spawn_legacy_migration(pool.clone());
+        }
+        let initial_sync = actors.initial_sync != Phase::Done;
+        Self::restore_missing_storage(&mut *conn, initial_sync, legacy).await?;
+        let env = Environment::<B, R, C>::new(
+            ctx.backend().clone(),
+            client,
+            actors.storage.clone(),
+            ctx.config().old_storage,
+            ctx.config().storage_source,
+            ctx.config().strict_state_root,
+        );
         let env = AssertUnwindSafe(env);
 
         let runner = coil::Runner::builder(env, crate::TaskExecutor, &pool)
@@ -217,17 +326,44 @@ where
             .max_tasks(500)
             .build()?;
 
+        let window = ctx.workers * JOBS_PER_WORKER;
+        let mut rebuild = actors.initial_sync;
         loop {
+            if !ctx.config().external_execution {
+                Self::schedule(&mut *conn, window, ctx.config().tip_weight).await?;
+            }
             let tasks = runner.run_all_sync_tasks().fuse();
             futures::pin_mut!(tasks);
             futures::select! {
                 t = tasks => {
                     if t? == 0 {
-                        smol::Timer::new(std::time::Duration::from_millis(3600)).await;
+                        if let Err(e) = Self::catch_up_decoding(&mut *conn, &actors).await {
+                            log::error!("{}", e.to_string());
+                        }
+                        // an interrupted rebuild resumes right away
+                        if rebuild != Phase::Done
+                            && (rebuild != Phase::Ingesting
+                                || Self::caught_up(&ctx, &mut *conn).await?)
+                        {
+                            log::info!("Initial sync caught up with the chain, rebuilding indexes");
+                            actors.db_pool.send(workers::FinishInitialSync.into()).await?.await?;
+                            rebuild = Phase::Done;
+                        }
+                        // wait for the listener to queue a block. Failed jobs are retried after a while
+                        let queued = queued_rx.next().fuse();
+                        let idle = smol::Timer::new(IDLE_TIMEOUT).fuse();
+                        futures::pin_mut!(queued, idle);
+                        futures::select! {
+                            _ = queued => (),
+                            _ = idle => (),
+                            _ = rx.recv_async() => break,
+                        }
                     }
                 },
                 _ = rx.recv_async() => break,
             }
+            // one run executes every job queued so far
+            while let Ok(Some(())) = queued_rx.try_next() {}
         }
         listener.kill_async().await;
         Self::kill_actors(actors).await?;
@@ -235,20 +371,57 @@ where
     }
 
     async fn spawn_actors(ctx: ActorContext<B>) -> Result<Actors<B>> {
-        let db = workers::DatabaseActor::<B>::new(ctx.pg_url().into()).await?;
+        let db = Database::new(ctx.pg_url().into()).await?;
+        let initial_sync =
+            initial_sync::start(&mut *db.conn().await?, ctx.config().initial_sync).await?;
+        let dropped = DroppedBlocks::default();
+        let db = workers::DatabaseActor::<B>::with_db(db)
+            .initial_sync(initial_sync)
+            .dedup_storage(ctx.config().dedup_storage)
+            .latency(ctx.latency().clone())
+            .pending_monitor(ctx.pending().clone())
+            .dropped_blocks(dropped.clone());
         let db_pool = actor_pool::ActorPool::new(db, 8).spawn();
-        let storage = workers::StorageAggregator::new(db_pool.clone()).spawn();
-        let metadata = workers::Metadata::new(db_pool.clone(), ctx.meta().clone())
-            .await?
-            .spawn();
-        let blocks =
-            workers::BlocksIndexer::new(ctx.backend().clone(), db_pool.clone(), metadata.clone())
+        let decoder = if ctx.config().decode_storage {
+            Some(workers::StorageDecoder::new(db_pool.clone()).await?.spawn())
+        } else {
+            None
+        };
+        let balances = if ctx.config().index_balances {
+            let types = ctx.config().runtime_types.clone();
+            Some(
+                workers::BalanceIndexer::new(db_pool.clone(), types)
+                    .await?
+                    .spawn(),
+            )
+        } else {
+            None
+        };
+        let storage =
+            workers::StorageAggregator::new(db_pool.clone(), decoder.clone(), balances.clone())
                 .spawn();
+        let metadata =
+            workers::Metadata::new(db_pool.clone(), ctx.meta().clone(), ctx.backend().clone())
+                .await?
+                .spawn();
+        let blocks = workers::BlocksIndexer::new(
+            ctx.backend().clone(),
+            db_pool.clone(),
+            metadata.clone(),
+            ctx.config().crawl_memory,
+            ctx.config().indexing_mode,
+            ctx.latency().clone(),
+            dropped,
+        )
+        .spawn();
         Ok(Actors {
             storage,
+            decoder,
+            balances,
             blocks,
             metadata,
             db_pool,
+            initial_sync,
         })
     }
 
@@ -259,18 +432,44 @@ where
             actors.metadata.send(msg::Die),
         ];
         futures::future::join_all(fut).await;
+        if let Some(decoder) = actors.decoder {
+            let _ = decoder.send(msg::Die).await;
+        }
+        if let Some(balances) = actors.balances {
+            let _ = balances.send(msg::Die).await;
+        }
         let _ = actors.db_pool.send(msg::Die.into()).await?.await;
         Ok(())
     }
 
-    async fn init_listeners(pg_url: &str) -> Result<Listener> {
+    /// Listen for new blocks, queue them to be executed and notify `queued`.
+    /// Blocks near the best block of the node are executed before a backfill.
+    /// The best block is read again once it is older than `BEST_BLOCK_TTL`
+    async fn init_listeners(
+        pg_url: &str,
+        backend: Arc<ReadOnlyBackend<B>>,
+        queued: UnboundedSender<()>,
+    ) -> Result<Listener> {
+        let best_block: Arc<Mutex<Option<(Instant, u32)>>> = Arc::new(Mutex::new(None));
         Listener::builder(pg_url, move |notif, conn| {
+            let queued = queued.clone();
+            let backend = backend.clone();
+            let best_block = best_block.clone();
             async move {
                 let block = queries::get_full_block_by_id(conn, notif.id).await?;
-                let b: (B, u32) = SqlBlockBuilder::with_single(block)?;
-                crate::tasks::execute_block::<B, R, C>(b.0, PhantomData)
-                    .enqueue(conn)
-                    .await?;
+                let num = block.block_num as u32;
+                let cached = *best_block.lock();
+                let best = match cached {
+                    // a block of the node is never above its best block
+                    Some((read, best)) if read.elapsed() < BEST_BLOCK_TTL => best.max(num),
+                    _ => {
+                        let best: u32 = smol::unblock!(backend.source().meta())?.best_number.into();
+                        *best_block.lock() = Some((Instant::now(), best));
+                        best
+                    }
+                };
+                queue::push(conn, &[num], Priority::of(num, best)).await?;
+                let _ = queued.unbounded_send(());
                 Ok(())
             }
             .boxed()
@@ -280,32 +479,86 @@ where
         .await
     }
 
+    /// Send archived storage that was not decoded, or not searched for balances, to be:
+    /// storage written by standalone workers, and storage whose decoding did not complete.
+    /// Waits for the decoder and balance indexer, so that only one backlog is sent at a time.
+    async fn catch_up_decoding(conn: &mut sqlx::PgConnection, actors: &Actors<B>) -> Result<()> {
+        if let Some(decoder) = &actors.decoder {
+            let storage =
+                queries::storage_behind::<B>(conn, Stage::Decoded, DECODE_BACKLOG_BLOCKS).await?;
+            if !storage.is_empty() {
+                decoder.send(SharedStorage(Arc::new(storage))).await?;
+            }
+        }
+        if let Some(balances) = &actors.balances {
+            let storage =
+                queries::storage_behind::<B>(conn, Stage::Balances, DECODE_BACKLOG_BLOCKS).await?;
+            if !storage.is_empty() {
+                balances.send(SharedStorage(Arc::new(storage))).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Make up to `window` queued blocks into jobs, blocks near the tip first
+    async fn schedule(conn: &mut sqlx::PgConnection, window: usize, tip_weight: u32) -> Result<()> {
+        let mut tx = conn.begin().await?;
+        let blocks = queue::take(&mut tx, window, tip_weight).await?;
+        if blocks.is_empty() {
+            return Ok(());
+        }
+        let jobs: Vec<crate::tasks::execute_block::Job<B, R, C>> =
+            SqlBlockBuilder::with_vec(blocks)?
+                .into_iter()
+                .map(|b| crate::tasks::execute_block::<B, R, C>(b.inner.block, PhantomData))
+                .collect();
+        coil::JobExt::enqueue_batch(jobs, &mut *tx).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Whether every block of the chain is indexed and no blocks are waiting to be executed
+    async fn caught_up(ctx: &ActorContext<B>, conn: &mut sqlx::PgConnection) -> Result<bool> {
+        let best: u32 = ctx.backend().source().meta()?.best_number.into();
+        let indexed = queries::max_block(&mut *conn)
+            .await?
+            .map(|max| max >= best)
+            .unwrap_or(false);
+        // failed jobs wait to be retried, and standalone workers may hold claims
+        Ok(indexed && queue::status(conn).await?.is_empty())
+    }
+
     /// Checks if any blocks that should be executed are missing
     /// from the task queue.
     /// If any are found, they are re-queued.
-    async fn restore_missing_storage(conn: &mut sqlx::PgConnection) -> Result<()> {
+    /// Blocks up to `legacy` keep their storage in `_storage_legacy` until it is moved.
+    async fn restore_missing_storage(
+        conn: &mut sqlx::PgConnection,
+        initial_sync: bool,
+        legacy: Option<u32>,
+    ) -> Result<()> {
         log::info!("Restoring missing storage entries...");
         let blocks: HashSet<u32> = queries::get_all_blocks::<B>(conn)
             .await?
             .map(|b| Ok((*b?.header().number()).into()))
             .collect::<Result<_>>()?;
-        let mut missing_storage_blocks = queries::blocks_storage_intersection(conn).await?;
-        let missing_storage_nums: HashSet<u32> = missing_storage_blocks
-            .iter()
-            .map(|b| b.block_num as u32)
-            .collect();
-        let difference: HashSet<u32> = missing_storage_nums
-            .difference(&blocks)
-            .map(|b| *b)
-            .collect();
-        missing_storage_blocks.retain(|b| difference.contains(&(b.block_num as u32)));
-        let jobs: Vec<crate::tasks::execute_block::Job<B, R, C>> =
-            SqlBlockBuilder::with_vec(missing_storage_blocks)?
+        // storage that was moved from `_pending_storage` or imported may not be counted yet.
+        // Storage has no indexes to advance with during an initial sync
+        let cursor = if initial_sync {
+            cursors::get(conn, Stage::Storage).await?
+        } else {
+            cursors::advance(conn, Stage::Storage).await?
+        };
+        log::info!("Storage is indexed up to {:?}", cursor);
+        let missing: Vec<u32> =
+            queries::blocks_storage_intersection(conn, cursor.max(legacy).unwrap_or(0))
+                .await?
                 .into_iter()
-                .map(|b| crate::tasks::execute_block::<B, R, C>(b.inner.block, PhantomData))
+                .map(|b| b.block_num as u32)
+                .filter(|n| !blocks.contains(n))
                 .collect();
-        log::info!("Restoring {} missing storage entries", jobs.len());
-        coil::JobExt::enqueue_batch(jobs, &mut *conn).await?;
+        log::info!("Restoring {} missing storage entries", missing.len());
+        queue::push(conn, &missing, Priority::Backfill).await?;
         log::info!("Storage restored");
         Ok(())
     }
@@ -342,7 +595,7 @@ where
     fn shutdown(self) -> Result<()> {
         let _ = self.kill_tx.send(());
         self.handle.join()?;
-        if let Some(c) = self.context.backend().backing_db().catch_up_count() {
+        if let Some(c) = self.context.backend().source().catch_up_count() {
             log::info!("Caught Up {} times", c);
         }
         Ok(())
@@ -351,7 +604,7 @@ where
     fn boxed_shutdown(self: Box<Self>) -> Result<()> {
         let _ = self.kill_tx.send(());
         self.handle.join()?;
-        if let Some(c) = self.context.backend().backing_db().catch_up_count() {
+        if let Some(c) = self.context.backend().source().catch_up_count() {
             log::info!("Caught Up {} times", c);
         }
         Ok(())
@@ -360,4 +613,32 @@ where
     fn context(&self) -> Result<super::actors::ActorContext<B>> {
         Ok(self.context.clone())
     }
+
+    async fn storage_proof(&self, hash: B::Hash, key: &[u8]) -> Result<Option<StorageProof>> {
+        let mut conn = if self.context.config().cache_proofs {
+            Some(self.pool().await?.acquire().await?)
+        } else {
+            None
+        };
+        if let Some(conn) = conn.as_mut() {
+            if let Some(proof) = queries::storage_proof(&mut *conn, hash.as_ref(), key).await? {
+                return Ok(Some(proof));
+            }
+        }
+
+        let backend = self.context.backend().clone();
+        let keys = vec![key.to_vec()];
+        let proof = smol::unblock!(backend.storage_proof(hash, &keys))?;
+        if let (Some(proof), Some(conn)) = (proof.as_ref(), conn.as_mut()) {
+            CachedStorageProof::new(hash.as_ref(), key, proof)
+                .insert(conn)
+                .await?;
+        }
+        Ok(proof)
+    }
+
+    async fn queue_status(&self) -> Result<QueueStatus> {
+        let mut conn = self.pool().await?.acquire().await?;
+        queue::status(&mut *conn).await
+    }
 }
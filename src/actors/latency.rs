@@ -0,0 +1,134 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Latency of following the tip of the chain.
+//! A block is seen once the database of the node has it as its best block,
+//! which is as close to its import as a secondary instance of the database can tell.
+
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Blocks tracked at once. Blocks of a backfill beyond this are not tracked.
+const MAX_TRACKED: u32 = 4096;
+
+/// Latencies from blocks being seen at the tip to them being archived
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LatencyMetrics {
+    /// blocks seen at the tip whose storage was archived
+    pub archived: u64,
+    /// from seen to inserted into `blocks`, of the last block inserted
+    pub last_indexed: Option<Duration>,
+    /// from seen to storage archived, of the last block archived
+    pub last_archived: Option<Duration>,
+    /// mean from seen to storage archived
+    pub mean_archived: Option<Duration>,
+    /// longest from seen to storage archived
+    pub max_archived: Option<Duration>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// when blocks that are not archived yet were seen
+    seen: BTreeMap<u32, Instant>,
+    total: Duration,
+    metrics: LatencyMetrics,
+}
+
+/// Tracks the latency of blocks between the actors of the archive.
+/// Read it from a running archive with `Archive::context` and `ActorContext::latency`
+#[derive(Clone, Default)]
+pub struct TipLatency {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl TipLatency {
+    /// Blocks `numbers` were just seen
+    pub fn seen(&self, numbers: RangeInclusive<u32>) {
+        let (start, end) = numbers.into_inner();
+        let start = std::cmp::max(start, end.saturating_sub(MAX_TRACKED - 1));
+        let now = Instant::now();
+        let mut inner = self.inner.lock();
+        for n in start..=end {
+            inner.seen.entry(n).or_insert(now);
+        }
+        while inner.seen.len() > MAX_TRACKED as usize {
+            let first = *inner.seen.keys().next().expect("not empty");
+            inner.seen.remove(&first);
+        }
+    }
+
+    /// Blocks `numbers` were inserted into `blocks`
+    pub fn indexed(&self, numbers: &[u32]) {
+        let mut inner = self.inner.lock();
+        let last = numbers
+            .iter()
+            .filter_map(|n| inner.seen.get(n).copied())
+            .max();
+        if let Some(seen) = last {
+            inner.metrics.last_indexed = Some(seen.elapsed());
+        }
+    }
+
+    /// The storage of blocks `numbers` was archived
+    pub fn archived(&self, numbers: &[u32]) {
+        let mut inner = self.inner.lock();
+        for n in numbers {
+            if let Some(seen) = inner.seen.remove(n) {
+                let latency = seen.elapsed();
+                inner.total += latency;
+                let m = &mut inner.metrics;
+                m.archived += 1;
+                m.last_archived = Some(latency);
+                m.max_archived = std::cmp::max(m.max_archived, Some(latency));
+            }
+        }
+        let archived = inner.metrics.archived as u32;
+        if archived > 0 {
+            inner.metrics.mean_archived = Some(inner.total / archived);
+        }
+    }
+
+    /// Latencies of the blocks archived so far
+    pub fn metrics(&self) -> LatencyMetrics {
+        self.inner.lock().metrics.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_track_latency_of_seen_blocks() {
+        let latency = TipLatency::default();
+        latency.seen(0..=10_000);
+        assert_eq!(latency.inner.lock().seen.len(), MAX_TRACKED as usize);
+
+        latency.indexed(&[10_000]);
+        latency.archived(&[9_999, 10_000, 1]);
+        let metrics = latency.metrics();
+        assert_eq!(metrics.archived, 2);
+        assert!(metrics.last_indexed.is_some());
+        assert!(metrics.mean_archived <= metrics.max_archived);
+
+        // blocks are only counted once
+        latency.archived(&[10_000]);
+        assert_eq!(latency.metrics().archived, 2);
+    }
+}
@@ -16,8 +16,13 @@
 
 //! Main messages and NewTypes that can be sent between actors
 
-use crate::{error::Result, types::*};
+use crate::{
+    decode::{AccountBalance, DecodedStorage, ExpandedMetadata},
+    error::Result,
+    types::*,
+};
 use sp_runtime::traits::Block as BlockT;
+use std::sync::Arc;
 use xtra::prelude::*;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -30,7 +35,15 @@ impl Message for Metadata {
     type Result = ();
 }
 
-impl<B: BlockT> Message for Block<B> {
+impl Message for RuntimeCode {
+    type Result = ();
+}
+
+impl Message for StateRootMismatch {
+    type Result = ();
+}
+
+impl Message for ExpandedMetadata {
     type Result = ();
 }
 
@@ -48,3 +61,35 @@ pub struct VecStorageWrap<B: BlockT>(pub Vec<Storage<B>>);
 impl<B: BlockT> Message for VecStorageWrap<B> {
     type Result = ();
 }
+
+/// Storage shared by the actors that only read it
+#[derive(Debug)]
+pub struct SharedStorage<B: BlockT>(pub Arc<Vec<Storage<B>>>);
+
+impl<B: BlockT> Message for SharedStorage<B> {
+    type Result = ();
+}
+
+/// Storage decoded from the storage of `blocks`
+#[derive(Debug)]
+pub struct VecDecodedStorage {
+    pub storage: Vec<DecodedStorage>,
+    /// number and hash of the blocks whose storage was decoded
+    pub blocks: Vec<(u32, Vec<u8>)>,
+}
+
+impl Message for VecDecodedStorage {
+    type Result = ();
+}
+
+/// Balances found in the storage of `blocks`
+#[derive(Debug, Clone)]
+pub struct VecAccountBalance {
+    pub balances: Vec<AccountBalance>,
+    /// number and hash of the blocks whose storage was searched
+    pub blocks: Vec<(u32, Vec<u8>)>,
+}
+
+impl Message for VecAccountBalance {
+    type Result = ();
+}
@@ -14,17 +14,56 @@
 // You should have received a copy of the GNU General Public License
 // along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
 
+mod balances;
 mod blocks;
 mod database;
 mod metadata;
 mod storage_aggregator;
+mod storage_decoder;
 
 /// Database message to get state internal database state
 pub use self::database::*;
 pub use self::metadata::*;
+pub use balances::*;
 pub use blocks::*;
 pub use database::*;
 pub use storage_aggregator::*;
+pub use storage_decoder::*;
 
 use super::actor_pool::ActorPool;
 use super::msg::Die;
+use crate::database::DbConn;
+use crate::error::Result;
+use crate::queries;
+use crate::types::Storage;
+use sp_runtime::traits::Block as BlockT;
+use xtra::prelude::*;
+
+/// Wait for the blocks of `storage` to be committed, then find the runtime version of each.
+/// Storage of blocks that are not indexed, or were indexed with another hash, is left out:
+/// it stays behind the cursor of its stage, and is picked up from the archived storage.
+async fn storage_specs<'a, B: BlockT + Unpin>(
+    db: &Address<ActorPool<DatabaseActor<B>>>,
+    conn: &mut DbConn,
+    storage: &'a [Storage<B>],
+) -> Result<Vec<(&'a Storage<B>, u32)>>
+where
+    B::Hash: Unpin,
+{
+    // storage may arrive before its block is indexed
+    let mut nums: Vec<u32> = storage.iter().map(|s| s.block_num()).collect();
+    nums.sort_unstable();
+    nums.dedup();
+    let committed = db.send(AwaitBlocks(nums).into()).await?.await?;
+    if committed.await.is_err() {
+        log::warn!("Blocks were not indexed in time, their storage is picked up later");
+    }
+
+    let mut specs = Vec::with_capacity(storage.len());
+    for s in storage.iter() {
+        if let Some(spec) = queries::block_spec(conn, s.hash().as_ref()).await? {
+            specs.push((s, spec));
+        }
+    }
+    Ok(specs)
+}
@@ -0,0 +1,125 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Picks account balance changes out of storage for the `account_balances` table,
+//! decoding them with the types of the runtime version of their block
+
+use super::{database::GetState, ActorPool, DatabaseActor};
+use crate::actors::msg::{SharedStorage, VecAccountBalance};
+use crate::database::DbConn;
+use crate::decode::{self, BalanceDecoder, RuntimeTypes};
+use crate::error::Result;
+use crate::queries;
+use crate::types::Storage;
+use hashbrown::HashMap;
+use sp_runtime::traits::Block as BlockT;
+use xtra::prelude::*;
+
+pub struct BalanceIndexer<B: BlockT + Unpin> {
+    db: Address<ActorPool<DatabaseActor<B>>>,
+    conn: DbConn,
+    types: RuntimeTypes,
+    /// decoders per runtime version. `None` if the metadata could not be expanded.
+    decoders: HashMap<u32, Option<BalanceDecoder>>,
+}
+
+impl<B: BlockT + Unpin> BalanceIndexer<B>
+where
+    B::Hash: Unpin,
+{
+    pub async fn new(
+        db: Address<ActorPool<DatabaseActor<B>>>,
+        types: RuntimeTypes,
+    ) -> Result<Self> {
+        let conn = db.send(GetState::Conn.into()).await?.await?.conn();
+        Ok(Self {
+            db,
+            conn,
+            types,
+            decoders: HashMap::new(),
+        })
+    }
+
+    async fn load_decoder(&mut self, spec: u32) -> Result<()> {
+        if self.decoders.contains_key(&spec) {
+            return Ok(());
+        }
+        let decoder = match queries::get_metadata(&mut self.conn, spec).await? {
+            Some(meta) => match decode::expand_metadata(spec, &meta) {
+                Ok(expanded) => Some(BalanceDecoder::new(&expanded, self.types.for_spec(spec))),
+                Err(e) => {
+                    log::warn!("Not indexing balances of version {}: {}", spec, e);
+                    None
+                }
+            },
+            None => None,
+        };
+        self.decoders.insert(spec, decoder);
+        Ok(())
+    }
+
+    async fn index(&mut self, storage: &[Storage<B>]) -> Result<()> {
+        let specs = super::storage_specs(&self.db, &mut self.conn, storage).await?;
+
+        let mut balances = Vec::new();
+        let mut blocks = Vec::new();
+        for (s, spec) in specs {
+            let hash = s.hash().as_ref();
+            blocks.push((s.block_num(), hash.to_vec()));
+            self.load_decoder(spec).await?;
+            if let Some(Some(decoder)) = self.decoders.get(&spec) {
+                balances.extend(s.changes().iter().filter_map(|(key, value)| {
+                    decoder.decode(
+                        s.block_num(),
+                        hash,
+                        key.0.as_slice(),
+                        value.as_ref().map(|v| v.0.as_slice()),
+                    )
+                }));
+            }
+        }
+        if !blocks.is_empty() {
+            let balances = VecAccountBalance { balances, blocks };
+            self.db.send(balances.into()).await?;
+        }
+        Ok(())
+    }
+}
+
+impl<B: BlockT + Unpin> Actor for BalanceIndexer<B> where B::Hash: Unpin {}
+
+#[async_trait::async_trait]
+impl<B: BlockT + Unpin> Handler<SharedStorage<B>> for BalanceIndexer<B>
+where
+    B::Hash: Unpin,
+{
+    async fn handle(&mut self, storage: SharedStorage<B>, _: &mut Context<Self>) {
+        if let Err(e) = self.index(&storage.0).await {
+            log::error!("{}", e.to_string());
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<B: BlockT + Unpin> Handler<super::Die> for BalanceIndexer<B>
+where
+    B::Hash: Unpin,
+{
+    async fn handle(&mut self, _: super::Die, ctx: &mut Context<Self>) -> Result<()> {
+        ctx.stop();
+        Ok(())
+    }
+}
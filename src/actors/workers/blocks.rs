@@ -15,20 +15,70 @@
 
 use super::{ActorPool, DatabaseActor, GetState, Metadata};
 use crate::{
+    actors::TipLatency,
     backend::{ReadOnlyBackend, RuntimeVersionCache},
-    database::queries,
-    error::Result,
-    types::{BatchBlock, Block},
+    database::{
+        cursors::{self, Stage},
+        finality, queries,
+    },
+    error::{Error, Result},
+    types::BatchBlock,
 };
+use codec::Encode;
+use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
 use sp_runtime::{
-    generic::SignedBlock,
+    generic::{BlockId, SignedBlock},
     traits::{Block as BlockT, Header as _, NumberFor},
 };
 use std::sync::Arc;
+use std::time::Duration;
 use xtra::prelude::*;
 
 type DatabaseAct<B> = Address<ActorPool<DatabaseActor<B>>>;
 
+/// Most blocks in a batch, regardless of their size
+const MAX_BATCH_BLOCKS: usize = 10_000;
+/// Batches in memory at once: one being loaded, one waiting, and one being indexed
+const BATCHES_IN_MEMORY: usize = 3;
+/// How often the database of the node is checked for a new best block
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Which blocks of the chain are indexed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexingMode {
+    /// only blocks up to the last finalized block, which are never reverted
+    Finalized,
+    /// blocks up to the best block. Blocks ahead of finality are marked as not finalized,
+    /// and are confirmed once finality reaches them, or rolled back if they leave the canon chain
+    Best,
+}
+
+impl Default for IndexingMode {
+    fn default() -> Self {
+        IndexingMode::Best
+    }
+}
+
+/// The lowest block dropped by the `DatabaseActor` before it was inserted,
+/// which the `BlocksIndexer` crawls again from
+#[derive(Clone, Default)]
+pub struct DroppedBlocks {
+    lowest: Arc<Mutex<Option<u32>>>,
+}
+
+impl DroppedBlocks {
+    /// Blocks `numbers` were dropped
+    pub fn dropped(&self, numbers: impl Iterator<Item = u32>) {
+        let mut lowest = self.lowest.lock();
+        *lowest = numbers.chain(*lowest).min();
+    }
+
+    fn take(&self) -> Option<u32> {
+        self.lowest.lock().take()
+    }
+}
+
 pub struct BlocksIndexer<B: BlockT>
 where
     NumberFor<B>: Into<u32>,
@@ -40,82 +90,211 @@ where
     db: DatabaseAct<B>,
     meta: Address<Metadata<B>>,
     rt_cache: RuntimeVersionCache<B>,
-    /// the last maximum block number from which we are sure every block before then is indexed
-    last_max: u32,
+    /// the highest block number sent to be indexed, `None` before the first block.
+    /// Blocks below it that are missing are found from the cursor of `Stage::Blocks` on start
+    last_max: Option<u32>,
+    /// encoded size at which a batch of blocks is sent to be indexed
+    batch_bytes: usize,
+    mode: IndexingMode,
+    /// number and hash of the block indexed up to, and the finalized block number, at the last check
+    head: Option<(u32, B::Hash, u32)>,
+    latency: TipLatency,
+    dropped: DroppedBlocks,
 }
 
-impl<B: BlockT + Unpin> BlocksIndexer<B>
+impl<B: BlockT + Unpin + DeserializeOwned> BlocksIndexer<B>
 where
     B::Hash: Unpin,
     NumberFor<B>: Into<u32>,
 {
+    /// Crawl blocks with at most about `memory` bytes of blocks loaded
+    /// but not yet indexed at a time
     pub fn new(
         backend: Arc<ReadOnlyBackend<B>>,
         db_addr: DatabaseAct<B>,
         meta: Address<Metadata<B>>,
+        memory: usize,
+        mode: IndexingMode,
+        latency: TipLatency,
+        dropped: DroppedBlocks,
     ) -> Self {
         Self {
             rt_cache: RuntimeVersionCache::new(backend.clone()),
-            last_max: 0,
+            last_max: None,
+            batch_bytes: std::cmp::max(memory / BATCHES_IN_MEMORY, 1),
+            mode,
+            head: None,
+            latency,
+            dropped,
             backend,
             db: db_addr,
             meta,
         }
     }
 
-    /// A async wrapper around the backend fn `iter_blocks` which
-    /// runs in a `spawn_blocking` async task (its own thread)
-    async fn collect_blocks(
-        &self,
-        fun: impl Fn(u32) -> bool + Send + 'static,
-    ) -> Result<Vec<Block<B>>> {
+    /// Load the blocks with `numbers` in batches, on a blocking thread,
+    /// and send each batch to be indexed once the one before it is committed.
+    /// Loading waits while a batch is waiting to be sent, so memory stays bounded
+    /// however far behind the archive is.
+    /// Blocks above `finalized` are inserted as not finalized.
+    /// Returns the highest block number sent.
+    async fn index_blocks(
+        &mut self,
+        numbers: impl Iterator<Item = u32> + Send + 'static,
+        finalized: u32,
+    ) -> Result<Option<u32>> {
+        let (tx, rx) = flume::bounded::<Vec<SignedBlock<B>>>(1);
         let backend = self.backend.clone();
-        let now = std::time::Instant::now();
-        let gather_blocks = move || -> Result<Vec<SignedBlock<B>>> {
-            Ok(backend
-                .iter_blocks(|n| fun(n))?
-                .enumerate()
-                .map(|(_, b)| b)
-                .collect())
+        let batch_bytes = self.batch_bytes;
+        let load = move || -> Result<()> {
+            let mut batch = Vec::new();
+            let mut bytes = 0;
+            for block in backend.blocks_by_number(numbers) {
+                bytes += block.encoded_size();
+                batch.push(block);
+                if bytes >= batch_bytes || batch.len() >= MAX_BATCH_BLOCKS {
+                    bytes = 0;
+                    if tx.send(std::mem::take(&mut batch)).is_err() {
+                        // indexing failed, nothing is waiting for more blocks
+                        return Ok(());
+                    }
+                }
+            }
+            if !batch.is_empty() {
+                let _ = tx.send(batch);
+            }
+            Ok(())
         };
-        let blocks = smol::unblock!(gather_blocks())?;
-        log::info!("Took {:?} to load {} blocks", now.elapsed(), blocks.len());
-        let cache = self.rt_cache.clone();
-        let blocks = smol::unblock!(cache.find_versions_as_blocks(blocks))?;
-        Ok(blocks)
+        let loader = smol::Task::spawn(async move { smol::unblock!(load()) });
+
+        let mut max = None;
+        while let Ok(blocks) = rx.recv_async().await {
+            let now = std::time::Instant::now();
+            let len = blocks.len();
+            let cache = self.rt_cache.clone();
+            let blocks = smol::unblock!(cache.find_versions_as_blocks(blocks))?;
+            let batch_max = blocks
+                .iter()
+                .map(|b| (*b.inner.block.header().number()).into())
+                .max();
+            // resolves once the blocks are committed, or wait on their metadata
+            let batch = BatchBlock::new(blocks).finalized_up_to(finalized);
+            self.meta.send(batch).await?;
+            max = std::cmp::max(max, batch_max);
+            if len > 1000 {
+                log::info!("Took {:?} to index {} blocks", now.elapsed(), len);
+            } else {
+                log::debug!("Took {:?} to index {} blocks", now.elapsed(), len);
+            }
+        }
+        loader.await?;
+        Ok(max)
     }
 
     /// First run of indexing
-    /// gets any blocks that are missing from database and indexes those.
+    /// gets any blocks above the persisted cursor that are missing from database and indexes those.
     /// sets the `last_max` value.
-    async fn re_index(&mut self) -> Result<Option<Vec<Block<B>>>> {
+    async fn re_index(&mut self) -> Result<()> {
+        let source = self.backend.source();
+        let finalized: u32 = smol::unblock!(source.meta())?.finalized_number.into();
         let mut conn = self.db.send(GetState::Conn.into()).await?.await?.conn();
-        let numbers = queries::missing_blocks_min_max(&mut conn, self.last_max).await?;
-        let len = numbers.len();
-        log::info!("{} missing blocks", len);
+        // catch up with blocks that were inserted without the indexer, for instance by an import
+        let cursor = cursors::advance(&mut conn, Stage::Blocks).await?;
+        log::info!("Blocks are indexed up to {:?}", cursor);
+        let from = cursor.map(|c| c + 1).unwrap_or(0);
+        let mut numbers: Vec<u32> = queries::missing_blocks_min_max(&mut conn, from)
+            .await?
+            .into_iter()
+            .filter(|n| self.mode == IndexingMode::Best || *n <= finalized)
+            .collect();
+        log::info!("{} missing blocks", numbers.len());
         self.last_max = if let Some(m) = queries::max_block(&mut conn).await? {
-            m
+            Some(m)
         } else {
             // a `None` means that the blocks table is not populated yet
-            return Ok(None);
+            return Ok(());
         };
-        let blocks = self.collect_blocks(move |n| numbers.contains(&n)).await?;
-        Ok(Some(blocks))
+        std::mem::drop(conn);
+        if !numbers.is_empty() {
+            numbers.sort_unstable();
+            self.index_blocks(numbers.into_iter(), finalized).await?;
+        }
+        Ok(())
     }
 
-    async fn crawl(&mut self) -> Result<Vec<Block<B>>> {
-        let copied_last_max = self.last_max;
-        let blocks = self.collect_blocks(move |n| n > copied_last_max).await?;
-        self.last_max = blocks
-            .iter()
-            .map(|b| (*b.inner.block.header().number()).into())
-            .fold(self.last_max, |ac, e| if e > ac { e } else { ac });
-        Ok(blocks)
+    /// Index the blocks above `last_max` if the block to index up to changed since the last check:
+    /// the best block, or the finalized block with `IndexingMode::Finalized`.
+    async fn follow(&mut self) -> Result<()> {
+        if let Some(lowest) = self.dropped.take() {
+            // the gap is below `last_max`, where crawling does not go back to
+            log::warn!("Indexing missing blocks again from #{}", lowest);
+            self.last_max = std::cmp::min(self.last_max, lowest.checked_sub(1));
+            self.re_index().await?;
+        }
+        let source = self.backend.source();
+        let meta = smol::unblock!({
+            source.catch_up()?;
+            source.meta()
+        })?;
+        let finalized = meta.finalized_number.into();
+        let head = match self.mode {
+            IndexingMode::Finalized => (finalized, meta.finalized_hash, finalized),
+            IndexingMode::Best => (meta.best_number.into(), meta.best_hash, finalized),
+        };
+        if self.head == Some(head) {
+            return Ok(());
+        }
+        if let Some(reverted) = self.settle(finalized).await? {
+            // index the blocks that replaced the ones rolled back
+            self.last_max = reverted.checked_sub(1);
+        }
+        // the genesis block is indexed as well if nothing was indexed yet
+        let from = self.last_max.map(|m| m + 1).unwrap_or(0);
+        if head.0 >= from {
+            // blocks found on the first check are a backlog rather than the tip
+            if self.head.is_some() {
+                self.latency.seen(from..=head.0);
+            }
+            let max = self.index_blocks(from..=head.0, finalized).await?;
+            self.last_max = std::cmp::max(self.last_max, max);
+        }
+        self.head = Some(head);
+        Ok(())
+    }
+
+    /// Confirm the blocks that finality reached, and roll back the blocks that are not
+    /// in the canon chain anymore. Returns the lowest block number rolled back, if any.
+    async fn settle(&mut self, finalized: u32) -> Result<Option<u32>> {
+        let mut conn = self.db.send(GetState::Conn.into()).await?.await?.conn();
+        let unfinalized = finality::unfinalized(&mut conn).await?;
+        let source = self.backend.source();
+        let reverted = smol::unblock!(unfinalized
+            .into_iter()
+            .filter_map(
+                |(num, hash)| match source.header(BlockId::Number(num.into())) {
+                    Ok(Some(header)) if header.hash().as_ref() == hash.as_slice() => None,
+                    Ok(_) => Some(Ok((num, hash))),
+                    Err(e) => Some(Err(e)),
+                }
+            )
+            .collect::<Result<Vec<_>>>())?;
+        let from = reverted.first().map(|(num, _)| *num);
+        if let Some(from) = from {
+            log::warn!(
+                "Rolling back {} blocks from #{} that left the canon chain",
+                reverted.len(),
+                from
+            );
+            let nums: Vec<u32> = reverted.iter().map(|(num, _)| *num).collect();
+            finality::roll_back::<B>(&mut conn, &nums).await?;
+        }
+        finality::settle(&mut conn, finalized).await?;
+        Ok(from)
     }
 }
 
 #[async_trait::async_trait]
-impl<B: BlockT> Actor for BlocksIndexer<B>
+impl<B: BlockT + DeserializeOwned> Actor for BlocksIndexer<B>
 where
     NumberFor<B>: Into<u32>,
     B: Unpin,
@@ -124,36 +303,42 @@ where
     async fn started(&mut self, ctx: &mut Context<Self>) {
         // using this instead of notify_immediately because
         // ReIndexing is async process
-        ctx.address()
-            .expect("Actor just started")
-            .do_send(ReIndex)
+        let addr = ctx.address().expect("Actor just started");
+        addr.do_send(ReIndex)
+            .expect("Actor cannot be disconnected; just started");
+        addr.do_send(Follow)
             .expect("Actor cannot be disconnected; just started");
-
-        ctx.notify_interval(std::time::Duration::from_secs(5), || Crawl);
     }
 }
 
-struct Crawl;
-impl Message for Crawl {
+/// Check for new blocks, and schedule the next check once done.
+/// Unlike an interval, checks do not pile up while a backlog is indexed
+struct Follow;
+impl Message for Follow {
     type Result = ();
 }
 
 #[async_trait::async_trait]
-impl<B: BlockT + Unpin> Handler<Crawl> for BlocksIndexer<B>
+impl<B: BlockT + Unpin + DeserializeOwned> Handler<Follow> for BlocksIndexer<B>
 where
     NumberFor<B>: Into<u32>,
     B::Hash: Unpin,
 {
-    async fn handle(&mut self, _: Crawl, ctx: &mut Context<Self>) {
-        match self.crawl().await {
-            Err(e) => log::error!("{}", e.to_string()),
-            Ok(b) => {
-                if !b.is_empty() {
-                    if let Err(_) = self.meta.send(BatchBlock::new(b)).await {
-                        ctx.stop();
-                    }
-                }
+    async fn handle(&mut self, _: Follow, ctx: &mut Context<Self>) {
+        match self.follow().await {
+            Err(Error::Disconnected) => {
+                ctx.stop();
+                return;
             }
+            Err(e) => log::error!("{}", e.to_string()),
+            Ok(()) => (),
+        }
+        if let Ok(addr) = ctx.address() {
+            smol::Task::spawn(async move {
+                smol::Timer::new(FOLLOW_INTERVAL).await;
+                let _ = addr.do_send(Follow);
+            })
+            .detach();
         }
     }
 }
@@ -164,28 +349,22 @@ impl Message for ReIndex {
 }
 
 #[async_trait::async_trait]
-impl<B: BlockT + Unpin> Handler<ReIndex> for BlocksIndexer<B>
+impl<B: BlockT + Unpin + DeserializeOwned> Handler<ReIndex> for BlocksIndexer<B>
 where
     NumberFor<B>: Into<u32>,
     B::Hash: Unpin,
 {
     async fn handle(&mut self, _: ReIndex, ctx: &mut Context<Self>) {
         match self.re_index().await {
-            Ok(Some(b)) => {
-                if let Err(_) = self.meta.send(BatchBlock::new(b)).await {
-                    ctx.stop();
-                }
-            }
-            Ok(None) => {
-                return;
-            }
+            Err(Error::Disconnected) => ctx.stop(),
             Err(e) => log::error!("{}", e.to_string()),
+            Ok(()) => (),
         }
     }
 }
 
 #[async_trait::async_trait]
-impl<B: BlockT + Unpin> Handler<super::Die> for BlocksIndexer<B>
+impl<B: BlockT + Unpin + DeserializeOwned> Handler<super::Die> for BlocksIndexer<B>
 where
     NumberFor<B>: Into<u32>,
     B::Hash: Unpin,
@@ -195,3 +374,134 @@ where
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actors::msg::Die;
+    use crate::backend::runtime_api_with_source;
+    use crate::harness::{run_archive_with, Executor, TempDatabase, TestChain, Until};
+    use node_template_runtime::{opaque::Block, RuntimeApi};
+    use sqlx::{Connection, PgConnection};
+
+    const BLOCKS: u32 = 10;
+
+    fn finalized_blocks(db: &TempDatabase) -> Vec<(i32, bool)> {
+        smol::block_on(async {
+            let mut conn = PgConnection::connect(&db.url).await.unwrap();
+            sqlx::query_as("SELECT block_num, finalized FROM blocks ORDER BY block_num")
+                .fetch_all(&mut conn)
+                .await
+                .unwrap()
+        })
+    }
+
+    #[test]
+    fn should_index_blocks_of_in_memory_source() {
+        crate::initialize();
+        let chain = TestChain::generate(BLOCKS).unwrap();
+        let db = TempDatabase::new().unwrap();
+        let source = Arc::new(chain.in_memory().unwrap());
+        let backend = Arc::new(ReadOnlyBackend::<Block>::with_source(source.clone()));
+        let client = Arc::new(
+            runtime_api_with_source::<Block, RuntimeApi, Executor>(source, 1, 256).unwrap(),
+        );
+
+        smol::run(async {
+            crate::migrations::migrate(&db.url).await.unwrap();
+            let db_pool =
+                ActorPool::new(DatabaseActor::new(db.url.clone()).await.unwrap(), 2).spawn();
+            let meta = Metadata::new(db_pool.clone(), client, backend.clone())
+                .await
+                .unwrap()
+                .spawn();
+            let indexer = BlocksIndexer::new(
+                backend,
+                db_pool.clone(),
+                meta.clone(),
+                1024 * 1024,
+                IndexingMode::Best,
+                TipLatency::default(),
+                DroppedBlocks::default(),
+            )
+            .spawn();
+            Until::Cursor(Stage::Blocks, BLOCKS)
+                .wait(&db.url)
+                .await
+                .unwrap();
+
+            let mut conn = PgConnection::connect(&db.url).await.unwrap();
+            let blocks: Vec<(i32, Vec<u8>, i32)> = sqlx::query_as(
+                "SELECT block_num, hash, spec FROM blocks WHERE block_num > 0 ORDER BY block_num",
+            )
+            .fetch_all(&mut conn)
+            .await
+            .unwrap();
+            assert_eq!(blocks.len(), BLOCKS as usize);
+            for ((num, hash, spec), expected) in blocks.into_iter().zip(chain.hashes.iter()) {
+                assert_eq!(hash.as_slice(), expected.as_ref(), "hash of block {}", num);
+                assert_eq!(spec as u32, node_template_runtime::VERSION.spec_version);
+            }
+            let metadata = cursors::get(&mut conn, Stage::Metadata).await.unwrap();
+            assert_eq!(metadata, Some(BLOCKS));
+
+            let _ = indexer.send(Die).await;
+            let _ = meta.send(Die).await;
+            let _ = db_pool.send(Die.into()).await;
+        });
+    }
+
+    #[test]
+    fn should_crawl_in_bounded_batches() {
+        crate::initialize();
+        let chain = TestChain::generate(BLOCKS).unwrap();
+        let db = TempDatabase::new().unwrap();
+
+        // every block is a batch of its own
+        run_archive_with(
+            &chain,
+            &db,
+            |builder| builder.crawl_memory(1),
+            Until::Count("SELECT COUNT(*) FROM blocks", BLOCKS as i64 + 1),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn should_only_index_finalized_blocks() {
+        crate::initialize();
+        let chain = TestChain::generate_finalized(BLOCKS, BLOCKS / 2).unwrap();
+        let db = TempDatabase::new().unwrap();
+
+        run_archive_with(
+            &chain,
+            &db,
+            |builder| builder.indexing_mode(IndexingMode::Finalized),
+            Until::Cursor(Stage::Storage, BLOCKS / 2),
+        )
+        .unwrap();
+
+        let blocks = finalized_blocks(&db);
+        assert_eq!(blocks.len(), BLOCKS as usize / 2 + 1);
+        assert!(blocks.iter().all(|(_, finalized)| *finalized));
+    }
+
+    #[test]
+    fn should_mark_blocks_ahead_of_finality() {
+        crate::initialize();
+        let chain = TestChain::generate_finalized(BLOCKS, BLOCKS / 2).unwrap();
+        let db = TempDatabase::new().unwrap();
+
+        run_archive_with(
+            &chain,
+            &db,
+            |builder| builder.indexing_mode(IndexingMode::Best),
+            Until::Cursor(Stage::Blocks, BLOCKS),
+        )
+        .unwrap();
+
+        for (num, finalized) in finalized_blocks(&db) {
+            assert_eq!(finalized, num <= BLOCKS as i32 / 2, "block {}", num);
+        }
+    }
+}
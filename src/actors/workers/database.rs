@@ -14,122 +14,448 @@
 // You should have received a copy of the GNU General Public License
 // along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
 
-use crate::actors::msg::VecStorageWrap;
-use crate::database::{Database, DbConn, StorageModel};
-use crate::error::Result;
+mod pending;
+
+use self::pending::Pending;
+pub use self::pending::{PendingMetrics, PendingMonitor};
+use super::DroppedBlocks;
+use crate::actors::{
+    msg::{VecAccountBalance, VecDecodedStorage, VecStorageWrap},
+    TipLatency,
+};
+use crate::database::{
+    cursors::{self, Stage},
+    initial_sync::{self, Phase},
+    release_pending_storage, Database, DbConn, DedupStorage, PendingStorage, StageBlocks,
+    StorageModel, UncheckedStorage,
+};
+use crate::decode::ExpandedMetadata;
+use crate::error::{Error, Result};
 use crate::queries;
-use crate::types::{BatchBlock, Block, Metadata, Storage};
-use sp_runtime::traits::{Block as BlockT, NumberFor};
-use std::marker::PhantomData;
+use crate::types::{BatchBlock, Metadata, RuntimeCode, StateRootMismatch, Storage};
+use futures::channel::oneshot;
+use hashbrown::{HashMap, HashSet};
+use parking_lot::Mutex;
+use sp_runtime::{
+    traits::{Block as BlockT, Header as _, NumberFor},
+    SaturatedConversion,
+};
+use sqlx::Connection;
+use std::sync::Arc;
 use std::time::Duration;
 use xtra::prelude::*;
 
+/// How long an insert may wait in memory for the rows it references
+const PENDING_TIMEOUT: Duration = Duration::from_secs(60);
+/// Maximum number of inserts waiting in memory.
+/// Storage that does not fit is written to the `_pending_storage` table instead,
+/// and balances that do not fit are indexed again from archived storage
+const PENDING_CAPACITY: usize = 1024;
+/// How often waiting inserts are checked against the database
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// An insert that references rows which are not committed yet
+enum Waiting<B: BlockT> {
+    /// blocks wait on the metadata of their runtime version
+    Blocks(BatchBlock<B>),
+    /// storage and balances wait on their block
+    Storage(Vec<StorageModel<B>>),
+    Balances(VecAccountBalance),
+    /// an actor waiting for blocks to be committed
+    Notify(oneshot::Sender<()>),
+}
+
+impl<B: BlockT> Waiting<B> {
+    /// A copy to retry the insert with if it fails. `None` for waits, which cannot fail
+    fn try_clone(&self) -> Option<Self> {
+        match self {
+            Waiting::Blocks(blks) => Some(Waiting::Blocks(blks.clone())),
+            Waiting::Storage(storage) => Some(Waiting::Storage(storage.clone())),
+            Waiting::Balances(balances) => Some(Waiting::Balances(balances.clone())),
+            Waiting::Notify(_) => None,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct DatabaseActor<B: BlockT> {
     db: Database,
-    _marker: PhantomData<B>,
+    /// inserts waiting on other inserts, shared by all actors of a pool
+    pending: Arc<Mutex<Pending<Waiting<B>>>>,
+    /// phase of an initial sync, which decides how storage is inserted
+    initial_sync: Arc<Mutex<Phase>>,
+    /// storage values are kept once in `storage_values`
+    dedup: bool,
+    latency: TipLatency,
+    /// blocks dropped after waiting too long, crawled again by the `BlocksIndexer`
+    dropped: DroppedBlocks,
 }
 
 impl<B: BlockT> DatabaseActor<B> {
     pub async fn new(url: String) -> Result<Self> {
-        Ok(Self {
-            db: Database::new(url).await?,
-            _marker: PhantomData,
-        })
+        Ok(Self::with_db(Database::new(url).await?))
     }
 
-    #[allow(unused)]
     pub fn with_db(db: Database) -> Self {
         Self {
             db,
-            _marker: PhantomData,
+            pending: Arc::new(Mutex::new(Pending::new(PENDING_TIMEOUT, PENDING_CAPACITY))),
+            initial_sync: Arc::new(Mutex::new(Phase::Done)),
+            dedup: false,
+            latency: TipLatency::default(),
+            dropped: DroppedBlocks::default(),
         }
     }
 
-    async fn block_handler(&self, blk: Block<B>) -> Result<()>
-    where
-        NumberFor<B>: Into<u32>,
-    {
-        let mut conn = self.db.conn().await?;
-        while !queries::check_if_meta_exists(blk.spec, &mut conn).await? {
-            smol::Timer::new(Duration::from_millis(20)).await;
+    /// Insert storage for the `phase` of an initial sync, see `database::initial_sync`
+    pub fn initial_sync(self, phase: Phase) -> Self {
+        *self.initial_sync.lock() = phase;
+        self
+    }
+
+    /// Insert storage values once into `storage_values`, referenced from `storage` by their hash
+    pub fn dedup_storage(mut self, dedup: bool) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    /// Record the latency of blocks at the tip in `latency`
+    pub fn latency(mut self, latency: TipLatency) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Report the state of inserts waiting on other inserts to `monitor`
+    pub fn pending_monitor(mut self, monitor: PendingMonitor) -> Self {
+        self.pending = Arc::new(Mutex::new(
+            Pending::new(PENDING_TIMEOUT, PENDING_CAPACITY).with_monitor(monitor),
+        ));
+        self
+    }
+
+    /// Report blocks dropped after waiting too long on their metadata to `dropped`
+    pub fn dropped_blocks(mut self, dropped: DroppedBlocks) -> Self {
+        self.dropped = dropped;
+        self
+    }
+
+    async fn insert_storage(&self, storage: Vec<StorageModel<B>>) -> Result<u64> {
+        let mut nums: Vec<u32> = storage.iter().map(|s| s.block_num()).collect();
+        nums.dedup();
+        let rows = self.insert_storage_rows(storage).await?;
+        self.latency.archived(&nums);
+        Ok(rows)
+    }
+
+    async fn insert_storage_rows(&self, storage: Vec<StorageModel<B>>) -> Result<u64> {
+        let phase = *self.initial_sync.lock();
+        if self.dedup {
+            self.db
+                .insert_advancing(DedupStorage(storage), &[Stage::Storage])
+                .await
+        } else if phase == Phase::Ingesting {
+            self.db.bulk_insert(UncheckedStorage(storage), &[]).await
+        } else if !phase.has_unique_index() {
+            // moved into `storage` once its unique index is rebuilt
+            self.db.insert(PendingStorage(storage)).await
+        } else {
+            self.db.bulk_insert(storage, &[Stage::Storage]).await
         }
+    }
+
+    async fn batch_block_handler(&self, blks: BatchBlock<B>) -> Result<()> {
+        let mut conn = self.db.conn().await?;
+        let versions: HashSet<u32> = queries::get_versions(&mut conn)
+            .await?
+            .into_iter()
+            .collect();
         std::mem::drop(conn);
-        self.db.insert(blk).await?;
-        Ok(())
+        let missing: Vec<u32> = blks
+            .inner()
+            .iter()
+            .map(|b| b.spec)
+            .filter(|s| !versions.contains(s))
+            .collect();
+        if missing.is_empty() {
+            let released = self.commit_one(Waiting::Blocks(blks)).await?;
+            self.commit_released(released).await;
+            Ok(())
+        } else {
+            log::debug!("Blocks waiting on metadata for versions {:?}", missing);
+            self.pending
+                .lock()
+                .defer(Waiting::Blocks(blks), None, missing);
+            Ok(())
+        }
     }
 
-    // Returns true if all versions are in database
-    // false if versions are missing
-    async fn db_contains_metadata(blocks: &[Block<B>], conn: &mut DbConn) -> Result<bool> {
-        let specs: hashbrown::HashSet<u32> = blocks.iter().map(|b| b.spec).collect();
-        let versions: hashbrown::HashSet<u32> =
-            queries::get_versions(conn).await?.into_iter().collect();
-        Ok(specs.is_subset(&versions))
+    async fn batch_storage_handler(&self, storage: Vec<Storage<B>>) -> Result<()> {
+        let storage = Vec::<StorageModel<B>>::from(VecStorageWrap(storage));
+        let (ready, waiting) = self.split_indexed(storage).await?;
+        if !waiting.is_empty() {
+            let mut missing: Vec<u32> = waiting.iter().map(|s| s.block_num()).collect();
+            missing.sort_unstable();
+            missing.dedup();
+            self.defer_storage(waiting, missing).await?;
+        }
+        if !ready.is_empty() {
+            self.insert_storage(ready).await?;
+        }
+        Ok(())
     }
 
-    async fn batch_block_handler(&self, blks: BatchBlock<B>) -> Result<()>
-    where
-        NumberFor<B>: Into<u32>,
-    {
+    /// Split `storage` into the rows of blocks that are indexed and the rows of blocks
+    /// that are not indexed yet. Rows of blocks indexed with another hash are dropped:
+    /// their block was rolled back after it was executed.
+    async fn split_indexed(
+        &self,
+        storage: Vec<StorageModel<B>>,
+    ) -> Result<(Vec<StorageModel<B>>, Vec<StorageModel<B>>)> {
+        let mut block_nums: Vec<u32> = storage.iter().map(|s| s.block_num()).collect();
+        block_nums.sort_unstable();
+        block_nums.dedup();
         let mut conn = self.db.conn().await?;
-        while !Self::db_contains_metadata(blks.inner(), &mut conn).await? {
-            log::info!("Doesn't contain metadata");
-            smol::Timer::new(Duration::from_millis(50)).await;
-        }
+        let indexed: HashMap<u32, Vec<u8>> =
+            queries::block_hashes(block_nums.as_slice(), &mut conn)
+                .await?
+                .into_iter()
+                .collect();
         std::mem::drop(conn);
-        self.db.insert(blks).await?;
+
+        let total = storage.len();
+        let (ready, waiting): (Vec<_>, Vec<_>) = storage
+            .into_iter()
+            .filter(|s| match indexed.get(&s.block_num()) {
+                Some(hash) => hash.as_slice() == s.hash().as_ref(),
+                None => true,
+            })
+            .partition(|s| indexed.contains_key(&s.block_num()));
+        let stale = total - ready.len() - waiting.len();
+        if stale > 0 {
+            log::debug!("Dropping {} storage rows of rolled back blocks", stale);
+        }
+        Ok((ready, waiting))
+    }
+
+    /// Buffer storage until `blocks` are committed,
+    /// or persist it in `_pending_storage` if the buffer is full
+    async fn defer_storage(&self, storage: Vec<StorageModel<B>>, blocks: Vec<u32>) -> Result<()> {
+        {
+            let mut pending = self.pending.lock();
+            if !pending.is_full() {
+                pending.defer(Waiting::Storage(storage), blocks, None);
+                return Ok(());
+            }
+            pending.record_overflow(1);
+        }
+        log::warn!(
+            "Too many pending inserts, persisting storage of blocks {:?}",
+            blocks
+        );
+        self.db.insert(PendingStorage(storage)).await?;
         Ok(())
     }
 
-    async fn storage_handler(&self, storage: Storage<B>) -> Result<()> {
+    async fn balances_handler(&self, balances: VecAccountBalance) -> Result<()> {
+        let mut block_nums: Vec<u32> = balances.blocks.iter().map(|(n, _)| *n).collect();
+        block_nums.sort_unstable();
+        block_nums.dedup();
         let mut conn = self.db.conn().await?;
-        while !queries::has_block::<B>(*storage.hash(), &mut conn).await? {
-            smol::Timer::new(Duration::from_millis(10)).await;
-        }
-        let storage = Vec::<StorageModel<B>>::from(storage);
+        let indexed = queries::has_blocks::<B>(block_nums.as_slice(), &mut conn).await?;
         std::mem::drop(conn);
-        self.db.insert(storage).await?;
+        if indexed.len() == block_nums.len() {
+            self.insert_balances(balances).await?;
+            return Ok(());
+        }
+        let missing: Vec<u32> = block_nums
+            .into_iter()
+            .filter(|n| !indexed.contains(n))
+            .collect();
+        let mut pending = self.pending.lock();
+        if pending.is_full() {
+            // their blocks stay behind the cursor of `Stage::Balances`,
+            // and are searched again once their storage is archived
+            pending.record_overflow(1);
+            log::warn!(
+                "Too many pending inserts, indexing balances of blocks {:?} from archived storage",
+                missing
+            );
+        } else {
+            pending.defer(Waiting::Balances(balances), missing, None);
+        }
         Ok(())
     }
 
-    async fn batch_storage_handler(&self, storage: Vec<Storage<B>>) -> Result<()> {
+    async fn insert_balances(&self, balances: VecAccountBalance) -> Result<u64> {
+        let blocks = StageBlocks {
+            stage: Stage::Balances,
+            blocks: balances.blocks,
+        };
+        self.db.insert_stage(balances.balances, blocks).await
+    }
+
+    async fn metadata_handler(&self, meta: Metadata) -> Result<()> {
+        let version = meta.version();
+        self.db.insert(meta).await?;
+        let released = self.pending.lock().resolve_specs(&[version]);
+        self.commit_released(released).await;
+        Ok(())
+    }
+
+    /// Insert entries whose dependencies are committed,
+    /// along with everything that was only waiting on them.
+    /// Entries that fail to insert are kept pending, and retried on the next sweep
+    async fn commit_released(&self, mut queue: Vec<Waiting<B>>) {
+        while let Some(entry) = queue.pop() {
+            let retry = entry.try_clone();
+            match self.commit_one(entry).await {
+                Ok(released) => queue.extend(released),
+                Err(e) => {
+                    log::error!("Retrying a released insert on the next sweep: {}", e);
+                    if let Some(entry) = retry {
+                        self.pending.lock().retry(entry);
+                    }
+                }
+            }
+        }
+    }
+
+    async fn commit_one(&self, entry: Waiting<B>) -> Result<Vec<Waiting<B>>> {
+        match entry {
+            Waiting::Blocks(blks) => {
+                let nums: Vec<u32> = blks
+                    .inner()
+                    .iter()
+                    .map(|b| (*b.inner.block.header().number()).saturated_into())
+                    .collect();
+                // blocks are only committed once the metadata of their version is
+                self.db
+                    .bulk_insert(blks, &[Stage::Blocks, Stage::Metadata])
+                    .await?;
+                self.latency.indexed(&nums);
+                let released = self.pending.lock().resolve_blocks(&nums);
+                Ok(released)
+            }
+            Waiting::Storage(storage) => {
+                // storage waits on a block number, which may have been indexed with another hash
+                let (ready, waiting) = self.split_indexed(storage).await?;
+                if !waiting.is_empty() {
+                    self.db.insert(PendingStorage(waiting)).await?;
+                }
+                if !ready.is_empty() {
+                    self.insert_storage(ready).await?;
+                }
+                Ok(Vec::new())
+            }
+            Waiting::Balances(balances) => {
+                self.insert_balances(balances).await?;
+                Ok(Vec::new())
+            }
+            Waiting::Notify(tx) => {
+                let _ = tx.send(());
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    /// Check the rows waited on against the database, in case they were committed
+    /// by something other than this pool. Moves inserts that waited for too long out of memory,
+    /// and storage of `_pending_storage` whose block is committed into `storage`.
+    async fn sweep(&self) -> Result<()> {
+        let (blocks, specs) = {
+            let mut pending = self.pending.lock();
+            if !pending.begin_sweep(SWEEP_INTERVAL) {
+                return Ok(());
+            }
+            pending.waiting_on()
+        };
         let mut conn = self.db.conn().await?;
-        let mut block_nums: Vec<u32> = storage.iter().map(|s| s.block_num()).collect();
-        block_nums.sort();
-        log::debug!(
-            "Inserting: {:#?}, {} .. {}",
-            block_nums.len(),
-            block_nums[0],
-            block_nums.last().unwrap()
-        );
-        let len = block_nums.len();
-        while queries::has_blocks::<B>(block_nums.as_slice(), &mut conn)
-            .await?
-            .len()
-            != len
-        {
-            smol::Timer::new(std::time::Duration::from_millis(50)).await;
+        // entries that failed to commit
+        let mut released = self.pending.lock().take_ready();
+        if !blocks.is_empty() {
+            let indexed = queries::has_blocks::<B>(blocks.as_slice(), &mut conn).await?;
+            released.extend(self.pending.lock().resolve_blocks(&indexed));
+        }
+        if !specs.is_empty() {
+            let versions = queries::get_versions(&mut conn).await?;
+            released.extend(self.pending.lock().resolve_specs(&versions));
         }
-        // we drop the connection early so that the insert() has the use of all db connections
+        // moving pending storage relies on the unique index of `storage`
+        let moved = if !self.initial_sync.lock().has_unique_index() {
+            0
+        } else {
+            let mut tx = conn.begin().await?;
+            let moved = release_pending_storage::<B>(&mut tx, self.dedup).await?;
+            if moved > 0 {
+                cursors::advance(&mut tx, Stage::Storage).await?;
+            }
+            tx.commit().await?;
+            moved
+        };
         std::mem::drop(conn);
-        let storage = Vec::<StorageModel<B>>::from(VecStorageWrap(storage));
-        self.db.insert(storage).await?;
+        if moved > 0 {
+            log::debug!("Moved {} rows from pending storage", moved);
+        }
+
+        self.commit_released(released).await;
+
+        let expired = self.pending.lock().expire();
+        for entry in expired {
+            match entry {
+                Waiting::Storage(storage) => {
+                    self.pending.lock().record_overflow(1);
+                    self.db.insert(PendingStorage(storage)).await?;
+                }
+                // the `BlocksIndexer` crawls again from the lowest dropped block
+                Waiting::Blocks(blks) => {
+                    log::warn!(
+                        "Dropping {} blocks whose metadata was not indexed in {:?}",
+                        blks.inner().len(),
+                        PENDING_TIMEOUT
+                    );
+                    self.dropped.dropped(
+                        blks.inner()
+                            .iter()
+                            .map(|b| (*b.inner.block.header().number()).saturated_into()),
+                    );
+                }
+                // balances are indexed again from the storage of their blocks,
+                // which stay behind the cursor of `Stage::Balances`
+                Waiting::Balances(balances) => log::warn!(
+                    "Dropping {} account balances whose blocks were not indexed in {:?}",
+                    balances.balances.len(),
+                    PENDING_TIMEOUT
+                ),
+                // cancels the wait
+                Waiting::Notify(_) => (),
+            }
+        }
+
+        let metrics = self.pending.lock().metrics();
+        if metrics.buffered > 0 {
+            log::debug!("{:?}", metrics);
+        }
         Ok(())
     }
 }
 
-impl<B: BlockT> Actor for DatabaseActor<B> {}
+#[async_trait::async_trait]
+impl<B: BlockT> Actor for DatabaseActor<B> {
+    async fn started(&mut self, ctx: &mut Context<Self>) {
+        ctx.notify_interval(SWEEP_INTERVAL, || Sweep);
+    }
+}
+
+struct Sweep;
+impl Message for Sweep {
+    type Result = ();
+}
 
 #[async_trait::async_trait]
-impl<B> Handler<Block<B>> for DatabaseActor<B>
-where
-    B: BlockT,
-    NumberFor<B>: Into<u32>,
-{
-    async fn handle(&mut self, blk: Block<B>, _: &mut Context<Self>) {
-        if let Err(e) = self.block_handler(blk).await {
-            log::error!("{}", e.to_string())
+impl<B: BlockT> Handler<Sweep> for DatabaseActor<B> {
+    async fn handle(&mut self, _: Sweep, _: &mut Context<Self>) {
+        if let Err(e) = self.sweep().await {
+            log::error!("{}", e.to_string());
         }
     }
 }
@@ -157,16 +483,66 @@ where
 #[async_trait::async_trait]
 impl<B: BlockT> Handler<Metadata> for DatabaseActor<B> {
     async fn handle(&mut self, meta: Metadata, _ctx: &mut Context<Self>) {
+        if let Err(e) = self.metadata_handler(meta).await {
+            log::error!("{}", e.to_string());
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<B: BlockT> Handler<RuntimeCode> for DatabaseActor<B> {
+    async fn handle(&mut self, code: RuntimeCode, _ctx: &mut Context<Self>) {
+        if let Err(e) = self.db.insert(code).await {
+            log::error!("{}", e.to_string());
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<B: BlockT> Handler<StateRootMismatch> for DatabaseActor<B> {
+    async fn handle(&mut self, mismatch: StateRootMismatch, _ctx: &mut Context<Self>) {
+        // storage of the block may be skipped, which should not hold back the cursor
+        if let Err(e) = self.db.insert_advancing(mismatch, &[Stage::Storage]).await {
+            log::error!("{}", e.to_string());
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<B: BlockT> Handler<ExpandedMetadata> for DatabaseActor<B> {
+    async fn handle(&mut self, meta: ExpandedMetadata, _ctx: &mut Context<Self>) {
         if let Err(e) = self.db.insert(meta).await {
             log::error!("{}", e.to_string());
         }
     }
 }
 
+#[async_trait::async_trait]
+impl<B: BlockT> Handler<VecDecodedStorage> for DatabaseActor<B> {
+    async fn handle(&mut self, storage: VecDecodedStorage, _ctx: &mut Context<Self>) {
+        let blocks = StageBlocks {
+            stage: Stage::Decoded,
+            blocks: storage.blocks,
+        };
+        if let Err(e) = self.db.insert_stage(storage.storage, blocks).await {
+            log::error!("{}", e.to_string());
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<B: BlockT> Handler<VecAccountBalance> for DatabaseActor<B> {
+    async fn handle(&mut self, balances: VecAccountBalance, _ctx: &mut Context<Self>) {
+        if let Err(e) = self.balances_handler(balances).await {
+            log::error!("{}", e.to_string());
+        }
+    }
+}
+
 #[async_trait::async_trait]
 impl<B: BlockT> Handler<Storage<B>> for DatabaseActor<B> {
     async fn handle(&mut self, storage: Storage<B>, _ctx: &mut Context<Self>) {
-        if let Err(e) = self.storage_handler(storage).await {
+        if let Err(e) = self.batch_storage_handler(vec![storage]).await {
             log::error!("{}", e.to_string())
         }
     }
@@ -183,6 +559,79 @@ impl<B: BlockT> Handler<VecStorageWrap<B>> for DatabaseActor<B> {
     }
 }
 
+/// Rebuild the indexes of `storage` once an initial sync has caught up with the chain.
+/// The rebuild runs in the background; an interrupted rebuild resumes when the archive restarts.
+#[derive(Debug)]
+pub struct FinishInitialSync;
+
+impl Message for FinishInitialSync {
+    type Result = Result<()>;
+}
+
+#[async_trait::async_trait]
+impl<B: BlockT> Handler<FinishInitialSync> for DatabaseActor<B> {
+    async fn handle(&mut self, _: FinishInitialSync, _: &mut Context<Self>) -> Result<()> {
+        let db = self.db.clone();
+        let phase = self.initial_sync.clone();
+        let dedup = self.dedup;
+        smol::Task::spawn(async move {
+            let finished = async {
+                let mut conn = db.conn().await?;
+                initial_sync::rebuild(&mut conn, |p| *phase.lock() = p).await?;
+                let moved = release_pending_storage::<B>(&mut conn, dedup).await?;
+                log::info!(
+                    "Moved {} rows stored during the rebuild from pending storage",
+                    moved
+                );
+                // storage ingested without its indexes did not advance the cursor
+                cursors::advance(&mut conn, Stage::Storage).await?;
+                Ok::<_, Error>(())
+            };
+            match finished.await {
+                Ok(()) => log::info!("Initial sync finished, following the chain"),
+                Err(e) => log::error!("Failed to rebuild the indexes of storage: {}", e),
+            }
+        })
+        .detach();
+        Ok(())
+    }
+}
+
+/// Wait for blocks to be committed. The receiver resolves once they are,
+/// or is cancelled if they are not committed before inserts waiting on them time out
+#[derive(Debug)]
+pub struct AwaitBlocks(pub Vec<u32>);
+
+impl Message for AwaitBlocks {
+    type Result = Result<oneshot::Receiver<()>>;
+}
+
+#[async_trait::async_trait]
+impl<B: BlockT> Handler<AwaitBlocks> for DatabaseActor<B> {
+    async fn handle(
+        &mut self,
+        blocks: AwaitBlocks,
+        _: &mut Context<Self>,
+    ) -> Result<oneshot::Receiver<()>> {
+        let (tx, rx) = oneshot::channel();
+        let mut conn = self.db.conn().await?;
+        let indexed = queries::has_blocks::<B>(blocks.0.as_slice(), &mut conn).await?;
+        let missing: Vec<u32> = blocks
+            .0
+            .into_iter()
+            .filter(|n| !indexed.contains(n))
+            .collect();
+        if missing.is_empty() {
+            let _ = tx.send(());
+        } else {
+            self.pending
+                .lock()
+                .defer(Waiting::Notify(tx), missing, None);
+        }
+        Ok(rx)
+    }
+}
+
 // this is an enum in case there is some more state
 // that might be needed in the future
 /// Get Some State from the Database Actor
@@ -211,7 +660,7 @@ impl StateResponse {
     pub fn conn(self) -> DbConn {
         match self {
             StateResponse::Conn(v) => v,
-            StateResponse::Pool(_) => panic!("Not a connection"),
+            _ => panic!("Not a connection"),
         }
     }
 
@@ -222,7 +671,7 @@ impl StateResponse {
     pub fn pool(self) -> sqlx::PgPool {
         match self {
             StateResponse::Pool(v) => v,
-            StateResponse::Conn(_) => panic!("Not a pool"),
+            _ => panic!("Not a pool"),
         }
     }
 }
@@ -0,0 +1,247 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Tracks inserts that cannot be committed yet because a row they reference
+//! (a block, or the metadata of a runtime version) is not in the database.
+//! Entries are released once everything they depend on has been committed.
+
+use hashbrown::HashSet;
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Counters describing the state of the dependency tracker
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PendingMetrics {
+    /// entries currently buffered in memory
+    pub buffered: usize,
+    /// entries released after their dependencies were committed
+    pub released: u64,
+    /// entries moved out of memory: storage into the `_pending_storage` table,
+    /// balances left to be indexed again from archived storage
+    pub overflowed: u64,
+    /// entries that waited longer than the timeout
+    pub timed_out: u64,
+}
+
+/// Reads the metrics of a tracker from outside of the actors sharing it
+#[derive(Debug, Default, Clone)]
+pub struct PendingMonitor {
+    inner: Arc<Mutex<PendingMetrics>>,
+}
+
+impl PendingMonitor {
+    /// Metrics as of the last change to the tracker
+    pub fn metrics(&self) -> PendingMetrics {
+        self.inner.lock().clone()
+    }
+}
+
+struct Entry<T> {
+    item: T,
+    /// numbers of blocks not yet committed
+    blocks: HashSet<u32>,
+    /// runtime versions whose metadata is not yet committed
+    specs: HashSet<u32>,
+    since: Instant,
+}
+
+impl<T> Entry<T> {
+    fn is_ready(&self) -> bool {
+        self.blocks.is_empty() && self.specs.is_empty()
+    }
+}
+
+pub struct Pending<T> {
+    entries: Vec<Entry<T>>,
+    timeout: Duration,
+    capacity: usize,
+    last_sweep: Instant,
+    monitor: PendingMonitor,
+}
+
+impl<T> Pending<T> {
+    /// Buffer at most `capacity` entries, each for at most `timeout`
+    pub fn new(timeout: Duration, capacity: usize) -> Self {
+        Self {
+            entries: Vec::new(),
+            timeout,
+            capacity,
+            last_sweep: Instant::now(),
+            monitor: PendingMonitor::default(),
+        }
+    }
+
+    /// Report metrics to `monitor`
+    pub fn with_monitor(mut self, monitor: PendingMonitor) -> Self {
+        *monitor.inner.lock() = self.metrics();
+        self.monitor = monitor;
+        self
+    }
+
+    /// Whether the buffer has reached its capacity
+    pub fn is_full(&self) -> bool {
+        self.entries.len() >= self.capacity
+    }
+
+    /// Buffer `item` until all of `blocks` and `specs` are committed
+    pub fn defer(
+        &mut self,
+        item: T,
+        blocks: impl IntoIterator<Item = u32>,
+        specs: impl IntoIterator<Item = u32>,
+    ) {
+        self.entries.push(Entry {
+            item,
+            blocks: blocks.into_iter().collect(),
+            specs: specs.into_iter().collect(),
+            since: Instant::now(),
+        });
+        self.monitor.inner.lock().buffered = self.entries.len();
+    }
+
+    /// Buffer `item` again after it failed to commit, to be returned by the next `take_ready`
+    pub fn retry(&mut self, item: T) {
+        self.defer(item, None, None);
+    }
+
+    /// Mark blocks as committed, returning the entries that no longer wait on anything
+    pub fn resolve_blocks(&mut self, blocks: &[u32]) -> Vec<T> {
+        for entry in self.entries.iter_mut() {
+            for b in blocks {
+                entry.blocks.remove(b);
+            }
+        }
+        self.take_ready()
+    }
+
+    /// Mark the metadata of runtime versions as committed,
+    /// returning the entries that no longer wait on anything
+    pub fn resolve_specs(&mut self, specs: &[u32]) -> Vec<T> {
+        for entry in self.entries.iter_mut() {
+            for s in specs {
+                entry.specs.remove(s);
+            }
+        }
+        self.take_ready()
+    }
+
+    /// All block numbers and runtime versions still waited on
+    pub fn waiting_on(&self) -> (Vec<u32>, Vec<u32>) {
+        let mut blocks = HashSet::new();
+        let mut specs = HashSet::new();
+        for entry in self.entries.iter() {
+            blocks.extend(entry.blocks.iter().copied());
+            specs.extend(entry.specs.iter().copied());
+        }
+        (blocks.into_iter().collect(), specs.into_iter().collect())
+    }
+
+    /// Remove and return entries that have been waiting longer than the timeout
+    pub fn expire(&mut self) -> Vec<T> {
+        let timeout = self.timeout;
+        let (expired, waiting) = std::mem::take(&mut self.entries)
+            .into_iter()
+            .partition::<Vec<_>, _>(|e| e.since.elapsed() >= timeout);
+        self.entries = waiting;
+        let mut metrics = self.monitor.inner.lock();
+        metrics.buffered = self.entries.len();
+        metrics.timed_out += expired.len() as u64;
+        expired.into_iter().map(|e| e.item).collect()
+    }
+
+    /// Returns true at most once every `interval`,
+    /// so that only one actor of a pool sharing this tracker sweeps it
+    pub fn begin_sweep(&mut self, interval: Duration) -> bool {
+        if self.last_sweep.elapsed() >= interval {
+            self.last_sweep = Instant::now();
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn record_overflow(&mut self, n: usize) {
+        self.monitor.inner.lock().overflowed += n as u64;
+    }
+
+    pub fn metrics(&self) -> PendingMetrics {
+        self.monitor.metrics()
+    }
+
+    /// Remove and return entries that no longer wait on anything
+    pub fn take_ready(&mut self) -> Vec<T> {
+        let (ready, waiting) = std::mem::take(&mut self.entries)
+            .into_iter()
+            .partition::<Vec<_>, _>(Entry::is_ready);
+        self.entries = waiting;
+        let mut metrics = self.monitor.inner.lock();
+        metrics.buffered = self.entries.len();
+        metrics.released += ready.len() as u64;
+        ready.into_iter().map(|e| e.item).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_release_entries_once_dependencies_commit() {
+        let mut pending = Pending::new(Duration::from_secs(60), 2);
+        pending.defer("storage", vec![1, 2], None);
+        pending.defer("blocks", None, vec![7]);
+        assert!(pending.is_full());
+
+        assert!(pending.resolve_blocks(&[1]).is_empty());
+        let (mut blocks, specs) = pending.waiting_on();
+        blocks.sort();
+        assert_eq!((blocks, specs), (vec![2], vec![7]));
+
+        assert_eq!(pending.resolve_specs(&[7]), vec!["blocks"]);
+        assert_eq!(pending.resolve_blocks(&[2, 3]), vec!["storage"]);
+        assert_eq!(
+            pending.metrics(),
+            PendingMetrics {
+                buffered: 0,
+                released: 2,
+                overflowed: 0,
+                timed_out: 0
+            }
+        );
+    }
+
+    #[test]
+    fn should_return_retried_entries() {
+        let mut pending = Pending::new(Duration::from_secs(60), 2);
+        pending.defer("storage", vec![1], None);
+        pending.retry("balances");
+        assert_eq!(pending.take_ready(), vec!["balances"]);
+        assert_eq!(pending.metrics().buffered, 1);
+        assert_eq!(pending.take_ready(), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn should_expire_entries_after_timeout() {
+        let monitor = PendingMonitor::default();
+        let mut pending = Pending::new(Duration::from_millis(0), 8).with_monitor(monitor.clone());
+        pending.defer(1u32, vec![1], None);
+        assert_eq!(monitor.metrics().buffered, 1);
+        assert_eq!(pending.expire(), vec![1]);
+        assert_eq!(monitor.metrics().timed_out, 1);
+        assert_eq!(monitor.metrics().buffered, 0);
+    }
+}
@@ -15,17 +15,20 @@
 
 use super::{database::GetState, ActorPool};
 use crate::{
-    backend::Meta,
+    backend::{Meta, ReadOnlyBackend},
     database::DbConn,
+    decode,
     error::Result,
     queries,
-    types::{BatchBlock, Block, Metadata as MetadataT},
+    types::{BatchBlock, Block, Metadata as MetadataT, RuntimeCode},
 };
 use itertools::Itertools;
 use sp_runtime::{
     generic::BlockId,
-    traits::{Block as BlockT, Header as _, NumberFor},
+    traits::{Block as BlockT, NumberFor},
 };
+use sp_storage::well_known_keys;
+use std::{collections::HashSet, sync::Arc};
 use xtra::prelude::*;
 
 /// Actor to fetch metadata about a block/blocks from RPC
@@ -34,15 +37,45 @@ pub struct Metadata<B: BlockT> {
     conn: DbConn,
     addr: Address<ActorPool<super::DatabaseActor<B>>>,
     meta: Meta<B>,
+    backend: Arc<ReadOnlyBackend<B>>,
+    /// versions and hashes of `:code` whose code is archived, or missing from the chain data
+    codes: HashSet<(u32, Option<B::Hash>)>,
 }
 
 impl<B: BlockT + Unpin> Metadata<B> {
     pub async fn new(
         addr: Address<ActorPool<super::DatabaseActor<B>>>,
         meta: Meta<B>,
+        backend: Arc<ReadOnlyBackend<B>>,
     ) -> Result<Self> {
         let conn = addr.send(GetState::Conn.into()).await?.await?.conn();
-        Ok(Self { conn, addr, meta })
+        let mut this = Self {
+            conn,
+            addr,
+            meta,
+            backend,
+            codes: HashSet::new(),
+        };
+        this.expand_missing().await?;
+        Ok(this)
+    }
+
+    // expand metadata that was inserted before the `metadata_*` tables existed
+    async fn expand_missing(&mut self) -> Result<()> {
+        for (ver, meta) in queries::unexpanded_metadata(&mut self.conn).await? {
+            self.expand(ver, &meta).await?;
+        }
+        Ok(())
+    }
+
+    // decode metadata into the `metadata_*` tables.
+    // Metadata that can't be decoded is still archived in its raw form.
+    async fn expand(&mut self, ver: u32, meta: &[u8]) -> Result<()> {
+        match decode::expand_metadata(ver, meta) {
+            Ok(expanded) => self.addr.send(expanded.into()).await?.await,
+            Err(e) => log::warn!("Not expanding metadata for version {}: {}", ver, e),
+        }
+        Ok(())
     }
 
     // checks if the metadata exists in the database
@@ -57,19 +90,48 @@ impl<B: BlockT + Unpin> Metadata<B> {
             );
             let meta = smol::unblock!(meta.metadata(&BlockId::hash(hash)))?;
             let meta: sp_core::Bytes = meta.into();
+            let expand = meta.0.clone();
             let meta = MetadataT::new(ver, meta.0);
             self.addr.send(meta.into()).await?.await;
+            self.expand(ver, &expand).await?;
         }
+        self.code_checker(ver, hash).await?;
         Ok(())
     }
 
-    async fn block_handler(&mut self, blk: Block<B>) -> Result<()>
-    where
-        NumberFor<B>: Into<u32>,
-    {
-        let hash = blk.inner.block.header().hash();
-        self.meta_checker(blk.spec, hash).await?;
-        self.addr.send(blk.into()).await?;
+    // checks if the runtime code at `hash` exists in the database for a version
+    // if it doesn't, read `:code` at `hash` and insert it.
+    // The code is looked up by its hash, since a runtime may be upgraded without a version bump.
+    // Sources without a state trie, like block files, have no code to insert.
+    // Must run after the metadata for `ver` is inserted.
+    async fn code_checker(&mut self, ver: u32, hash: B::Hash) -> Result<()> {
+        let backend = self.backend.clone();
+        let code_hash = smol::unblock!(backend.storage_hash(hash, well_known_keys::CODE));
+        if self.codes.contains(&(ver, code_hash)) {
+            return Ok(());
+        }
+        match code_hash {
+            // `RuntimeCode` hashes with blake2-256, like the state of substrate chains
+            Some(code_hash) => {
+                if !queries::check_if_code_exists(code_hash.as_ref(), ver, &mut self.conn).await? {
+                    let backend = self.backend.clone();
+                    log::info!(
+                        "Getting runtime code for hash {}, version {}",
+                        hex::encode(hash.as_ref()),
+                        ver
+                    );
+                    let code = smol::unblock!(backend.storage(hash, well_known_keys::CODE));
+                    if let Some(code) = code {
+                        self.addr
+                            .send(RuntimeCode::new(ver, code).into())
+                            .await?
+                            .await;
+                    }
+                }
+            }
+            None => log::warn!("No runtime code for version {} in the chain data", ver),
+        }
+        self.codes.insert((ver, code_hash));
         Ok(())
     }
 
@@ -85,26 +147,14 @@ impl<B: BlockT + Unpin> Metadata<B> {
         for b in versions.iter() {
             self.meta_checker(b.spec, b.inner.block.hash()).await?;
         }
-        self.addr.send(blks.into()).await?;
+        // wait for the insert, so that the crawler does not load blocks faster than they are inserted
+        self.addr.send(blks.into()).await?.await;
         Ok(())
     }
 }
 
 impl<B: BlockT> Actor for Metadata<B> {}
 
-#[async_trait::async_trait]
-impl<B> Handler<Block<B>> for Metadata<B>
-where
-    B: BlockT + Unpin,
-    NumberFor<B>: Into<u32>,
-{
-    async fn handle(&mut self, blk: Block<B>, _: &mut Context<Self>) {
-        if let Err(e) = self.block_handler(blk).await {
-            log::error!("{}", e.to_string());
-        }
-    }
-}
-
 #[async_trait::async_trait]
 impl<B> Handler<BatchBlock<B>> for Metadata<B>
 where
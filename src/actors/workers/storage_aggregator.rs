@@ -17,15 +17,20 @@
 //! Module that accepts individual storage entries and wraps them up into batch requests for
 //! Postgres
 
-use super::{ActorPool, DatabaseActor};
-use crate::actors::msg::VecStorageWrap;
+use super::{ActorPool, BalanceIndexer, DatabaseActor, StorageDecoder};
+use crate::actors::msg::{SharedStorage, VecStorageWrap};
 use crate::error::Result;
-use crate::types::Storage;
+use crate::types::{StateRootMismatch, Storage};
 use sp_runtime::traits::Block as BlockT;
+use std::sync::Arc;
 use xtra::prelude::*;
 
 pub struct StorageAggregator<B: BlockT + Unpin> {
     db: Address<ActorPool<DatabaseActor<B>>>,
+    /// receives a copy of all storage, if storage is decoded
+    decoder: Option<Address<StorageDecoder<B>>>,
+    /// receives a copy of all storage, if balances are indexed
+    balances: Option<Address<BalanceIndexer<B>>>,
     storage: Vec<Storage<B>>,
 }
 
@@ -33,12 +38,43 @@ impl<B: BlockT + Unpin> StorageAggregator<B>
 where
     B::Hash: Unpin,
 {
-    pub fn new(db: Address<ActorPool<DatabaseActor<B>>>) -> Self {
+    pub fn new(
+        db: Address<ActorPool<DatabaseActor<B>>>,
+        decoder: Option<Address<StorageDecoder<B>>>,
+        balances: Option<Address<BalanceIndexer<B>>>,
+    ) -> Self {
         Self {
             db,
+            decoder,
+            balances,
             storage: Vec::with_capacity(500),
         }
     }
+
+    /// Hand `storage` to the decoder and the balance indexer, waiting until they have handled it,
+    /// so that storage does not pile up in their mailboxes faster than they get through it
+    async fn decode(&self, storage: &[Storage<B>]) {
+        if self.decoder.is_none() && self.balances.is_none() {
+            return;
+        }
+        // one copy, read by both the decoder and the balance indexer
+        let shared = Arc::new(storage.to_vec());
+        let decoded = async {
+            if let Some(decoder) = &self.decoder {
+                if decoder.send(SharedStorage(shared.clone())).await.is_err() {
+                    log::error!("Storage decoder disconnected");
+                }
+            }
+        };
+        let indexed = async {
+            if let Some(balances) = &self.balances {
+                if balances.send(SharedStorage(shared.clone())).await.is_err() {
+                    log::error!("Balance indexer disconnected");
+                }
+            }
+        };
+        futures::join!(decoded, indexed);
+    }
 }
 
 #[async_trait::async_trait]
@@ -62,6 +98,7 @@ where
     async fn stopped(&mut self, _: &mut Context<Self>) {
         let len = self.storage.len();
         let storage = std::mem::take(&mut self.storage);
+        self.decode(&storage).await;
         // insert any storage left in queue
         let task = self.db.send(VecStorageWrap(storage).into()).await;
         match task {
@@ -91,6 +128,7 @@ where
         let storage = std::mem::take(&mut self.storage);
         if !storage.is_empty() {
             log::info!("Indexing storage {} bps", storage.len());
+            self.decode(&storage).await;
             if let Err(e) = self.db.send(VecStorageWrap(storage).into()).await {
                 log::error!("{:?}", e);
             }
@@ -108,6 +146,18 @@ where
     }
 }
 
+#[async_trait::async_trait]
+impl<B: BlockT + Unpin> Handler<StateRootMismatch> for StorageAggregator<B>
+where
+    B::Hash: Unpin,
+{
+    async fn handle(&mut self, mismatch: StateRootMismatch, _: &mut Context<Self>) {
+        if let Err(e) = self.db.send(mismatch.into()).await {
+            log::error!("{:?}", e);
+        }
+    }
+}
+
 #[async_trait::async_trait]
 impl<B: BlockT + Unpin> Handler<super::Die> for StorageAggregator<B>
 where
@@ -15,10 +15,13 @@
 // along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
 
 use crate::{
-    actors::System,
-    backend::{self, frontend::TArchiveClient, ReadOnlyBackend},
-    error::Result,
+    actors::{IndexingMode, System, SystemConfig},
+    backend::{self, frontend::TArchiveClient, ChainDataSource, ReadOnlyBackend, StorageSource},
+    decode::RuntimeTypes,
+    error::{Error, Result},
+    tasks::ExecutionEnv,
     types,
+    worker::{Worker, WorkerConfig},
 };
 
 use sc_chain_spec::ChainSpec;
@@ -37,9 +40,11 @@ use std::{marker::PhantomData, path::PathBuf, sync::Arc};
 const CHAIN_DATA_VAR: &str = "CHAIN_DATA_DB";
 const POSTGRES_VAR: &str = "DATABASE_URL";
 
-pub struct Builder<B, R, D> {
+pub struct Builder<B: BlockT, R, D> {
     /// Path to the rocksdb database
     pub chain_data_path: Option<String>,
+    /// Chain data to read instead of a rocksdb database
+    pub chain_data_source: Option<Arc<dyn ChainDataSource<B>>>,
     /// url to the Postgres Database
     pub pg_url: Option<String>,
     /// how much Cache should Rocksdb Keep
@@ -50,24 +55,67 @@ pub struct Builder<B, R, D> {
     pub wasm_pages: Option<u64>,
     /// Chain spec describing the chain
     pub chain_spec: Option<Box<dyn ChainSpec>>,
+    /// Decode storage into the `storage_decoded` table
+    pub decode_storage: Option<bool>,
+    /// Index account balances into the `account_balances` table
+    pub index_balances: Option<bool>,
+    /// Types of older runtime versions that metadata does not describe
+    pub runtime_types: Option<RuntimeTypes>,
+    /// Store the previous value of changed storage keys
+    pub old_storage: Option<bool>,
+    /// Cache storage proofs in the `storage_proofs` table
+    pub cache_proofs: Option<bool>,
+    /// Ingest without the indexes of the `storage` table until caught up
+    pub initial_sync: Option<bool>,
+    /// Store storage values once in `storage_values`
+    pub dedup_storage: Option<bool>,
+    /// Where storage changes of blocks come from
+    pub storage_source: Option<StorageSource>,
+    /// Do not archive storage of blocks whose execution results in the wrong state root
+    pub strict_state_root: Option<bool>,
+    /// Memory for blocks loaded by the crawler but not yet inserted, in bytes
+    pub crawl_memory: Option<usize>,
+    /// Index blocks ahead of finality, or only finalized blocks
+    pub indexing_mode: Option<IndexingMode>,
+    /// Blocks near the tip executed for every block of a backfill
+    pub tip_weight: Option<u32>,
+    /// Execute queued blocks in the archive, next to standalone workers
+    pub local_execution: Option<bool>,
+    /// Name of a standalone worker
+    pub worker_id: Option<String>,
     pub _marker: PhantomData<(B, R, D)>,
 }
 
-impl<B, R, D> Default for Builder<B, R, D> {
+impl<B: BlockT, R, D> Default for Builder<B, R, D> {
     fn default() -> Self {
         Self {
             chain_data_path: None,
+            chain_data_source: None,
             cache_size: None,
             pg_url: None,
             block_workers: None,
             wasm_pages: None,
             chain_spec: None,
+            decode_storage: None,
+            index_balances: None,
+            runtime_types: None,
+            old_storage: None,
+            cache_proofs: None,
+            initial_sync: None,
+            dedup_storage: None,
+            storage_source: None,
+            strict_state_root: None,
+            crawl_memory: None,
+            indexing_mode: None,
+            tip_weight: None,
+            local_execution: None,
+            worker_id: None,
             _marker: PhantomData,
         }
     }
 }
 
-impl<B, R, D> Builder<B, R, D> {
+impl<B: BlockT, R, D> Builder<B, R, D> {
     /// Set the chain data backend path to use for this instance.
     ///
     /// # Default
@@ -77,6 +125,17 @@ impl<B, R, D> Builder<B, R, D> {
         self
     }
 
+    /// Read blocks and state from `source` instead of a rocksdb database,
+    /// for example a `block_file::BlockFileSource`.
+    /// Blocks of a source without their state cannot be executed, see `local_execution`.
+    ///
+    /// # Default
+    /// defaults to the rocksdb database at `chain_data_db`
+    pub fn chain_data_source(mut self, source: Arc<dyn ChainDataSource<B>>) -> Self {
+        self.chain_data_source = Some(source);
+        self
+    }
+
     /// Set the url to the Postgres Database
     ///
     /// # Default
@@ -122,22 +181,189 @@ impl<B, R, D> Builder<B, R, D> {
         self.chain_spec = Some(spec);
         self
     }
+
+    /// Decode storage keys and values against the metadata of their runtime version,
+    /// into the `storage_decoded` table.
+    ///
+    /// # Default
+    /// defaults to false
+    pub fn decode_storage(mut self, decode: bool) -> Self {
+        self.decode_storage = Some(decode);
+        self
+    }
+
+    /// Index the balance history of accounts, from `System.Account`
+    /// and the legacy `Balances.FreeBalance`, into the `account_balances` table.
+    ///
+    /// # Default
+    /// defaults to false
+    pub fn index_balances(mut self, index: bool) -> Self {
+        self.index_balances = Some(index);
+        self
+    }
+
+    /// Define the types that changed between runtime versions and that metadata does not
+    /// describe, e.g `RefCount`, for decoding balances. Balances that don't decode
+    /// as the types of their runtime version are logged and not indexed.
+    ///
+    /// # Default
+    /// defaults to the types of current runtimes
+    pub fn runtime_types(mut self, types: RuntimeTypes) -> Self {
+        self.runtime_types = Some(types);
+        self
+    }
+
+    /// Store the value every changed storage key had before the block in `storage.old_storage`,
+    /// next to the new value. Costs one extra trie lookup per changed key.
+    ///
+    /// # Default
+    /// defaults to false
+    pub fn old_storage(mut self, old_storage: bool) -> Self {
+        self.old_storage = Some(old_storage);
+        self
+    }
+
+    /// Cache the proofs returned by `Archive::storage_proof` in the `storage_proofs` table.
+    ///
+    /// # Default
+    /// defaults to false
+    pub fn cache_proofs(mut self, cache: bool) -> Self {
+        self.cache_proofs = Some(cache);
+        self
+    }
+
+    /// Sync a new archive without the indexes and foreign key of the `storage` table,
+    /// rebuilding them once the archive has caught up with the chain.
+    /// Speeds up the first sync considerably. Progress is kept in the database,
+    /// so an interrupted sync or rebuild continues on the next start.
+    /// Has no effect on an archive that already finished an initial sync.
+    /// Cannot be combined with `dedup_storage`.
+    ///
+    /// # Default
+    /// defaults to false
+    pub fn initial_sync(mut self, initial_sync: bool) -> Self {
+        self.initial_sync = Some(initial_sync);
+        self
+    }
+
+    /// Store every distinct storage value once in the `storage_values` table,
+    /// with rows of `storage` referencing it by its `blake2_256` hash in `value_hash`.
+    /// Read values of both layouts through the `storage_with_values` view, and see
+    /// `queries::storage_space` for the space saved.
+    ///
+    /// Deduplicated storage is always inserted with multi-row `INSERT`s rather than `COPY`.
+    /// Cannot be combined with `initial_sync`, since deduplicated storage is inserted
+    /// against the unique index an initial sync drops.
+    ///
+    /// # Default
+    /// defaults to false
+    pub fn dedup_storage(mut self, dedup: bool) -> Self {
+        self.dedup_storage = Some(dedup);
+        self
+    }
+
+    /// Where the storage changes of blocks come from.
+    /// `StorageSource::TrieDiff` diffs the state tries of blocks against the tries of their parents
+    /// instead of executing them. Much faster, but needs every state trie, so the node must run with
+    /// `--pruning archive`. `StorageSource::ChangesTrie` reads changed keys from the changes tries of chains
+    /// that enable them. Blocks whose tries are not available are executed.
+    ///
+    /// # Default
+    /// defaults to `StorageSource::Execution`
+    pub fn storage_source(mut self, source: StorageSource) -> Self {
+        self.storage_source = Some(source);
+        self
+    }
+
+    /// Do not archive the storage of blocks whose execution results in a different state root
+    /// than the one in their header. Mismatches are recorded in the `state_root_mismatches` table
+    /// either way.
+    ///
+    /// # Default
+    /// defaults to false
+    pub fn strict_state_root(mut self, strict: bool) -> Self {
+        self.strict_state_root = Some(strict);
+        self
+    }
+
+    /// Bound the memory taken by blocks the crawler loaded but did not insert yet.
+    /// Blocks are loaded in batches of about a third of this, and loading waits while
+    /// the database is behind, so an initial sync runs in fixed memory.
+    ///
+    /// # Default
+    /// defaults to 256MB
+    pub fn crawl_memory(mut self, bytes: usize) -> Self {
+        self.crawl_memory = Some(bytes);
+        self
+    }
+
+    /// Which blocks are indexed. `IndexingMode::Finalized` stops at the last finalized block
+    /// of the node. `IndexingMode::Best` indexes up to the best block, marking the rows of blocks
+    /// ahead of finality with `blocks.finalized = false` until finality confirms them.
+    /// Blocks that leave the canon chain before that are rolled back along with their storage.
+    ///
+    /// # Default
+    /// defaults to `IndexingMode::Best`
+    pub fn indexing_mode(mut self, mode: IndexingMode) -> Self {
+        self.indexing_mode = Some(mode);
+        self
+    }
+
+    /// Blocks within 64 blocks of the best block are executed before the blocks of a backfill.
+    /// While both are waiting, `weight` blocks near the tip are executed for every block of the backfill,
+    /// so that a backfill still progresses while the chain produces blocks.
+    /// `0` executes the backfill first.
+    ///
+    /// # Default
+    /// defaults to 4
+    pub fn tip_weight(mut self, weight: u32) -> Self {
+        self.tip_weight = Some(weight);
+        self
+    }
+
+    /// Execute queued blocks in the archive. Disable to leave execution to standalone workers,
+    /// see `build_worker`.
+    ///
+    /// # Default
+    /// defaults to true
+    pub fn local_execution(mut self, local: bool) -> Self {
+        self.local_execution = Some(local);
+        self
+    }
+
+    /// Name of the worker built by `build_worker`, which must be unique among running workers.
+    ///
+    /// # Default
+    /// defaults to the process id and the time the worker was built
+    pub fn worker_id<S: Into<String>>(mut self, id: S) -> Self {
+        self.worker_id = Some(id.into());
+        self
+    }
 }
 
-fn parse_urls(chain_data_path: Option<String>, pg_url: Option<String>) -> (String, String) {
-    let chain_path = if let Some(path) = chain_data_path {
+fn parse_chain_path(chain_data_path: Option<String>) -> String {
+    if let Some(path) = chain_data_path {
         path
     } else {
         std::env::var(CHAIN_DATA_VAR).expect("CHAIN_DATA_DB must be set if not passed initially.")
-    };
+    }
+}
 
-    let pg_url = if let Some(url) = pg_url {
+fn parse_pg_url(pg_url: Option<String>) -> String {
+    if let Some(url) = pg_url {
         url
     } else {
         std::env::var(POSTGRES_VAR).expect("DATABASE_URL must be set if not passed initially.")
-    };
+    }
+}
 
-    (chain_path, pg_url)
+/// Process id and the time in milliseconds since the unix epoch
+fn default_worker_id() -> String {
+    let millis = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default();
+    format!("{}-{}", std::process::id(), millis)
 }
 
 /// Create rocksdb secondary directory if it doesn't exist yet.
@@ -194,25 +420,121 @@ where
     /// Panics if one of chain_data_db or pg_url is not passed to the builder
     /// and their respective environment variables are not set.
     pub fn build(self) -> Result<impl types::Archive<B>> {
+        if self.dedup_storage.unwrap_or(false) && self.initial_sync.unwrap_or(false) {
+            return Err(Error::from(
+                "deduplicated storage cannot be ingested by an initial sync",
+            ));
+        }
         let num_cpus = num_cpus::get();
-        let (chain_path, pg_url) = parse_urls(self.chain_data_path, self.pg_url);
+        let pg_url = parse_pg_url(self.pg_url);
         let cache_size = self.cache_size.unwrap_or(128);
         let block_workers = self.block_workers.unwrap_or(num_cpus);
         let wasm_pages = self.wasm_pages.unwrap_or(64 * num_cpus as u64);
-        let db_path = create_database_path(self.chain_spec)?;
-        smol::block_on(crate::migrations::migrate(&pg_url))?;
-        let db = Arc::new(backend::util::open_database(
-            chain_path.as_str(),
+        let config = SystemConfig {
+            decode_storage: self.decode_storage.unwrap_or(false),
+            index_balances: self.index_balances.unwrap_or(false),
+            runtime_types: self.runtime_types.unwrap_or_default(),
+            old_storage: self.old_storage.unwrap_or(false),
+            cache_proofs: self.cache_proofs.unwrap_or(false),
+            initial_sync: self.initial_sync.unwrap_or(false),
+            dedup_storage: self.dedup_storage.unwrap_or(false),
+            storage_source: self.storage_source.unwrap_or_default(),
+            strict_state_root: self.strict_state_root.unwrap_or(false),
+            crawl_memory: self.crawl_memory.unwrap_or(256 * 1024 * 1024),
+            indexing_mode: self.indexing_mode.unwrap_or_default(),
+            tip_weight: self.tip_weight.unwrap_or(4),
+            external_execution: !self.local_execution.unwrap_or(true),
+        };
+        let (client, backend) = match self.chain_data_source {
+            Some(source) => Self::open_source(source, &pg_url, block_workers, wasm_pages)?,
+            None => Self::open(
+                &parse_chain_path(self.chain_data_path),
+                &pg_url,
+                cache_size,
+                create_database_path(self.chain_spec)?,
+                block_workers,
+                wasm_pages,
+            )?,
+        };
+
+        let ctx = System::<_, R, _>::new(client, backend, block_workers, pg_url.as_str(), config)?;
+        Ok(ctx)
+    }
+
+    /// Build a standalone worker, executing the blocks queued by an archive
+    /// running against the same Postgres database. See `worker`.
+    /// The worker opens its own RocksDB secondary in a temporary directory.
+    ///
+    /// # Panics
+    /// Panics if one of chain_data_db or pg_url is not passed to the builder
+    /// and their respective environment variables are not set.
+    pub fn build_worker(self) -> Result<Worker> {
+        let num_cpus = num_cpus::get();
+        let (chain_path, pg_url) = (
+            parse_chain_path(self.chain_data_path),
+            parse_pg_url(self.pg_url),
+        );
+        let cache_size = self.cache_size.unwrap_or(128);
+        let block_workers = self.block_workers.unwrap_or(num_cpus);
+        let wasm_pages = self.wasm_pages.unwrap_or(64 * num_cpus as u64);
+        let db_path = create_database_path(None)?;
+        let (client, backend) = Self::open(
+            &chain_path,
+            &pg_url,
             cache_size,
             db_path,
+            block_workers,
+            wasm_pages,
+        )?;
+
+        let env = ExecutionEnv::<B, R, _>::new(
+            backend,
+            client,
+            self.old_storage.unwrap_or(false),
+            self.storage_source.unwrap_or_default(),
+            self.strict_state_root.unwrap_or(false),
+        );
+        let config = WorkerConfig {
+            id: self.worker_id.unwrap_or_else(default_worker_id),
+            threads: block_workers,
+            tip_weight: self.tip_weight.unwrap_or(4),
+            dedup_storage: self.dedup_storage.unwrap_or(false),
+        };
+        Ok(Worker::spawn(env, &pg_url, config))
+    }
+
+    /// Run the migrations and open the chain data at `chain_path`
+    fn open(
+        chain_path: &str,
+        pg_url: &str,
+        cache_size: usize,
+        db_path: PathBuf,
+        block_workers: usize,
+        wasm_pages: u64,
+    ) -> Result<(Arc<TArchiveClient<B, R, D>>, Arc<ReadOnlyBackend<B>>)> {
+        smol::block_on(crate::migrations::migrate(pg_url))?;
+        let db = Arc::new(backend::util::open_database(
+            chain_path, cache_size, db_path,
         )?);
         let client = backend::runtime_api::<B, R, D>(db.clone(), block_workers, wasm_pages)?;
         let client = Arc::new(client);
-        let backend = Arc::new(ReadOnlyBackend::new(db.clone(), true));
+        let backend = Arc::new(ReadOnlyBackend::new(db, true));
         Self::startup_info(&client, &backend)?;
+        Ok((client, backend))
+    }
 
-        let ctx = System::<_, R, _>::new(client, backend, block_workers, pg_url.as_str())?;
-        Ok(ctx)
+    /// Run the migrations and read chain data from `source`
+    fn open_source(
+        source: Arc<dyn ChainDataSource<B>>,
+        pg_url: &str,
+        block_workers: usize,
+        wasm_pages: u64,
+    ) -> Result<(Arc<TArchiveClient<B, R, D>>, Arc<ReadOnlyBackend<B>>)> {
+        smol::block_on(crate::migrations::migrate(pg_url))?;
+        let client =
+            backend::runtime_api_with_source::<B, R, D>(source.clone(), block_workers, wasm_pages)?;
+        let backend = Arc::new(ReadOnlyBackend::with_source(source));
+        Ok((Arc::new(client), backend))
     }
 
     /// Log some general startup info
@@ -17,19 +17,31 @@
 //! Read Only Interface with Substrate Backend (kvdb-rocksdb)
 
 mod block_exec;
+pub mod changes_trie;
 mod database;
 pub mod frontend;
+mod pg_source;
+mod pg_state;
+mod proof;
 mod read_only_backend;
 mod runtime_version_cache;
+mod source;
+pub mod trie_diff;
 // #[cfg(test)]
 // pub mod test_util;
 pub mod util;
 
 // re-exports
-pub use self::block_exec::{BlockChanges, BlockExecutor};
-pub use self::frontend::{GetMetadata, GetRuntimeVersion, TArchiveClient};
+pub use self::block_exec::{BlockChanges, BlockExecutor, StorageSource};
+pub use self::frontend::{
+    postgres_runtime_api, runtime_api_with_source, GetMetadata, GetRuntimeVersion, TArchiveClient,
+};
+pub use self::pg_source::PgSource;
+pub use self::pg_state::PgStateBackend;
+pub use self::proof::verify_storage_proof;
 pub use self::read_only_backend::{ReadOnlyBackend, TrieState};
 pub use self::runtime_version_cache::{RuntimeVersionCache, VersionRange};
+pub use self::source::{ChainDataSource, InMemorySource, RocksDbSource};
 pub use self::{database::ReadOnlyDatabase, frontend::runtime_api, util::open_database};
 
 use sc_client_api::Backend as BackendT;
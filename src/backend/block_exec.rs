@@ -14,9 +14,12 @@
 // You should have received a copy of the GNU General Public License
 // along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
 
-use crate::{error::Result, types::Storage};
-use sc_client_api::backend;
-use sp_api::{ApiExt, ApiRef};
+use crate::{
+    error::{Error, Result},
+    types::Storage,
+};
+use sc_client_api::backend::{self, StateBackend as _};
+use sp_api::{ApiExt, ApiRef, Core};
 use sp_block_builder::BlockBuilder as BlockBuilderApi;
 use sp_runtime::{
     generic::BlockId,
@@ -30,6 +33,25 @@ pub type StorageValue = Vec<u8>;
 pub type StorageCollection = Vec<(StorageKey, Option<StorageValue>)>;
 pub type ChildStorageCollection = Vec<(StorageKey, StorageCollection)>;
 
+/// Where the storage changes of a block come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageSource {
+    /// execute the block in the runtime
+    Execution,
+    /// diff the state tries of the block and its parent (see `backend::trie_diff`),
+    /// executing the block if they are not available
+    TrieDiff,
+    /// read the changed keys from the changes trie of the block (see `backend::changes_trie`),
+    /// executing blocks without a changes trie
+    ChangesTrie,
+}
+
+impl Default for StorageSource {
+    fn default() -> Self {
+        StorageSource::Execution
+    }
+}
+
 /// Storage Changes that occur as a result of a block's executions
 #[derive(Clone, Debug)]
 pub struct BlockChanges<Block: BlockT> {
@@ -37,6 +59,12 @@ pub struct BlockChanges<Block: BlockT> {
     pub storage_changes: StorageCollection,
     /// In memory arrays of storage values for multiple child tries.
     pub child_storage: ChildStorageCollection,
+    /// Values the changed keys had before the block, in the order of `storage_changes`.
+    /// Only captured if requested from the `BlockExecutor`.
+    pub old_storage: Option<Vec<Option<StorageValue>>>,
+    /// State root the changes result in, if the block was executed.
+    /// Differs from the state root of the header if execution diverged from the chain.
+    pub computed_root: Option<Block::Hash>,
     /// Hash of the block these changes come from
     pub block_hash: Block::Hash,
     pub block_num: NumberFor<Block>,
@@ -56,7 +84,7 @@ where
         let hash = changes.block_hash;
         let num: u32 = changes.block_num.into();
 
-        Storage::new(
+        let storage = Storage::new(
             hash,
             num,
             false,
@@ -65,7 +93,13 @@ where
                 .into_iter()
                 .map(|s| (StorageKeyWrapper(s.0), s.1.map(StorageData)))
                 .collect::<Vec<(StorageKeyWrapper, Option<StorageData>)>>(),
-        )
+        );
+        match changes.old_storage {
+            Some(old) => {
+                storage.with_old_values(old.into_iter().map(|v| v.map(StorageData)).collect())
+            }
+            None => storage,
+        }
     }
 }
 
@@ -80,6 +114,7 @@ where
     backend: &'a Arc<B>,
     block: Block,
     id: BlockId<Block>,
+    old_storage: bool,
 }
 
 impl<'a, Block, Api, B> BlockExecutor<'a, Block, Api, B>
@@ -100,9 +135,16 @@ where
             backend,
             block,
             id,
+            old_storage: false,
         })
     }
 
+    /// Also read the value each changed key had in the parent state
+    pub fn old_storage(mut self, capture: bool) -> Self {
+        self.old_storage = capture;
+        self
+    }
+
     pub fn block_into_storage(self) -> Result<BlockChanges<Block>> {
         let header = (&self.block).header();
         let parent_hash = *header.parent_hash();
@@ -111,23 +153,97 @@ where
 
         let state = self.backend.state_at(self.id)?;
 
-        // FIXME: For some reason, wasm runtime calculates a different number of digest items
-        // then what we have in the block
-        // We don't do anything with consensus
-        // so digest isn't very important (we don't currently index digest items anyway)
-        // popping a digest item has no effect on storage changes afaik
+        // seals are added by consensus after the runtime built the header,
+        // so the runtime rejects headers that still carry them
         let (mut header, ext) = self.block.deconstruct();
-        header.digest_mut().pop();
-        let block = Block::new(header, ext);
-
-        self.api.execute_block(&self.id, block)?;
+        while header
+            .digest()
+            .logs()
+            .last()
+            .and_then(|log| log.as_seal())
+            .is_some()
+        {
+            header.digest_mut().pop();
+        }
+
+        // `execute_block` panics in the runtime when the state root of the header is wrong,
+        // so the block is replayed the way it was built, and the roots are compared afterwards
+        self.api.initialize_block(&self.id, &header)?;
+        for ext in ext {
+            self.api.apply_extrinsic(&self.id, ext)?.map_err(|e| {
+                Error::from(format!("invalid extrinsic in block {}: {:?}", hash, e))
+            })?;
+        }
+        self.api.finalize_block(&self.id)?;
         let storage_changes = self.api.into_storage_changes(&state, None, parent_hash)?;
 
+        let old_storage = if self.old_storage {
+            let old = storage_changes
+                .main_storage_changes
+                .iter()
+                .map(|(key, _)| state.storage(key))
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .map_err(|e| Error::from(format!("failed to read parent state: {}", e)))?;
+            Some(old)
+        } else {
+            None
+        };
+
         Ok(BlockChanges {
             storage_changes: storage_changes.main_storage_changes,
             child_storage: storage_changes.child_storage_changes,
+            old_storage,
+            computed_root: Some(storage_changes.transaction_storage_root),
             block_hash: hash,
             block_num: num,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{runtime_api_with_source, ReadOnlyBackend};
+    use crate::harness::{timestamp_key, Executor, TestChain, SLOT_DURATION};
+    use codec::Encode;
+    use node_template_runtime::{opaque::Block, RuntimeApi};
+    use sp_api::ProvideRuntimeApi;
+
+    const BLOCKS: u32 = 3;
+
+    #[test]
+    fn should_execute_blocks_of_in_memory_source() {
+        crate::initialize();
+        let chain = TestChain::generate(BLOCKS).unwrap();
+        let source = Arc::new(chain.in_memory().unwrap());
+        let backend = Arc::new(ReadOnlyBackend::<Block>::with_source(source.clone()));
+        let client =
+            runtime_api_with_source::<Block, RuntimeApi, Executor>(source, 1, 256).unwrap();
+
+        for (num, hash) in chain.hashes.iter().enumerate() {
+            let block = backend.block(&BlockId::Hash(*hash)).unwrap().block;
+            let state_root = *block.header().state_root();
+            let changes = BlockExecutor::new(client.runtime_api(), &backend, block)
+                .unwrap()
+                .old_storage(true)
+                .block_into_storage()
+                .unwrap();
+            assert_eq!(changes.block_hash, *hash);
+            assert_eq!(changes.computed_root, Some(state_root));
+
+            let timestamp = changes
+                .storage_changes
+                .iter()
+                .position(|(key, _)| *key == timestamp_key())
+                .expect("Every block sets the timestamp");
+            let now = (num as u64 + 1) * SLOT_DURATION;
+            assert_eq!(changes.storage_changes[timestamp].1, Some(now.encode()));
+            // genesis does not set the timestamp
+            let before = Some(now - SLOT_DURATION).filter(|t| *t > 0);
+            assert_eq!(
+                changes.old_storage.unwrap()[timestamp],
+                before.map(|t| t.encode())
+            );
+        }
+    }
+}
@@ -0,0 +1,174 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Storage changes of a block from its changes trie, on chains that enable changes tries.
+//! The changes trie of a block lists the keys the block changed, but not their values,
+//! which are read from the state of the block.
+//! Digest entries, which summarize the changes of earlier blocks, and changes of child tries are skipped.
+
+use super::{
+    block_exec::BlockChanges, source::ChainDataSource, trie_diff, ReadOnlyBackend, TrieState,
+};
+use crate::error::{Error, Result};
+use codec::Decode;
+use hash_db::Prefix;
+use sc_client_api::backend::{Backend as BackendT, StateBackend as _};
+use sp_runtime::{
+    generic::{BlockId, DigestItem},
+    traits::{Block as BlockT, Header as _, NumberFor},
+};
+
+/// Type byte of the `ExtrinsicIndex` keys of a changes trie, which list the keys changed by the block itself
+const EXTRINSIC_INDEX: u8 = 1;
+
+/// The root of the changes trie of a block, if changes tries were enabled for it
+pub fn changes_trie_root<Block: BlockT>(header: &Block::Header) -> Option<Block::Hash> {
+    header
+        .digest()
+        .log(DigestItem::as_changes_trie_root)
+        .copied()
+}
+
+/// Keys changed by a block, from its changes trie with `root`, ordered by key
+pub fn changed_keys<Block: BlockT>(
+    source: &dyn ChainDataSource<Block>,
+    root: Block::Hash,
+) -> Result<Vec<Vec<u8>>> {
+    let nodes = |hash: &Block::Hash, _: Prefix| source.changes_trie_node(hash);
+    let mut keys = trie_diff::trie_entries::<Block>(&nodes, root)?
+        .into_iter()
+        .filter(|(key, _)| key.first() == Some(&EXTRINSIC_INDEX))
+        .map(|(key, _)| {
+            let (_, key): (NumberFor<Block>, Vec<u8>) = Decode::decode(&mut &key[1..])?;
+            Ok(key)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    keys.sort();
+    Ok(keys)
+}
+
+fn read<Block: BlockT>(state: &TrieState<Block>, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    state
+        .storage(key)
+        .map_err(|e| Error::from(format!("failed to read state: {}", e)))
+}
+
+/// Storage changes of the block with `header`, without executing it.
+/// `None` if the block has no changes trie.
+pub fn block_changes<Block: BlockT>(
+    backend: &ReadOnlyBackend<Block>,
+    header: &Block::Header,
+    old_storage: bool,
+) -> Result<Option<BlockChanges<Block>>> {
+    let root = match changes_trie_root::<Block>(header) {
+        Some(root) => root,
+        None => return Ok(None),
+    };
+    let keys = changed_keys(&*backend.source(), root)?;
+
+    let state = BackendT::state_at(backend, BlockId::Hash(header.hash()))?;
+    let storage_changes = keys
+        .iter()
+        .map(|key| Ok((key.clone(), read(&state, key)?)))
+        .collect::<Result<Vec<_>>>()?;
+    let old_storage = if old_storage {
+        let parent = BackendT::state_at(backend, BlockId::Hash(*header.parent_hash()))?;
+        Some(
+            keys.iter()
+                .map(|key| read(&parent, key))
+                .collect::<Result<Vec<_>>>()?,
+        )
+    } else {
+        None
+    };
+
+    Ok(Some(BlockChanges {
+        storage_changes,
+        child_storage: Vec::new(),
+        old_storage,
+        computed_root: None,
+        block_hash: header.hash(),
+        block_num: *header.number(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::InMemorySource;
+    use codec::Encode;
+    use sp_runtime::{
+        generic::SignedBlock,
+        testing::{Block as TestBlock, ExtrinsicWrapper, Header},
+    };
+    use std::sync::Arc;
+
+    type Block = TestBlock<ExtrinsicWrapper<u64>>;
+
+    #[test]
+    fn should_read_changes_from_changes_trie() {
+        let source = Arc::new(InMemorySource::<Block>::new());
+        let parent_root = source.insert_state(vec![(b"alice".to_vec(), vec![1])]);
+        let root = source.insert_state(vec![
+            (b"alice".to_vec(), vec![2]),
+            (b"bob".to_vec(), vec![3]),
+        ]);
+        let changes_root = source.insert_changes_trie(vec![
+            (
+                (EXTRINSIC_INDEX, 1u64, b"bob".to_vec()).encode(),
+                vec![0u32].encode(),
+            ),
+            (
+                (EXTRINSIC_INDEX, 1u64, b"alice".to_vec()).encode(),
+                vec![0u32].encode(),
+            ),
+            // digest entries of earlier blocks are skipped
+            (
+                (2u8, 0u64, b"charlie".to_vec()).encode(),
+                vec![0u64].encode(),
+            ),
+        ]);
+
+        let mut parent = Header::new_from_number(0);
+        parent.state_root = parent_root;
+        let mut header = Header::new_from_number(1);
+        header.parent_hash = parent.hash();
+        header.state_root = root;
+        header
+            .digest
+            .push(DigestItem::ChangesTrieRoot(changes_root));
+        for header in vec![parent, header.clone()] {
+            source.insert_block(SignedBlock {
+                block: Block {
+                    header,
+                    extrinsics: Vec::new(),
+                },
+                justification: None,
+            });
+        }
+        let backend = ReadOnlyBackend::with_source(source);
+
+        let changes = block_changes(&backend, &header, true).unwrap().unwrap();
+        assert_eq!(
+            changes.storage_changes,
+            vec![
+                (b"alice".to_vec(), Some(vec![2])),
+                (b"bob".to_vec(), Some(vec![3]))
+            ]
+        );
+        assert_eq!(changes.old_storage, Some(vec![Some(vec![1]), None]));
+    }
+}
@@ -23,7 +23,9 @@ use sc_client_api::{
 };
 // use sc_client_db::Backend;
 use self::executor::ArchiveExecutor;
-use crate::{backend::database::ReadOnlyDatabase, error::Error as ArchiveError};
+use crate::{
+    backend::database::ReadOnlyDatabase, database::ClientPool, error::Error as ArchiveError,
+};
 use futures::{task::SpawnExt, Future};
 use sc_executor::{NativeExecutionDispatch, NativeExecutor, WasmExecutionMethod};
 use sp_api::ConstructRuntimeApi;
@@ -31,7 +33,7 @@ use sp_core::traits::SpawnNamed;
 use sp_runtime::traits::{BlakeTwo256, Block as BlockT};
 use std::sync::Arc;
 
-use super::{ReadOnlyBackend, RuntimeApiCollection};
+use super::{ChainDataSource, PgSource, ReadOnlyBackend, RuntimeApiCollection};
 
 /// Archive Client Condensed Type
 pub type TArchiveClient<TBl, TRtApi, TExecDisp> =
@@ -61,8 +63,60 @@ where
     Dispatch: NativeExecutionDispatch + 'static,
     <Runtime::RuntimeApi as sp_api::ApiExt<Block>>::StateBackend: sp_api::StateBackend<BlakeTwo256>,
 {
-    let backend = Arc::new(ReadOnlyBackend::new(db, true));
+    client(
+        Arc::new(ReadOnlyBackend::new(db, true)),
+        block_workers,
+        wasm_pages,
+    )
+}
+
+/// Client for calling into runtimes with `Client::state_call_with`, without a RocksDB database.
+/// Its backend reads blocks archived in the database of `pool`, which has no state trie,
+/// so the runtime API of the client itself is not usable.
+pub fn postgres_runtime_api<Block, Runtime, Dispatch>(
+    pool: ClientPool,
+    block_workers: usize,
+    wasm_pages: u64,
+) -> Result<TArchiveClient<Block, Runtime, Dispatch>, ArchiveError>
+where
+    Block: BlockT,
+    Dispatch: NativeExecutionDispatch + 'static,
+{
+    let source = Arc::new(PgSource::<Block>::new(pool));
+    client(
+        Arc::new(ReadOnlyBackend::with_source(source)),
+        block_workers,
+        wasm_pages,
+    )
+}
 
+/// Client for a backend reading chain data from `source`, see `runtime_api`.
+/// The runtime API is only usable if the source has the state trie of the blocks it is called at.
+pub fn runtime_api_with_source<Block, Runtime, Dispatch>(
+    source: Arc<dyn ChainDataSource<Block>>,
+    block_workers: usize,
+    wasm_pages: u64,
+) -> Result<TArchiveClient<Block, Runtime, Dispatch>, ArchiveError>
+where
+    Block: BlockT,
+    Dispatch: NativeExecutionDispatch + 'static,
+{
+    client(
+        Arc::new(ReadOnlyBackend::with_source(source)),
+        block_workers,
+        wasm_pages,
+    )
+}
+
+fn client<Block, Runtime, Dispatch>(
+    backend: Arc<ReadOnlyBackend<Block>>,
+    block_workers: usize,
+    wasm_pages: u64,
+) -> Result<TArchiveClient<Block, Runtime, Dispatch>, ArchiveError>
+where
+    Block: BlockT,
+    Dispatch: NativeExecutionDispatch + 'static,
+{
     let executor = NativeExecutor::<Dispatch>::new(
         WasmExecutionMethod::Interpreted,
         Some(wasm_pages),
@@ -21,6 +21,7 @@
 //! It's recommended to use the backend (ReadOnlyBackend) for anything that requires getting blocks, querying
 //! storage, or similar operations. Client usage should be reserved for calling into the Runtime
 
+use super::executor::ArchiveExecutor;
 use crate::{
     backend::{ReadOnlyBackend, TrieState},
     error::Result,
@@ -30,17 +31,18 @@ use codec::{Decode, Encode};
 use sc_client_api::{
     backend::Backend as _, execution_extensions::ExecutionExtensions, CallExecutor,
 };
-use sc_executor::RuntimeVersion;
+use sc_executor::{RuntimeInfo, RuntimeVersion};
 use sp_api::{
     ApiRef, CallApiAt, CallApiAtParams, ConstructRuntimeApi, Core as CoreApi, Metadata,
     ProvideRuntimeApi,
 };
 use sp_blockchain::HeaderBackend as _;
-use sp_core::NativeOrEncoded;
+use sp_core::{traits::CodeExecutor, NativeOrEncoded};
 use sp_runtime::{
     generic::BlockId,
-    traits::{Block as BlockT, Header as HeaderT, One},
+    traits::{Block as BlockT, HashFor, Header as HeaderT, One},
 };
+use sp_state_machine::ExecutionStrategy;
 use std::{marker::PhantomData, panic::UnwindSafe, sync::Arc};
 
 // FIXME: should use the trait sp_version::GetRuntimeVersion
@@ -112,6 +114,29 @@ where
     }
 }
 
+impl<B, E, Block, RA> Client<ArchiveExecutor<B, E>, Block, RA>
+where
+    E: CodeExecutor + RuntimeInfo + Clone + 'static,
+    Block: BlockT,
+{
+    /// Call `method` of the runtime in `state` with SCALE-encoded `call_data`, returning the encoded result.
+    /// Runs against any state backend, for instance a `PgStateBackend` that needs no RocksDB database.
+    pub fn state_call_with<S>(&self, state: &S, method: &str, call_data: &[u8]) -> Result<Vec<u8>>
+    where
+        S: sp_state_machine::Backend<HashFor<Block>>,
+    {
+        self.executor
+            .call_with_state::<Block, _>(
+                state,
+                method,
+                call_data,
+                ExecutionStrategy::AlwaysWasm,
+                None,
+            )
+            .map_err(Error::from)
+    }
+}
+
 impl<Exec, Block, RA> GetRuntimeVersion<Block> for Client<Exec, Block, RA>
 where
     Exec: CallExecutor<Block, Backend = ReadOnlyBackend<Block>> + Send + Sync,
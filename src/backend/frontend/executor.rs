@@ -83,6 +83,50 @@ impl<B, E> ArchiveExecutor<B, E> {
     }
 }
 
+impl<B, E> ArchiveExecutor<B, E>
+where
+    E: CodeExecutor + RuntimeInfo + Clone + 'static,
+{
+    /// Call `method` against `state` rather than the state of a block of the backend
+    pub fn call_with_state<Block, S>(
+        &self,
+        state: &S,
+        method: &str,
+        call_data: &[u8],
+        strategy: ExecutionStrategy,
+        extensions: Option<Extensions>,
+    ) -> sp_blockchain::Result<Vec<u8>>
+    where
+        Block: BlockT,
+        S: sp_state_machine::Backend<HashFor<Block>>,
+    {
+        let mut changes = OverlayedChanges::default();
+        let mut offchain_changes = OffchainOverlayedChanges::disabled();
+
+        let state_runtime_code = sp_state_machine::backend::BackendRuntimeCode::new(state);
+        // changes trie block number is not used, so we set it to u32
+        // these types can be removed if changes-trie is decided to be used
+        let return_data = StateMachine::<_, _, u32, _>::new(
+            state,
+            None, // Changes Trie
+            &mut changes,
+            &mut offchain_changes,
+            &self.executor,
+            method,
+            call_data,
+            extensions.unwrap_or_default(),
+            &state_runtime_code.runtime_code()?,
+            self.spawn_handle.clone(),
+        )
+        .execute_using_consensus_failure_handler::<_, NeverNativeValue, fn() -> _>(
+            strategy.get_manager(),
+            None,
+        )?;
+
+        Ok(return_data.into_encoded())
+    }
+}
+
 impl<B, E> Clone for ArchiveExecutor<B, E>
 where
     E: Clone,
@@ -114,31 +158,8 @@ where
         strategy: ExecutionStrategy,
         extensions: Option<Extensions>,
     ) -> sp_blockchain::Result<Vec<u8>> {
-        let mut changes = OverlayedChanges::default();
-        let mut offchain_changes = OffchainOverlayedChanges::disabled();
-
         let state = self.backend.state_at(*id)?;
-        let state_runtime_code = sp_state_machine::backend::BackendRuntimeCode::new(&state);
-        // changes trie block number is not used, so we set it to u32
-        // these types can be removed if changes-trie is decided to be used
-        let return_data = StateMachine::<_, _, u32, _>::new(
-            &state,
-            None, // Changes Trie
-            &mut changes,
-            &mut offchain_changes,
-            &self.executor,
-            method,
-            call_data,
-            extensions.unwrap_or_default(),
-            &state_runtime_code.runtime_code()?,
-            self.spawn_handle.clone(),
-        )
-        .execute_using_consensus_failure_handler::<_, NeverNativeValue, fn() -> _>(
-            strategy.get_manager(),
-            None,
-        )?;
-
-        Ok(return_data.into_encoded())
+        self.call_with_state::<Block, _>(&state, method, call_data, strategy, extensions)
     }
 
     fn contextual_call<
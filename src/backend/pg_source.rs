@@ -0,0 +1,164 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Chain data read from the `blocks` table of an archive.
+//! Blocks and chain metadata are answered from Postgres; the state trie is not archived,
+//! so state is read with a `PgStateBackend` instead.
+
+use super::{source::ChainDataSource, util::Meta};
+use crate::{database::ClientPool, error::Result};
+use codec::Decode;
+use hash_db::Prefix;
+use kvdb::DBValue;
+use postgres::Row;
+use sp_runtime::{
+    generic::BlockId,
+    traits::{Block as BlockT, Header as HeaderT, NumberFor, SaturatedConversion, Zero},
+    Justification,
+};
+use std::marker::PhantomData;
+
+/// Chain data of the blocks archived in Postgres
+pub struct PgSource<Block: BlockT> {
+    pool: ClientPool,
+    _marker: PhantomData<Block>,
+}
+
+impl<Block: BlockT> PgSource<Block> {
+    pub fn new(pool: ClientPool) -> Self {
+        Self {
+            pool,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Query the row of block `id`, selecting `columns`
+    fn block_row(&self, columns: &str, id: BlockId<Block>) -> Result<Option<Row>> {
+        self.pool.with(|client| {
+            Ok(match id {
+                BlockId::Hash(hash) => client.query_opt(
+                    format!("SELECT {} FROM blocks WHERE hash = $1", columns).as_str(),
+                    &[&hash.as_ref()],
+                )?,
+                BlockId::Number(num) => client.query_opt(
+                    format!("SELECT {} FROM blocks WHERE block_num = $1", columns).as_str(),
+                    &[&(num.saturated_into::<u32>() as i32)],
+                )?,
+            })
+        })
+    }
+
+    /// Hash and number of the highest block matching `filter`
+    fn highest(&self, filter: &str) -> Result<Option<(Block::Hash, NumberFor<Block>)>> {
+        let query = format!(
+            "SELECT hash, block_num FROM blocks WHERE {} ORDER BY block_num DESC LIMIT 1",
+            filter
+        );
+        let row = self
+            .pool
+            .with(|client| Ok(client.query_opt(query.as_str(), &[])?))?;
+        row.map(|row| {
+            let hash: Vec<u8> = row.get(0);
+            let num: i32 = row.get(1);
+            Ok((
+                Decode::decode(&mut hash.as_slice())?,
+                (num as u32).saturated_into(),
+            ))
+        })
+        .transpose()
+    }
+}
+
+impl<Block: BlockT> ChainDataSource<Block> for PgSource<Block> {
+    fn header(&self, id: BlockId<Block>) -> Result<Option<Block::Header>> {
+        let row = self.block_row(
+            "block_num, extrinsics_root, state_root, parent_hash, digest",
+            id,
+        )?;
+        row.map(|row| {
+            let (num, extrinsics_root, state_root, parent_hash, digest): (
+                i32,
+                Vec<u8>,
+                Vec<u8>,
+                Vec<u8>,
+                Vec<u8>,
+            ) = (row.get(0), row.get(1), row.get(2), row.get(3), row.get(4));
+            Ok(Block::Header::new(
+                (num as u32).saturated_into(),
+                Decode::decode(&mut extrinsics_root.as_slice())?,
+                Decode::decode(&mut state_root.as_slice())?,
+                Decode::decode(&mut parent_hash.as_slice())?,
+                Decode::decode(&mut digest.as_slice())?,
+            ))
+        })
+        .transpose()
+    }
+
+    fn body(&self, id: BlockId<Block>) -> Result<Option<Vec<Block::Extrinsic>>> {
+        let row = self.block_row("ext", id)?;
+        row.map(|row| {
+            let ext: Vec<u8> = row.get(0);
+            Ok(Decode::decode(&mut ext.as_slice())?)
+        })
+        .transpose()
+    }
+
+    /// Justifications are not archived
+    fn justification(&self, _: BlockId<Block>) -> Result<Option<Justification>> {
+        Ok(None)
+    }
+
+    /// The state trie is not archived
+    fn state_node(&self, _: &Block::Hash, _: Prefix) -> Result<Option<DBValue>> {
+        Ok(None)
+    }
+
+    fn meta(&self) -> Result<Meta<NumberFor<Block>, Block::Hash>> {
+        let empty = || (Default::default(), Zero::zero());
+        let (genesis_hash, _) = self.highest("block_num = 0")?.unwrap_or_else(empty);
+        let (best_hash, best_number) = self.highest("true")?.unwrap_or_else(empty);
+        let (finalized_hash, finalized_number) = self.highest("finalized")?.unwrap_or_else(empty);
+        Ok(Meta {
+            best_hash,
+            best_number,
+            finalized_hash,
+            finalized_number,
+            genesis_hash,
+        })
+    }
+
+    fn aux(&self, _: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn canonical_numbers<'a>(&'a self) -> Box<dyn Iterator<Item = u32> + 'a> {
+        let rows = self.pool.with(|client| {
+            Ok(client.query("SELECT block_num FROM blocks ORDER BY block_num", &[])?)
+        });
+        match rows {
+            Ok(rows) => Box::new(rows.into_iter().map(|r| r.get::<_, i32>(0) as u32)),
+            Err(e) => {
+                log::error!("Failed to read archived block numbers: {}", e);
+                Box::new(std::iter::empty())
+            }
+        }
+    }
+
+    /// Queries always see committed rows
+    fn catch_up(&self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,369 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! State of a block read from the archived `storage` table instead of the RocksDB trie.
+//! The value of a key at a block is its latest change at or before that block;
+//! keys not changed since genesis are read from the genesis state, archived as the storage of block 0.
+//! Child tries are not archived, and new storage roots cannot be computed without the trie.
+//!
+//! Supported are runtime calls that only read storage, like `Core_version`, `Metadata_metadata`
+//! or `TransactionPaymentApi_query_info`. Calls that write storage and ask for the root of their
+//! changes are not: the root they get is the unchanged one of the archived block.
+//! `Core_execute_block` then fails its state root check and returns an error,
+//! while the headers of `BlockBuilder_finalize_block` carry a wrong root.
+
+use crate::{
+    database::{cursors::Stage, ClientPool},
+    error::{Error, Result},
+};
+use codec::Decode;
+use hash_db::Hasher;
+use sp_core::storage::ChildInfo;
+use sp_runtime::traits::{Block as BlockT, HashFor};
+use sp_state_machine::{Backend as StateBackend, StateMachineStats, UsageInfo};
+use sp_trie::MemoryDB;
+use std::marker::PhantomData;
+
+type StateResult<T> = std::result::Result<T, String>;
+
+/// `sp_state_machine::Backend` at a block, answered by the Postgres database of the archive
+pub struct PgStateBackend<B: BlockT> {
+    pool: ClientPool,
+    block_num: i32,
+    state_root: B::Hash,
+    _marker: PhantomData<B>,
+}
+
+impl<B: BlockT> PgStateBackend<B> {
+    /// State at the archived block `hash`, read through connections of `pool`.
+    /// Errors unless the storage of every block up to `hash` is archived.
+    pub fn new(pool: ClientPool, hash: &[u8]) -> Result<Self> {
+        let row = pool
+            .with(|client| {
+                Ok(client.query_opt(
+                    r#"
+                    SELECT b.block_num, b.state_root, s.cursor FROM blocks b, indexer_state s
+                    WHERE b.hash = $1 AND s.stage = $2
+                    "#,
+                    &[&hash, &Stage::Storage.as_str()],
+                )?)
+            })?
+            .ok_or_else(|| Error::from(format!("block {} is not archived", hex::encode(hash))))?;
+        let (block_num, cursor): (i32, i32) = (row.get(0), row.get(2));
+        if cursor < block_num {
+            return Err(Error::from(format!(
+                "storage is archived up to block {}, not up to block {}",
+                cursor, block_num
+            )));
+        }
+        let state_root: Vec<u8> = row.get(1);
+        Ok(Self {
+            block_num,
+            state_root: Decode::decode(&mut state_root.as_slice())?,
+            pool,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Get the state at block `hash` on a connection of its own to the database at `url`.
+    /// Share a `ClientPool` with `new` to read the states of many blocks.
+    pub fn connect(url: &str, hash: &[u8]) -> Result<Self> {
+        Self::new(ClientPool::new(url.to_string(), 1), hash)
+    }
+
+    /// Number of the block this is the state of
+    pub fn block_num(&self) -> u32 {
+        self.block_num as u32
+    }
+
+    fn pairs_with_prefix(&self, prefix: &[u8]) -> StateResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        let rows = self
+            .pool
+            .with(|client| {
+                Ok(client.query(
+                    r#"
+                SELECT key, storage FROM (
+                    SELECT DISTINCT ON (key) key, storage FROM storage_with_values
+                    WHERE key >= $1 AND substring(key from 1 for length($1)) = $1
+                        AND block_num <= $2
+                    ORDER BY key, block_num DESC
+                ) latest
+                WHERE storage IS NOT NULL
+                ORDER BY key
+                "#,
+                    &[&prefix, &self.block_num],
+                )?)
+            })
+            .map_err(|e| e.to_string())?;
+        Ok(rows.into_iter().map(|r| (r.get(0), r.get(1))).collect())
+    }
+
+    fn for_pairs_with_prefix(&self, prefix: &[u8], mut f: impl FnMut(&[u8], &[u8])) {
+        match self.pairs_with_prefix(prefix) {
+            Ok(pairs) => pairs.iter().for_each(|(k, v)| f(k, v)),
+            Err(e) => log::error!("Failed to iterate over storage: {}", e),
+        }
+    }
+}
+
+impl<B: BlockT> std::fmt::Debug for PgStateBackend<B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PgStateBackend at block {}", self.block_num)
+    }
+}
+
+impl<B: BlockT> StateBackend<HashFor<B>> for PgStateBackend<B> {
+    type Error = String;
+    type Transaction = MemoryDB<HashFor<B>>;
+    type TrieBackendStorage = MemoryDB<HashFor<B>>;
+
+    fn storage(&self, key: &[u8]) -> StateResult<Option<Vec<u8>>> {
+        let row = self
+            .pool
+            .with(|client| {
+                Ok(client.query_opt(
+                    r#"
+                SELECT storage FROM storage_with_values
+                WHERE key = $1 AND block_num <= $2
+                ORDER BY block_num DESC
+                LIMIT 1
+                "#,
+                    &[&key, &self.block_num],
+                )?)
+            })
+            .map_err(|e| e.to_string())?;
+        Ok(row.and_then(|r| r.get(0)))
+    }
+
+    fn storage_hash(&self, key: &[u8]) -> StateResult<Option<B::Hash>> {
+        Ok(self.storage(key)?.map(|v| HashFor::<B>::hash(&v)))
+    }
+
+    fn child_storage(&self, _: &ChildInfo, _: &[u8]) -> StateResult<Option<Vec<u8>>> {
+        Err("child storage is not archived".into())
+    }
+
+    fn exists_storage(&self, key: &[u8]) -> StateResult<bool> {
+        Ok(self.storage(key)?.is_some())
+    }
+
+    fn exists_child_storage(&self, child_info: &ChildInfo, key: &[u8]) -> StateResult<bool> {
+        Ok(self.child_storage(child_info, key)?.is_some())
+    }
+
+    fn next_storage_key(&self, key: &[u8]) -> StateResult<Option<Vec<u8>>> {
+        // the latest change of every key after `key`, skipping keys that it deleted,
+        // in one scan of `storage_key_block_num_index`
+        let row = self
+            .pool
+            .with(|client| {
+                Ok(client.query_opt(
+                    r#"
+                SELECT key FROM (
+                    SELECT DISTINCT ON (key) key, storage IS NOT NULL OR value_hash IS NOT NULL AS present
+                    FROM storage
+                    WHERE key > $1 AND block_num <= $2
+                    ORDER BY key, block_num DESC
+                ) latest
+                WHERE present
+                ORDER BY key
+                LIMIT 1
+                "#,
+                    &[&key, &self.block_num],
+                )?)
+            })
+            .map_err(|e| e.to_string())?;
+        Ok(row.map(|r| r.get(0)))
+    }
+
+    fn next_child_storage_key(&self, _: &ChildInfo, _: &[u8]) -> StateResult<Option<Vec<u8>>> {
+        Err("child storage is not archived".into())
+    }
+
+    fn for_keys_with_prefix<F: FnMut(&[u8])>(&self, prefix: &[u8], mut f: F) {
+        self.for_pairs_with_prefix(prefix, |k, _| f(k))
+    }
+
+    fn for_key_values_with_prefix<F: FnMut(&[u8], &[u8])>(&self, prefix: &[u8], f: F) {
+        self.for_pairs_with_prefix(prefix, f)
+    }
+
+    fn for_keys_in_child_storage<F: FnMut(&[u8])>(&self, _: &ChildInfo, _: F) {
+        log::warn!("child storage is not archived");
+    }
+
+    fn for_child_keys_with_prefix<F: FnMut(&[u8])>(&self, _: &ChildInfo, _: &[u8], _: F) {
+        log::warn!("child storage is not archived");
+    }
+
+    /// The root of the archived state.
+    /// Changes are ignored, since their root cannot be computed without the trie.
+    fn storage_root<'a>(
+        &self,
+        mut delta: impl Iterator<Item = (&'a [u8], Option<&'a [u8]>)>,
+    ) -> (B::Hash, Self::Transaction)
+    where
+        B::Hash: Ord,
+    {
+        if delta.next().is_some() {
+            log::error!(
+                "Storage root of changes to the archived state of block {} cannot be computed, \
+                 using the root of the block",
+                self.block_num
+            );
+        }
+        (self.state_root, Default::default())
+    }
+
+    fn child_storage_root<'a>(
+        &self,
+        _: &ChildInfo,
+        _: impl Iterator<Item = (&'a [u8], Option<&'a [u8]>)>,
+    ) -> (B::Hash, bool, Self::Transaction)
+    where
+        B::Hash: Ord,
+    {
+        log::warn!("child storage is not archived");
+        (Default::default(), true, Default::default())
+    }
+
+    fn pairs(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.pairs_with_prefix(&[]).unwrap_or_else(|e| {
+            log::error!("Failed to iterate over storage: {}", e);
+            Vec::new()
+        })
+    }
+
+    fn keys(&self, prefix: &[u8]) -> Vec<Vec<u8>> {
+        let mut keys = Vec::new();
+        self.for_keys_with_prefix(prefix, |k| keys.push(k.to_vec()));
+        keys
+    }
+
+    fn child_keys(&self, _: &ChildInfo, _: &[u8]) -> Vec<Vec<u8>> {
+        Vec::new()
+    }
+
+    fn as_trie_backend(
+        &mut self,
+    ) -> Option<&sp_state_machine::TrieBackend<Self::TrieBackendStorage, HashFor<B>>> {
+        None
+    }
+
+    fn register_overlay_stats(&mut self, _: &StateMachineStats) {}
+
+    fn usage_info(&self) -> UsageInfo {
+        UsageInfo::empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{postgres_runtime_api, ReadOnlyBackend};
+    use crate::harness::{
+        self, run_archive, timestamp_key, Executor, TempDatabase, TestChain, Until, SLOT_DURATION,
+    };
+    use codec::Encode;
+    use node_template_runtime::{opaque::Block, RuntimeApi};
+    use sc_client_api::Backend as _;
+    use sp_blockchain::HeaderBackend as _;
+    use sp_core::{storage::well_known_keys, twox_128};
+    use sp_runtime::{generic::BlockId, traits::Header as _};
+
+    const BLOCKS: u32 = 10;
+
+    #[test]
+    fn should_call_runtime_against_postgres() {
+        crate::initialize();
+        let chain = TestChain::generate(BLOCKS).unwrap();
+        let db = TempDatabase::new().unwrap();
+
+        run_archive(&chain, &db, Until::Cursor(Stage::Storage, BLOCKS)).unwrap();
+
+        let pool = ClientPool::new(db.url.clone(), 2);
+        let state = PgStateBackend::<Block>::new(pool.clone(), chain.hashes[4].as_ref()).unwrap();
+        assert_eq!(
+            state.storage(&timestamp_key()).unwrap(),
+            Some((5 * SLOT_DURATION).encode())
+        );
+        // the code is only set by the genesis state
+        assert_eq!(
+            state.storage(well_known_keys::CODE).unwrap().as_deref(),
+            node_template_runtime::WASM_BINARY
+        );
+        // the trie of the node is the reference for keys that are deleted at the end of a block
+        let secondary = tempfile::tempdir().unwrap();
+        let rocks = harness::open_chain(&chain, secondary.path()).unwrap();
+        let trie = ReadOnlyBackend::<Block>::new(rocks, true)
+            .state_at(BlockId::Hash(chain.hashes[4]))
+            .unwrap();
+        let next = trie.next_storage_key(&timestamp_key()).unwrap();
+        assert!(next.is_some());
+        assert_eq!(state.next_storage_key(&timestamp_key()).unwrap(), next);
+        assert!(state
+            .keys(&twox_128(b"Timestamp"))
+            .contains(&timestamp_key()));
+
+        let client = postgres_runtime_api::<Block, RuntimeApi, Executor>(pool, 1, 256).unwrap();
+        let header = client
+            .backend()
+            .header(BlockId::Hash(chain.hashes[4]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(header.hash(), chain.hashes[4]);
+        let version = client.state_call_with(&state, "Core_version", &[]).unwrap();
+        let version = sp_version::RuntimeVersion::decode(&mut version.as_slice()).unwrap();
+        assert_eq!(version.spec_name, node_template_runtime::VERSION.spec_name);
+
+        // blocks above the storage cursor may be missing storage
+        let pool = ClientPool::new(db.url.clone(), 1);
+        pool.with(|client| {
+            Ok(client.execute(
+                "UPDATE indexer_state SET cursor = 3 WHERE stage = $1",
+                &[&Stage::Storage.as_str()],
+            )?)
+        })
+        .unwrap();
+        assert!(PgStateBackend::<Block>::new(pool, chain.hashes[4].as_ref()).is_err());
+    }
+
+    #[test]
+    fn should_fail_writing_runtime_calls() {
+        crate::initialize();
+        let chain = TestChain::generate(BLOCKS).unwrap();
+        let db = TempDatabase::new().unwrap();
+
+        run_archive(&chain, &db, Until::Cursor(Stage::Storage, BLOCKS)).unwrap();
+
+        let secondary = tempfile::tempdir().unwrap();
+        let rocks = harness::open_chain(&chain, secondary.path()).unwrap();
+        let block = ReadOnlyBackend::<Block>::new(rocks, true)
+            .block(&BlockId::Hash(chain.hashes[5]))
+            .unwrap()
+            .block;
+
+        let pool = ClientPool::new(db.url.clone(), 2);
+        let state = PgStateBackend::<Block>::new(pool.clone(), chain.hashes[4].as_ref()).unwrap();
+        let client = postgres_runtime_api::<Block, RuntimeApi, Executor>(pool, 1, 256).unwrap();
+        // the root of the changes of the block is not computed, so it does not match its header
+        assert!(client
+            .state_call_with(&state, "Core_execute_block", &block.encode())
+            .is_err());
+        // and the client is still usable
+        assert!(client.state_call_with(&state, "Core_version", &[]).is_ok());
+    }
+}
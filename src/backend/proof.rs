@@ -0,0 +1,76 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Checking storage proofs produced by `ReadOnlyBackend::storage_proof`
+
+use crate::error::{Error, Result};
+use sp_runtime::traits::{Block as BlockT, HashFor};
+use sp_state_machine::StorageProof;
+use std::collections::HashMap;
+
+/// Check `proof` against the state root of a block,
+/// returning the proven value of each of `keys` (`None` if the key does not exist).
+/// Fails if the proof does not belong to `state_root` or does not cover all of `keys`.
+pub fn verify_storage_proof<B: BlockT>(
+    state_root: B::Hash,
+    proof: StorageProof,
+    keys: &[Vec<u8>],
+) -> Result<HashMap<Vec<u8>, Option<Vec<u8>>>> {
+    sp_state_machine::read_proof_check::<HashFor<B>, _>(state_root, proof, keys)
+        .map_err(|e| Error::from(format!("invalid storage proof: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{InMemorySource, ReadOnlyBackend};
+    use sp_runtime::{
+        generic::SignedBlock,
+        testing::{Block as TestBlock, ExtrinsicWrapper, Header},
+        traits::Header as _,
+    };
+    use std::sync::Arc;
+
+    type Block = TestBlock<ExtrinsicWrapper<u64>>;
+
+    #[test]
+    fn should_prove_and_verify_storage() {
+        let source = Arc::new(InMemorySource::<Block>::new());
+        let root = source.insert_state(vec![
+            (b"alice".to_vec(), vec![1]),
+            (b"bob".to_vec(), vec![2]),
+        ]);
+        let mut header = Header::new_from_number(1);
+        header.state_root = root;
+        let hash = header.hash();
+        source.insert_block(SignedBlock {
+            block: Block {
+                header,
+                extrinsics: Vec::new(),
+            },
+            justification: None,
+        });
+        let backend = ReadOnlyBackend::with_source(source);
+
+        let keys = vec![b"alice".to_vec(), b"charlie".to_vec()];
+        let proof = backend.storage_proof(hash, &keys).unwrap().unwrap();
+        let values = verify_storage_proof::<Block>(root, proof.clone(), &keys).unwrap();
+        assert_eq!(values[&b"alice".to_vec()], Some(vec![1]));
+        assert_eq!(values[&b"charlie".to_vec()], None);
+
+        assert!(verify_storage_proof::<Block>(Default::default(), proof, &keys).is_err());
+    }
+}
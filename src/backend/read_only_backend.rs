@@ -31,9 +31,8 @@ mod state_backend;
 pub use self::state_backend::TrieState;
 use self::state_backend::{DbState, StateVault};
 use super::database::ReadOnlyDatabase;
-use super::util::columns;
-use crate::error::Result;
-use codec::Decode;
+use super::source::{ChainDataSource, RocksDbSource};
+use crate::error::{Error, Result};
 use hash_db::Prefix;
 use kvdb::DBValue;
 use sc_client_api::backend::StateBackend;
@@ -43,10 +42,11 @@ use sp_runtime::{
     traits::{Block as BlockT, HashFor, Header},
     Justification,
 };
-use std::{convert::TryInto, sync::Arc};
+use sp_state_machine::StorageProof;
+use std::sync::Arc;
 
 pub struct ReadOnlyBackend<Block: BlockT> {
-    db: Arc<ReadOnlyDatabase>,
+    source: Arc<dyn ChainDataSource<Block>>,
     storage: Arc<StateVault<Block>>,
 }
 
@@ -55,13 +55,21 @@ where
     Block: BlockT,
 {
     pub fn new(db: Arc<ReadOnlyDatabase>, prefix_keys: bool) -> Self {
-        let vault = Arc::new(StateVault::new(db.clone(), prefix_keys));
-        Self { db, storage: vault }
+        Self::with_source(Arc::new(RocksDbSource::new(db, prefix_keys)))
     }
 
-    /// get a reference to the backing database
-    pub fn backing_db(&self) -> Arc<ReadOnlyDatabase> {
-        self.db.clone()
+    /// Create a backend that reads chain data from `source`
+    pub fn with_source(source: Arc<dyn ChainDataSource<Block>>) -> Self {
+        let vault = Arc::new(StateVault::new(source.clone()));
+        Self {
+            source,
+            storage: vault,
+        }
+    }
+
+    /// get a reference to the source of chain data
+    pub fn source(&self) -> Arc<dyn ChainDataSource<Block>> {
+        self.source.clone()
     }
 
     fn state_at(&self, hash: Block::Hash) -> Option<TrieState<Block>> {
@@ -91,13 +99,10 @@ where
     fn state_root(&self, hash: Block::Hash) -> Option<Block::Hash> {
         // db / root / key
         // flesh it out
-        let header = super::util::read_header::<Block>(
-            &self.db,
-            columns::KEY_LOOKUP,
-            columns::HEADER,
-            BlockId::Hash(hash),
-        )
-        .expect("Header metadata lookup failed");
+        let header = self
+            .source
+            .header(BlockId::Hash(hash))
+            .expect("Header metadata lookup failed");
         header.map(|h| *h.state_root())
     }
 
@@ -128,6 +133,25 @@ where
         }
     }
 
+    /// Get a merkle proof of the values of `keys` in the state of a block.
+    /// Returns `None` if the state of the block is not known.
+    pub fn storage_proof(
+        &self,
+        hash: Block::Hash,
+        keys: &[Vec<u8>],
+    ) -> Result<Option<StorageProof>> {
+        let mut state = match self.state_at(hash) {
+            Some(state) => state,
+            None => return Ok(None),
+        };
+        let trie = state
+            .as_trie_backend()
+            .ok_or_else(|| Error::from("state is not backed by a trie"))?;
+        sp_state_machine::prove_read_on_trie_backend(trie, keys)
+            .map(Some)
+            .map_err(|e| Error::from(format!("failed to prove storage: {}", e)))
+    }
+
     /// Get a block from the canon chain
     /// This also tries to catch up with the primary rocksdb instance
     pub fn block(&self, id: &BlockId<Block>) -> Option<SignedBlock<Block>> {
@@ -144,33 +168,18 @@ where
         &'a self,
         fun: impl Fn(u32) -> bool + 'a,
     ) -> Result<impl Iterator<Item = SignedBlock<Block>> + 'a> {
-        let readable_db = self.db.clone();
-        self.db.try_catch_up_with_primary()?;
-        Ok(self
-            .db
-            .iter(super::util::columns::KEY_LOOKUP)
-            .take_while(|(_, value)| !value.is_empty())
-            .filter_map(move |(key, value)| {
-                let arr: &[u8; 4] = key[0..4].try_into().ok()?;
-                let num = u32::from_be_bytes(*arr);
-                if key.len() == 4 && fun(num) {
-                    let head: Option<Block::Header> = readable_db
-                        .get(super::util::columns::HEADER, &value)
-                        .map(|bytes| Decode::decode(&mut &bytes[..]).ok())
-                        .flatten();
-                    let body: Option<Vec<Block::Extrinsic>> = readable_db
-                        .get(super::util::columns::BODY, &value)
-                        .map(|bytes| Decode::decode(&mut &bytes[..]).ok())
-                        .flatten();
-                    let justif: Option<Justification> = readable_db
-                        .get(super::util::columns::JUSTIFICATION, &value)
-                        .map(|bytes| Decode::decode(&mut &bytes[..]).ok())
-                        .flatten();
-                    construct_block(head, body, justif)
-                } else {
-                    None
-                }
-            }))
+        self.source.catch_up()?;
+        Ok(self.source.canonical_blocks(Box::new(fun)))
+    }
+
+    /// Blocks of the canon chain with the numbers `numbers`,
+    /// skipping numbers the canon chain does not have.
+    /// Unlike `iter_blocks`, does not scan the numbers of every block of the chain.
+    pub fn blocks_by_number<'a>(
+        &'a self,
+        numbers: impl Iterator<Item = u32> + 'a,
+    ) -> impl Iterator<Item = SignedBlock<Block>> + 'a {
+        numbers.filter_map(move |num| self.block(&BlockId::Number(num.into())))
     }
 }
 
@@ -17,8 +17,6 @@
 //! Implements Blockchain Backend (and required associated traits) for ReadOnlyBackend type
 
 use super::ReadOnlyBackend;
-use crate::backend::util::{self, columns};
-use codec::Decode;
 use sp_blockchain::{
     Backend as BlockchainBackend, BlockStatus, Cache, CachedHeaderMetadata,
     Error as BlockchainError, HeaderBackend, HeaderMetadata, Info,
@@ -34,35 +32,22 @@ type ChainResult<T> = Result<T, BlockchainError>;
 
 impl<Block: BlockT> BlockchainBackend<Block> for ReadOnlyBackend<Block> {
     fn body(&self, id: BlockId<Block>) -> ChainResult<Option<Vec<<Block as BlockT>::Extrinsic>>> {
-        let res = util::read_db::<Block>(&self.db, columns::KEY_LOOKUP, columns::BODY, id)
-            .map_err(|e| BlockchainError::Msg(e.to_string()))?;
-
-        match res {
-            Some(body) => match Decode::decode(&mut &body[..]) {
-                Ok(body) => Ok(Some(body)),
-                Err(_) => Err(BlockchainError::Msg("Could not decode extrinsics".into())),
-            },
-            None => Ok(None),
-        }
+        self.source
+            .body(id)
+            .map_err(|e| BlockchainError::Msg(e.to_string()))
     }
 
     fn justification(&self, id: BlockId<Block>) -> ChainResult<Option<Justification>> {
-        let res = util::read_db::<Block>(&self.db, columns::KEY_LOOKUP, columns::JUSTIFICATION, id)
-            .map_err(|e| BlockchainError::Msg(e.to_string()))?;
-
-        match res {
-            Some(justification) => match Decode::decode(&mut &justification[..]) {
-                Ok(justification) => Ok(Some(justification)),
-                Err(_) => Err(BlockchainError::Msg(
-                    "Could not decode block justification".into(),
-                )),
-            },
-            None => Ok(None),
-        }
+        self.source
+            .justification(id)
+            .map_err(|e| BlockchainError::Msg(e.to_string()))
     }
 
     fn last_finalized(&self) -> ChainResult<Block::Hash> {
-        Ok(util::read_meta::<Block>(&self.db, columns::HEADER)?.finalized_hash)
+        self.source
+            .meta()
+            .map(|m| m.finalized_hash)
+            .map_err(|e| BlockchainError::Backend(e.to_string()))
     }
 
     // no cache for Read Only Backend (yet)
@@ -94,14 +79,14 @@ impl<Block: BlockT> BlockchainBackend<Block> for ReadOnlyBackend<Block> {
 
 impl<Block: BlockT> HeaderBackend<Block> for ReadOnlyBackend<Block> {
     fn header(&self, id: BlockId<Block>) -> ChainResult<Option<Block::Header>> {
-        util::read_header::<Block>(&self.db, columns::KEY_LOOKUP, columns::HEADER, id)
+        self.source
+            .header(id)
             .map_err(|e| BlockchainError::Msg(e.to_string()))
     }
 
     fn info(&self) -> Info<Block> {
         // TODO: Remove expect
-        let meta = util::read_meta::<Block>(&self.db, columns::HEADER)
-            .expect("Metadata could not be read");
+        let meta = self.source.meta().expect("Metadata could not be read");
         log::warn!("Leaves are not counted on the Read Only Backend!");
         Info {
             best_hash: meta.best_hash,
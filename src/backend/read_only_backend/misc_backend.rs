@@ -20,7 +20,6 @@
 //! They should never be called under normal circumstances
 
 use super::ReadOnlyBackend;
-use crate::backend::util::columns;
 use sc_client_api::backend::{AuxStore, BlockImportOperation, NewBlockState, TransactionForSB};
 use sp_blockchain::{well_known_cache_keys::Id, Error as BlockchainError};
 use sp_core::offchain::OffchainStorage;
@@ -160,6 +159,6 @@ impl<Block: BlockT> AuxStore for ReadOnlyBackend<Block> {
     }
 
     fn get_aux(&self, key: &[u8]) -> ChainResult<Option<Vec<u8>>> {
-        Ok(self.db.get(columns::AUX, key))
+        Ok(self.source.aux(key))
     }
 }
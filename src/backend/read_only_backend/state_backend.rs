@@ -16,14 +16,13 @@
 
 //! State Backend Interface
 
-use crate::backend::database::ReadOnlyDatabase;
+use crate::backend::source::ChainDataSource;
 use hash_db::Prefix;
 use kvdb::DBValue;
 use sc_client_api::backend::StateBackend;
 use sp_core::storage::ChildInfo;
 use sp_runtime::traits::{Block as BlockT, HashFor};
 use sp_state_machine::{StateMachineStats, TrieBackend, UsageInfo as StateUsageInfo};
-use std::marker::PhantomData;
 use std::sync::Arc;
 
 /// DB-backed patricia trie state, transaction type is an overlay of changes to commit.
@@ -32,30 +31,21 @@ pub type DbState<B> = TrieBackend<Arc<dyn sp_state_machine::Storage<HashFor<B>>>
 /// Holds a reference to the disk backend
 /// that trie operations can make use of
 pub struct StateVault<Block: BlockT> {
-    /// disk backend
-    pub db: Arc<ReadOnlyDatabase>,
-    prefix_keys: bool,
-    _marker: PhantomData<Block>,
+    /// source of trie nodes
+    pub source: Arc<dyn ChainDataSource<Block>>,
 }
 
 impl<Block: BlockT> StateVault<Block> {
-    pub fn new(db: Arc<ReadOnlyDatabase>, prefix_keys: bool) -> Self {
-        Self {
-            db,
-            prefix_keys,
-            _marker: PhantomData,
-        }
+    pub fn new(source: Arc<dyn ChainDataSource<Block>>) -> Self {
+        Self { source }
     }
 }
 
 impl<Block: BlockT> sp_state_machine::Storage<HashFor<Block>> for StateVault<Block> {
     fn get(&self, key: &Block::Hash, prefix: Prefix) -> Result<Option<DBValue>, String> {
-        if self.prefix_keys {
-            let key = sp_trie::prefixed_key::<HashFor<Block>>(key, prefix);
-            Ok(self.db.get(super::columns::STATE, &key))
-        } else {
-            Ok(self.db.get(super::columns::STATE, key.as_ref()))
-        }
+        self.source
+            .state_node(key, prefix)
+            .map_err(|e| e.to_string())
     }
 }
 
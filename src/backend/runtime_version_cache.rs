@@ -115,7 +115,7 @@ impl<B: BlockT> RuntimeVersionCache<B> {
         Ok(versions)
     }
 
-    /// Finds the versions of all the blocks.
+    /// Finds the versions of all the blocks, or takes them from the chain data source if it knows them.
     /// Returns a new set of type `Block`.
     ///
     /// # Panics
@@ -124,6 +124,18 @@ impl<B: BlockT> RuntimeVersionCache<B> {
     where
         NumberFor<B>: Into<u32>,
     {
+        let source = self.backend.source();
+        let known = blocks
+            .iter()
+            .map(|b| source.spec_version((*b.block.header().number()).into()))
+            .collect::<Option<Vec<u32>>>();
+        if let Some(specs) = known {
+            return Ok(blocks
+                .into_iter()
+                .zip(specs)
+                .map(|(b, spec)| Block::new(b, spec))
+                .collect());
+        }
         let versions = self.find_versions(blocks.as_slice())?;
         Ok(blocks
             .into_iter()
@@ -211,3 +223,62 @@ fn decode_version(version: &[u8]) -> Result<sp_version::RuntimeVersion> {
         Ok(v)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::InMemorySource;
+    use node_template_runtime::opaque::{Block as TBlock, Header};
+    use sp_runtime::traits::Header as _;
+
+    fn block(num: u32, state_root: <TBlock as BlockT>::Hash) -> SignedBlock<TBlock> {
+        let header = Header::new(
+            num,
+            Default::default(),
+            state_root,
+            Default::default(),
+            Default::default(),
+        );
+        SignedBlock {
+            block: TBlock::new(header, Vec::new()),
+            justification: None,
+        }
+    }
+
+    #[test]
+    fn should_find_versions_of_in_memory_blocks() {
+        let code = node_template_runtime::WASM_BINARY.expect("Runtime is built with its Wasm");
+        let source = Arc::new(InMemorySource::<TBlock>::new());
+        let with_code = source.insert_state(vec![(well_known_keys::CODE.to_vec(), code.to_vec())]);
+        let without_code = source.insert_state(vec![(b"key".to_vec(), b"value".to_vec())]);
+        let blocks = (1..=6).map(|n| block(n, with_code)).collect::<Vec<_>>();
+        for b in blocks.iter() {
+            source.insert_block(b.clone());
+        }
+        source.insert_block(block(7, without_code));
+        let cache = RuntimeVersionCache::new(Arc::new(ReadOnlyBackend::with_source(source)));
+
+        let spec = node_template_runtime::VERSION.spec_version;
+        let version = cache.get(blocks[0].block.header.hash()).unwrap().unwrap();
+        assert_eq!(version.spec_version, spec);
+        assert_eq!(
+            cache.find_versions(&blocks).unwrap(),
+            vec![VersionRange {
+                start: 1,
+                end: 6,
+                version
+            }]
+        );
+        let specs = cache
+            .find_versions_as_blocks(blocks)
+            .unwrap()
+            .into_iter()
+            .map(|b| b.spec)
+            .collect::<Vec<_>>();
+        assert_eq!(specs, vec![spec; 6]);
+
+        assert!(cache
+            .get(block(7, without_code).block.header.hash())
+            .is_err());
+    }
+}
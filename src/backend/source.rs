@@ -0,0 +1,438 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Where the read-only backend gets chain data from.
+//! `RocksDbSource` reads the secondary RocksDB instance of a running node,
+//! `InMemorySource` holds everything in memory and is meant for tests.
+//! Other databases (e.g ParityDB) can be supported by implementing [`ChainDataSource`].
+
+use super::{
+    database::ReadOnlyDatabase,
+    util::{self, columns, Meta},
+};
+use crate::error::Result;
+use codec::Decode;
+use hash_db::Prefix;
+use hashbrown::HashMap;
+use kvdb::DBValue;
+use parking_lot::RwLock;
+use sp_runtime::{
+    generic::{BlockId, SignedBlock},
+    traits::{Block as BlockT, HashFor, Header as HeaderT, NumberFor, SaturatedConversion, Zero},
+    Justification,
+};
+use sp_trie::TrieMut;
+use std::{collections::BTreeMap, convert::TryInto, marker::PhantomData, sync::Arc};
+
+/// Read access to the data of a chain:
+/// blocks, the nodes of the state trie, and chain metadata.
+pub trait ChainDataSource<Block: BlockT>: Send + Sync {
+    fn header(&self, id: BlockId<Block>) -> Result<Option<Block::Header>>;
+
+    fn body(&self, id: BlockId<Block>) -> Result<Option<Vec<Block::Extrinsic>>>;
+
+    fn justification(&self, id: BlockId<Block>) -> Result<Option<Justification>>;
+
+    /// Get a node of the state trie by its hash and prefix
+    fn state_node(&self, key: &Block::Hash, prefix: Prefix) -> Result<Option<DBValue>>;
+
+    /// Get a node of a changes trie by its hash, if the source keeps changes tries
+    fn changes_trie_node(&self, _key: &Block::Hash) -> Result<Option<DBValue>> {
+        Ok(None)
+    }
+
+    /// Best, finalized and genesis blocks
+    fn meta(&self) -> Result<Meta<NumberFor<Block>, Block::Hash>>;
+
+    fn aux(&self, key: &[u8]) -> Option<Vec<u8>>;
+
+    /// Numbers of the blocks in the canonical chain, in ascending order
+    fn canonical_numbers<'a>(&'a self) -> Box<dyn Iterator<Item = u32> + 'a>;
+
+    /// Blocks of the canonical chain whose numbers match `filter`, in ascending order.
+    /// Sources that can read blocks in order should override this to avoid looking up every block.
+    fn canonical_blocks<'a>(
+        &'a self,
+        filter: Box<dyn Fn(u32) -> bool + 'a>,
+    ) -> Box<dyn Iterator<Item = SignedBlock<Block>> + 'a> {
+        Box::new(
+            self.canonical_numbers()
+                .filter(move |num| filter(*num))
+                .filter_map(move |num| {
+                    let id = BlockId::Number(num.saturated_into());
+                    Some(SignedBlock {
+                        block: Block::new(self.header(id).ok()??, self.body(id).ok()??),
+                        justification: self.justification(id).ok()?,
+                    })
+                }),
+        )
+    }
+
+    /// Runtime version of block `number`, for sources that know it without a state trie
+    fn spec_version(&self, _number: u32) -> Option<u32> {
+        None
+    }
+
+    /// Make data written since the source was opened visible
+    fn catch_up(&self) -> Result<()>;
+
+    /// Number of times the source has caught up, if tracked
+    fn catch_up_count(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// Chain data from a secondary instance of a node's RocksDB database
+pub struct RocksDbSource<Block: BlockT> {
+    db: Arc<ReadOnlyDatabase>,
+    prefix_keys: bool,
+    _marker: PhantomData<Block>,
+}
+
+impl<Block: BlockT> RocksDbSource<Block> {
+    pub fn new(db: Arc<ReadOnlyDatabase>, prefix_keys: bool) -> Self {
+        Self {
+            db,
+            prefix_keys,
+            _marker: PhantomData,
+        }
+    }
+
+    fn decode<T: Decode>(&self, col: u32, id: BlockId<Block>, what: &str) -> Result<Option<T>> {
+        match util::read_db::<Block>(&self.db, columns::KEY_LOOKUP, col, id)? {
+            Some(bytes) => match Decode::decode(&mut &bytes[..]) {
+                Ok(v) => Ok(Some(v)),
+                Err(_) => Err(format!("Could not decode {}", what).into()),
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+impl<Block: BlockT> ChainDataSource<Block> for RocksDbSource<Block> {
+    fn header(&self, id: BlockId<Block>) -> Result<Option<Block::Header>> {
+        util::read_header::<Block>(&self.db, columns::KEY_LOOKUP, columns::HEADER, id)
+    }
+
+    fn body(&self, id: BlockId<Block>) -> Result<Option<Vec<Block::Extrinsic>>> {
+        self.decode(columns::BODY, id, "extrinsics")
+    }
+
+    fn justification(&self, id: BlockId<Block>) -> Result<Option<Justification>> {
+        self.decode(columns::JUSTIFICATION, id, "block justification")
+    }
+
+    fn state_node(&self, key: &Block::Hash, prefix: Prefix) -> Result<Option<DBValue>> {
+        if self.prefix_keys {
+            let key = sp_trie::prefixed_key::<HashFor<Block>>(key, prefix);
+            Ok(self.db.get(columns::STATE, &key))
+        } else {
+            Ok(self.db.get(columns::STATE, key.as_ref()))
+        }
+    }
+
+    fn changes_trie_node(&self, key: &Block::Hash) -> Result<Option<DBValue>> {
+        // changes trie nodes are not prefixed
+        Ok(self.db.get(columns::CHANGES_TRIE, key.as_ref()))
+    }
+
+    fn meta(&self) -> Result<Meta<NumberFor<Block>, Block::Hash>> {
+        Ok(util::read_meta::<Block>(&self.db, columns::HEADER)?)
+    }
+
+    fn aux(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.db.get(columns::AUX, key)
+    }
+
+    fn canonical_numbers<'a>(&'a self) -> Box<dyn Iterator<Item = u32> + 'a> {
+        // number keys are big-endian, so they come out of the column in order
+        Box::new(
+            self.db
+                .iter(columns::KEY_LOOKUP)
+                .take_while(|(_, value)| !value.is_empty())
+                .filter_map(|(key, _)| {
+                    let arr: [u8; 4] = key.as_ref().try_into().ok()?;
+                    Some(u32::from_be_bytes(arr))
+                }),
+        )
+    }
+
+    /// Reads the columns of blocks with the lookup keys of the number index,
+    /// in one pass over it
+    fn canonical_blocks<'a>(
+        &'a self,
+        filter: Box<dyn Fn(u32) -> bool + 'a>,
+    ) -> Box<dyn Iterator<Item = SignedBlock<Block>> + 'a> {
+        Box::new(
+            self.db
+                .iter(columns::KEY_LOOKUP)
+                .take_while(|(_, value)| !value.is_empty())
+                .filter_map(move |(key, value)| {
+                    let arr: [u8; 4] = key.as_ref().try_into().ok()?;
+                    if !filter(u32::from_be_bytes(arr)) {
+                        return None;
+                    }
+                    let read = |col| self.db.get(col, &value);
+                    let header = Decode::decode(&mut &read(columns::HEADER)?[..]).ok()?;
+                    let body = Decode::decode(&mut &read(columns::BODY)?[..]).ok()?;
+                    let justification = read(columns::JUSTIFICATION)
+                        .and_then(|bytes| Decode::decode(&mut &bytes[..]).ok());
+                    Some(SignedBlock {
+                        block: Block::new(header, body),
+                        justification,
+                    })
+                }),
+        )
+    }
+
+    fn catch_up(&self) -> Result<()> {
+        self.db.try_catch_up_with_primary()
+    }
+
+    fn catch_up_count(&self) -> Option<usize> {
+        self.db.catch_up_count()
+    }
+}
+
+/// Chain data held in memory
+pub struct InMemorySource<Block: BlockT> {
+    inner: RwLock<InMemoryChain<Block>>,
+}
+
+struct InMemoryChain<Block: BlockT> {
+    headers: HashMap<Block::Hash, Block::Header>,
+    bodies: HashMap<Block::Hash, Vec<Block::Extrinsic>>,
+    justifications: HashMap<Block::Hash, Justification>,
+    canon: BTreeMap<u32, Block::Hash>,
+    finalized: Option<Block::Hash>,
+    /// trie nodes, by prefixed key
+    state: HashMap<Vec<u8>, DBValue>,
+    /// changes trie nodes, by hash
+    changes_tries: HashMap<Vec<u8>, DBValue>,
+    aux: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl<Block: BlockT> Default for InMemorySource<Block> {
+    fn default() -> Self {
+        Self {
+            inner: RwLock::new(InMemoryChain {
+                headers: HashMap::new(),
+                bodies: HashMap::new(),
+                justifications: HashMap::new(),
+                canon: BTreeMap::new(),
+                finalized: None,
+                state: HashMap::new(),
+                changes_tries: HashMap::new(),
+                aux: HashMap::new(),
+            }),
+        }
+    }
+}
+
+impl<Block: BlockT> InMemorySource<Block> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a block, making it the canonical block at its height
+    pub fn insert_block(&self, block: SignedBlock<Block>) {
+        let (header, extrinsics) = block.block.deconstruct();
+        let hash = header.hash();
+        let num: u32 = (*header.number()).saturated_into();
+        let mut inner = self.inner.write();
+        inner.canon.insert(num, hash);
+        inner.headers.insert(hash, header);
+        inner.bodies.insert(hash, extrinsics);
+        if let Some(justification) = block.justification {
+            inner.justifications.insert(hash, justification);
+        }
+    }
+
+    /// Mark a block as finalized
+    pub fn finalize(&self, hash: Block::Hash) {
+        self.inner.write().finalized = Some(hash);
+    }
+
+    /// Build a state trie from key-value pairs and store its nodes, returning the state root
+    pub fn insert_state<I>(&self, pairs: I) -> Block::Hash
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        let (root, nodes) = Self::build_trie(pairs);
+        self.inner.write().state.extend(nodes);
+        root
+    }
+
+    /// Build a changes trie from its encoded keys and values and store its nodes, returning its root
+    pub fn insert_changes_trie<I>(&self, pairs: I) -> Block::Hash
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        let (root, nodes) = Self::build_trie(pairs);
+        let hash_len = root.as_ref().len();
+        // changes trie nodes are stored by hash alone, which ends the prefixed key
+        self.inner.write().changes_tries.extend(
+            nodes
+                .into_iter()
+                .map(|(key, value)| (key[key.len() - hash_len..].to_vec(), value)),
+        );
+        root
+    }
+
+    /// Root and nodes, by prefixed key, of a trie of `pairs`
+    fn build_trie<I>(pairs: I) -> (Block::Hash, Vec<(Vec<u8>, DBValue)>)
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        let mut db = sp_trie::MemoryDB::<HashFor<Block>>::default();
+        let mut root = Default::default();
+        {
+            let mut trie =
+                sp_trie::trie_types::TrieDBMut::<HashFor<Block>>::new(&mut db, &mut root);
+            for (key, value) in pairs {
+                trie.insert(&key, &value)
+                    .expect("Inserting into an in-memory trie does not fail");
+            }
+        }
+        let nodes = db
+            .drain()
+            .into_iter()
+            .filter(|(_, (_, rc))| *rc > 0)
+            .map(|(key, (value, _))| (key, value))
+            .collect();
+        (root, nodes)
+    }
+
+    pub fn insert_aux(&self, key: Vec<u8>, value: Vec<u8>) {
+        self.inner.write().aux.insert(key, value);
+    }
+
+    fn hash(inner: &InMemoryChain<Block>, id: BlockId<Block>) -> Option<Block::Hash> {
+        match id {
+            BlockId::Hash(h) if inner.headers.contains_key(&h) => Some(h),
+            BlockId::Hash(_) => None,
+            BlockId::Number(n) => inner.canon.get(&n.saturated_into::<u32>()).copied(),
+        }
+    }
+}
+
+impl<Block: BlockT> ChainDataSource<Block> for InMemorySource<Block> {
+    fn header(&self, id: BlockId<Block>) -> Result<Option<Block::Header>> {
+        let inner = self.inner.read();
+        Ok(Self::hash(&inner, id).and_then(|h| inner.headers.get(&h).cloned()))
+    }
+
+    fn body(&self, id: BlockId<Block>) -> Result<Option<Vec<Block::Extrinsic>>> {
+        let inner = self.inner.read();
+        Ok(Self::hash(&inner, id).and_then(|h| inner.bodies.get(&h).cloned()))
+    }
+
+    fn justification(&self, id: BlockId<Block>) -> Result<Option<Justification>> {
+        let inner = self.inner.read();
+        Ok(Self::hash(&inner, id).and_then(|h| inner.justifications.get(&h).cloned()))
+    }
+
+    fn state_node(&self, key: &Block::Hash, prefix: Prefix) -> Result<Option<DBValue>> {
+        let key = sp_trie::prefixed_key::<HashFor<Block>>(key, prefix);
+        Ok(self.inner.read().state.get(&key).cloned())
+    }
+
+    fn changes_trie_node(&self, key: &Block::Hash) -> Result<Option<DBValue>> {
+        Ok(self.inner.read().changes_tries.get(key.as_ref()).cloned())
+    }
+
+    fn meta(&self) -> Result<Meta<NumberFor<Block>, Block::Hash>> {
+        let inner = self.inner.read();
+        let number = |hash: &Block::Hash| {
+            inner
+                .headers
+                .get(hash)
+                .map(|h| *h.number())
+                .unwrap_or_else(Zero::zero)
+        };
+        let genesis_hash = inner.canon.get(&0).copied().unwrap_or_default();
+        let best_hash = inner
+            .canon
+            .values()
+            .next_back()
+            .copied()
+            .unwrap_or(genesis_hash);
+        let finalized_hash = inner.finalized.unwrap_or(genesis_hash);
+        Ok(Meta {
+            best_hash,
+            best_number: number(&best_hash),
+            finalized_hash,
+            finalized_number: number(&finalized_hash),
+            genesis_hash,
+        })
+    }
+
+    fn aux(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.inner.read().aux.get(key).cloned()
+    }
+
+    fn canonical_numbers<'a>(&'a self) -> Box<dyn Iterator<Item = u32> + 'a> {
+        let numbers = self.inner.read().canon.keys().copied().collect::<Vec<_>>();
+        Box::new(numbers.into_iter())
+    }
+
+    fn catch_up(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::ReadOnlyBackend;
+    use sp_runtime::testing::{Block as TestBlock, ExtrinsicWrapper, Header};
+
+    type Block = TestBlock<ExtrinsicWrapper<u64>>;
+
+    fn block(num: u64, state_root: <Block as BlockT>::Hash) -> SignedBlock<Block> {
+        let mut header = Header::new_from_number(num);
+        header.state_root = state_root;
+        SignedBlock {
+            block: Block {
+                header,
+                extrinsics: vec![ExtrinsicWrapper::from(num)],
+            },
+            justification: None,
+        }
+    }
+
+    #[test]
+    fn should_read_blocks_and_state_from_memory() {
+        let source = Arc::new(InMemorySource::<Block>::new());
+        let root = source.insert_state(vec![(b"key".to_vec(), b"value".to_vec())]);
+        for num in 0..5 {
+            source.insert_block(block(num, root));
+        }
+        let backend = ReadOnlyBackend::with_source(source.clone());
+
+        let blocks = backend.iter_blocks(|n| n >= 2).unwrap().collect::<Vec<_>>();
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[0], block(2, root));
+
+        let meta = source.meta().unwrap();
+        assert_eq!(meta.best_number, 4);
+        assert_eq!(meta.finalized_hash, meta.genesis_hash);
+
+        let hash = block(3, root).block.header.hash();
+        assert_eq!(backend.storage(hash, b"key"), Some(b"value".to_vec()));
+        assert_eq!(backend.storage(hash, b"missing"), None);
+    }
+}
@@ -0,0 +1,397 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Storage changes of a block from the difference between its state trie and the state trie of its parent.
+//! Subtries with the same hash in both states are skipped, so only the paths to changed keys are read.
+//! Needs the nodes of both states, so it only works against archive nodes (`--pruning archive`).
+//! Child tries are not descended into; changes of their roots show up as changes of the top trie.
+
+use super::{block_exec::BlockChanges, source::ChainDataSource, ReadOnlyBackend};
+use crate::error::{Error, Result};
+use hash_db::Prefix;
+use kvdb::DBValue;
+use sp_runtime::{
+    generic::BlockId,
+    traits::{Block as BlockT, HashFor, Header as _},
+};
+use sp_trie::{empty_trie_root, Layout, NodeCodec};
+use std::collections::BTreeMap;
+use trie_db::{
+    node::{Node, NodeHandle},
+    NibbleSlice, NodeCodec as _,
+};
+
+/// A changed key, with its value before and after
+pub type Change = (Vec<u8>, Option<Vec<u8>>, Option<Vec<u8>>);
+
+#[derive(Clone, PartialEq)]
+enum NodeRef<H> {
+    Hash(H),
+    Inline(Vec<u8>),
+}
+
+/// A decoded trie node. Leaves are branches without children.
+struct OwnedNode<H> {
+    /// partial key, in nibbles
+    partial: Vec<u8>,
+    value: Option<Vec<u8>>,
+    children: Vec<Option<NodeRef<H>>>,
+}
+
+/// Looks up a trie node by its hash and prefix
+type Nodes<'a, H> = &'a dyn Fn(&H, Prefix) -> Result<Option<DBValue>>;
+
+struct TrieDiff<'a, Block: BlockT> {
+    nodes: Nodes<'a, Block::Hash>,
+    empty_root: Block::Hash,
+    /// old and new value of every key read in either trie
+    values: BTreeMap<Vec<u8>, (Option<Vec<u8>>, Option<Vec<u8>>)>,
+}
+
+impl<'a, Block: BlockT> TrieDiff<'a, Block> {
+    fn diff(
+        &mut self,
+        old: Option<&NodeRef<Block::Hash>>,
+        new: Option<&NodeRef<Block::Hash>>,
+        path: &mut Vec<u8>,
+    ) -> Result<()> {
+        if old == new {
+            return Ok(());
+        }
+        match (self.load(old, path)?, self.load(new, path)?) {
+            (Some(old), Some(new)) if old.partial == new.partial => {
+                let len = path.len();
+                path.extend_from_slice(&old.partial);
+                if old.value != new.value {
+                    let entry = self.values.entry(key_of(path)?).or_default();
+                    *entry = (old.value, new.value);
+                }
+                for (i, (o, n)) in old.children.iter().zip(new.children.iter()).enumerate() {
+                    path.push(i as u8);
+                    self.diff(o.as_ref(), n.as_ref(), path)?;
+                    path.pop();
+                }
+                path.truncate(len);
+            }
+            // the shape of the trie changed here, so compare every key below
+            (old, new) => {
+                if let Some(old) = old {
+                    self.collect(old, path, false)?;
+                }
+                if let Some(new) = new {
+                    self.collect(new, path, true)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn collect(
+        &mut self,
+        node: OwnedNode<Block::Hash>,
+        path: &mut Vec<u8>,
+        new: bool,
+    ) -> Result<()> {
+        let len = path.len();
+        path.extend_from_slice(&node.partial);
+        if let Some(value) = node.value {
+            let entry = self.values.entry(key_of(path)?).or_default();
+            if new {
+                entry.1 = Some(value);
+            } else {
+                entry.0 = Some(value);
+            }
+        }
+        for (i, child) in node.children.iter().enumerate() {
+            path.push(i as u8);
+            if let Some(child) = self.load(child.as_ref(), path)? {
+                self.collect(child, path, new)?;
+            }
+            path.pop();
+        }
+        path.truncate(len);
+        Ok(())
+    }
+
+    fn load(
+        &self,
+        node: Option<&NodeRef<Block::Hash>>,
+        path: &[u8],
+    ) -> Result<Option<OwnedNode<Block::Hash>>> {
+        match node {
+            None => Ok(None),
+            Some(NodeRef::Hash(hash)) if *hash == self.empty_root => Ok(None),
+            Some(NodeRef::Hash(hash)) => {
+                let (prefix, padded) = prefix_of(path);
+                let data = (self.nodes)(hash, (&prefix, padded))?
+                    .ok_or_else(|| Error::from(format!("trie node {:?} is not available", hash)))?;
+                decode::<Block>(&data)
+            }
+            Some(NodeRef::Inline(data)) => decode::<Block>(data),
+        }
+    }
+}
+
+fn decode<Block: BlockT>(data: &[u8]) -> Result<Option<OwnedNode<Block::Hash>>> {
+    let node = NodeCodec::<HashFor<Block>>::decode(data)
+        .map_err(|e| Error::from(format!("invalid trie node: {:?}", e)))?;
+    Ok(match node {
+        Node::Empty => None,
+        Node::Leaf(partial, value) => Some(OwnedNode {
+            partial: nibbles(partial),
+            value: Some(value.to_vec()),
+            children: vec![None; 16],
+        }),
+        Node::Branch(handles, value) => Some(OwnedNode {
+            partial: Vec::new(),
+            value: value.map(|v| v.to_vec()),
+            children: children::<Block>(&handles)?,
+        }),
+        Node::NibbledBranch(partial, handles, value) => Some(OwnedNode {
+            partial: nibbles(partial),
+            value: value.map(|v| v.to_vec()),
+            children: children::<Block>(&handles)?,
+        }),
+        Node::Extension(..) => return Err("extension nodes are not supported".into()),
+    })
+}
+
+fn nibbles(partial: NibbleSlice) -> Vec<u8> {
+    (0..partial.len()).map(|i| partial.at(i)).collect()
+}
+
+fn children<Block: BlockT>(
+    handles: &[Option<NodeHandle>],
+) -> Result<Vec<Option<NodeRef<Block::Hash>>>> {
+    handles
+        .iter()
+        .map(|h| h.map(child_ref::<Block>).transpose())
+        .collect()
+}
+
+fn child_ref<Block: BlockT>(handle: NodeHandle) -> Result<NodeRef<Block::Hash>> {
+    match handle {
+        NodeHandle::Hash(bytes) => {
+            let mut hash = Block::Hash::default();
+            if hash.as_ref().len() != bytes.len() {
+                return Err("trie node has a hash of the wrong length".into());
+            }
+            hash.as_mut().copy_from_slice(bytes);
+            Ok(NodeRef::Hash(hash))
+        }
+        NodeHandle::Inline(data) => Ok(NodeRef::Inline(data.to_vec())),
+    }
+}
+
+/// The database prefix of a node at `path`: the packed nibbles, and a last odd nibble in the high bits
+fn prefix_of(path: &[u8]) -> (Vec<u8>, Option<u8>) {
+    let packed = path.chunks_exact(2).map(|p| p[0] << 4 | p[1]).collect();
+    let padded = if path.len() % 2 == 1 {
+        path.last().map(|n| n << 4)
+    } else {
+        None
+    };
+    (packed, padded)
+}
+
+fn key_of(path: &[u8]) -> Result<Vec<u8>> {
+    match prefix_of(path) {
+        (key, None) => Ok(key),
+        (_, Some(_)) => Err("trie value at an odd number of nibbles".into()),
+    }
+}
+
+impl<'a, Block: BlockT> TrieDiff<'a, Block> {
+    fn new(nodes: Nodes<'a, Block::Hash>) -> Self {
+        Self {
+            nodes,
+            empty_root: empty_trie_root::<Layout<HashFor<Block>>>(),
+            values: BTreeMap::new(),
+        }
+    }
+}
+
+/// Keys that differ between the tries with roots `old_root` and `new_root`, ordered by key
+pub fn diff_tries<Block: BlockT>(
+    source: &dyn ChainDataSource<Block>,
+    old_root: Block::Hash,
+    new_root: Block::Hash,
+) -> Result<Vec<Change>> {
+    let nodes = |hash: &Block::Hash, prefix: Prefix| source.state_node(hash, prefix);
+    let mut diff = TrieDiff::<Block>::new(&nodes);
+    diff.diff(
+        Some(&NodeRef::Hash(old_root)),
+        Some(&NodeRef::Hash(new_root)),
+        &mut Vec::new(),
+    )?;
+    Ok(diff
+        .values
+        .into_iter()
+        .filter(|(_, (old, new))| old != new)
+        .map(|(key, (old, new))| (key, old, new))
+        .collect())
+}
+
+/// Every key and value of the trie with `root`, with nodes from `nodes`
+pub(crate) fn trie_entries<Block: BlockT>(
+    nodes: Nodes<'_, Block::Hash>,
+    root: Block::Hash,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut diff = TrieDiff::<Block>::new(nodes);
+    let mut path = Vec::new();
+    if let Some(node) = diff.load(Some(&NodeRef::Hash(root)), &path)? {
+        diff.collect(node, &mut path, true)?;
+    }
+    Ok(diff
+        .values
+        .into_iter()
+        .filter_map(|(key, (_, value))| Some((key, value?)))
+        .collect())
+}
+
+/// Storage changes of the block with `header`, without executing it.
+/// `None` if the header of the parent is not available.
+/// Fails if trie nodes of either state are missing, for instance on a pruned node.
+pub fn block_changes<Block: BlockT>(
+    backend: &ReadOnlyBackend<Block>,
+    header: &Block::Header,
+    old_storage: bool,
+) -> Result<Option<BlockChanges<Block>>> {
+    let source = backend.source();
+    let parent = match source.header(BlockId::Hash(*header.parent_hash()))? {
+        Some(parent) => parent,
+        None => return Ok(None),
+    };
+    let changes = diff_tries(&*source, *parent.state_root(), *header.state_root())?;
+    let (storage_changes, old): (Vec<_>, Vec<_>) = changes
+        .into_iter()
+        .map(|(key, old, new)| ((key, new), old))
+        .unzip();
+    Ok(Some(BlockChanges {
+        storage_changes,
+        child_storage: Vec::new(),
+        old_storage: if old_storage { Some(old) } else { None },
+        computed_root: None,
+        block_hash: header.hash(),
+        block_num: *header.number(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::InMemorySource;
+    use sp_runtime::testing::{Block as TestBlock, ExtrinsicWrapper};
+
+    type Block = TestBlock<ExtrinsicWrapper<u64>>;
+
+    #[test]
+    fn should_diff_tries() {
+        let source = InMemorySource::<Block>::new();
+        let old: BTreeMap<Vec<u8>, Vec<u8>> = (0u16..500)
+            .map(|i| (i.to_be_bytes().to_vec(), vec![i as u8; 40]))
+            .chain(vec![(b"a".to_vec(), vec![1]), (b"ab".to_vec(), vec![2])])
+            .collect();
+        let mut new = old.clone();
+        new.insert(7u16.to_be_bytes().to_vec(), vec![0; 3]);
+        new.remove(&300u16.to_be_bytes().to_vec());
+        new.remove(&b"a".to_vec());
+        new.insert(b"abc".to_vec(), vec![3]);
+        new.insert(1000u16.to_be_bytes().to_vec(), vec![4]);
+        let old_root = source.insert_state(old.clone());
+        let new_root = source.insert_state(new.clone());
+
+        let expected: Vec<Change> = old
+            .keys()
+            .chain(new.keys())
+            .collect::<std::collections::BTreeSet<_>>()
+            .into_iter()
+            .map(|k| (k.clone(), old.get(k).cloned(), new.get(k).cloned()))
+            .filter(|(_, o, n)| o != n)
+            .collect();
+        assert_eq!(expected.len(), 5);
+        assert_eq!(
+            diff_tries::<Block>(&source, old_root, new_root).unwrap(),
+            expected
+        );
+        assert!(diff_tries::<Block>(&source, new_root, new_root)
+            .unwrap()
+            .is_empty());
+    }
+
+    /// Compares execution with trie diffs, run with `cargo test bench_trie_diff -- --ignored`
+    #[test]
+    #[ignore]
+    fn bench_trie_diff_against_execution() {
+        use crate::backend::{runtime_api, BlockExecutor};
+        use crate::harness::{self, Executor, TestChain};
+        use node_template_runtime::{opaque::Block, RuntimeApi};
+        use sp_api::ProvideRuntimeApi;
+        use sp_runtime::traits::Block as _;
+        use std::{
+            sync::Arc,
+            time::{Duration, Instant},
+        };
+
+        const BLOCKS: u32 = 10;
+
+        crate::initialize();
+        let chain = TestChain::generate(BLOCKS).unwrap();
+        let secondary = tempfile::tempdir().unwrap();
+        let db = harness::open_chain(&chain, secondary.path()).unwrap();
+        let backend = Arc::new(ReadOnlyBackend::<Block>::new(db.clone(), true));
+        let client = runtime_api::<Block, RuntimeApi, Executor>(db, 1, 256).unwrap();
+
+        let (mut executed, mut diffed) = (Duration::default(), Duration::default());
+        for hash in chain.hashes.iter() {
+            let block = backend.block(&BlockId::Hash(*hash)).unwrap().block;
+
+            let now = Instant::now();
+            let diff = block_changes(&backend, block.header(), true)
+                .unwrap()
+                .unwrap();
+            diffed += now.elapsed();
+
+            let now = Instant::now();
+            let execution = BlockExecutor::new(client.runtime_api(), &backend, block)
+                .unwrap()
+                .old_storage(true)
+                .block_into_storage()
+                .unwrap();
+            executed += now.elapsed();
+
+            // execution also reports keys written with the value they already had
+            let changed = |c: Vec<(Vec<u8>, Option<Vec<u8>>)>, old: Vec<Option<Vec<u8>>>| {
+                c.into_iter()
+                    .zip(old)
+                    .filter(|((_, new), old)| new != old)
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(
+                changed(diff.storage_changes, diff.old_storage.unwrap()),
+                changed(execution.storage_changes, execution.old_storage.unwrap()),
+                "changes of block {}",
+                hash
+            );
+        }
+        log::info!(
+            "{} blocks: execution {:?}, trie diff {:?}",
+            BLOCKS,
+            executed,
+            diffed
+        );
+    }
+}
@@ -0,0 +1,502 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Index blocks from a file instead of a RocksDB database.
+//!
+//! The file is in the format written by `export-blocks --binary` of substrate nodes:
+//! a SCALE-encoded `u64` block count, followed by that many SCALE-encoded `SignedBlock`s.
+//! A [`BlockFileSource`] serves the blocks of a file to an archive as its chain data,
+//! so they are crawled and indexed like the blocks of a node database.
+//! Without chain state, the runtime version of each block comes from a [`SpecSchedule`],
+//! and the metadata of each version must be given or already be in the database.
+//! Storage is not indexed; blocks are queued for execution like any other
+//! and are executed by workers running against a node with their state.
+
+use crate::{
+    backend::{util::Meta, ChainDataSource},
+    database::{
+        cursors::{self, Stage},
+        Insert,
+    },
+    error::{Error, Result},
+    queries,
+    types::Metadata,
+};
+use codec::{Decode, IoReader};
+use hash_db::Prefix;
+use hashbrown::HashMap;
+use kvdb::DBValue;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sp_runtime::{
+    generic::{BlockId, SignedBlock},
+    traits::{Block as BlockT, Header as _, NumberFor, SaturatedConversion},
+    Justification,
+};
+use sqlx::{Connection, PgConnection};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// A runtime upgrade: blocks from `block` onwards run runtime version `spec`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SpecChange {
+    pub block: u32,
+    pub spec: u32,
+}
+
+/// The runtime version that each block ran
+#[derive(Debug, Clone, Default)]
+pub struct SpecSchedule {
+    changes: Vec<SpecChange>,
+}
+
+impl SpecSchedule {
+    pub fn new(mut changes: Vec<SpecChange>) -> Self {
+        changes.sort_by_key(|c| c.block);
+        Self { changes }
+    }
+
+    /// Every block runs runtime version `spec`
+    pub fn single(spec: u32) -> Self {
+        Self::new(vec![SpecChange { block: 0, spec }])
+    }
+
+    /// Read a schedule from a JSON file of the form `[{ "block": 0, "spec": 1020 }, ..]`
+    pub fn from_json(path: &Path) -> Result<Self> {
+        let changes: Vec<SpecChange> = serde_json::from_reader(File::open(path)?)?;
+        Ok(Self::new(changes))
+    }
+
+    /// Runtime version of block `num`
+    pub fn spec_at(&self, num: u32) -> Option<u32> {
+        self.changes
+            .iter()
+            .take_while(|c| c.block <= num)
+            .last()
+            .map(|c| c.spec)
+    }
+
+    /// All runtime versions in the schedule
+    pub fn versions(&self) -> impl Iterator<Item = u32> + '_ {
+        self.changes.iter().map(|c| c.spec)
+    }
+}
+
+/// Reads `SignedBlock`s from a file written by `export-blocks --binary`
+pub struct BlockFile<B: BlockT> {
+    reader: Counting<BufReader<File>>,
+    remaining: u64,
+    _marker: PhantomData<B>,
+}
+
+impl<B: BlockT> BlockFile<B> {
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut reader = Counting::new(BufReader::new(File::open(path)?));
+        let remaining = u64::decode(&mut IoReader(&mut reader))?;
+        Ok(Self {
+            reader,
+            remaining,
+            _marker: PhantomData,
+        })
+    }
+
+    /// number of blocks left to read
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    /// offset in the file of the next block
+    fn position(&self) -> u64 {
+        self.reader.read
+    }
+}
+
+impl<B: BlockT> Iterator for BlockFile<B> {
+    type Item = Result<SignedBlock<B>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        Some(SignedBlock::<B>::decode(&mut IoReader(&mut self.reader)).map_err(Into::into))
+    }
+}
+
+/// Counts the bytes read through it
+struct Counting<R> {
+    inner: R,
+    read: u64,
+}
+
+impl<R> Counting<R> {
+    fn new(inner: R) -> Self {
+        Self { inner, read: 0 }
+    }
+}
+
+impl<R: Read> Read for Counting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.read += read as u64;
+        Ok(read)
+    }
+}
+
+/// The blocks of a block file as the chain data of an archive.
+/// Opening the file reads it once to find where each block starts; blocks are read again as they are requested.
+/// Every block of the file is final. The file has no state, so state reads find nothing.
+pub struct BlockFileSource<B: BlockT> {
+    path: PathBuf,
+    reader: Mutex<BufReader<File>>,
+    /// hash and offset in the file of each block, by number
+    blocks: BTreeMap<u32, (B::Hash, u64)>,
+    numbers: HashMap<B::Hash, u32>,
+    schedule: SpecSchedule,
+}
+
+impl<B: BlockT> BlockFileSource<B> {
+    /// Open the block file at `path`, whose blocks ran the runtime versions of `schedule`
+    pub fn open(path: &Path, schedule: SpecSchedule) -> Result<Self> {
+        let mut file = BlockFile::<B>::open(path)?;
+        log::info!(
+            "Reading {} blocks from {}",
+            file.remaining(),
+            path.display()
+        );
+        let mut blocks = BTreeMap::new();
+        let mut numbers = HashMap::new();
+        loop {
+            let offset = file.position();
+            let block = match file.next() {
+                Some(block) => block?,
+                None => break,
+            };
+            let num: u32 = (*block.block.header().number()).saturated_into();
+            let hash = block.block.header().hash();
+            if schedule.spec_at(num).is_none() {
+                return Err(Error::from(format!(
+                    "no runtime version scheduled for block {}",
+                    num
+                )));
+            }
+            blocks.insert(num, (hash, offset));
+            numbers.insert(hash, num);
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            reader: Mutex::new(BufReader::new(File::open(path)?)),
+            blocks,
+            numbers,
+            schedule,
+        })
+    }
+
+    /// Number of the last block of the file
+    pub fn last_number(&self) -> Option<u32> {
+        self.blocks.keys().next_back().copied()
+    }
+
+    fn block(&self, id: BlockId<B>) -> Result<Option<SignedBlock<B>>> {
+        let num = match id {
+            BlockId::Hash(hash) => self.numbers.get(&hash).copied(),
+            BlockId::Number(num) => Some(num.saturated_into()),
+        };
+        let offset = match num.and_then(|n| self.blocks.get(&n)) {
+            Some((_, offset)) => *offset,
+            None => return Ok(None),
+        };
+        let mut reader = self.reader.lock();
+        reader.seek(SeekFrom::Start(offset))?;
+        Ok(Some(SignedBlock::<B>::decode(&mut IoReader(&mut *reader))?))
+    }
+}
+
+impl<B: BlockT> ChainDataSource<B> for BlockFileSource<B> {
+    fn header(&self, id: BlockId<B>) -> Result<Option<B::Header>> {
+        Ok(self.block(id)?.map(|b| b.block.deconstruct().0))
+    }
+
+    fn body(&self, id: BlockId<B>) -> Result<Option<Vec<B::Extrinsic>>> {
+        Ok(self.block(id)?.map(|b| b.block.deconstruct().1))
+    }
+
+    fn justification(&self, id: BlockId<B>) -> Result<Option<Justification>> {
+        Ok(self.block(id)?.and_then(|b| b.justification))
+    }
+
+    /// Block files have no state
+    fn state_node(&self, _: &B::Hash, _: Prefix) -> Result<Option<DBValue>> {
+        Ok(None)
+    }
+
+    fn meta(&self) -> Result<Meta<NumberFor<B>, B::Hash>> {
+        let (best_number, best_hash) = self
+            .blocks
+            .iter()
+            .next_back()
+            .map(|(num, (hash, _))| (*num, *hash))
+            .ok_or_else(|| Error::from(format!("{} has no blocks", self.path.display())))?;
+        Ok(Meta {
+            best_hash,
+            best_number: best_number.saturated_into(),
+            finalized_hash: best_hash,
+            finalized_number: best_number.saturated_into(),
+            genesis_hash: self.blocks.get(&0).map(|(h, _)| *h).unwrap_or_default(),
+        })
+    }
+
+    fn aux(&self, _: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn canonical_numbers<'a>(&'a self) -> Box<dyn Iterator<Item = u32> + 'a> {
+        Box::new(self.blocks.keys().copied())
+    }
+
+    fn canonical_blocks<'a>(
+        &'a self,
+        filter: Box<dyn Fn(u32) -> bool + 'a>,
+    ) -> Box<dyn Iterator<Item = SignedBlock<B>> + 'a> {
+        let file = match BlockFile::<B>::open(&self.path) {
+            Ok(file) => file,
+            Err(e) => {
+                log::error!("Failed to read {}: {}", self.path.display(), e);
+                return Box::new(std::iter::empty());
+            }
+        };
+        Box::new(
+            file.filter_map(|b| b.map_err(|e| log::error!("{}", e)).ok())
+                .filter(move |b| filter((*b.block.header().number()).saturated_into())),
+        )
+    }
+
+    fn spec_version(&self, number: u32) -> Option<u32> {
+        self.schedule.spec_at(number)
+    }
+
+    /// Block files do not change
+    fn catch_up(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Read the metadata of runtime versions from a directory of `<spec>.scale` files.
+/// Files may contain raw SCALE bytes, or `0x`-prefixed hex (the output of `state_getMetadata`).
+pub fn metadata_from_dir(dir: &Path) -> Result<HashMap<u32, Vec<u8>>> {
+    let mut metadata = HashMap::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let spec = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse::<u32>().ok());
+        if let Some(spec) = spec {
+            let bytes = std::fs::read(&path)?;
+            let bytes = match bytes.strip_prefix(b"0x") {
+                Some(encoded) => std::str::from_utf8(encoded)
+                    .map_err(|e| e.to_string())
+                    .and_then(|h| hex::decode(h.trim()).map_err(|e| e.to_string()))
+                    .map_err(|e| Error::from(format!("{}: {}", path.display(), e)))?,
+                None => bytes,
+            };
+            metadata.insert(spec, bytes);
+        }
+    }
+    Ok(metadata)
+}
+
+/// Prepares a block file to be indexed by an archive
+pub struct BlockFileImport<B: BlockT> {
+    pg_url: String,
+    path: PathBuf,
+    schedule: Option<SpecSchedule>,
+    metadata: HashMap<u32, Vec<u8>>,
+    _marker: PhantomData<B>,
+}
+
+impl<B: BlockT> BlockFileImport<B> {
+    pub fn new<S: Into<String>>(pg_url: S, path: PathBuf) -> Self {
+        Self {
+            pg_url: pg_url.into(),
+            path,
+            schedule: None,
+            metadata: HashMap::new(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Runtime upgrades of the chain
+    ///
+    /// # Default
+    /// every block runs the only runtime version with metadata in the database
+    pub fn schedule(mut self, schedule: SpecSchedule) -> Self {
+        self.schedule = Some(schedule);
+        self
+    }
+
+    /// Metadata for runtime versions that are not yet in the database
+    pub fn metadata(mut self, metadata: HashMap<u32, Vec<u8>>) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Insert the metadata, and open the block file as the chain data source of an archive.
+    /// Runs the database migrations.
+    pub fn source(self) -> Result<BlockFileSource<B>> {
+        let schedule = smol::block_on(self.prepare())?;
+        BlockFileSource::open(&self.path, schedule)
+    }
+
+    async fn prepare(&self) -> Result<SpecSchedule> {
+        crate::migrations::migrate(&self.pg_url).await?;
+        let mut conn = PgConnection::connect(&self.pg_url).await?;
+        for (spec, meta) in self.metadata.iter() {
+            if !queries::check_if_meta_exists(*spec, &mut conn).await? {
+                Metadata::new(*spec, meta.clone()).insert(&mut conn).await?;
+            }
+        }
+        // the metadata actor of the archive expands the metadata
+        let versions = queries::get_versions(&mut conn).await?;
+        let schedule = match (&self.schedule, versions.as_slice()) {
+            (Some(schedule), _) => schedule.clone(),
+            (None, [spec]) => SpecSchedule::single(*spec),
+            (None, _) => {
+                return Err(Error::from(format!(
+                    "runtime versions {:?} have metadata, a schedule is needed to tell which blocks ran them",
+                    versions
+                )))
+            }
+        };
+        if let Some(missing) = schedule.versions().find(|v| !versions.contains(v)) {
+            return Err(Error::from(format!(
+                "no metadata for runtime version {}",
+                missing
+            )));
+        }
+        Ok(schedule)
+    }
+}
+
+/// How long `wait_for_block` waits for the blocks cursor to move before giving up
+const STALL_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Wait until the archive at `pg_url` indexed the blocks up to `number`.
+/// Waits on the cursor of `Stage::Blocks`, so that blocks of earlier runs above a gap do not count.
+/// Errors if the cursor does not move for `STALL_TIMEOUT`, e.g. when an actor of the archive died.
+pub fn wait_for_block(pg_url: &str, number: u32) -> Result<()> {
+    smol::block_on(async {
+        let mut conn = PgConnection::connect(pg_url).await?;
+        let mut cursor = cursors::get(&mut conn, Stage::Blocks).await?;
+        let mut progressed = Instant::now();
+        while cursor.map(|c| c < number).unwrap_or(true) {
+            if progressed.elapsed() > STALL_TIMEOUT {
+                return Err(Error::from(format!(
+                    "blocks stopped being indexed at {:?}, waiting for {}",
+                    cursor, number
+                )));
+            }
+            smol::Timer::new(Duration::from_secs(1)).await;
+            let next = cursors::get(&mut conn, Stage::Blocks).await?;
+            if next > cursor {
+                cursor = next;
+                progressed = Instant::now();
+            }
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codec::Encode;
+    use sp_runtime::testing::{Block as TestBlock, ExtrinsicWrapper, Header};
+    use sp_runtime::traits::Header as _;
+    use std::io::Write;
+
+    type Block = TestBlock<ExtrinsicWrapper<u64>>;
+
+    #[test]
+    fn should_get_spec_from_schedule() {
+        let schedule = SpecSchedule::new(vec![
+            SpecChange {
+                block: 100,
+                spec: 2,
+            },
+            SpecChange { block: 0, spec: 1 },
+        ]);
+        assert_eq!(schedule.spec_at(0), Some(1));
+        assert_eq!(schedule.spec_at(99), Some(1));
+        assert_eq!(schedule.spec_at(100), Some(2));
+    }
+
+    fn write_blocks() -> (Vec<SignedBlock<Block>>, tempfile::NamedTempFile) {
+        let blocks = (0..3u64)
+            .map(|n| SignedBlock {
+                block: Block {
+                    header: Header::new_from_number(n),
+                    extrinsics: vec![ExtrinsicWrapper::from(n)],
+                },
+                justification: None,
+            })
+            .collect::<Vec<_>>();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&(blocks.len() as u64).encode()).unwrap();
+        for b in blocks.iter() {
+            file.write_all(&b.encode()).unwrap();
+        }
+        (blocks, file)
+    }
+
+    #[test]
+    fn should_read_block_file() {
+        let (blocks, file) = write_blocks();
+        let read = BlockFile::<Block>::open(file.path())
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(read, blocks);
+    }
+
+    #[test]
+    fn should_serve_blocks_of_file() {
+        let (blocks, file) = write_blocks();
+        let source = BlockFileSource::<Block>::open(file.path(), SpecSchedule::single(1)).unwrap();
+        assert_eq!(source.last_number(), Some(2));
+        assert_eq!(
+            source.header(BlockId::Number(1)).unwrap(),
+            Some(blocks[1].block.header.clone())
+        );
+        let hash = blocks[2].block.header.hash();
+        assert_eq!(
+            source.body(BlockId::Hash(hash)).unwrap(),
+            Some(blocks[2].block.extrinsics.clone())
+        );
+        assert_eq!(source.meta().unwrap().finalized_hash, hash);
+        assert_eq!(source.spec_version(2), Some(1));
+        let odd = source
+            .canonical_blocks(Box::new(|n| n % 2 == 1))
+            .collect::<Vec<_>>();
+        assert_eq!(odd, vec![blocks[1].clone()]);
+    }
+}
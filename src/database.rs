@@ -18,28 +18,64 @@
 //! Handles inserting of data into the database
 
 mod batch;
+mod copy;
+pub mod cursors;
+pub(crate) mod finality;
+pub(crate) mod initial_sync;
 pub mod listener;
 mod models;
+pub(crate) mod partitions;
 pub mod queries;
+pub mod queue;
 
 use async_trait::async_trait;
 use batch::Batch;
-use codec::Encode;
-use sp_runtime::traits::{Block as BlockT, Header as _, NumberFor};
+use codec::{Decode, Encode};
+use copy::{BulkInsert, COPY_THRESHOLD};
+use cursors::Stage;
+use sp_runtime::{
+    traits::{Block as BlockT, Header as _},
+    SaturatedConversion,
+};
+use sp_storage::{StorageData, StorageKey};
 use sqlx::prelude::*;
-use sqlx::{postgres::PgPoolOptions, PgPool, Postgres};
+use sqlx::{
+    postgres::{PgConnection, PgPoolOptions},
+    PgPool, Postgres,
+};
 
+pub use self::copy::ClientPool;
 pub use self::listener::*;
 pub use self::models::*;
 
-use crate::{error::Result, types::*};
+use crate::{
+    decode::{AccountBalance, DecodedStorage, ExpandedMetadata},
+    error::Result,
+    types::*,
+};
+
+/// Most connections open at once for `COPY`, in addition to the SQLx pool
+const COPY_CONNECTIONS: usize = 4;
+
+/// Rows of `_pending_storage` moved into deduplicated storage at once
+const RELEASE_CHUNK: i64 = 10_000;
+
+/// `block_num, hash, is_full, key, storage, old_storage` of `_pending_storage`
+type PendingRow = (
+    i32,
+    Vec<u8>,
+    bool,
+    Vec<u8>,
+    Option<Vec<u8>>,
+    Option<Vec<u8>>,
+);
 
 pub type DbReturn = Result<u64>;
 pub type DbConn = sqlx::pool::PoolConnection<Postgres>;
 
 #[async_trait]
 pub trait Insert: Send {
-    async fn insert(mut self, conn: &mut DbConn) -> DbReturn
+    async fn insert(mut self, conn: &mut PgConnection) -> DbReturn
     where
         Self: Sized;
 }
@@ -48,7 +84,8 @@ pub trait Insert: Send {
 pub struct Database {
     /// pool of database connections
     pool: PgPool,
-    url: String,
+    /// connections for `COPY`, which SQLx does not support
+    copy_pool: ClientPool,
 }
 
 impl Database {
@@ -60,13 +97,16 @@ impl Database {
             .idle_timeout(std::time::Duration::from_millis(3600)) // kill connections after 5 minutes of idle
             .connect(url.as_str())
             .await?;
-        Ok(Self { pool, url })
+        Ok(Self::with_pool(url, pool))
     }
 
     /// Start the database with a pre-defined pool
     #[allow(unused)]
     pub fn with_pool(url: String, pool: PgPool) -> Self {
-        Self { pool, url }
+        Self {
+            pool,
+            copy_pool: ClientPool::new(url, COPY_CONNECTIONS),
+        }
     }
 
     #[allow(unused)]
@@ -76,6 +116,50 @@ impl Database {
         Ok(res)
     }
 
+    /// Insert `data` and advance the cursors of `stages` in the same transaction
+    pub(crate) async fn insert_advancing(
+        &self,
+        data: impl Insert,
+        stages: &[Stage],
+    ) -> Result<u64> {
+        let mut conn = self.pool.acquire().await?;
+        let mut tx = conn.begin().await?;
+        let rows = data.insert(&mut tx).await?;
+        for stage in stages {
+            cursors::advance(&mut tx, *stage).await?;
+        }
+        tx.commit().await?;
+        Ok(rows)
+    }
+
+    /// Insert `data` of a stage that does not write a row for every block,
+    /// recording the blocks that went through the stage and advancing its cursor in the same transaction
+    pub(crate) async fn insert_stage(&self, data: impl Insert, blocks: StageBlocks) -> Result<u64> {
+        let stage = blocks.stage;
+        let mut conn = self.pool.acquire().await?;
+        let mut tx = conn.begin().await?;
+        let rows = data.insert(&mut tx).await?;
+        blocks.insert(&mut tx).await?;
+        cursors::advance(&mut tx, stage).await?;
+        tx.commit().await?;
+        Ok(rows)
+    }
+
+    /// Insert `data`, using `COPY` if it is large enough to benefit from it.
+    /// Advances the cursors of the stages in `advance` with the insert.
+    pub(crate) async fn bulk_insert<T: BulkInsert + 'static>(
+        &self,
+        data: T,
+        advance: &[Stage],
+    ) -> Result<u64> {
+        if data.rows() < COPY_THRESHOLD {
+            return self.insert_advancing(data, advance).await;
+        }
+        let pool = self.copy_pool.clone();
+        let advance = advance.to_vec();
+        smol::unblock!(pool.with(|client| data.copy(client, &advance)))
+    }
+
     pub async fn conn(&self) -> Result<DbConn> {
         self.pool.acquire().await.map_err(Into::into)
     }
@@ -86,74 +170,27 @@ impl Database {
 }
 
 #[async_trait]
-impl<B> Insert for Block<B>
-where
-    B: BlockT,
-    NumberFor<B>: Into<u32>,
-{
-    async fn insert(mut self, conn: &mut DbConn) -> DbReturn {
-        log::info!("Inserting single block");
-        log::trace!(
-            "block_num = {:?}, hash = {:X?}",
-            self.inner.block.header().number(),
-            hex::encode(self.inner.block.header().hash().as_ref())
-        );
-        let query = sqlx::query(
-            r#"
-            INSERT INTO blocks (parent_hash, hash, block_num, state_root, extrinsics_root, digest, ext, spec) VALUES($1, $2, $3, $4, $5, $6, $7, $8)
-            ON CONFLICT DO NOTHING
-        "#,
-        );
-        let parent_hash = self.inner.block.header().parent_hash().as_ref();
-        let hash = self.inner.block.header().hash();
-        let block_num: u32 = (*self.inner.block.header().number()).into();
-        let state_root = self.inner.block.header().state_root().as_ref();
-        let extrinsics_root = self.inner.block.header().extrinsics_root().as_ref();
-        let digest = self.inner.block.header().digest().encode();
-        let extrinsics = self.inner.block.extrinsics().encode();
-
-        query
-            .bind(parent_hash)
-            .bind(hash.as_ref())
-            .bind(block_num)
-            .bind(state_root)
-            .bind(extrinsics_root)
-            .bind(digest.as_slice())
-            .bind(extrinsics.as_slice())
-            .bind(self.spec)
-            .execute(conn)
-            .await
-            .map(|d| d.rows_affected())
-            .map_err(Into::into)
-    }
-}
-
-#[async_trait]
-impl<B> Insert for BatchBlock<B>
-where
-    B: BlockT,
-    NumberFor<B>: Into<u32>,
-{
-    async fn insert(mut self, conn: &mut DbConn) -> DbReturn {
+impl<B: BlockT> Insert for BatchBlock<B> {
+    async fn insert(mut self, conn: &mut PgConnection) -> DbReturn {
         let mut batch = Batch::new(
             "blocks",
             r#"
             INSERT INTO "blocks" (
-                parent_hash, hash, block_num, state_root, extrinsics_root, digest, ext, spec
+                parent_hash, hash, block_num, state_root, extrinsics_root, digest, ext, spec, finalized
             ) VALUES
             "#,
             r#"
             ON CONFLICT DO NOTHING
             "#,
         );
-        for b in self.inner.into_iter() {
-            batch.reserve(8)?;
+        for b in self.inner.iter() {
+            batch.reserve(9)?;
             if batch.current_num_arguments() > 0 {
                 batch.append(",");
             }
             let parent_hash = b.inner.block.header().parent_hash().as_ref();
             let hash = b.inner.block.header().hash();
-            let block_num: u32 = (*b.inner.block.header().number()).into();
+            let block_num: u32 = (*b.inner.block.header().number()).saturated_into();
             let state_root = b.inner.block.header().state_root().as_ref();
             let extrinsics_root = b.inner.block.header().extrinsics_root().as_ref();
             let digest = b.inner.block.header().digest().encode();
@@ -174,6 +211,8 @@ where
             batch.bind(extrinsics.as_slice())?;
             batch.append(",");
             batch.bind(b.spec)?;
+            batch.append(",");
+            batch.bind(self.is_finalized(block_num))?;
             batch.append(")");
         }
         Ok(batch.execute(conn).await?)
@@ -182,18 +221,19 @@ where
 
 #[async_trait]
 impl<B: BlockT> Insert for StorageModel<B> {
-    async fn insert(mut self, conn: &mut DbConn) -> DbReturn {
+    async fn insert(mut self, conn: &mut PgConnection) -> DbReturn {
         log::info!("Inserting Single Storage");
         sqlx::query(
             r#"
                 INSERT INTO storage (
-                    block_num, hash, is_full, key, storage
-                ) VALUES ($1, $2, $3, $4, $5)
-                ON CONFLICT (hash, key, md5(storage)) DO UPDATE SET
+                    block_num, hash, is_full, key, storage, old_storage
+                ) VALUES ($1, $2, $3, $4, $5, $6)
+                ON CONFLICT (block_num, hash, key, md5(storage)) DO UPDATE SET
                     hash = EXCLUDED.hash,
                     key = EXCLUDED.key,
                     storage = EXCLUDED.storage,
-                    is_full = EXCLUDED.is_full
+                    is_full = EXCLUDED.is_full,
+                    old_storage = COALESCE(EXCLUDED.old_storage, storage.old_storage)
             "#,
         )
         .bind(self.block_num())
@@ -201,6 +241,7 @@ impl<B: BlockT> Insert for StorageModel<B> {
         .bind(self.is_full())
         .bind(self.key().0.as_slice())
         .bind(self.data().map(|d| d.0.as_slice()))
+        .bind(self.old_data().map(|d| d.0.as_slice()))
         .execute(conn)
         .await
         .map(|d| d.rows_affected())
@@ -210,25 +251,26 @@ impl<B: BlockT> Insert for StorageModel<B> {
 
 #[async_trait]
 impl<B: BlockT> Insert for Vec<StorageModel<B>> {
-    async fn insert(mut self, conn: &mut DbConn) -> DbReturn {
+    async fn insert(mut self, conn: &mut PgConnection) -> DbReturn {
         let mut batch = Batch::new(
             "storage",
             r#"
             INSERT INTO "storage" (
-                block_num, hash, is_full, key, storage
+                block_num, hash, is_full, key, storage, old_storage
             ) VALUES
             "#,
             r#"
-            ON CONFLICT (hash, key, md5(storage)) DO UPDATE SET
+            ON CONFLICT (block_num, hash, key, md5(storage)) DO UPDATE SET
                 hash = EXCLUDED.hash,
                 key = EXCLUDED.key,
                 storage = EXCLUDED.storage,
-                is_full = EXCLUDED.is_full
+                is_full = EXCLUDED.is_full,
+                old_storage = COALESCE(EXCLUDED.old_storage, storage.old_storage)
             "#,
         );
 
         for s in self.into_iter() {
-            batch.reserve(5)?;
+            batch.reserve(6)?;
             if batch.current_num_arguments() > 0 {
                 batch.append(",");
             }
@@ -242,15 +284,268 @@ impl<B: BlockT> Insert for Vec<StorageModel<B>> {
             batch.bind(s.key().0.as_slice())?;
             batch.append(",");
             batch.bind(s.data().map(|d| d.0.as_slice()))?;
+            batch.append(",");
+            batch.bind(s.old_data().map(|d| d.0.as_slice()))?;
+            batch.append(")");
+        }
+        Ok(batch.execute(conn).await?)
+    }
+}
+
+#[async_trait]
+impl<B: BlockT> Insert for PendingStorage<B> {
+    async fn insert(mut self, conn: &mut PgConnection) -> DbReturn {
+        let mut batch = Batch::new(
+            "_pending_storage",
+            r#"
+            INSERT INTO "_pending_storage" (
+                block_num, hash, is_full, key, storage, old_storage
+            ) VALUES
+            "#,
+            "",
+        );
+
+        for s in self.0.into_iter() {
+            batch.reserve(6)?;
+            if batch.current_num_arguments() > 0 {
+                batch.append(",");
+            }
+            batch.append("(");
+            batch.bind(s.block_num())?;
+            batch.append(",");
+            batch.bind(s.hash().as_ref())?;
+            batch.append(",");
+            batch.bind(s.is_full())?;
+            batch.append(",");
+            batch.bind(s.key().0.as_slice())?;
+            batch.append(",");
+            batch.bind(s.data().map(|d| d.0.as_slice()))?;
+            batch.append(",");
+            batch.bind(s.old_data().map(|d| d.0.as_slice()))?;
+            batch.append(")");
+        }
+        Ok(batch.execute(conn).await?)
+    }
+}
+
+#[async_trait]
+impl<B: BlockT> Insert for UncheckedStorage<B> {
+    async fn insert(mut self, conn: &mut PgConnection) -> DbReturn {
+        let mut batch = Batch::new(
+            "storage",
+            r#"
+            INSERT INTO "storage" (
+                block_num, hash, is_full, key, storage, old_storage
+            ) VALUES
+            "#,
+            "",
+        );
+
+        for s in self.0.into_iter() {
+            batch.reserve(6)?;
+            if batch.current_num_arguments() > 0 {
+                batch.append(",");
+            }
+            batch.append("(");
+            batch.bind(s.block_num())?;
+            batch.append(",");
+            batch.bind(s.hash().as_ref())?;
+            batch.append(",");
+            batch.bind(s.is_full())?;
+            batch.append(",");
+            batch.bind(s.key().0.as_slice())?;
+            batch.append(",");
+            batch.bind(s.data().map(|d| d.0.as_slice()))?;
+            batch.append(",");
+            batch.bind(s.old_data().map(|d| d.0.as_slice()))?;
+            batch.append(")");
+        }
+        Ok(batch.execute(conn).await?)
+    }
+}
+
+#[async_trait]
+impl<B: BlockT> Insert for DedupStorage<B> {
+    async fn insert(mut self, conn: &mut PgConnection) -> DbReturn {
+        let mut values = Batch::new(
+            "storage_values",
+            r#"
+            INSERT INTO "storage_values" (value_hash, value) VALUES
+            "#,
+            r#"
+            ON CONFLICT DO NOTHING
+            "#,
+        );
+        let mut storage = Batch::new(
+            "storage",
+            r#"
+            INSERT INTO "storage" (
+                block_num, hash, is_full, key, value_hash, old_storage
+            ) VALUES
+            "#,
+            r#"
+            ON CONFLICT (block_num, hash, key, value_hash) DO UPDATE SET
+                is_full = EXCLUDED.is_full,
+                old_storage = COALESCE(EXCLUDED.old_storage, storage.old_storage)
+            "#,
+        );
+
+        let mut seen = hashbrown::HashSet::new();
+        for s in self.0.into_iter() {
+            let value_hash = s.data().map(|d| sp_core::blake2_256(&d.0));
+            if let (Some(hash), Some(value)) = (value_hash, s.data()) {
+                if seen.insert(hash) {
+                    values.reserve(2)?;
+                    if values.current_num_arguments() > 0 {
+                        values.append(",");
+                    }
+                    values.append("(");
+                    values.bind(&hash[..])?;
+                    values.append(",");
+                    values.bind(value.0.as_slice())?;
+                    values.append(")");
+                }
+            }
+            storage.reserve(6)?;
+            if storage.current_num_arguments() > 0 {
+                storage.append(",");
+            }
+            storage.append("(");
+            storage.bind(s.block_num())?;
+            storage.append(",");
+            storage.bind(s.hash().as_ref())?;
+            storage.append(",");
+            storage.bind(s.is_full())?;
+            storage.append(",");
+            storage.bind(s.key().0.as_slice())?;
+            storage.append(",");
+            storage.bind(value_hash.as_ref().map(|h| &h[..]))?;
+            storage.append(",");
+            storage.bind(s.old_data().map(|d| d.0.as_slice()))?;
+            storage.append(")");
+        }
+        // values are committed with the rows referencing them
+        let mut tx = conn.begin().await?;
+        values.execute(&mut tx).await?;
+        let rows = storage.execute(&mut tx).await?;
+        tx.commit().await?;
+        Ok(rows)
+    }
+}
+
+/// Number and hash of blocks that went through `stage`, see `cursors::Stage::done`
+#[derive(Debug, Clone)]
+pub struct StageBlocks {
+    pub stage: Stage,
+    pub blocks: Vec<(u32, Vec<u8>)>,
+}
+
+#[async_trait]
+impl Insert for StageBlocks {
+    async fn insert(mut self, conn: &mut PgConnection) -> DbReturn {
+        let mut batch = Batch::new(
+            "_stage_blocks",
+            r#"
+            INSERT INTO "_stage_blocks" (stage, block_num, hash) VALUES
+            "#,
+            r#"
+            ON CONFLICT (stage, hash) DO NOTHING
+            "#,
+        );
+        for (num, hash) in self.blocks.into_iter() {
+            batch.reserve(3)?;
+            if batch.current_num_arguments() > 0 {
+                batch.append(",");
+            }
+            batch.append("(");
+            batch.bind(self.stage.as_str())?;
+            batch.append(",");
+            batch.bind(num as i32)?;
+            batch.append(",");
+            batch.bind(hash)?;
             batch.append(")");
         }
         Ok(batch.execute(conn).await?)
     }
 }
 
+/// Move rows of `_pending_storage` whose block has been indexed into `storage`.
+/// Postgres cannot compute the hashes of deduplicated values,
+/// so with `dedup` the rows are moved through the archive, `RELEASE_CHUNK` rows at a time.
+pub(crate) async fn release_pending_storage<B: BlockT>(
+    conn: &mut PgConnection,
+    dedup: bool,
+) -> DbReturn {
+    if dedup {
+        return release_pending_dedup_storage::<B>(conn).await;
+    }
+    sqlx::query(
+        r#"
+        WITH ready AS (
+            DELETE FROM _pending_storage p USING blocks b
+            WHERE p.hash = b.hash
+            RETURNING p.block_num, p.hash, p.is_full, p.key, p.storage, p.old_storage
+        )
+        INSERT INTO storage (block_num, hash, is_full, key, storage, old_storage)
+        SELECT DISTINCT ON (block_num, hash, key, md5(storage))
+            block_num, hash, is_full, key, storage, old_storage
+        FROM ready
+        ON CONFLICT (block_num, hash, key, md5(storage)) DO UPDATE SET
+            is_full = EXCLUDED.is_full,
+            old_storage = COALESCE(EXCLUDED.old_storage, storage.old_storage)
+    "#,
+    )
+    .execute(conn)
+    .await
+    .map(|d| d.rows_affected())
+    .map_err(Into::into)
+}
+
+async fn release_pending_dedup_storage<B: BlockT>(conn: &mut PgConnection) -> DbReturn {
+    let mut tx = conn.begin().await?;
+    let mut moved = 0;
+    loop {
+        let rows: Vec<PendingRow> = sqlx::query_as(
+            r#"
+            DELETE FROM _pending_storage WHERE id IN (
+                SELECT p.id FROM _pending_storage p JOIN blocks b ON b.hash = p.hash LIMIT $1
+            )
+            RETURNING block_num, hash, is_full, key, storage, old_storage
+            "#,
+        )
+        .bind(RELEASE_CHUNK)
+        .fetch_all(&mut tx)
+        .await?;
+        if rows.is_empty() {
+            break;
+        }
+        // a row is only updated once per statement
+        let mut seen = hashbrown::HashSet::new();
+        let storage = rows
+            .into_iter()
+            .filter(|(num, hash, _, key, value, _)| {
+                seen.insert((*num, hash.clone(), key.clone(), value.clone()))
+            })
+            .map(|(num, hash, is_full, key, value, old)| {
+                Ok(StorageModel::<B>::new(
+                    Decode::decode(&mut hash.as_slice())?,
+                    num as u32,
+                    is_full,
+                    StorageKey(key),
+                    value.map(StorageData),
+                )
+                .with_old_data(old.map(StorageData)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        moved += DedupStorage(storage).insert(&mut tx).await?;
+    }
+    tx.commit().await?;
+    Ok(moved)
+}
+
 #[async_trait]
 impl Insert for Metadata {
-    async fn insert(mut self, conn: &mut DbConn) -> DbReturn {
+    async fn insert(mut self, conn: &mut PgConnection) -> DbReturn {
         log::debug!("Inserting Metadata");
         sqlx::query(
             r#"
@@ -268,8 +563,523 @@ impl Insert for Metadata {
     }
 }
 
+#[async_trait]
+impl Insert for RuntimeCode {
+    async fn insert(mut self, conn: &mut PgConnection) -> DbReturn {
+        log::debug!("Inserting Runtime Code");
+        sqlx::query(
+            r#"
+            INSERT INTO runtime_code (code_hash, spec, code)
+            VALUES($1, $2, $3)
+            ON CONFLICT DO NOTHING
+        "#,
+        )
+        .bind(self.code_hash())
+        .bind(self.spec())
+        .bind(self.code())
+        .execute(conn)
+        .await
+        .map(|d| d.rows_affected())
+        .map_err(Into::into)
+    }
+}
+
+#[async_trait]
+impl Insert for StateRootMismatch {
+    async fn insert(mut self, conn: &mut PgConnection) -> DbReturn {
+        log::debug!("Recording state root mismatch of block {}", self.block_num);
+        sqlx::query(
+            r#"
+            INSERT INTO state_root_mismatches (
+                hash, block_num, spec, expected_root, computed_root, changes, archived
+            )
+            VALUES($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (hash) DO UPDATE SET
+                spec = EXCLUDED.spec,
+                computed_root = EXCLUDED.computed_root,
+                changes = EXCLUDED.changes,
+                archived = EXCLUDED.archived,
+                detected_at = now()
+        "#,
+        )
+        .bind(self.hash.as_slice())
+        .bind(self.block_num as i32)
+        .bind(self.spec as i32)
+        .bind(self.expected_root.as_slice())
+        .bind(self.computed_root.as_slice())
+        .bind(self.changes as i32)
+        .bind(self.archived)
+        .execute(conn)
+        .await
+        .map(|d| d.rows_affected())
+        .map_err(Into::into)
+    }
+}
+
+#[async_trait]
+impl Insert for CachedStorageProof {
+    async fn insert(mut self, conn: &mut PgConnection) -> DbReturn {
+        log::debug!("Caching storage proof");
+        // only cache proofs of blocks the archive knows about
+        sqlx::query(
+            r#"
+            INSERT INTO storage_proofs (block_num, hash, key, proof)
+            SELECT block_num, hash, $2, $3 FROM blocks WHERE hash = $1
+            ON CONFLICT DO NOTHING
+        "#,
+        )
+        .bind(self.hash())
+        .bind(self.key())
+        .bind(self.proof())
+        .execute(conn)
+        .await
+        .map(|d| d.rows_affected())
+        .map_err(Into::into)
+    }
+}
+
+#[async_trait]
+impl Insert for ExpandedMetadata {
+    async fn insert(mut self, conn: &mut PgConnection) -> DbReturn {
+        log::debug!("Inserting expanded metadata for version {}", self.spec);
+        let spec = self.spec;
+        // insert everything or nothing, so versions that are missing rows can be re-expanded
+        let mut tx = conn.begin().await?;
+        let mut rows_affected = 0;
+
+        let mut batch = Batch::new(
+            "metadata_pallets",
+            r#"
+            INSERT INTO "metadata_pallets" (
+                spec, name, pallet_index, call_index, event_index, storage_prefix
+            ) VALUES
+            "#,
+            r#"
+            ON CONFLICT DO NOTHING
+            "#,
+        );
+        for p in self.pallets.into_iter() {
+            batch.reserve(6)?;
+            if batch.current_num_arguments() > 0 {
+                batch.append(",");
+            }
+            batch.append("(");
+            batch.bind(spec)?;
+            batch.append(",");
+            batch.bind(p.name)?;
+            batch.append(",");
+            batch.bind(p.index)?;
+            batch.append(",");
+            batch.bind(p.call_index)?;
+            batch.append(",");
+            batch.bind(p.event_index)?;
+            batch.append(",");
+            batch.bind(p.storage_prefix)?;
+            batch.append(")");
+        }
+        rows_affected += batch.execute(&mut tx).await?;
+
+        let mut batch = Batch::new(
+            "metadata_calls",
+            r#"
+            INSERT INTO "metadata_calls" (
+                spec, pallet, call_index, name, arguments, documentation
+            ) VALUES
+            "#,
+            r#"
+            ON CONFLICT DO NOTHING
+            "#,
+        );
+        for c in self.calls.into_iter() {
+            batch.reserve(6)?;
+            if batch.current_num_arguments() > 0 {
+                batch.append(",");
+            }
+            batch.append("(");
+            batch.bind(spec)?;
+            batch.append(",");
+            batch.bind(c.pallet)?;
+            batch.append(",");
+            batch.bind(c.index)?;
+            batch.append(",");
+            batch.bind(c.name)?;
+            batch.append(",");
+            batch.bind(sqlx::types::Json(c.arguments))?;
+            batch.append(",");
+            batch.bind(c.documentation)?;
+            batch.append(")");
+        }
+        rows_affected += batch.execute(&mut tx).await?;
+
+        let mut batch = Batch::new(
+            "metadata_events",
+            r#"
+            INSERT INTO "metadata_events" (
+                spec, pallet, event_index, name, arguments, documentation
+            ) VALUES
+            "#,
+            r#"
+            ON CONFLICT DO NOTHING
+            "#,
+        );
+        for e in self.events.into_iter() {
+            batch.reserve(6)?;
+            if batch.current_num_arguments() > 0 {
+                batch.append(",");
+            }
+            batch.append("(");
+            batch.bind(spec)?;
+            batch.append(",");
+            batch.bind(e.pallet)?;
+            batch.append(",");
+            batch.bind(e.index)?;
+            batch.append(",");
+            batch.bind(e.name)?;
+            batch.append(",");
+            batch.bind(e.arguments)?;
+            batch.append(",");
+            batch.bind(e.documentation)?;
+            batch.append(")");
+        }
+        rows_affected += batch.execute(&mut tx).await?;
+
+        let mut batch = Batch::new(
+            "metadata_errors",
+            r#"
+            INSERT INTO "metadata_errors" (
+                spec, pallet, error_index, name, documentation
+            ) VALUES
+            "#,
+            r#"
+            ON CONFLICT DO NOTHING
+            "#,
+        );
+        for e in self.errors.into_iter() {
+            batch.reserve(5)?;
+            if batch.current_num_arguments() > 0 {
+                batch.append(",");
+            }
+            batch.append("(");
+            batch.bind(spec)?;
+            batch.append(",");
+            batch.bind(e.pallet)?;
+            batch.append(",");
+            batch.bind(e.index)?;
+            batch.append(",");
+            batch.bind(e.name)?;
+            batch.append(",");
+            batch.bind(e.documentation)?;
+            batch.append(")");
+        }
+        rows_affected += batch.execute(&mut tx).await?;
+
+        let mut batch = Batch::new(
+            "metadata_constants",
+            r#"
+            INSERT INTO "metadata_constants" (
+                spec, pallet, name, ty, value, documentation
+            ) VALUES
+            "#,
+            r#"
+            ON CONFLICT DO NOTHING
+            "#,
+        );
+        for c in self.constants.into_iter() {
+            batch.reserve(6)?;
+            if batch.current_num_arguments() > 0 {
+                batch.append(",");
+            }
+            batch.append("(");
+            batch.bind(spec)?;
+            batch.append(",");
+            batch.bind(c.pallet)?;
+            batch.append(",");
+            batch.bind(c.name)?;
+            batch.append(",");
+            batch.bind(c.ty)?;
+            batch.append(",");
+            batch.bind(c.value)?;
+            batch.append(",");
+            batch.bind(c.documentation)?;
+            batch.append(")");
+        }
+        rows_affected += batch.execute(&mut tx).await?;
+
+        let mut batch = Batch::new(
+            "metadata_storage",
+            r#"
+            INSERT INTO "metadata_storage" (
+                spec, pallet, prefix, name, modifier, kind, hashers, keys,
+                value_ty, default_value, key_prefix, documentation
+            ) VALUES
+            "#,
+            r#"
+            ON CONFLICT DO NOTHING
+            "#,
+        );
+        for s in self.storage.into_iter() {
+            batch.reserve(12)?;
+            if batch.current_num_arguments() > 0 {
+                batch.append(",");
+            }
+            batch.append("(");
+            batch.bind(spec)?;
+            batch.append(",");
+            batch.bind(s.pallet)?;
+            batch.append(",");
+            batch.bind(s.prefix)?;
+            batch.append(",");
+            batch.bind(s.name)?;
+            batch.append(",");
+            batch.bind(s.modifier)?;
+            batch.append(",");
+            batch.bind(s.kind)?;
+            batch.append(",");
+            batch.bind(s.hashers)?;
+            batch.append(",");
+            batch.bind(s.keys)?;
+            batch.append(",");
+            batch.bind(s.value)?;
+            batch.append(",");
+            batch.bind(s.default)?;
+            batch.append(",");
+            batch.bind(s.key_prefix)?;
+            batch.append(",");
+            batch.bind(s.documentation)?;
+            batch.append(")");
+        }
+        rows_affected += batch.execute(&mut tx).await?;
+
+        tx.commit().await?;
+        Ok(rows_affected)
+    }
+}
+
+#[async_trait]
+impl Insert for Vec<DecodedStorage> {
+    async fn insert(mut self, conn: &mut PgConnection) -> DbReturn {
+        let mut batch = Batch::new(
+            "storage_decoded",
+            r#"
+            INSERT INTO "storage_decoded" (
+                block_num, hash, key, pallet, item, key_decoded, value
+            ) VALUES
+            "#,
+            r#"
+            ON CONFLICT (hash, key) DO UPDATE SET
+                pallet = EXCLUDED.pallet,
+                item = EXCLUDED.item,
+                key_decoded = EXCLUDED.key_decoded,
+                value = EXCLUDED.value
+            "#,
+        );
+        for s in self.into_iter() {
+            batch.reserve(7)?;
+            if batch.current_num_arguments() > 0 {
+                batch.append(",");
+            }
+            batch.append("(");
+            batch.bind(s.block_num)?;
+            batch.append(",");
+            batch.bind(s.hash)?;
+            batch.append(",");
+            batch.bind(s.key)?;
+            batch.append(",");
+            batch.bind(s.pallet)?;
+            batch.append(",");
+            batch.bind(s.item)?;
+            batch.append(",");
+            batch.bind(sqlx::types::Json(s.key_decoded))?;
+            batch.append(",");
+            batch.bind(s.value.map(sqlx::types::Json))?;
+            batch.append(")");
+        }
+        Ok(batch.execute(conn).await?)
+    }
+}
+
+#[async_trait]
+impl Insert for Vec<AccountBalance> {
+    async fn insert(mut self, conn: &mut PgConnection) -> DbReturn {
+        let mut batch = Batch::new(
+            "account_balances",
+            r#"
+            INSERT INTO "account_balances" (
+                account, block_num, hash, free, reserved, misc_frozen, fee_frozen, nonce
+            ) VALUES
+            "#,
+            r#"
+            ON CONFLICT (hash, account) DO UPDATE SET
+                free = EXCLUDED.free,
+                reserved = EXCLUDED.reserved,
+                misc_frozen = EXCLUDED.misc_frozen,
+                fee_frozen = EXCLUDED.fee_frozen,
+                nonce = EXCLUDED.nonce
+            "#,
+        );
+        // u128 does not fit any postgres integer, so balances are bound as text and cast
+        for b in self.into_iter() {
+            batch.reserve(8)?;
+            if batch.current_num_arguments() > 0 {
+                batch.append(",");
+            }
+            batch.append("(");
+            batch.bind(b.account)?;
+            batch.append(",");
+            batch.bind(b.block_num)?;
+            batch.append(",");
+            batch.bind(b.hash)?;
+            batch.append(",");
+            batch.bind(b.free.to_string())?;
+            batch.append("::NUMERIC,");
+            batch.bind(b.reserved.map(|r| r.to_string()))?;
+            batch.append("::NUMERIC,");
+            batch.bind(b.misc_frozen.map(|r| r.to_string()))?;
+            batch.append("::NUMERIC,");
+            batch.bind(b.fee_frozen.map(|r| r.to_string()))?;
+            batch.append("::NUMERIC,");
+            batch.bind(b.nonce.map(i64::from))?;
+            batch.append(")");
+        }
+        Ok(batch.execute(conn).await?)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     //! Must be connected to a local database
     use super::*;
+    use crate::harness::{
+        run_archive_with, timestamp_key, TempDatabase, TestChain, Until, SLOT_DURATION,
+    };
+    use node_template_runtime::opaque::Block;
+    use sp_core::H256;
+
+    const BLOCKS: u32 = 10;
+
+    #[test]
+    fn should_deduplicate_storage_values() {
+        crate::initialize();
+        let chain = TestChain::generate(BLOCKS).unwrap();
+        let db = TempDatabase::new().unwrap();
+
+        run_archive_with(
+            &chain,
+            &db,
+            |builder| builder.dedup_storage(true),
+            Until::Cursor(Stage::Storage, BLOCKS),
+        )
+        .unwrap();
+
+        smol::block_on(async {
+            let mut conn = PgConnection::connect(&db.url).await.unwrap();
+            for (num, hash) in chain.hashes.iter().enumerate() {
+                let value = queries::storage_value(&mut conn, hash.as_ref(), &timestamp_key())
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    value,
+                    Some(Some(((num as u64 + 1) * SLOT_DURATION).encode()))
+                );
+            }
+            let space = queries::storage_space(&mut conn).await.unwrap();
+            log::info!(
+                "Storage of a chain of {} blocks: {:?}, saved {} bytes",
+                BLOCKS,
+                space,
+                space.saved_bytes()
+            );
+
+            // every value is stored once, under its hash, and referenced instead of stored inline
+            let (distinct, inline, rows): (i64, i64, i64) = sqlx::query_as(
+                r#"
+                SELECT
+                    (SELECT COUNT(DISTINCT value_hash) FROM storage),
+                    (SELECT COUNT(*) FROM storage WHERE storage IS NOT NULL),
+                    (SELECT COUNT(*) FROM storage)
+                "#,
+            )
+            .fetch_one(&mut conn)
+            .await
+            .unwrap();
+            assert_eq!(space.distinct_values, distinct);
+            assert_eq!(inline, 0);
+            assert!(space.referencing_rows > 0 && space.referencing_rows <= rows);
+            let values: Vec<(Vec<u8>, Vec<u8>)> =
+                sqlx::query_as("SELECT value_hash, value FROM storage_values")
+                    .fetch_all(&mut conn)
+                    .await
+                    .unwrap();
+            for (hash, value) in values {
+                assert_eq!(hash, sp_core::blake2_256(&value).to_vec());
+            }
+            let (bytes,): (i64,) = sqlx::query_as(
+                "SELECT COALESCE(SUM(length(storage)), 0)::BIGINT FROM storage_with_values",
+            )
+            .fetch_one(&mut conn)
+            .await
+            .unwrap();
+            assert_eq!(space.inline_bytes, bytes);
+        });
+    }
+
+    #[test]
+    fn should_deduplicate_released_pending_storage() {
+        crate::initialize();
+        let db = TempDatabase::new().unwrap();
+        smol::block_on(async {
+            crate::migrations::migrate(&db.url).await.unwrap();
+            let mut conn = PgConnection::connect(&db.url).await.unwrap();
+            Metadata::new(0, vec![0]).insert(&mut conn).await.unwrap();
+            let (indexed, waiting) = (H256::repeat_byte(1), H256::repeat_byte(2));
+            sqlx::query(
+                r#"
+                INSERT INTO blocks (parent_hash, hash, block_num, state_root, extrinsics_root, digest, ext, spec)
+                VALUES ($1, $2, 1, $1, $1, $1, $1, 0)
+                "#,
+            )
+            .bind(&[0u8; 32][..])
+            .bind(indexed.as_bytes())
+            .execute(&mut conn)
+            .await
+            .unwrap();
+
+            let value = vec![7u8; 64];
+            let row = |hash: H256, key: &[u8]| {
+                StorageModel::<Block>::new(
+                    hash,
+                    1,
+                    false,
+                    StorageKey(key.to_vec()),
+                    Some(StorageData(value.clone())),
+                )
+            };
+            let pending = vec![
+                row(indexed, b"a"),
+                row(indexed, b"b"),
+                row(indexed, b"a"),
+                row(waiting, b"c"),
+            ];
+            PendingStorage(pending).insert(&mut conn).await.unwrap();
+
+            let moved = release_pending_storage::<Block>(&mut conn, true)
+                .await
+                .unwrap();
+            assert_eq!(moved, 2);
+            let counts: (i64, i64, i64) = sqlx::query_as(
+                r#"
+                SELECT
+                    (SELECT COUNT(*) FROM storage WHERE storage IS NULL AND value_hash = $1),
+                    (SELECT COUNT(*) FROM storage_values),
+                    (SELECT COUNT(*) FROM _pending_storage)
+                "#,
+            )
+            .bind(&sp_core::blake2_256(&value)[..])
+            .fetch_one(&mut conn)
+            .await
+            .unwrap();
+            // the storage of the block that is not indexed keeps waiting
+            assert_eq!(counts, (2, 1, 1));
+        });
+    }
 }
@@ -0,0 +1,355 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Bulk loading with `COPY FROM STDIN (FORMAT binary)`.
+//! Rows are copied into a temporary staging table, and moved into their table with the
+//! same conflict handling as the `Insert` implementations.
+//! SQLx has no support for `COPY`, so this uses the synchronous `postgres` client,
+//! on a small pool of long-lived connections kept next to the SQLx pool.
+
+use super::{
+    cursors::{self, Stage},
+    Insert, StorageModel, UncheckedStorage,
+};
+use crate::{error::Result, types::BatchBlock};
+use codec::Encode;
+use native_tls::TlsConnector;
+use postgres::{binary_copy::BinaryCopyInWriter, types::Type, Client};
+use postgres_native_tls::MakeTlsConnector;
+use sp_runtime::{
+    traits::{Block as BlockT, Header as _},
+    SaturatedConversion,
+};
+
+/// Batches of at least this many rows are copied rather than inserted
+pub const COPY_THRESHOLD: usize = 10_000;
+
+/// Data that can be loaded with `COPY`
+pub trait BulkInsert: Insert {
+    /// Number of rows this will insert
+    fn rows(&self) -> usize;
+
+    /// Copy into the database, returning the number of rows inserted.
+    /// Advances the cursors of the stages in `advance` in the same transaction.
+    fn copy(self, client: &mut Client, advance: &[Stage]) -> Result<u64>;
+}
+
+/// Open a synchronous connection, honouring the `sslmode` of `url` the way SQLx does:
+/// certificates are only verified under `verify-ca` and `verify-full`, host names only under `verify-full`,
+/// so that this connects to the same servers as the SQLx pool.
+/// `postgres` does not parse the verifying modes, so they connect as `require`.
+pub fn connect(url: &str) -> Result<Client> {
+    let verify_host = url.contains("sslmode=verify-full");
+    let verify = verify_host || url.contains("sslmode=verify-ca");
+    let url = url
+        .replace("sslmode=verify-full", "sslmode=require")
+        .replace("sslmode=verify-ca", "sslmode=require");
+    let connector = TlsConnector::builder()
+        .danger_accept_invalid_certs(!verify)
+        .danger_accept_invalid_hostnames(!verify_host)
+        .build()?;
+    let tls = MakeTlsConnector::new(connector);
+    Ok(Client::connect(&url, tls)?)
+}
+
+/// A bounded pool of synchronous connections, opened on first use and kept open.
+/// Connections that saw an error are dropped and opened again by the next user.
+#[derive(Clone)]
+pub struct ClientPool {
+    url: String,
+    /// a slot for every connection the pool may open, empty until it is first used
+    put: flume::Sender<Option<Client>>,
+    take: flume::Receiver<Option<Client>>,
+}
+
+impl ClientPool {
+    /// Open at most `size` connections to `url`
+    pub fn new(url: String, size: usize) -> Self {
+        let (put, take) = flume::bounded(size);
+        for _ in 0..size {
+            let _ = put.send(None);
+        }
+        Self { url, put, take }
+    }
+
+    /// Run `f` on a connection of the pool, blocking until one is free
+    pub fn with<T>(&self, f: impl FnOnce(&mut Client) -> Result<T>) -> Result<T> {
+        let slot = self.take.recv().expect("pool holds a sender; qed");
+        let mut client = match slot.filter(|c| !c.is_closed()) {
+            Some(client) => client,
+            None => match connect(&self.url) {
+                Ok(client) => client,
+                Err(e) => {
+                    let _ = self.put.send(None);
+                    return Err(e);
+                }
+            },
+        };
+        let res = f(&mut client);
+        let _ = self.put.send(res.as_ref().ok().map(|_| client));
+        res
+    }
+}
+
+impl<B: BlockT> BulkInsert for BatchBlock<B> {
+    fn rows(&self) -> usize {
+        self.inner.len()
+    }
+
+    fn copy(self, client: &mut Client, advance: &[Stage]) -> Result<u64> {
+        let mut tx = client.transaction()?;
+        tx.batch_execute(
+            r#"
+            CREATE TEMP TABLE _copy_blocks (
+                parent_hash bytea, hash bytea, block_num int, state_root bytea,
+                extrinsics_root bytea, digest bytea, ext bytea, spec int, finalized bool
+            ) ON COMMIT DROP
+            "#,
+        )?;
+        let sink = tx.copy_in(
+            "COPY _copy_blocks (parent_hash, hash, block_num, state_root, extrinsics_root, digest, ext, spec, finalized) FROM STDIN (FORMAT binary)",
+        )?;
+        let mut writer = BinaryCopyInWriter::new(
+            sink,
+            &[
+                Type::BYTEA,
+                Type::BYTEA,
+                Type::INT4,
+                Type::BYTEA,
+                Type::BYTEA,
+                Type::BYTEA,
+                Type::BYTEA,
+                Type::INT4,
+                Type::BOOL,
+            ],
+        );
+        for b in self.inner.iter() {
+            let header = b.inner.block.header();
+            let block_num: u32 = (*header.number()).saturated_into();
+            writer.write(&[
+                &header.parent_hash().as_ref(),
+                &header.hash().as_ref(),
+                &(block_num as i32),
+                &header.state_root().as_ref(),
+                &header.extrinsics_root().as_ref(),
+                &header.digest().encode(),
+                &b.inner.block.extrinsics().encode(),
+                &(b.spec as i32),
+                &self.is_finalized(block_num),
+            ])?;
+        }
+        writer.finish()?;
+        let rows = tx.execute(
+            r#"
+            INSERT INTO blocks (
+                parent_hash, hash, block_num, state_root, extrinsics_root, digest, ext, spec, finalized
+            )
+            SELECT parent_hash, hash, block_num, state_root, extrinsics_root, digest, ext, spec, finalized
+            FROM _copy_blocks
+            ON CONFLICT DO NOTHING
+            "#,
+            &[],
+        )?;
+        for stage in advance {
+            cursors::advance_sync(&mut tx, *stage)?;
+        }
+        tx.commit()?;
+        Ok(rows)
+    }
+}
+
+/// Types of the columns copied into `storage`
+const STORAGE_TYPES: &[Type] = &[
+    Type::INT4,
+    Type::BYTEA,
+    Type::BOOL,
+    Type::BYTEA,
+    Type::BYTEA,
+    Type::BYTEA,
+];
+
+fn write_storage<B: BlockT>(writer: &mut BinaryCopyInWriter, s: &StorageModel<B>) -> Result<()> {
+    writer.write(&[
+        &(s.block_num() as i32),
+        &s.hash().as_ref(),
+        &s.is_full(),
+        &s.key().0.as_slice(),
+        &s.data().map(|d| d.0.as_slice()),
+        &s.old_data().map(|d| d.0.as_slice()),
+    ])?;
+    Ok(())
+}
+
+impl<B: BlockT> BulkInsert for Vec<StorageModel<B>> {
+    fn rows(&self) -> usize {
+        self.len()
+    }
+
+    fn copy(self, client: &mut Client, advance: &[Stage]) -> Result<u64> {
+        let mut tx = client.transaction()?;
+        tx.batch_execute(
+            r#"
+            CREATE TEMP TABLE _copy_storage (
+                block_num int, hash bytea, is_full boolean, key bytea, storage bytea, old_storage bytea
+            ) ON COMMIT DROP
+            "#,
+        )?;
+        let sink = tx.copy_in(
+            "COPY _copy_storage (block_num, hash, is_full, key, storage, old_storage) FROM STDIN (FORMAT binary)",
+        )?;
+        let mut writer = BinaryCopyInWriter::new(sink, STORAGE_TYPES);
+        for s in self.iter() {
+            write_storage(&mut writer, s)?;
+        }
+        writer.finish()?;
+        // a single statement may not update the same row twice, unlike separate batches of `VALUES`
+        let rows = tx.execute(
+            r#"
+            INSERT INTO storage (block_num, hash, is_full, key, storage, old_storage)
+            SELECT DISTINCT ON (block_num, hash, key, md5(storage))
+                block_num, hash, is_full, key, storage, old_storage
+            FROM _copy_storage
+            ON CONFLICT (block_num, hash, key, md5(storage)) DO UPDATE SET
+                hash = EXCLUDED.hash,
+                key = EXCLUDED.key,
+                storage = EXCLUDED.storage,
+                is_full = EXCLUDED.is_full,
+                old_storage = COALESCE(EXCLUDED.old_storage, storage.old_storage)
+            "#,
+            &[],
+        )?;
+        for stage in advance {
+            cursors::advance_sync(&mut tx, *stage)?;
+        }
+        tx.commit()?;
+        Ok(rows)
+    }
+}
+
+impl<B: BlockT> BulkInsert for UncheckedStorage<B> {
+    fn rows(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Nothing to deduplicate, so this copies straight into `storage`.
+    /// Used during an initial sync, which advances cursors once it finishes, so `advance` is ignored.
+    fn copy(self, client: &mut Client, _: &[Stage]) -> Result<u64> {
+        let sink = client.copy_in(
+            "COPY storage (block_num, hash, is_full, key, storage, old_storage) FROM STDIN (FORMAT binary)",
+        )?;
+        let mut writer = BinaryCopyInWriter::new(sink, STORAGE_TYPES);
+        for s in self.0.iter() {
+            write_storage(&mut writer, s)?;
+        }
+        Ok(writer.finish()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        database::Database,
+        harness::TempDatabase,
+        types::{Block as ArchiveBlock, Metadata},
+    };
+    use sp_runtime::{
+        generic::SignedBlock,
+        testing::{Block as TestBlock, ExtrinsicWrapper, Header},
+        traits::Header as _,
+    };
+    use sp_storage::{StorageData, StorageKey};
+    use std::time::Instant;
+
+    type Block = TestBlock<ExtrinsicWrapper<u64>>;
+
+    const BLOCKS: u32 = 2_000;
+    const KEYS: u32 = 50;
+
+    fn blocks(range: std::ops::Range<u32>) -> BatchBlock<Block> {
+        BatchBlock::new(
+            range
+                .map(|n| {
+                    let block = Block {
+                        header: Header::new_from_number(n as u64),
+                        extrinsics: vec![ExtrinsicWrapper::from(n as u64)],
+                    };
+                    ArchiveBlock::new(
+                        SignedBlock {
+                            block,
+                            justification: None,
+                        },
+                        0,
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    fn storage(blocks: &BatchBlock<Block>) -> Vec<StorageModel<Block>> {
+        blocks
+            .inner()
+            .iter()
+            .flat_map(|b| {
+                let header = b.inner.block.header.clone();
+                (0..KEYS).map(move |k| {
+                    StorageModel::new(
+                        header.hash(),
+                        header.number as u32,
+                        false,
+                        StorageKey(k.encode()),
+                        Some(StorageData((header.number, k).encode())),
+                    )
+                })
+            })
+            .collect()
+    }
+
+    /// Compares `Batch` inserts with `COPY`, run with `cargo test copy -- --ignored`
+    #[test]
+    #[ignore]
+    fn bench_copy_against_batch() {
+        crate::initialize();
+        let tmp = TempDatabase::new().unwrap();
+        smol::block_on(crate::migrations::migrate(&tmp.url)).unwrap();
+        let db = smol::block_on(Database::new(tmp.url.clone())).unwrap();
+        let pool = ClientPool::new(tmp.url.clone(), 1);
+
+        // blocks reference the metadata of their runtime version
+        smol::block_on(db.insert(Metadata::new(0, Vec::new()))).unwrap();
+        let (batched, copied) = (blocks(0..BLOCKS), blocks(BLOCKS..2 * BLOCKS));
+        let (batched_storage, copied_storage) = (storage(&batched), storage(&copied));
+
+        let now = Instant::now();
+        smol::block_on(db.insert(batched)).unwrap();
+        let rows = smol::block_on(db.insert(batched_storage)).unwrap();
+        let batch_time = now.elapsed();
+
+        let now = Instant::now();
+        assert_eq!(pool.with(|c| copied.copy(c, &[])).unwrap(), BLOCKS as u64);
+        assert_eq!(pool.with(|c| copied_storage.copy(c, &[])).unwrap(), rows);
+        let copy_time = now.elapsed();
+
+        log::info!(
+            "{} blocks with {} storage rows: batch {:?}, copy {:?}, {:.2}x faster",
+            BLOCKS,
+            rows,
+            batch_time,
+            copy_time,
+            batch_time.as_secs_f64() / copy_time.as_secs_f64()
+        );
+    }
+}
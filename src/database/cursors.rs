@@ -0,0 +1,154 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Cursors of the stages of the indexing pipeline, kept in `indexer_state`.
+//! The cursor of a stage is the block number up to which every block went through the stage,
+//! so that restarts only look for missing work above it.
+//! Cursors are computed from the rows of the stage, and advanced in the transaction that inserts them.
+
+use crate::error::Result;
+use sqlx::postgres::PgConnection;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// blocks crawled into `blocks`
+    Blocks,
+    /// blocks whose runtime version has its metadata in `metadata`
+    Metadata,
+    /// blocks executed into `storage`, or skipped because of a state root mismatch
+    Storage,
+    /// blocks whose storage was decoded into `storage_decoded`, if storage is decoded
+    Decoded,
+    /// blocks whose storage was searched for balances, if balances are indexed
+    Balances,
+}
+
+impl Stage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stage::Blocks => "blocks",
+            Stage::Metadata => "metadata",
+            Stage::Storage => "storage",
+            Stage::Decoded => "decoded",
+            Stage::Balances => "balances",
+        }
+    }
+
+    /// Condition for block number `n` having gone through the stage.
+    /// Decoding and balances do not write a row for every block,
+    /// so the blocks they went through are recorded in `_stage_blocks`.
+    /// Blocks whose storage was skipped because of a state root mismatch have nothing to decode.
+    fn done(&self) -> &'static str {
+        match self {
+            Stage::Blocks => "EXISTS (SELECT 1 FROM blocks WHERE block_num = n)",
+            Stage::Metadata => {
+                r#"
+                EXISTS (
+                    SELECT 1 FROM blocks b JOIN metadata m ON m.version = b.spec
+                    WHERE b.block_num = n
+                )
+                "#
+            }
+            Stage::Storage => {
+                r#"
+                EXISTS (SELECT 1 FROM storage WHERE block_num = n)
+                OR EXISTS (SELECT 1 FROM state_root_mismatches WHERE block_num = n)
+                "#
+            }
+            Stage::Decoded => {
+                r#"
+                EXISTS (SELECT 1 FROM _stage_blocks WHERE stage = 'decoded' AND block_num = n)
+                OR EXISTS (SELECT 1 FROM state_root_mismatches WHERE block_num = n AND NOT archived)
+                "#
+            }
+            Stage::Balances => {
+                r#"
+                EXISTS (SELECT 1 FROM _stage_blocks WHERE stage = 'balances' AND block_num = n)
+                OR EXISTS (SELECT 1 FROM state_root_mismatches WHERE block_num = n AND NOT archived)
+                "#
+            }
+        }
+    }
+
+    /// Move the cursor up to the block before the first one above it that did not go through the stage.
+    /// The cursor never moves back, unless the blocks below it are rolled back with `rewind`.
+    fn advance_query(&self) -> String {
+        format!(
+            r#"
+            WITH bounds AS (
+                SELECT cursor, (SELECT max(block_num) FROM blocks) AS max_num
+                FROM indexer_state WHERE stage = $1
+            ), next AS (
+                SELECT COALESCE(
+                    (
+                        SELECT n - 1 FROM generate_series(bounds.cursor + 1, bounds.max_num) n
+                        WHERE NOT ({}) ORDER BY n LIMIT 1
+                    ),
+                    bounds.max_num,
+                    bounds.cursor
+                ) AS cursor
+                FROM bounds
+            )
+            UPDATE indexer_state SET
+                cursor = GREATEST(indexer_state.cursor, next.cursor),
+                updated_at = now()
+            FROM next
+            WHERE stage = $1
+            RETURNING indexer_state.cursor
+            "#,
+            self.done()
+        )
+    }
+}
+
+/// The cursor of `stage`. `None` if no block went through it yet.
+pub async fn get(conn: &mut PgConnection, stage: Stage) -> Result<Option<u32>> {
+    let row: Option<(i32,)> = sqlx::query_as("SELECT cursor FROM indexer_state WHERE stage = $1")
+        .bind(stage.as_str())
+        .fetch_optional(conn)
+        .await?;
+    Ok(to_cursor(row.map(|r| r.0)))
+}
+
+/// Advance the cursor of `stage` past the blocks that went through it
+pub async fn advance(conn: &mut PgConnection, stage: Stage) -> Result<Option<u32>> {
+    let row: Option<(i32,)> = sqlx::query_as(&stage.advance_query())
+        .bind(stage.as_str())
+        .fetch_optional(conn)
+        .await?;
+    Ok(to_cursor(row.map(|r| r.0)))
+}
+
+/// `advance` for the synchronous client used to `COPY`
+pub fn advance_sync(client: &mut impl postgres::GenericClient, stage: Stage) -> Result<()> {
+    client.execute(stage.advance_query().as_str(), &[&stage.as_str()])?;
+    Ok(())
+}
+
+/// Move the cursors of every stage back below `number`, once the blocks from `number` on are rolled back
+pub async fn rewind(conn: &mut PgConnection, number: u32) -> Result<()> {
+    sqlx::query(
+        "UPDATE indexer_state SET cursor = LEAST(cursor, $1), updated_at = now() WHERE cursor > $1",
+    )
+    .bind(number as i32 - 1)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+fn to_cursor(cursor: Option<i32>) -> Option<u32> {
+    cursor.filter(|c| *c >= 0).map(|c| c as u32)
+}
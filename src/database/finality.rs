@@ -0,0 +1,85 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Finality of the rows in `blocks`.
+//! Blocks indexed ahead of the finalized block of the node are marked as not finalized,
+//! and are either confirmed once finality reaches them or rolled back if they leave the canon chain.
+
+use super::{cursors, queue};
+use crate::error::Result;
+use serde::de::DeserializeOwned;
+use sp_runtime::traits::{Block as BlockT, NumberFor};
+use sqlx::{postgres::PgConnection, Connection};
+
+/// Number and hash of the blocks that are not finalized yet, in ascending order
+pub async fn unfinalized(conn: &mut PgConnection) -> Result<Vec<(u32, Vec<u8>)>> {
+    let rows: Vec<(i32, Vec<u8>)> =
+        sqlx::query_as("SELECT block_num, hash FROM blocks WHERE NOT finalized ORDER BY block_num")
+            .fetch_all(conn)
+            .await?;
+    Ok(rows.into_iter().map(|(n, h)| (n as u32, h)).collect())
+}
+
+/// Confirm the blocks up to `finalized`, and mark the blocks above it as not finalized
+pub async fn settle(conn: &mut PgConnection, finalized: u32) -> Result<()> {
+    let mut tx = conn.begin().await?;
+    sqlx::query("UPDATE blocks SET finalized = true WHERE NOT finalized AND block_num <= $1")
+        .bind(finalized as i32)
+        .execute(&mut tx)
+        .await?;
+    // blocks indexed before their finality was tracked
+    sqlx::query("UPDATE blocks SET finalized = false WHERE finalized AND block_num > $1")
+        .bind(finalized as i32)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Delete the blocks with numbers `nums` along with everything archived
+/// or waiting to be executed for them, and move the cursors back below the lowest of them
+pub async fn roll_back<B>(conn: &mut PgConnection, nums: &[u32]) -> Result<u64>
+where
+    B: BlockT + DeserializeOwned,
+    NumberFor<B>: Into<u32>,
+{
+    let from = match nums.iter().min() {
+        Some(from) => *from,
+        None => return Ok(0),
+    };
+    let mut tx = conn.begin().await?;
+    // rows of `_execution_queue` cascade from `blocks`, jobs made from them do not
+    queue::cancel_jobs::<B>(&mut tx, nums).await?;
+    let nums: Vec<i32> = nums.iter().map(|n| *n as i32).collect();
+    // the foreign key of `storage` is dropped during an initial sync
+    sqlx::query("DELETE FROM storage WHERE block_num = ANY($1)")
+        .bind(&nums)
+        .execute(&mut tx)
+        .await?;
+    // storage that arrived before its block is not released for a block that left the chain
+    sqlx::query("DELETE FROM _pending_storage WHERE block_num = ANY($1)")
+        .bind(&nums)
+        .execute(&mut tx)
+        .await?;
+    let rows = sqlx::query("DELETE FROM blocks WHERE block_num = ANY($1)")
+        .bind(&nums)
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+    cursors::rewind(&mut tx, from).await?;
+    tx.commit().await?;
+    Ok(rows)
+}
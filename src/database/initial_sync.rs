@@ -0,0 +1,383 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Initial sync of an archive without the indexes and foreign key of the `storage` table.
+//! Storage is inserted without conflict handling while ingesting. Once the archive has caught up
+//! with the chain, the indexes are rebuilt in the background one partition at a time with
+//! `CREATE INDEX CONCURRENTLY`, deduplicating each partition before its unique index is built.
+//! Storage waits in `_pending_storage` until the unique index is valid. Each finished phase is
+//! recorded in `_initial_sync` so that an interrupted rebuild resumes.
+
+use crate::error::{Error, Result};
+use sqlx::{postgres::PgConnection, Connection};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// indexes are dropped and storage is inserted as-is
+    Ingesting,
+    /// remove duplicates and rebuild the unique index
+    UniqueIndex,
+    /// rebuild the `block_num` and `(key, block_num)` indexes
+    BlockNumIndex,
+    /// restore the foreign key to `blocks`
+    ForeignKey,
+    Done,
+}
+
+impl Phase {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Phase::Ingesting => "ingesting",
+            Phase::UniqueIndex => "unique_index",
+            Phase::BlockNumIndex => "block_num_index",
+            Phase::ForeignKey => "foreign_key",
+            Phase::Done => "done",
+        }
+    }
+
+    fn parse(s: &str) -> Result<Self> {
+        match s {
+            "ingesting" => Ok(Phase::Ingesting),
+            "unique_index" => Ok(Phase::UniqueIndex),
+            "block_num_index" => Ok(Phase::BlockNumIndex),
+            "foreign_key" => Ok(Phase::ForeignKey),
+            "done" => Ok(Phase::Done),
+            _ => Err(Error::from(format!("unknown initial sync phase {}", s))),
+        }
+    }
+
+    /// Whether the unique index of `storage` is valid, so storage can be inserted with conflict handling
+    pub fn has_unique_index(&self) -> bool {
+        match self {
+            Phase::Ingesting | Phase::UniqueIndex => false,
+            Phase::BlockNumIndex | Phase::ForeignKey | Phase::Done => true,
+        }
+    }
+}
+
+/// An index of `storage`, built on each of its partitions
+struct PartitionedIndex {
+    name: &'static str,
+    /// appended to the name of a partition to name its index
+    suffix: &'static str,
+    columns: &'static str,
+    unique: bool,
+}
+
+const UNIQUE_INDEX: PartitionedIndex = PartitionedIndex {
+    name: "only_unique_hash_key_storage",
+    suffix: "unique",
+    columns: "block_num, hash, key, md5(storage)",
+    unique: true,
+};
+
+const BLOCK_NUM_INDEXES: [PartitionedIndex; 2] = [
+    PartitionedIndex {
+        name: "storage_block_num_index",
+        suffix: "block_num",
+        columns: "block_num",
+        unique: false,
+    },
+    PartitionedIndex {
+        name: "storage_key_block_num_index",
+        suffix: "key_block_num",
+        columns: "key, block_num",
+        unique: false,
+    },
+];
+
+/// Attempts at building the unique index of a partition that duplicates are inserted into meanwhile
+const UNIQUE_ATTEMPTS: usize = 3;
+
+/// The phase of the initial sync, if one was ever started
+pub async fn phase(conn: &mut PgConnection) -> Result<Option<Phase>> {
+    let row: Option<(String,)> = sqlx::query_as("SELECT phase FROM _initial_sync")
+        .fetch_optional(conn)
+        .await?;
+    row.map(|r| Phase::parse(&r.0)).transpose()
+}
+
+/// The phase of the initial sync, locking it until the transaction of `conn` ends,
+/// so that it does not move on while storage is inserted for it
+pub async fn lock_phase(conn: &mut PgConnection) -> Result<Option<Phase>> {
+    let row: Option<(String,)> = sqlx::query_as("SELECT phase FROM _initial_sync FOR SHARE")
+        .fetch_optional(conn)
+        .await?;
+    row.map(|r| Phase::parse(&r.0)).transpose()
+}
+
+async fn set_phase(conn: &mut PgConnection, phase: Phase) -> Result<()> {
+    sqlx::query(
+        r#"
+        INSERT INTO _initial_sync (id, phase) VALUES (1, $1)
+        ON CONFLICT (id) DO UPDATE SET phase = EXCLUDED.phase, updated_at = now()
+        "#,
+    )
+    .bind(phase.as_str())
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+async fn drop_indexes(conn: &mut PgConnection) -> Result<()> {
+    for stmt in &[
+        "ALTER TABLE storage DROP CONSTRAINT IF EXISTS storage_hash_fkey",
+        "DROP INDEX IF EXISTS only_unique_hash_key_storage",
+        "DROP INDEX IF EXISTS storage_block_num_index",
+        "DROP INDEX IF EXISTS storage_key_block_num_index",
+    ] {
+        sqlx::query(*stmt).execute(&mut *conn).await?;
+    }
+    Ok(())
+}
+
+/// Prepare the database when the archive starts.
+/// Begins an initial sync if `requested` and none ever ran.
+/// Returns the phase the archive starts in. A rebuild that was interrupted is resumed with `rebuild`.
+pub async fn start(conn: &mut PgConnection, requested: bool) -> Result<Phase> {
+    match phase(conn).await? {
+        None if requested => {
+            log::info!("Starting initial sync, dropping storage indexes");
+            let mut tx = conn.begin().await?;
+            drop_indexes(&mut tx).await?;
+            set_phase(&mut tx, Phase::Ingesting).await?;
+            tx.commit().await?;
+            Ok(Phase::Ingesting)
+        }
+        None => Ok(Phase::Done),
+        Some(Phase::Ingesting) => {
+            log::info!("Continuing initial sync");
+            // a migration may have recreated them
+            drop_indexes(conn).await?;
+            Ok(Phase::Ingesting)
+        }
+        Some(phase) => Ok(phase),
+    }
+}
+
+/// Rebuild and validate the indexes and foreign key of `storage`,
+/// starting from the last unfinished phase.
+/// `on_phase` is called as each phase begins, and storage must be inserted as the phase requires.
+pub async fn rebuild(conn: &mut PgConnection, mut on_phase: impl FnMut(Phase)) -> Result<()> {
+    if phase(conn).await? == Some(Phase::Ingesting) {
+        set_phase(conn, Phase::UniqueIndex).await?;
+    }
+    loop {
+        let current = phase(conn).await?.unwrap_or(Phase::Done);
+        on_phase(current);
+        log::info!("Initial sync phase: {}", current.as_str());
+        let next = match current {
+            Phase::Ingesting | Phase::UniqueIndex => {
+                build_index(conn, &UNIQUE_INDEX).await?;
+                Phase::BlockNumIndex
+            }
+            Phase::BlockNumIndex => {
+                for index in &BLOCK_NUM_INDEXES {
+                    build_index(conn, index).await?;
+                }
+                Phase::ForeignKey
+            }
+            Phase::ForeignKey => {
+                restore_foreign_key(conn).await?;
+                continue;
+            }
+            Phase::Done => return Ok(()),
+        };
+        set_phase(conn, next).await?;
+    }
+}
+
+/// Build `index` on every partition of `storage` without blocking inserts, attaching each to
+/// an index of `storage` that is valid once every partition has one.
+/// Partitions created meanwhile are indexed as they are created.
+async fn build_index(conn: &mut PgConnection, index: &PartitionedIndex) -> Result<()> {
+    let unique = if index.unique { "UNIQUE" } else { "" };
+    sqlx::query(&format!(
+        "CREATE {} INDEX IF NOT EXISTS {} ON ONLY storage ({})",
+        unique, index.name, index.columns
+    ))
+    .execute(&mut *conn)
+    .await?;
+    let partitions: Vec<(String,)> = sqlx::query_as(
+        r#"
+        SELECT c.relname::text FROM pg_inherits i JOIN pg_class c ON c.oid = i.inhrelid
+        WHERE i.inhparent = to_regclass('storage') AND NOT EXISTS (
+            SELECT 1 FROM pg_inherits p JOIN pg_index x ON x.indexrelid = p.inhrelid
+            WHERE p.inhparent = to_regclass($1) AND x.indrelid = c.oid
+        )
+        ORDER BY c.relname
+        "#,
+    )
+    .bind(index.name)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    for (partition,) in partitions {
+        let name = format!("{}_{}", partition, index.suffix);
+        let mut attempt = 1;
+        loop {
+            // an interrupted or failed build leaves an invalid index behind
+            sqlx::query(&format!("DROP INDEX CONCURRENTLY IF EXISTS {}", name))
+                .execute(&mut *conn)
+                .await?;
+            if index.unique {
+                let removed = remove_duplicates(conn, &partition).await?;
+                log::info!("Removed {} duplicate entries of {}", removed, partition);
+            }
+            let built = sqlx::query(&format!(
+                "CREATE {} INDEX CONCURRENTLY {} ON {} ({})",
+                unique, name, partition, index.columns
+            ))
+            .execute(&mut *conn)
+            .await;
+            match built {
+                Ok(_) => break,
+                // inserts that began before storage waited in `_pending_storage` may add duplicates
+                Err(e) if index.unique && attempt < UNIQUE_ATTEMPTS => {
+                    log::warn!("Could not build {}, retrying: {}", name, e);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        sqlx::query(&format!(
+            "ALTER INDEX {} ATTACH PARTITION {}",
+            index.name, name
+        ))
+        .execute(&mut *conn)
+        .await?;
+        log::info!("Built {} of {}", name, index.name);
+    }
+    Ok(())
+}
+
+async fn remove_duplicates(conn: &mut PgConnection, partition: &str) -> Result<u64> {
+    let removed = sqlx::query(&format!(
+        r#"
+        DELETE FROM {table} WHERE id IN (
+            SELECT id FROM (
+                SELECT id, row_number() OVER (
+                    PARTITION BY block_num, hash, key, md5(storage) ORDER BY id DESC
+                ) AS n
+                FROM {table} WHERE storage IS NOT NULL
+            ) dups WHERE n > 1
+        )
+        "#,
+        table = partition
+    ))
+    .execute(conn)
+    .await?
+    .rows_affected();
+    Ok(removed)
+}
+
+/// Restore the foreign key to `blocks` one partition at a time, so that only a partition is locked
+/// while its orphans are moved, and attach the validated keys to a foreign key of `storage`.
+/// Partitions created meanwhile get the key of `storage` when it is added.
+async fn restore_foreign_key(conn: &mut PgConnection) -> Result<()> {
+    let partitions: Vec<(String,)> = sqlx::query_as(
+        r#"
+        SELECT c.relname::text FROM pg_inherits i JOIN pg_class c ON c.oid = i.inhrelid
+        WHERE i.inhparent = to_regclass('storage')
+        ORDER BY c.relname
+        "#,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    for (partition,) in partitions {
+        restore_partition_foreign_key(conn, &partition).await?;
+    }
+
+    let mut tx = conn.begin().await?;
+    for stmt in &[
+        "ALTER TABLE storage DROP CONSTRAINT IF EXISTS storage_hash_fkey",
+        // partitioned tables cannot add foreign keys as `NOT VALID`;
+        // the validated keys of the partitions are attached instead of checked again
+        r#"
+        ALTER TABLE storage ADD CONSTRAINT storage_hash_fkey FOREIGN KEY (hash)
+        REFERENCES blocks(hash) ON DELETE CASCADE ON UPDATE CASCADE
+        "#,
+    ] {
+        sqlx::query(*stmt).execute(&mut tx).await?;
+    }
+    set_phase(&mut tx, Phase::Done).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Add the foreign key of `partition` as `NOT VALID`, which checks only new rows,
+/// move its rows without a block to `_pending_storage`, and validate the key,
+/// which does not block inserts
+async fn restore_partition_foreign_key(conn: &mut PgConnection, partition: &str) -> Result<()> {
+    let name = format!("{}_hash_fkey", partition);
+    let validated: Option<(bool,)> = sqlx::query_as(
+        "SELECT convalidated FROM pg_constraint WHERE conrelid = to_regclass($1) AND conname = $2",
+    )
+    .bind(partition)
+    .bind(&name)
+    .fetch_optional(&mut *conn)
+    .await?;
+    match validated {
+        Some((true,)) => return Ok(()),
+        Some((false,)) => (),
+        None => {
+            sqlx::query(&format!(
+                r#"
+                ALTER TABLE {} ADD CONSTRAINT {} FOREIGN KEY (hash)
+                REFERENCES blocks(hash) ON DELETE CASCADE ON UPDATE CASCADE NOT VALID
+                "#,
+                partition, name
+            ))
+            .execute(&mut *conn)
+            .await?;
+        }
+    }
+
+    // storage of blocks that are not indexed waits for them in `_pending_storage`,
+    // with deduplicated values resolved
+    let orphaned = sqlx::query(&format!(
+        r#"
+        WITH orphaned AS (
+            DELETE FROM {} s
+            WHERE NOT EXISTS (SELECT 1 FROM blocks b WHERE b.hash = s.hash)
+            RETURNING block_num, hash, is_full, key, storage, value_hash, old_storage
+        )
+        INSERT INTO _pending_storage (block_num, hash, is_full, key, storage, old_storage)
+        SELECT o.block_num, o.hash, o.is_full, o.key, COALESCE(o.storage, v.value), o.old_storage
+        FROM orphaned o LEFT JOIN storage_values v ON v.value_hash = o.value_hash
+        "#,
+        partition
+    ))
+    .execute(&mut *conn)
+    .await?
+    .rows_affected();
+    if orphaned > 0 {
+        log::warn!(
+            "Moved {} storage entries of {} without a block to pending storage",
+            orphaned,
+            partition
+        );
+    }
+
+    sqlx::query(&format!(
+        "ALTER TABLE {} VALIDATE CONSTRAINT {}",
+        partition, name
+    ))
+    .execute(&mut *conn)
+    .await?;
+    log::info!("Validated {}", name);
+    Ok(())
+}
@@ -45,6 +45,7 @@ pub struct StorageModel<Block: BlockT> {
     full_storage: bool,
     key: StorageKey,
     data: Option<StorageData>,
+    old_data: Option<StorageData>,
 }
 
 impl<Block: BlockT> StorageModel<Block> {
@@ -61,9 +62,16 @@ impl<Block: BlockT> StorageModel<Block> {
             full_storage,
             key,
             data,
+            old_data: None,
         }
     }
 
+    /// Set the value the key had before this block
+    pub fn with_old_data(mut self, old_data: Option<StorageData>) -> Self {
+        self.old_data = old_data;
+        self
+    }
+
     pub fn is_full(&self) -> bool {
         self.full_storage
     }
@@ -83,6 +91,10 @@ impl<Block: BlockT> StorageModel<Block> {
     pub fn data(&self) -> Option<&StorageData> {
         self.data.as_ref()
     }
+
+    pub fn old_data(&self) -> Option<&StorageData> {
+        self.old_data.as_ref()
+    }
 }
 
 impl<Block: BlockT> From<Storage<Block>> for Vec<StorageModel<Block>> {
@@ -90,10 +102,16 @@ impl<Block: BlockT> From<Storage<Block>> for Vec<StorageModel<Block>> {
         let hash = *original.hash();
         let block_num = original.block_num();
         let full_storage = original.is_full();
+        let len = original.changes.len();
+        let old_values = original.old_values.unwrap_or_else(|| vec![None; len]);
         original
             .changes
             .into_iter()
-            .map(|changes| StorageModel::new(hash, block_num, full_storage, changes.0, changes.1))
+            .zip(old_values)
+            .map(|(changes, old)| {
+                StorageModel::new(hash, block_num, full_storage, changes.0, changes.1)
+                    .with_old_data(old)
+            })
             .collect::<Vec<StorageModel<Block>>>()
     }
 }
@@ -107,3 +125,17 @@ impl<Block: BlockT> From<msg::VecStorageWrap<Block>> for Vec<StorageModel<Block>
             .collect()
     }
 }
+
+/// Storage whose block is not yet indexed, kept in the `_pending_storage` table
+/// until the block is committed
+#[derive(Debug)]
+pub struct PendingStorage<Block: BlockT>(pub Vec<StorageModel<Block>>);
+
+/// Storage inserted without conflict handling, while the unique index of `storage`
+/// is dropped during an initial sync
+#[derive(Debug)]
+pub struct UncheckedStorage<Block: BlockT>(pub Vec<StorageModel<Block>>);
+
+/// Storage with values kept once in `storage_values`, referenced by their hash
+#[derive(Debug)]
+pub struct DedupStorage<Block: BlockT>(pub Vec<StorageModel<Block>>);
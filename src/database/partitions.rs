@@ -0,0 +1,185 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! `storage` is range-partitioned by block number. Partitions are created by a trigger on `blocks`.
+//! Archives created before partitioning keep their storage in `_storage_legacy` until it is moved
+//! into the partitions here, a range of blocks at a time, so that an interrupted move resumes.
+//! The move runs in the background while the archive indexes new blocks.
+
+use crate::error::Result;
+use sqlx::{
+    postgres::{PgConnection, PgPool},
+    Connection,
+};
+
+/// Blocks of legacy storage moved per transaction. Divides the partition size,
+/// so every range lies in a single partition.
+const LEGACY_CHUNK: i32 = 10_000;
+
+/// Highest block with storage left in `_storage_legacy`, if the table exists
+pub async fn legacy_max_block(conn: &mut PgConnection) -> Result<Option<u32>> {
+    if !legacy_exists(&mut *conn).await? {
+        return Ok(None);
+    }
+    let (max,): (Option<i32>,) = sqlx::query_as("SELECT max(block_num) FROM _storage_legacy")
+        .fetch_one(conn)
+        .await?;
+    Ok(max.map(|m| m as u32))
+}
+
+/// Move legacy storage on a connection of its own, without blocking startup
+pub fn spawn_legacy_migration(pool: PgPool) {
+    smol::Task::spawn(async move {
+        let moved = match pool.acquire().await {
+            Ok(mut conn) => migrate_legacy_storage(&mut *conn).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = moved {
+            log::error!("Failed to move legacy storage into partitions: {}", e);
+        }
+    })
+    .detach();
+}
+
+async fn legacy_exists(conn: &mut PgConnection) -> Result<bool> {
+    let (exists,): (bool,) = sqlx::query_as("SELECT to_regclass('_storage_legacy') IS NOT NULL")
+        .fetch_one(conn)
+        .await?;
+    Ok(exists)
+}
+
+/// Move the storage of an unpartitioned archive into the partitions of `storage`,
+/// dropping `_storage_legacy` once it is empty. Returns the number of rows moved.
+pub async fn migrate_legacy_storage(conn: &mut PgConnection) -> Result<u64> {
+    if !legacy_exists(&mut *conn).await? {
+        return Ok(0);
+    }
+
+    let mut moved = 0;
+    loop {
+        let (min,): (Option<i32>,) = sqlx::query_as("SELECT min(block_num) FROM _storage_legacy")
+            .fetch_one(&mut *conn)
+            .await?;
+        let first = match min {
+            Some(min) => min - min % LEGACY_CHUNK,
+            None => break,
+        };
+        let mut tx = conn.begin().await?;
+        sqlx::query("SELECT create_storage_partition($1)")
+            .bind(first)
+            .execute(&mut tx)
+            .await?;
+        moved += sqlx::query(
+            r#"
+            WITH legacy AS (
+                DELETE FROM _storage_legacy WHERE block_num >= $1 AND block_num < $2
+                RETURNING block_num, hash, is_full, key, storage, old_storage
+            )
+            INSERT INTO storage (block_num, hash, is_full, key, storage, old_storage)
+            SELECT block_num, hash, is_full, key, storage, old_storage FROM legacy
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(first)
+        .bind(first + LEGACY_CHUNK)
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+        tx.commit().await?;
+        log::info!(
+            "Moved legacy storage of blocks up to {}",
+            first + LEGACY_CHUNK
+        );
+    }
+    sqlx::query("DROP TABLE _storage_legacy")
+        .execute(&mut *conn)
+        .await?;
+    log::info!("Moved {} legacy storage entries into partitions", moved);
+    Ok(moved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::cursors::Stage;
+    use crate::harness::{run_archive, TempDatabase, TestChain, Until};
+
+    const BLOCKS: u32 = 10;
+
+    #[test]
+    fn should_partition_storage_and_move_legacy_rows() {
+        crate::initialize();
+        let chain = TestChain::generate(BLOCKS).unwrap();
+        let db = TempDatabase::new().unwrap();
+
+        run_archive(&chain, &db, Until::Cursor(Stage::Storage, BLOCKS)).unwrap();
+
+        smol::block_on(async {
+            let mut conn = PgConnection::connect(&db.url).await.unwrap();
+            let partition_exists = |name: &'static str| {
+                sqlx::query_as::<_, (bool,)>("SELECT to_regclass($1) IS NOT NULL").bind(name)
+            };
+            let (exists,) = partition_exists("storage_0")
+                .fetch_one(&mut conn)
+                .await
+                .unwrap();
+            assert!(exists);
+
+            // the trigger creates the partition of a block far ahead
+            sqlx::query(
+                "INSERT INTO blocks (parent_hash, hash, block_num, state_root, extrinsics_root, digest, ext, spec)
+                SELECT parent_hash, '\\x01'::bytea || hash, 250000, state_root, extrinsics_root, digest, ext, spec
+                FROM blocks WHERE block_num = 1",
+            )
+            .execute(&mut conn)
+            .await
+            .unwrap();
+            let (exists,) = partition_exists("storage_200000")
+                .fetch_one(&mut conn)
+                .await
+                .unwrap();
+            assert!(exists);
+
+            // storage of an archive that predates partitioning
+            let (rows,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM storage")
+                .fetch_one(&mut conn)
+                .await
+                .unwrap();
+            sqlx::query("CREATE TABLE _storage_legacy (LIKE storage)")
+                .execute(&mut conn)
+                .await
+                .unwrap();
+            sqlx::query(
+                "WITH moved AS (DELETE FROM storage RETURNING block_num, hash, is_full, key, storage, old_storage)
+                INSERT INTO _storage_legacy (block_num, hash, is_full, key, storage, old_storage)
+                SELECT * FROM moved",
+            )
+            .execute(&mut conn)
+            .await
+            .unwrap();
+            assert_eq!(legacy_max_block(&mut conn).await.unwrap(), Some(BLOCKS));
+
+            let moved = migrate_legacy_storage(&mut conn).await.unwrap();
+            assert_eq!(moved, rows as u64);
+            let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM storage")
+                .fetch_one(&mut conn)
+                .await
+                .unwrap();
+            assert_eq!(count, rows);
+            assert_eq!(legacy_max_block(&mut conn).await.unwrap(), None);
+        });
+    }
+}
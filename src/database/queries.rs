@@ -16,13 +16,18 @@
 
 //! Common Sql queries on Archive Database abstracted into rust functions
 
-use super::BlockModel;
+use super::{cursors::Stage, BlockModel};
+use crate::decode::AccountBalance;
 use crate::error::{Error, Result};
+use crate::types::Storage;
+use codec::Decode;
 use futures::{stream::TryStreamExt, Stream};
 use hashbrown::HashSet;
 use serde::{de::DeserializeOwned, Deserialize};
 use sp_runtime::traits::Block as BlockT;
-use sqlx::PgConnection;
+use sp_state_machine::StorageProof;
+use sp_storage::{StorageData, StorageKey};
+use sqlx::{Connection, PgConnection};
 
 /// get missing blocks from relational database as a stream
 #[allow(unused)]
@@ -87,21 +92,23 @@ pub(crate) async fn max_block(conn: &mut PgConnection) -> Result<Option<u32>> {
     Ok(row.0.map(|v| v as u32))
 }
 
-/// Will get blocks such that they exist in the `blocks` table but they
-/// do not exist in the `storage` table
+/// Will get blocks above `after`, and the genesis block, such that they exist in the `blocks` table
+/// but they do not exist in the `storage` table
 /// blocks are ordered by spec version
 ///
 /// # Returns full blocks
 pub(crate) async fn blocks_storage_intersection(
     conn: &mut sqlx::PgConnection,
+    after: u32,
 ) -> Result<Vec<BlockModel>> {
     sqlx::query_as(
         "SELECT *
         FROM blocks
         WHERE NOT EXISTS (SELECT * FROM storage WHERE storage.block_num = blocks.block_num)
-        AND blocks.block_num != 0
+        AND (blocks.block_num > $1 OR blocks.block_num = 0)
         ORDER BY blocks.spec",
     )
+    .bind(after as i32)
     .fetch_all(conn)
     .await
     .map_err(Into::into)
@@ -152,13 +159,220 @@ pub(crate) async fn check_if_meta_exists(spec: u32, conn: &mut PgConnection) ->
     Ok(row.0)
 }
 
-pub(crate) async fn has_block<B: BlockT>(hash: B::Hash, conn: &mut PgConnection) -> Result<bool> {
-    let hash = hash.as_ref();
-    let row: (bool,) = sqlx::query_as(r#"SELECT EXISTS(SELECT 1 FROM blocks WHERE hash = $1)"#)
+/// check if the runtime code with hash `code_hash` exists in the database for a runtime version.
+/// A runtime may be upgraded without bumping its version, so the version alone is not enough.
+pub(crate) async fn check_if_code_exists(
+    code_hash: &[u8],
+    spec: u32,
+    conn: &mut PgConnection,
+) -> Result<bool> {
+    let row: (bool,) = sqlx::query_as(
+        r#"SELECT EXISTS(SELECT spec FROM runtime_code WHERE code_hash = $1 AND spec = $2)"#,
+    )
+    .bind(code_hash)
+    .bind(spec)
+    .fetch_one(conn)
+    .await?;
+    Ok(row.0)
+}
+
+/// Get the versions and raw metadata of all runtime versions whose metadata has not been expanded
+/// into the `metadata_*` tables yet
+pub(crate) async fn unexpanded_metadata(conn: &mut PgConnection) -> Result<Vec<(u32, Vec<u8>)>> {
+    let rows = sqlx::query_as::<_, (i32, Vec<u8>)>(
+        r#"
+        SELECT version, meta FROM metadata
+        WHERE NOT EXISTS (SELECT 1 FROM metadata_pallets WHERE spec = version)
+        "#,
+    )
+    .fetch_all(conn)
+    .await?;
+    Ok(rows.into_iter().map(|(v, m)| (v as u32, m)).collect())
+}
+
+/// Get the storage key prefix (`twox_128(pallet) ++ twox_128(item)`)
+/// of a storage item at runtime version `spec`
+pub async fn storage_key_prefix(
+    conn: &mut PgConnection,
+    spec: u32,
+    pallet: &str,
+    item: &str,
+) -> Result<Option<Vec<u8>>> {
+    let row = sqlx::query_as::<_, (Vec<u8>,)>(
+        "SELECT key_prefix FROM metadata_storage WHERE spec = $1 AND pallet = $2 AND name = $3",
+    )
+    .bind(spec)
+    .bind(pallet)
+    .bind(item)
+    .fetch_optional(conn)
+    .await?;
+    Ok(row.map(|r| r.0))
+}
+
+/// Get the raw runtime code (wasm blob) with blake2-256 hash `code_hash` that ran as a runtime version
+pub async fn runtime_code(
+    conn: &mut PgConnection,
+    code_hash: &[u8],
+    spec: u32,
+) -> Result<Option<Vec<u8>>> {
+    let row = sqlx::query_as::<_, (Vec<u8>,)>(
+        "SELECT code FROM runtime_code WHERE code_hash = $1 AND spec = $2",
+    )
+    .bind(code_hash)
+    .bind(spec)
+    .fetch_optional(conn)
+    .await?;
+    Ok(row.map(|r| r.0))
+}
+
+/// Get the hashes and raw runtime codes that ran as a runtime version, ordered by hash.
+/// A runtime upgraded without a version bump has several.
+pub async fn runtime_codes(conn: &mut PgConnection, spec: u32) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    sqlx::query_as("SELECT code_hash, code FROM runtime_code WHERE spec = $1 ORDER BY code_hash")
+        .bind(spec)
+        .fetch_all(conn)
+        .await
+        .map_err(Into::into)
+}
+
+/// Get the raw metadata of a runtime version
+pub(crate) async fn get_metadata(conn: &mut PgConnection, spec: u32) -> Result<Option<Vec<u8>>> {
+    let row = sqlx::query_as::<_, (Vec<u8>,)>("SELECT meta FROM metadata WHERE version = $1")
+        .bind(spec)
+        .fetch_optional(conn)
+        .await?;
+    Ok(row.map(|r| r.0))
+}
+
+/// Get the runtime version of a block, if the block exists
+pub(crate) async fn block_spec(conn: &mut PgConnection, hash: &[u8]) -> Result<Option<u32>> {
+    let row = sqlx::query_as::<_, (i32,)>("SELECT spec FROM blocks WHERE hash = $1")
         .bind(hash)
-        .fetch_one(conn)
+        .fetch_optional(conn)
         .await?;
-    Ok(row.0)
+    Ok(row.map(|r| r.0 as u32))
+}
+
+/// Get the balance of `account` as of block `block_num`,
+/// i.e the balance after the last change at or before `block_num`.
+/// Only changes in the indexed block of each number count, so changes in blocks of forks are ignored.
+///
+/// For chains that stored balances in the legacy `Balances.FreeBalance`,
+/// `account` must be the `blake2_256` hash of the account id.
+pub async fn balance_at(
+    conn: &mut PgConnection,
+    account: &[u8],
+    block_num: u32,
+) -> Result<Option<AccountBalance>> {
+    let row = sqlx::query_as::<
+        _,
+        (
+            Vec<u8>,
+            i32,
+            Vec<u8>,
+            String,
+            Option<String>,
+            Option<String>,
+            Option<String>,
+            Option<i64>,
+        ),
+    >(
+        r#"
+        SELECT b.account, b.block_num, b.hash, b.free::TEXT, b.reserved::TEXT,
+            b.misc_frozen::TEXT, b.fee_frozen::TEXT, b.nonce
+        FROM account_balances b
+        JOIN blocks ON blocks.hash = b.hash
+        WHERE b.account = $1 AND b.block_num <= $2
+        ORDER BY b.block_num DESC
+        LIMIT 1
+        "#,
+    )
+    .bind(account)
+    .bind(block_num)
+    .fetch_optional(conn)
+    .await?;
+
+    let parse = |n: String| {
+        n.parse::<u128>()
+            .map_err(|e| Error::from(format!("invalid balance {}: {}", n, e)))
+    };
+    row.map(|r| {
+        Ok(AccountBalance {
+            account: r.0,
+            block_num: r.1 as u32,
+            hash: r.2,
+            free: parse(r.3)?,
+            reserved: r.4.map(parse).transpose()?,
+            misc_frozen: r.5.map(parse).transpose()?,
+            fee_frozen: r.6.map(parse).transpose()?,
+            nonce: r.7.map(|n| n as u32),
+        })
+    })
+    .transpose()
+}
+
+/// Get the value `key` was set to in block `hash`, with either storage layout.
+/// Returns `None` if the key did not change in that block, and `Some(None)` if it was deleted.
+pub async fn storage_value(
+    conn: &mut PgConnection,
+    hash: &[u8],
+    key: &[u8],
+) -> Result<Option<Option<Vec<u8>>>> {
+    let row: Option<(Option<Vec<u8>>,)> =
+        sqlx::query_as("SELECT storage FROM storage_with_values WHERE hash = $1 AND key = $2")
+            .bind(hash)
+            .bind(key)
+            .fetch_optional(conn)
+            .await?;
+    Ok(row.map(|r| r.0))
+}
+
+/// Space taken by deduplicated storage values
+#[derive(Debug, Clone, PartialEq, Eq, sqlx::FromRow)]
+pub struct StorageSpace {
+    /// rows of `storage` referencing a value in `storage_values`
+    pub referencing_rows: i64,
+    /// values in `storage_values`
+    pub distinct_values: i64,
+    /// bytes the referenced values would take if every row stored its own
+    pub inline_bytes: i64,
+    /// bytes taken by `storage_values` and the value hashes of `storage`
+    pub deduplicated_bytes: i64,
+}
+
+impl StorageSpace {
+    /// Bytes saved by deduplication, negative if it costs more than it saves
+    pub fn saved_bytes(&self) -> i64 {
+        self.inline_bytes - self.deduplicated_bytes
+    }
+}
+
+/// Report the space saved by the deduplicated storage layout.
+/// Scans all of `storage`, so this is slow on large archives.
+pub async fn storage_space(conn: &mut PgConnection) -> Result<StorageSpace> {
+    sqlx::query_as(
+        r#"
+        SELECT
+            (SELECT COUNT(*) FROM storage WHERE value_hash IS NOT NULL) AS referencing_rows,
+            (SELECT COUNT(*) FROM storage_values) AS distinct_values,
+            (SELECT COALESCE(SUM(length(v.value)), 0) FROM storage s
+                JOIN storage_values v ON v.value_hash = s.value_hash)::BIGINT AS inline_bytes,
+            ((SELECT COALESCE(SUM(length(value) + length(value_hash)), 0) FROM storage_values)
+                + (SELECT COALESCE(SUM(length(value_hash)), 0) FROM storage))::BIGINT
+                AS deduplicated_bytes
+        "#,
+    )
+    .fetch_one(conn)
+    .await
+    .map_err(Into::into)
+}
+
+/// Connect to the database at `pg_url` and report the space saved by the deduplicated storage layout
+pub fn storage_space_at(pg_url: &str) -> Result<StorageSpace> {
+    smol::block_on(async {
+        let mut conn = PgConnection::connect(pg_url).await?;
+        storage_space(&mut conn).await
+    })
 }
 
 /// Returns a list of block_numbers, out of the passed-in blocknumbers, which exist in the database
@@ -174,6 +388,20 @@ pub(crate) async fn has_blocks<B: BlockT>(
     Ok(row.into_iter().map(|r| r.0 as u32).collect())
 }
 
+/// Number and hash of the blocks with numbers `nums` that are in the database
+pub(crate) async fn block_hashes(
+    nums: &[u32],
+    conn: &mut PgConnection,
+) -> Result<Vec<(u32, Vec<u8>)>> {
+    let rows = sqlx::query_as::<_, (i32, Vec<u8>)>(
+        "SELECT block_num, hash FROM blocks WHERE block_num = ANY ($1)",
+    )
+    .bind(nums)
+    .fetch_all(conn)
+    .await?;
+    Ok(rows.into_iter().map(|(n, h)| (n as u32, h)).collect())
+}
+
 pub(crate) async fn get_versions(conn: &mut PgConnection) -> Result<Vec<u32>> {
     let rows = sqlx::query_as::<_, (i32,)>("SELECT version FROM metadata")
         .fetch_all(conn)
@@ -181,6 +409,66 @@ pub(crate) async fn get_versions(conn: &mut PgConnection) -> Result<Vec<u32>> {
     Ok(rows.into_iter().map(|r| r.0 as u32).collect())
 }
 
+/// Get a cached merkle proof of the value of `key` at block `hash` from the `storage_proofs` table
+pub async fn storage_proof(
+    conn: &mut PgConnection,
+    hash: &[u8],
+    key: &[u8],
+) -> Result<Option<StorageProof>> {
+    let row = sqlx::query_as::<_, (Vec<u8>,)>(
+        "SELECT proof FROM storage_proofs WHERE hash = $1 AND key = $2",
+    )
+    .bind(hash)
+    .bind(key)
+    .fetch_optional(conn)
+    .await?;
+    match row {
+        Some((proof,)) => Ok(Some(Decode::decode(&mut proof.as_slice())?)),
+        None => Ok(None),
+    }
+}
+
+/// Storage of up to `limit` blocks above the cursor of `stage` that were archived,
+/// but did not go through `stage`, lowest blocks first.
+/// Used for `Stage::Decoded` and `Stage::Balances`.
+pub(crate) async fn storage_behind<B: BlockT>(
+    conn: &mut PgConnection,
+    stage: Stage,
+    limit: usize,
+) -> Result<Vec<Storage<B>>> {
+    let rows: Vec<(i32, Vec<u8>, bool, Vec<u8>, Option<Vec<u8>>)> = sqlx::query_as(
+        r#"
+        WITH behind AS (
+            SELECT b.block_num, b.hash FROM blocks b
+            WHERE b.block_num > (SELECT cursor FROM indexer_state WHERE stage = $1)
+            AND EXISTS (SELECT 1 FROM storage s WHERE s.block_num = b.block_num AND s.hash = b.hash)
+            AND NOT EXISTS (SELECT 1 FROM _stage_blocks sb WHERE sb.stage = $1 AND sb.hash = b.hash)
+            ORDER BY b.block_num LIMIT $2
+        )
+        SELECT s.block_num, s.hash, s.is_full, s.key, s.storage
+        FROM storage_with_values s JOIN behind USING (block_num, hash)
+        ORDER BY s.block_num
+        "#,
+    )
+    .bind(stage.as_str())
+    .bind(limit as i64)
+    .fetch_all(conn)
+    .await?;
+
+    let mut storage: Vec<Storage<B>> = Vec::new();
+    for (num, hash, is_full, key, value) in rows {
+        let change = (StorageKey(key), value.map(StorageData));
+        match storage.last_mut() {
+            Some(s) if s.hash().as_ref() == hash.as_slice() => s.changes.push(change),
+            _ => {
+                let hash = B::Hash::decode(&mut hash.as_slice())?;
+                storage.push(Storage::new(hash, num as u32, is_full, vec![change]));
+            }
+        }
+    }
+    Ok(storage)
+}
+
 pub(crate) async fn get_all_blocks<B: BlockT + DeserializeOwned>(
     conn: &mut PgConnection,
 ) -> Result<impl Iterator<Item = Result<B>>> {
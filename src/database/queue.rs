@@ -0,0 +1,346 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Priorities of the blocks waiting to be executed, kept in `_execution_queue`.
+//! coil runs the jobs in `_background_tasks` in the order they were queued,
+//! so blocks only become jobs a window at a time, and blocks near the tip of the chain
+//! do not wait behind a backfill.
+//! Standalone workers (see `crate::worker`) claim blocks from the same queue with leases,
+//! and blocks they claimed are skipped until the lease expires.
+
+use super::BlockModel;
+use crate::error::Result;
+use serde::{de::DeserializeOwned, Deserialize};
+use sp_runtime::traits::{Block as BlockT, Header as _, NumberFor};
+use sqlx::{postgres::PgConnection, Connection};
+use std::time::Duration;
+
+/// Blocks at most this far below the best block of the node are near the tip
+const TIP_DEPTH: u32 = 64;
+
+/// How soon a block is executed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// blocks near the best block of the node
+    Tip,
+    /// blocks further behind, from a backfill or restored on start
+    Backfill,
+}
+
+impl Priority {
+    /// Priority of block `num` when the best block of the node is `best`
+    pub fn of(num: u32, best: u32) -> Self {
+        if num.saturating_add(TIP_DEPTH) >= best {
+            Priority::Tip
+        } else {
+            Priority::Backfill
+        }
+    }
+
+    fn as_i16(&self) -> i16 {
+        match self {
+            Priority::Tip => 0,
+            Priority::Backfill => 1,
+        }
+    }
+}
+
+/// Composition of the queue of blocks to execute
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct QueueStatus {
+    /// blocks near the tip waiting to be executed
+    pub tip: u64,
+    /// blocks of a backfill waiting to be executed
+    pub backfill: u64,
+    /// blocks claimed by standalone workers
+    pub claimed: u64,
+    /// `execute_block` jobs in `_background_tasks`, running or waiting to run
+    pub jobs: u64,
+    /// jobs that failed at least once, waiting to be retried
+    pub retrying: u64,
+}
+
+impl QueueStatus {
+    /// No blocks are waiting to be executed or executing
+    pub fn is_empty(&self) -> bool {
+        self.tip + self.backfill + self.claimed + self.jobs == 0
+    }
+}
+
+/// Queue the blocks with numbers `nums` with `priority`.
+/// Blocks that are queued already keep the higher of their priorities.
+pub async fn push(conn: &mut PgConnection, nums: &[u32], priority: Priority) -> Result<u64> {
+    let nums: Vec<i32> = nums.iter().map(|n| *n as i32).collect();
+    let rows = sqlx::query(
+        r#"
+        INSERT INTO _execution_queue (block_num, priority)
+        SELECT n, $2 FROM UNNEST($1::int[]) n
+        ON CONFLICT (block_num) DO UPDATE
+        SET priority = LEAST(_execution_queue.priority, EXCLUDED.priority)
+        "#,
+    )
+    .bind(nums)
+    .bind(priority.as_i16())
+    .execute(conn)
+    .await?
+    .rows_affected();
+    Ok(rows)
+}
+
+/// Numbers of up to `window` blocks that are not claimed, lowest block numbers first.
+/// While blocks of both priorities wait, `tip_weight` blocks near the tip are picked
+/// for every block of a backfill. The rows stay locked until the end of the transaction.
+async fn pick(conn: &mut PgConnection, window: usize, tip_weight: u32) -> Result<Vec<i32>> {
+    let waiting = |priority: Priority| {
+        sqlx::query_as::<_, (i32,)>(
+            r#"
+            SELECT block_num FROM _execution_queue
+            WHERE priority = $1 AND (lease_expires IS NULL OR lease_expires < now())
+            ORDER BY block_num LIMIT $2
+            FOR UPDATE SKIP LOCKED
+            "#,
+        )
+        .bind(priority.as_i16())
+        .bind(window as i64)
+    };
+    let tip = waiting(Priority::Tip).fetch_all(&mut *conn).await?;
+    let backfill = waiting(Priority::Backfill).fetch_all(&mut *conn).await?;
+    let (tip_len, backfill_len) = split(window, tip_weight, tip.len(), backfill.len());
+    Ok(tip
+        .into_iter()
+        .take(tip_len)
+        .chain(backfill.into_iter().take(backfill_len))
+        .map(|(n,)| n)
+        .collect())
+}
+
+async fn blocks(conn: &mut PgConnection, nums: &[i32]) -> Result<Vec<BlockModel>> {
+    sqlx::query_as(
+        r#"
+        SELECT id, parent_hash, hash, block_num, state_root, extrinsics_root, digest, ext, spec
+        FROM blocks WHERE block_num = ANY($1) ORDER BY block_num
+        "#,
+    )
+    .bind(nums)
+    .fetch_all(conn)
+    .await
+    .map_err(Into::into)
+}
+
+/// Take up to `window` blocks off the queue, see `pick`. Blocks claimed by workers are skipped.
+/// Should run in the transaction that makes the blocks into jobs.
+pub async fn take(
+    conn: &mut PgConnection,
+    window: usize,
+    tip_weight: u32,
+) -> Result<Vec<BlockModel>> {
+    let nums = pick(&mut *conn, window, tip_weight).await?;
+    if nums.is_empty() {
+        return Ok(Vec::new());
+    }
+    sqlx::query("DELETE FROM _execution_queue WHERE block_num = ANY($1)")
+        .bind(&nums)
+        .execute(&mut *conn)
+        .await?;
+    blocks(conn, &nums).await
+}
+
+/// Claim up to `window` blocks for `worker` until `lease` elapses, see `pick`.
+/// The claim holds as long as the worker renews it, and the blocks stay in the queue
+/// until the worker completes them.
+pub async fn claim(
+    conn: &mut PgConnection,
+    worker: &str,
+    window: usize,
+    tip_weight: u32,
+    lease: Duration,
+) -> Result<Vec<BlockModel>> {
+    let mut tx = conn.begin().await?;
+    let nums = pick(&mut tx, window, tip_weight).await?;
+    if nums.is_empty() {
+        return Ok(Vec::new());
+    }
+    sqlx::query(
+        r#"
+        UPDATE _execution_queue SET leased_by = $2, lease_expires = now() + make_interval(secs => $3)
+        WHERE block_num = ANY($1)
+        "#,
+    )
+    .bind(&nums)
+    .bind(worker)
+    .bind(lease.as_secs_f64())
+    .execute(&mut tx)
+    .await?;
+    let blocks = blocks(&mut tx, &nums).await?;
+    tx.commit().await?;
+    Ok(blocks)
+}
+
+/// Extend the claims of `worker` by `lease`. Returns the number of blocks it holds
+pub async fn renew(conn: &mut PgConnection, worker: &str, lease: Duration) -> Result<u64> {
+    let rows = sqlx::query(
+        r#"
+        UPDATE _execution_queue SET lease_expires = now() + make_interval(secs => $2)
+        WHERE leased_by = $1
+        "#,
+    )
+    .bind(worker)
+    .bind(lease.as_secs_f64())
+    .execute(conn)
+    .await?
+    .rows_affected();
+    Ok(rows)
+}
+
+/// Remove block `num` from the queue once `worker` archived it.
+/// Returns false if the claim of the worker expired and the block may be claimed by another.
+pub async fn complete(conn: &mut PgConnection, worker: &str, num: u32) -> Result<bool> {
+    let rows = sqlx::query("DELETE FROM _execution_queue WHERE block_num = $1 AND leased_by = $2")
+        .bind(num as i32)
+        .bind(worker)
+        .execute(conn)
+        .await?
+        .rows_affected();
+    Ok(rows > 0)
+}
+
+/// Give up the claim of `worker` on block `num` after it failed,
+/// backing off exponentially before the block is claimed again
+pub async fn fail(conn: &mut PgConnection, worker: &str, num: u32) -> Result<()> {
+    sqlx::query(
+        r#"
+        UPDATE _execution_queue SET
+            leased_by = NULL,
+            attempts = attempts + 1,
+            lease_expires = now() + make_interval(secs => LEAST(power(2, attempts), 3600))
+        WHERE block_num = $1 AND leased_by = $2
+        "#,
+    )
+    .bind(num as i32)
+    .bind(worker)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// Give up every claim of `worker`, so other workers claim the blocks right away
+pub async fn release(conn: &mut PgConnection, worker: &str) -> Result<u64> {
+    let rows = sqlx::query(
+        "UPDATE _execution_queue SET leased_by = NULL, lease_expires = NULL WHERE leased_by = $1",
+    )
+    .bind(worker)
+    .execute(conn)
+    .await?
+    .rows_affected();
+    Ok(rows)
+}
+
+/// How many of `window` blocks are taken near the tip and of a backfill,
+/// out of `tip` and `backfill` waiting. A window is filled with either if the other runs out.
+fn split(window: usize, tip_weight: u32, tip: usize, backfill: usize) -> (usize, usize) {
+    let weight = tip_weight as u64;
+    let tip_share = (window as u64 * weight / (weight + 1)) as usize;
+    let tip_share = if weight > 0 {
+        std::cmp::max(tip_share, 1)
+    } else {
+        0
+    };
+    let backfill_len = std::cmp::min(backfill, window - std::cmp::min(tip, tip_share));
+    let tip_len = std::cmp::min(tip, window - backfill_len);
+    (tip_len, backfill_len)
+}
+
+/// Delete the `execute_block` jobs of blocks `nums` that are not running.
+/// Storage of jobs already running is dropped when it is inserted, see `DatabaseActor`.
+pub async fn cancel_jobs<B>(conn: &mut PgConnection, nums: &[u32]) -> Result<u64>
+where
+    B: BlockT + DeserializeOwned,
+    NumberFor<B>: Into<u32>,
+{
+    // temporary struct to deserialize job
+    #[derive(Deserialize)]
+    struct JobIn<BL: BlockT> {
+        block: BL,
+    }
+    let jobs: Vec<(i64, Vec<u8>)> = sqlx::query_as(
+        r#"
+        SELECT id, data FROM _background_tasks WHERE job_type = 'execute_block'
+        FOR UPDATE SKIP LOCKED
+        "#,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    let mut ids = Vec::new();
+    for (id, data) in jobs {
+        let job: JobIn<B> = rmp_serde::from_read(data.as_slice())?;
+        if nums.contains(&(*job.block.header().number()).into()) {
+            ids.push(id);
+        }
+    }
+    if ids.is_empty() {
+        return Ok(0);
+    }
+    let rows = sqlx::query("DELETE FROM _background_tasks WHERE id = ANY($1)")
+        .bind(&ids)
+        .execute(conn)
+        .await?
+        .rows_affected();
+    Ok(rows)
+}
+
+/// Blocks waiting in the queue, by priority, and jobs in the job queue
+pub async fn status(conn: &mut PgConnection) -> Result<QueueStatus> {
+    let (tip, backfill, claimed, jobs, retrying): (i64, i64, i64, i64, i64) = sqlx::query_as(
+        r#"
+        SELECT
+            (SELECT COUNT(*) FROM _execution_queue WHERE priority = 0 AND leased_by IS NULL),
+            (SELECT COUNT(*) FROM _execution_queue WHERE priority = 1 AND leased_by IS NULL),
+            (SELECT COUNT(*) FROM _execution_queue WHERE leased_by IS NOT NULL),
+            (SELECT COUNT(*) FROM _background_tasks WHERE job_type = 'execute_block'),
+            (SELECT COUNT(*) FROM _background_tasks WHERE job_type = 'execute_block' AND retries > 0)
+        "#,
+    )
+    .fetch_one(conn)
+    .await?;
+    Ok(QueueStatus {
+        tip: tip as u64,
+        backfill: backfill as u64,
+        claimed: claimed as u64,
+        jobs: jobs as u64,
+        retrying: retrying as u64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_split_windows_between_tip_and_backfill() {
+        // both waiting
+        assert_eq!(split(10, 4, 100, 100), (8, 2));
+        assert_eq!(split(10, 1, 100, 100), (5, 5));
+        // without a weight, blocks near the tip wait for the backfill
+        assert_eq!(split(10, 0, 100, 100), (0, 10));
+        // one of them runs out
+        assert_eq!(split(10, 4, 3, 100), (3, 7));
+        assert_eq!(split(10, 4, 100, 1), (9, 1));
+        assert_eq!(split(10, 4, 100, 0), (10, 0));
+        assert_eq!(split(10, 4, 0, 0), (0, 0));
+        // a small window still takes from the tip
+        assert_eq!(split(1, 4, 5, 5), (1, 0));
+    }
+}
@@ -0,0 +1,222 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Decodes account balances from `System.Account` and the legacy `Balances.FreeBalance` storage,
+//! with the types the metadata of the runtime version gives them
+
+use super::{
+    metadata::{ExpandedMetadata, StorageMeta},
+    value::decode_exact,
+    TypeDefs,
+};
+use crate::error::{Error, Result};
+use serde_json::Value;
+
+/// The balance of an account after a block
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccountBalance {
+    /// The `AccountId`. For balances from `Balances.FreeBalance`,
+    /// this is the `blake2_256` hash of the `AccountId` the storage key was made from.
+    pub account: Vec<u8>,
+    pub block_num: u32,
+    pub hash: Vec<u8>,
+    pub free: u128,
+    /// `None` for balances from `Balances.FreeBalance`
+    pub reserved: Option<u128>,
+    pub misc_frozen: Option<u128>,
+    pub fee_frozen: Option<u128>,
+    pub nonce: Option<u32>,
+}
+
+/// Key prefix and value type of a storage item
+struct Item {
+    key_prefix: Vec<u8>,
+    value: String,
+}
+
+impl From<&StorageMeta> for Item {
+    fn from(meta: &StorageMeta) -> Self {
+        Self {
+            key_prefix: meta.key_prefix.clone(),
+            value: meta.value.clone(),
+        }
+    }
+}
+
+/// Decodes balance changes out of the storage changes of one runtime version
+pub struct BalanceDecoder {
+    system_account: Option<Item>,
+    free_balance: Option<Item>,
+    defs: TypeDefs,
+}
+
+impl BalanceDecoder {
+    /// Decoder for the runtime version with metadata `meta`
+    /// and the type definitions `defs` of that version
+    pub fn new(meta: &ExpandedMetadata, defs: TypeDefs) -> Self {
+        let item = |prefix: &str, name: &str| {
+            meta.storage
+                .iter()
+                .find(|s| s.prefix == prefix && s.name == name)
+                .map(Item::from)
+        };
+        Self {
+            system_account: item("System", "Account"),
+            free_balance: item("Balances", "FreeBalance"),
+            defs,
+        }
+    }
+
+    /// Decode a storage change into a balance.
+    /// Returns `None` if the key is not a balance key, or if the value does not decode
+    /// as the type of the runtime. A deleted (reaped) account has a balance of zero.
+    pub fn decode(
+        &self,
+        block_num: u32,
+        hash: &[u8],
+        key: &[u8],
+        value: Option<&[u8]>,
+    ) -> Option<AccountBalance> {
+        if key.len() <= 32 {
+            return None;
+        }
+        let (prefix, rest) = key.split_at(32);
+        let decoded = match (&self.system_account, &self.free_balance) {
+            (Some(item), _) if prefix == item.key_prefix.as_slice() => {
+                // Blake2_128Concat(AccountId)
+                if rest.len() <= 16 {
+                    return None;
+                }
+                self.account_info(&item.value, value).map(
+                    |(nonce, [free, reserved, misc_frozen, fee_frozen])| AccountBalance {
+                        account: rest[16..].to_vec(),
+                        block_num,
+                        hash: hash.to_vec(),
+                        free,
+                        reserved: Some(reserved),
+                        misc_frozen: Some(misc_frozen),
+                        fee_frozen: Some(fee_frozen),
+                        nonce: Some(nonce),
+                    },
+                )
+            }
+            // Blake2_256(AccountId); the account itself can't be recovered
+            (_, Some(item)) if prefix == item.key_prefix.as_slice() => value
+                .map(|v| balance(&decode_exact(&item.value, v, &self.defs)?))
+                .unwrap_or(Ok(0))
+                .map(|free| AccountBalance {
+                    account: rest.to_vec(),
+                    block_num,
+                    hash: hash.to_vec(),
+                    free,
+                    reserved: None,
+                    misc_frozen: None,
+                    fee_frozen: None,
+                    nonce: None,
+                }),
+            _ => return None,
+        };
+        decoded
+            .map_err(|e| log::warn!("Not indexing balance of block {}: {}", block_num, e))
+            .ok()
+    }
+
+    /// Nonce and `free`, `reserved`, `misc_frozen` and `fee_frozen` balances
+    /// of an `AccountInfo` of type `ty`
+    fn account_info(&self, ty: &str, value: Option<&[u8]>) -> Result<(u32, [u128; 4])> {
+        let info = match value {
+            Some(v) => decode_exact(ty, v, &self.defs)?,
+            None => return Ok((0, [0; 4])),
+        };
+        let nonce = info["nonce"]
+            .as_u64()
+            .ok_or_else(|| Error::from(format!("no nonce in `{}`", ty)))?;
+        let data = &info["data"];
+        Ok((
+            nonce as u32,
+            [
+                balance(&data["free"])?,
+                balance(&data["reserved"])?,
+                balance(&data["misc_frozen"])?,
+                balance(&data["fee_frozen"])?,
+            ],
+        ))
+    }
+}
+
+/// A balance, which `decode_value` turns into a string
+fn balance(value: &Value) -> Result<u128> {
+    value
+        .as_str()
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| Error::from(format!("expected a balance, found {}", value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::{storage_key_prefix, RuntimeTypes};
+    use codec::Encode;
+
+    fn system_account() -> StorageMeta {
+        StorageMeta {
+            pallet: "System".into(),
+            prefix: "System".into(),
+            name: "Account".into(),
+            modifier: "Default".into(),
+            kind: "Map".into(),
+            hashers: vec!["Blake2_128Concat".into()],
+            keys: vec!["T::AccountId".into()],
+            value: "AccountInfo<T::Index, T::AccountData>".into(),
+            default: Vec::new(),
+            key_prefix: storage_key_prefix("System", "Account"),
+            documentation: String::new(),
+        }
+    }
+
+    #[test]
+    fn should_decode_system_account() {
+        let meta = ExpandedMetadata {
+            storage: vec![system_account()],
+            ..ExpandedMetadata::default()
+        };
+        let types = RuntimeTypes::default().define(0..=10, "RefCount", "u8");
+        let account = [7u8; 32];
+        let mut key = storage_key_prefix("System", "Account");
+        key.extend_from_slice(&sp_core::hashing::blake2_128(&account));
+        key.extend_from_slice(&account);
+        // nonce, u8 refcount, AccountData
+        let value = (3u32, 1u8, [100u128, 20, 5, 5]).encode();
+
+        let decoder = BalanceDecoder::new(&meta, types.for_spec(10));
+        let balance = decoder.decode(10, &[0; 32], &key, Some(&value)).unwrap();
+        assert_eq!(balance.account, account.to_vec());
+        assert_eq!(balance.free, 100);
+        assert_eq!(balance.reserved, Some(20));
+        assert_eq!(balance.nonce, Some(3));
+
+        let reaped = decoder.decode(11, &[0; 32], &key, None).unwrap();
+        assert_eq!(reaped.free, 0);
+        assert!(decoder.decode(10, &[0; 32], b":code", None).is_none());
+
+        // `RefCount` is a u32 in later runtimes
+        let decoder = BalanceDecoder::new(&meta, types.for_spec(11));
+        assert!(decoder.decode(11, &[0; 32], &key, Some(&value)).is_none());
+        let value = (3u32, 1u32, [100u128, 20, 5, 5]).encode();
+        let balance = decoder.decode(11, &[0; 32], &key, Some(&value)).unwrap();
+        assert_eq!(balance.fee_frozen, Some(5));
+    }
+}
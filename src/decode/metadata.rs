@@ -0,0 +1,404 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Expands the opaque metadata blob of a runtime version into
+//! the rows of the relational `metadata_*` tables.
+
+use crate::error::{Error, Result};
+use codec::Decode;
+use frame_metadata::{
+    DecodeDifferent, RuntimeMetadata, RuntimeMetadataPrefixed, RuntimeMetadataV11,
+    StorageEntryType, META_RESERVED,
+};
+use serde::{Deserialize, Serialize};
+use sp_core::hashing::twox_128;
+
+/// The metadata of one runtime version, flattened into table rows
+#[derive(Debug, Default)]
+pub struct ExpandedMetadata {
+    pub spec: u32,
+    pub pallets: Vec<PalletMeta>,
+    pub calls: Vec<CallMeta>,
+    pub events: Vec<EventMeta>,
+    pub errors: Vec<ErrorMeta>,
+    pub constants: Vec<ConstantMeta>,
+    pub storage: Vec<StorageMeta>,
+}
+
+#[derive(Debug)]
+pub struct PalletMeta {
+    /// position of the pallet in the runtime
+    pub index: u32,
+    pub name: String,
+    /// index used to encode calls into this pallet, if it has any calls
+    pub call_index: Option<u32>,
+    /// index used to encode events from this pallet, if it has any events
+    pub event_index: Option<u32>,
+    pub storage_prefix: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct CallMeta {
+    pub pallet: String,
+    pub index: u32,
+    pub name: String,
+    pub arguments: Vec<ArgumentMeta>,
+    pub documentation: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ArgumentMeta {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ty: String,
+}
+
+#[derive(Debug)]
+pub struct EventMeta {
+    pub pallet: String,
+    pub index: u32,
+    pub name: String,
+    pub arguments: Vec<String>,
+    pub documentation: String,
+}
+
+#[derive(Debug)]
+pub struct ErrorMeta {
+    pub pallet: String,
+    pub index: u32,
+    pub name: String,
+    pub documentation: String,
+}
+
+#[derive(Debug)]
+pub struct ConstantMeta {
+    pub pallet: String,
+    pub name: String,
+    pub ty: String,
+    pub value: Vec<u8>,
+    pub documentation: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct StorageMeta {
+    pub pallet: String,
+    /// the storage prefix of the pallet (usually, but not always, the pallet name)
+    pub prefix: String,
+    pub name: String,
+    /// `Optional` or `Default`
+    pub modifier: String,
+    /// `Plain`, `Map` or `DoubleMap`
+    pub kind: String,
+    /// hashers of the keys, in order
+    pub hashers: Vec<String>,
+    /// types of the keys, in order
+    pub keys: Vec<String>,
+    pub value: String,
+    pub default: Vec<u8>,
+    /// `twox_128(prefix) ++ twox_128(name)`. Every key of this item starts with these bytes.
+    pub key_prefix: Vec<u8>,
+    pub documentation: String,
+}
+
+/// Compute the prefix that every key of storage item `name` in the pallet with storage `prefix` starts with
+pub fn storage_key_prefix(prefix: &str, name: &str) -> Vec<u8> {
+    let mut key = twox_128(prefix.as_bytes()).to_vec();
+    key.extend_from_slice(&twox_128(name.as_bytes()));
+    key
+}
+
+/// Expand the SCALE-encoded `RuntimeMetadataPrefixed` of runtime version `spec`.
+///
+/// # Errors
+/// Errors if the metadata cannot be decoded or is of an unsupported version.
+/// Only V11 metadata is currently supported.
+pub fn expand_metadata(spec: u32, meta: &[u8]) -> Result<ExpandedMetadata> {
+    // the first four bytes are the `meta` magic, the fifth the enum index (version) of the metadata
+    let version = meta.get(4).copied();
+    let meta: RuntimeMetadataPrefixed = Decode::decode(&mut &meta[..])?;
+    if meta.0 != META_RESERVED {
+        return Err(Error::from(
+            "metadata does not begin with the `meta` magic number",
+        ));
+    }
+    match meta.1 {
+        RuntimeMetadata::V11(m) => expand_v11(spec, m),
+        _ => Err(Error::from(format!(
+            "metadata version {:?} of runtime {} is not supported",
+            version, spec
+        ))),
+    }
+}
+
+fn expand_v11(spec: u32, meta: RuntimeMetadataV11) -> Result<ExpandedMetadata> {
+    let mut expanded = ExpandedMetadata {
+        spec,
+        ..ExpandedMetadata::default()
+    };
+    let (mut call_index, mut event_index) = (0, 0);
+
+    for (index, module) in decoded(meta.modules)?.into_iter().enumerate() {
+        let pallet = decoded(module.name)?;
+
+        let storage_prefix = if let Some(storage) = module.storage {
+            let storage = decoded(storage)?;
+            let prefix = decoded(storage.prefix)?;
+            for entry in decoded(storage.entries)? {
+                let name = decoded(entry.name)?;
+                let (kind, hashers, keys, value) = match entry.ty {
+                    StorageEntryType::Plain(value) => {
+                        ("Plain", Vec::new(), Vec::new(), decoded(value)?)
+                    }
+                    StorageEntryType::Map {
+                        hasher, key, value, ..
+                    } => (
+                        "Map",
+                        vec![format!("{:?}", hasher)],
+                        vec![decoded(key)?],
+                        decoded(value)?,
+                    ),
+                    StorageEntryType::DoubleMap {
+                        hasher,
+                        key1,
+                        key2,
+                        value,
+                        key2_hasher,
+                    } => (
+                        "DoubleMap",
+                        vec![format!("{:?}", hasher), format!("{:?}", key2_hasher)],
+                        vec![decoded(key1)?, decoded(key2)?],
+                        decoded(value)?,
+                    ),
+                };
+                expanded.storage.push(StorageMeta {
+                    pallet: pallet.clone(),
+                    prefix: prefix.clone(),
+                    key_prefix: storage_key_prefix(&prefix, &name),
+                    name,
+                    modifier: format!("{:?}", entry.modifier),
+                    kind: kind.to_string(),
+                    hashers,
+                    keys,
+                    value,
+                    default: decoded(entry.default)?,
+                    documentation: docs(entry.documentation)?,
+                });
+            }
+            Some(prefix)
+        } else {
+            None
+        };
+
+        let pallet_call_index = if let Some(calls) = module.calls {
+            for (i, call) in decoded(calls)?.into_iter().enumerate() {
+                let arguments = decoded(call.arguments)?
+                    .into_iter()
+                    .map(|a| {
+                        Ok(ArgumentMeta {
+                            name: decoded(a.name)?,
+                            ty: decoded(a.ty)?,
+                        })
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                expanded.calls.push(CallMeta {
+                    pallet: pallet.clone(),
+                    index: i as u32,
+                    name: decoded(call.name)?,
+                    arguments,
+                    documentation: docs(call.documentation)?,
+                });
+            }
+            call_index += 1;
+            Some(call_index - 1)
+        } else {
+            None
+        };
+
+        let pallet_event_index = if let Some(events) = module.event {
+            for (i, event) in decoded(events)?.into_iter().enumerate() {
+                expanded.events.push(EventMeta {
+                    pallet: pallet.clone(),
+                    index: i as u32,
+                    name: decoded(event.name)?,
+                    arguments: decoded(event.arguments)?,
+                    documentation: docs(event.documentation)?,
+                });
+            }
+            event_index += 1;
+            Some(event_index - 1)
+        } else {
+            None
+        };
+
+        for constant in decoded(module.constants)? {
+            expanded.constants.push(ConstantMeta {
+                pallet: pallet.clone(),
+                name: decoded(constant.name)?,
+                ty: decoded(constant.ty)?,
+                value: decoded(constant.value)?,
+                documentation: docs(constant.documentation)?,
+            });
+        }
+
+        for (i, error) in decoded(module.errors)?.into_iter().enumerate() {
+            expanded.errors.push(ErrorMeta {
+                pallet: pallet.clone(),
+                index: i as u32,
+                name: decoded(error.name)?,
+                documentation: docs(error.documentation)?,
+            });
+        }
+
+        expanded.pallets.push(PalletMeta {
+            index: index as u32,
+            name: pallet,
+            call_index: pallet_call_index,
+            event_index: pallet_event_index,
+            storage_prefix,
+        });
+    }
+    Ok(expanded)
+}
+
+/// Metadata that was decoded (rather than built in a runtime) is always `Decoded`
+fn decoded<B: 'static, O: 'static>(d: DecodeDifferent<B, O>) -> Result<O> {
+    match d {
+        DecodeDifferent::Decoded(o) => Ok(o),
+        DecodeDifferent::Encode(_) => Err(Error::from("metadata item is not decoded")),
+    }
+}
+
+fn docs<B: 'static>(d: DecodeDifferent<B, Vec<String>>) -> Result<String> {
+    Ok(decoded(d)?.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_compute_storage_key_prefix() {
+        // `twox_128("System") ++ twox_128("Account")`
+        let expected =
+            hex::decode("26aa394eea5630e07c48ae0c9558cef7b99d880ec681799c0cf30e8886371da9")
+                .unwrap();
+        assert_eq!(storage_key_prefix("System", "Account"), expected);
+    }
+
+    fn node_template_metadata() -> Vec<u8> {
+        use codec::Encode;
+        node_template_runtime::Runtime::metadata().encode()
+    }
+
+    #[test]
+    fn should_expand_node_template_metadata() {
+        use codec::Encode;
+        use node_template_runtime::{Call, TimestampCall};
+
+        let spec = node_template_runtime::VERSION.spec_version;
+        let expanded = expand_metadata(spec, &node_template_metadata()).unwrap();
+        assert_eq!(expanded.spec, spec);
+        let pallet = |name: &str| {
+            expanded
+                .pallets
+                .iter()
+                .find(|p| p.name == name)
+                .unwrap_or_else(|| panic!("no pallet {}", name))
+        };
+        for (i, p) in expanded.pallets.iter().enumerate() {
+            assert_eq!(p.index, i as u32);
+        }
+
+        // calls are encoded with the index of their pallet among the pallets with calls
+        let system = pallet("System");
+        let timestamp = pallet("Timestamp");
+        let set = Call::Timestamp(TimestampCall::set(0)).encode();
+        assert_eq!(system.call_index, Some(0));
+        assert_eq!(timestamp.call_index, Some(set[0] as u32));
+        let call = expanded
+            .calls
+            .iter()
+            .find(|c| c.pallet == "Timestamp" && c.name == "set")
+            .unwrap();
+        assert_eq!(call.index, set[1] as u32);
+        assert_eq!(
+            call.arguments,
+            vec![ArgumentMeta {
+                name: "now".into(),
+                ty: "Compact<T::Moment>".into()
+            }]
+        );
+
+        // pallets without events, like `Timestamp`, do not count towards event indices
+        let with_events = expanded
+            .pallets
+            .iter()
+            .filter_map(|p| p.event_index)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            with_events,
+            (0..with_events.len() as u32).collect::<Vec<_>>()
+        );
+        assert_eq!(system.event_index, Some(0));
+        assert_eq!(timestamp.event_index, None);
+        let balances = pallet("Balances");
+        assert!(balances.event_index.unwrap() < balances.index);
+        let event = |name: &str| {
+            expanded
+                .events
+                .iter()
+                .find(|e| e.pallet == "System" && e.name == name)
+                .unwrap()
+                .index
+        };
+        assert_eq!(
+            (event("ExtrinsicSuccess"), event("ExtrinsicFailed")),
+            (0, 1)
+        );
+
+        let storage = |pallet: &str, name: &str| {
+            expanded
+                .storage
+                .iter()
+                .find(|s| s.pallet == pallet && s.name == name)
+                .unwrap()
+        };
+        let now = storage("Timestamp", "Now");
+        assert_eq!(timestamp.storage_prefix.as_deref(), Some("Timestamp"));
+        assert_eq!(
+            (now.kind.as_str(), now.value.as_str()),
+            ("Plain", "T::Moment")
+        );
+        assert!(now.hashers.is_empty());
+        assert_eq!(now.key_prefix, storage_key_prefix("Timestamp", "Now"));
+        let account = storage("System", "Account");
+        assert_eq!(account.kind, "Map");
+        assert_eq!(account.hashers, vec!["Blake2_128Concat".to_string()]);
+        assert_eq!(account.keys, vec!["T::AccountId".to_string()]);
+        assert_eq!(account.key_prefix, storage_key_prefix("System", "Account"));
+    }
+
+    #[test]
+    fn should_not_expand_other_metadata_versions() {
+        let mut meta = node_template_metadata();
+        // the enum index of V10
+        meta[4] = 10;
+        assert!(expand_metadata(1, &meta).is_err());
+        // missing the magic number
+        assert!(expand_metadata(1, &node_template_metadata()[4..]).is_err());
+        assert!(expand_metadata(1, &[]).is_err());
+    }
+}
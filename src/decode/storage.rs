@@ -0,0 +1,164 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Matches raw storage keys to the storage items of a runtime version and decodes them
+
+use super::{
+    metadata::{ExpandedMetadata, StorageMeta},
+    value::{decode_prefix, decode_value, to_hex},
+};
+use hashbrown::HashMap;
+use serde_json::Value;
+
+/// A storage entry, decoded against the metadata of the runtime it was written by
+#[derive(Debug, Clone)]
+pub struct DecodedStorage {
+    pub block_num: u32,
+    pub hash: Vec<u8>,
+    pub key: Vec<u8>,
+    pub pallet: String,
+    pub item: String,
+    /// the map key(s), or `null` for plain storage items
+    pub key_decoded: Value,
+    /// `None` if the entry was deleted
+    pub value: Option<Value>,
+}
+
+/// The storage items of one runtime version, indexed by their key prefix
+pub struct StorageLookup {
+    items: HashMap<Vec<u8>, StorageMeta>,
+}
+
+impl StorageLookup {
+    pub fn new(meta: ExpandedMetadata) -> Self {
+        let items = meta
+            .storage
+            .into_iter()
+            .map(|s| (s.key_prefix.clone(), s))
+            .collect();
+        Self { items }
+    }
+
+    /// Decode a storage entry.
+    /// Returns `None` if the key does not belong to any storage item of this runtime
+    /// (e.g well-known keys like `:code`).
+    pub fn decode(
+        &self,
+        block_num: u32,
+        hash: &[u8],
+        key: &[u8],
+        value: Option<&[u8]>,
+    ) -> Option<DecodedStorage> {
+        if key.len() < 32 {
+            return None;
+        }
+        let meta = self.items.get(&key[..32])?;
+        Some(DecodedStorage {
+            block_num,
+            hash: hash.to_vec(),
+            key: key.to_vec(),
+            pallet: meta.pallet.clone(),
+            item: meta.name.clone(),
+            key_decoded: decode_keys(meta, &key[32..]),
+            value: value.map(|v| decode_value(&meta.value, v)),
+        })
+    }
+}
+
+fn decode_keys(meta: &StorageMeta, mut input: &[u8]) -> Value {
+    let mut keys = meta
+        .hashers
+        .iter()
+        .zip(meta.keys.iter())
+        .map(|(hasher, ty)| decode_key(hasher, ty, &mut input))
+        .collect::<Vec<Value>>();
+    match keys.len() {
+        0 => Value::Null,
+        1 => keys.remove(0),
+        _ => Value::Array(keys),
+    }
+}
+
+/// Decode one hashed key. Keys are only recoverable from transparent hashers,
+/// opaque hashers yield the hash itself.
+fn decode_key(hasher: &str, ty: &str, input: &mut &[u8]) -> Value {
+    let (hash_len, transparent) = match hasher {
+        "Blake2_128Concat" => (16, true),
+        "Twox64Concat" => (8, true),
+        "Identity" => (0, true),
+        "Blake2_128" | "Twox128" => (16, false),
+        _ => (32, false),
+    };
+    let len = hash_len.min(input.len());
+    let (hash, rest) = input.split_at(len);
+    *input = rest;
+    if !transparent {
+        return to_hex(hash);
+    }
+    let before = *input;
+    match decode_prefix(ty, input) {
+        Ok(v) => v,
+        Err(_) => {
+            // the key can't be decoded, so the rest of the input can't be split
+            *input = &[];
+            to_hex(before)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::storage_key_prefix;
+    use codec::Encode;
+    use serde_json::json;
+
+    #[test]
+    fn should_recover_map_keys() {
+        let meta = StorageMeta {
+            pallet: "Example".into(),
+            prefix: "Example".into(),
+            name: "Values".into(),
+            modifier: "Default".into(),
+            kind: "DoubleMap".into(),
+            hashers: vec!["Twox64Concat".into(), "Blake2_128".into()],
+            keys: vec!["u32".into(), "u64".into()],
+            value: "T::Balance".into(),
+            default: Vec::new(),
+            key_prefix: storage_key_prefix("Example", "Values"),
+            documentation: String::new(),
+        };
+        let lookup = StorageLookup::new(ExpandedMetadata {
+            storage: vec![meta.clone()],
+            ..ExpandedMetadata::default()
+        });
+
+        let mut key = meta.key_prefix.clone();
+        key.extend_from_slice(&[0u8; 8]);
+        key.extend_from_slice(&7u32.encode());
+        key.extend_from_slice(&[0xff; 16]);
+        let value = 10u128.encode();
+
+        let decoded = lookup.decode(1, &[0; 32], &key, Some(&value)).unwrap();
+        assert_eq!(decoded.item, "Values");
+        assert_eq!(
+            decoded.key_decoded,
+            json!([7, "0xffffffffffffffffffffffffffffffff"])
+        );
+        assert_eq!(decoded.value, Some(json!("10")));
+        assert!(lookup.decode(1, &[0; 32], b":code", None).is_none());
+    }
+}
@@ -0,0 +1,49 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Definitions of the type names whose layout metadata does not describe,
+//! e.g `RefCount`, which was a `u8` in older runtimes and is a `u32` since.
+
+use hashbrown::HashMap;
+use std::ops::RangeInclusive;
+
+/// Type names and their definitions in one runtime version
+pub type TypeDefs = HashMap<String, String>;
+
+/// Definitions of type names for ranges of runtime versions.
+/// Names without a definition are decoded as the type of current runtimes.
+#[derive(Debug, Clone, Default)]
+pub struct RuntimeTypes {
+    defs: Vec<(RangeInclusive<u32>, String, String)>,
+}
+
+impl RuntimeTypes {
+    /// Define the type name `name` as `ty` in the runtime versions `specs`.
+    /// `ty` may only be made of known types, e.g `u8` or `(u32, u32)`.
+    pub fn define(mut self, specs: RangeInclusive<u32>, name: &str, ty: &str) -> Self {
+        self.defs.push((specs, name.to_string(), ty.to_string()));
+        self
+    }
+
+    /// The definitions of runtime version `spec`. Later definitions override earlier ones.
+    pub fn for_spec(&self, spec: u32) -> TypeDefs {
+        self.defs
+            .iter()
+            .filter(|(specs, _, _)| specs.contains(&spec))
+            .map(|(_, name, ty)| (name.clone(), ty.clone()))
+            .collect()
+    }
+}
@@ -0,0 +1,284 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Decodes SCALE-encoded values into JSON, guided by the type names found in metadata.
+//! Only primitives, common substrate aliases and generic containers are understood.
+//! Anything else is an error, and callers fall back to hex.
+//! Names whose type changed between runtime versions are decoded with their definitions
+//! from `RuntimeTypes`, if any.
+
+use super::TypeDefs;
+use crate::error::{Error, Result};
+use codec::{Compact, Decode};
+use serde_json::{json, Value};
+
+/// Decode `data` as the metadata type `ty` into JSON.
+/// Values of unknown types, or values that don't decode exactly, become a `0x`-prefixed hex string.
+pub fn decode_value(ty: &str, data: &[u8]) -> Value {
+    decode_exact(ty, data, &TypeDefs::new()).unwrap_or_else(|_| to_hex(data))
+}
+
+/// Decode `data` as the metadata type `ty`, with the type names defined in `defs`.
+/// Errors unless all of `data` is decoded.
+pub(crate) fn decode_exact(ty: &str, data: &[u8], defs: &TypeDefs) -> Result<Value> {
+    let mut input = data;
+    let value = decode_type(&parse_type(ty)?, &mut input, defs)?;
+    if !input.is_empty() {
+        return Err(Error::from(format!(
+            "{} bytes left after decoding `{}`",
+            input.len(),
+            ty
+        )));
+    }
+    Ok(value)
+}
+
+/// Decode one value of type `ty` from the front of `input`, advancing it.
+pub(crate) fn decode_prefix(ty: &str, input: &mut &[u8]) -> Result<Value> {
+    decode_type(&parse_type(ty)?, input, &TypeDefs::new())
+}
+
+pub(crate) fn to_hex(data: &[u8]) -> Value {
+    Value::String(format!("0x{}", hex::encode(data)))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Type {
+    Named(String),
+    Generic(String, Vec<Type>),
+    Tuple(Vec<Type>),
+    Array(Box<Type>, usize),
+}
+
+/// Strip trait paths (`T::`, `<T as Trait>::`) and whitespace from a type name
+fn normalize(ty: &str) -> String {
+    let mut ty = ty.to_string();
+    while let Some(start) = ty.find("<T as ") {
+        match ty[start..].find(">::") {
+            Some(end) => ty.replace_range(start..start + end + 3, ""),
+            None => break,
+        }
+    }
+    ty.replace("T::", "").replace(char::is_whitespace, "")
+}
+
+fn parse_type(ty: &str) -> Result<Type> {
+    let ty = normalize(ty);
+    let (parsed, rest) = parse(&ty)?;
+    if !rest.is_empty() {
+        return Err(Error::from(format!(
+            "unexpected `{}` in type `{}`",
+            rest, ty
+        )));
+    }
+    Ok(parsed)
+}
+
+fn parse(s: &str) -> Result<(Type, &str)> {
+    if let Some(mut rest) = s.strip_prefix('(') {
+        let mut items = Vec::new();
+        while !rest.starts_with(')') {
+            let (item, r) = parse(rest)?;
+            items.push(item);
+            rest = r.strip_prefix(',').unwrap_or(r);
+        }
+        Ok((Type::Tuple(items), &rest[1..]))
+    } else if let Some(rest) = s.strip_prefix('[') {
+        let (item, rest) = parse(rest)?;
+        let rest = rest
+            .strip_prefix(';')
+            .ok_or_else(|| Error::from("expected `;` in array type"))?;
+        let end = rest
+            .find(']')
+            .ok_or_else(|| Error::from("expected `]` in array type"))?;
+        let len = rest[..end]
+            .parse()
+            .map_err(|_| Error::from("invalid array length"))?;
+        Ok((Type::Array(Box::new(item), len), &rest[end + 1..]))
+    } else {
+        let end = s
+            .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == ':'))
+            .unwrap_or_else(|| s.len());
+        if end == 0 {
+            return Err(Error::from(format!("expected a type name at `{}`", s)));
+        }
+        let name = s[..end].rsplit("::").next().unwrap_or_default().to_string();
+        let mut rest = &s[end..];
+        if let Some(mut r) = rest.strip_prefix('<') {
+            let mut params = Vec::new();
+            while !r.starts_with('>') {
+                let (param, next) = parse(r)?;
+                params.push(param);
+                r = next.strip_prefix(',').unwrap_or(next);
+            }
+            rest = &r[1..];
+            Ok((Type::Generic(name, params), rest))
+        } else {
+            Ok((Type::Named(name), rest))
+        }
+    }
+}
+
+fn decode_type(ty: &Type, input: &mut &[u8], defs: &TypeDefs) -> Result<Value> {
+    match ty {
+        Type::Named(name) => decode_named(name, input, defs),
+        Type::Tuple(items) => items
+            .iter()
+            .map(|t| decode_type(t, input, defs))
+            .collect::<Result<Vec<_>>>()
+            .map(Value::Array),
+        Type::Array(item, len) if **item == Type::Named("u8".into()) => {
+            Ok(to_hex(&take(input, *len)?))
+        }
+        Type::Array(item, len) => (0..*len)
+            .map(|_| decode_type(item, input, defs))
+            .collect::<Result<Vec<_>>>()
+            .map(Value::Array),
+        Type::Generic(name, params) => decode_generic(name, params, input, defs),
+    }
+}
+
+fn decode_named(name: &str, input: &mut &[u8], defs: &TypeDefs) -> Result<Value> {
+    // definitions are made of known types only, so they can't refer to themselves
+    if let Some(def) = defs.get(name) {
+        return decode_type(&parse_type(def)?, input, &TypeDefs::new());
+    }
+    let value = match name {
+        "bool" => json!(bool::decode(input)?),
+        "u8" | "Percent" => json!(u8::decode(input)?),
+        "u16" => json!(u16::decode(input)?),
+        "u32" | "BlockNumber" | "Index" | "AccountIndex" | "SessionIndex" | "EraIndex"
+        | "ProposalIndex" | "ReferendumIndex" | "RefCount" | "Perbill" | "Permill" => {
+            json!(u32::decode(input)?)
+        }
+        "u64" | "Moment" | "Weight" => json!(u64::decode(input)?),
+        // u128 does not fit into a JSON number
+        "u128" | "Balance" | "BalanceOf" => json!(u128::decode(input)?.to_string()),
+        "i8" => json!(i8::decode(input)?),
+        "i16" => json!(i16::decode(input)?),
+        "i32" => json!(i32::decode(input)?),
+        "i64" => json!(i64::decode(input)?),
+        "i128" => json!(i128::decode(input)?.to_string()),
+        "AccountId" | "Hash" | "H256" | "BlockHash" | "CodeHash" => to_hex(&take(input, 32)?),
+        "Bytes" | "OpaqueCall" => {
+            let len = Compact::<u32>::decode(input)?.0 as usize;
+            to_hex(&take(input, len)?)
+        }
+        "AccountData" => json!({
+            "free": u128::decode(input)?.to_string(),
+            "reserved": u128::decode(input)?.to_string(),
+            "misc_frozen": u128::decode(input)?.to_string(),
+            "fee_frozen": u128::decode(input)?.to_string(),
+        }),
+        _ => return Err(Error::from(format!("unknown type `{}`", name))),
+    };
+    Ok(value)
+}
+
+fn decode_generic(
+    name: &str,
+    params: &[Type],
+    input: &mut &[u8],
+    defs: &TypeDefs,
+) -> Result<Value> {
+    let value = match (name, params) {
+        ("Compact", [_]) => {
+            let n = Compact::<u128>::decode(input)?.0;
+            if n <= u64::MAX as u128 {
+                json!(n as u64)
+            } else {
+                json!(n.to_string())
+            }
+        }
+        ("Vec", [Type::Named(u8_ty)]) if u8_ty == "u8" => {
+            let len = Compact::<u32>::decode(input)?.0 as usize;
+            to_hex(&take(input, len)?)
+        }
+        ("Vec", [item]) | ("BTreeSet", [item]) => {
+            let len = Compact::<u32>::decode(input)?.0;
+            (0..len)
+                .map(|_| decode_type(item, input, defs))
+                .collect::<Result<Vec<_>>>()
+                .map(Value::Array)?
+        }
+        ("BTreeMap", [key, value]) => {
+            let len = Compact::<u32>::decode(input)?.0;
+            (0..len)
+                .map(|_| {
+                    Ok(json!([
+                        decode_type(key, input, defs)?,
+                        decode_type(value, input, defs)?
+                    ]))
+                })
+                .collect::<Result<Vec<_>>>()
+                .map(Value::Array)?
+        }
+        ("Option", [item]) => match u8::decode(input)? {
+            0 => Value::Null,
+            1 => decode_type(item, input, defs)?,
+            _ => return Err(Error::from("invalid Option discriminant")),
+        },
+        ("Box", [item]) => decode_type(item, input, defs)?,
+        // `BalanceOf<T, I>` for instanced pallets
+        (alias, _) if alias == "BalanceOf" => decode_named(alias, input, defs)?,
+        ("AccountInfo", [index, data]) => json!({
+            "nonce": decode_type(index, input, defs)?,
+            "refcount": decode_named("RefCount", input, defs)?,
+            "data": decode_type(data, input, defs)?,
+        }),
+        _ => return Err(Error::from(format!("unknown type `{}`", name))),
+    };
+    Ok(value)
+}
+
+fn take(input: &mut &[u8], len: usize) -> Result<Vec<u8>> {
+    if input.len() < len {
+        return Err(Error::from("not enough data to decode value"));
+    }
+    let (head, tail) = input.split_at(len);
+    *input = tail;
+    Ok(head.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codec::Encode;
+
+    #[test]
+    fn should_decode_known_types() {
+        assert_eq!(decode_value("T::BlockNumber", &5u32.encode()), json!(5));
+        assert_eq!(
+            decode_value("<T as Trait<I>>::Balance", &1000u128.encode()),
+            json!("1000")
+        );
+        assert_eq!(
+            decode_value("Vec<(u32, bool)>", &vec![(1u32, true)].encode()),
+            json!([[1, true]])
+        );
+        assert_eq!(
+            decode_value("Option<Vec<u8>>", &Some(vec![0xde_u8, 0xad]).encode()),
+            json!("0xdead")
+        );
+    }
+
+    #[test]
+    fn should_fall_back_to_hex() {
+        assert_eq!(decode_value("SomethingElse", &[1, 2]), json!("0x0102"));
+        // too many bytes for a u8
+        assert_eq!(decode_value("u8", &[1, 2]), json!("0x0102"));
+    }
+}
@@ -33,6 +33,10 @@ pub enum Error {
     Serialization(#[from] serde_json::Error),
     #[error("sqlx error: {0}")]
     Sql(#[from] sqlx::Error),
+    #[error("postgres error: {0}")]
+    Postgres(#[from] postgres::Error),
+    #[error("tls error: {0}")]
+    Tls(#[from] native_tls::Error),
     #[error("migration error: {0}")]
     Migration(#[from] sqlx::migrate::MigrateError),
     #[error("blockchain error: {0}")]
@@ -49,6 +53,8 @@ pub enum Error {
     /// Error occured while serializing/deserializing data
     #[error("Error while decoding job data {0}")]
     De(#[from] rmp_serde::decode::Error),
+    #[error("Error while encoding data {0}")]
+    Ser(#[from] rmp_serde::encode::Error),
     #[error(
         "the chain given to substrate-archive is different then the running chain. Trying to run {0}, running {1}"
     )]
@@ -0,0 +1,508 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Export a range of the archive into portable files, and import them back.
+//!
+//! An export is a directory with a `manifest.json` and one file per table.
+//! Each file is a DEFLATE-compressed stream of frames:
+//! a little-endian `u32` length followed by a MessagePack-encoded row.
+//! Imports go through the same `Insert` implementations the indexer uses,
+//! so importing the same export twice is harmless.
+
+use crate::{
+    database::{BlockModel, DbConn, Insert, StorageModel},
+    error::{Error, Result},
+    sql_block_builder::BlockBuilder as SqlBlockBuilder,
+    types::{BatchBlock, Metadata},
+};
+use codec::Decode;
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use futures::TryStreamExt;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sp_runtime::traits::{Block as BlockT, NumberFor};
+use sp_storage::{StorageData, StorageKey};
+use sqlx::{Connection, FromRow, PgConnection, Row};
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    marker::PhantomData,
+    path::Path,
+};
+
+/// version of the export format
+/// - 2: blocks record whether they are finalized
+/// - 3: the manifest records the genesis hash of the chain
+pub const EXPORT_VERSION: u32 = 3;
+
+const MANIFEST: &str = "manifest.json";
+const METADATA_FILE: &str = "metadata.msgpack.deflate";
+const BLOCKS_FILE: &str = "blocks.msgpack.deflate";
+const STORAGE_FILE: &str = "storage.msgpack.deflate";
+
+/// number of rows inserted at once on import
+const IMPORT_CHUNK: usize = 5_000;
+
+/// Describes the contents of an export
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    /// first block of the export (inclusive)
+    pub from: u32,
+    /// last block of the export (inclusive)
+    pub to: u32,
+    /// hex-encoded hash of the genesis block of the exported chain
+    pub genesis_hash: String,
+    pub metadata: u64,
+    pub blocks: u64,
+    pub storage: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MetadataRow {
+    version: u32,
+    meta: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BlockRow {
+    block: BlockModel,
+    finalized: bool,
+}
+
+#[derive(Debug, sqlx::FromRow, Serialize, Deserialize)]
+struct StorageRow {
+    block_num: i32,
+    hash: Vec<u8>,
+    is_full: bool,
+    key: Vec<u8>,
+    storage: Option<Vec<u8>>,
+    #[serde(default)]
+    old_storage: Option<Vec<u8>>,
+}
+
+/// Export blocks `from..=to`, their storage, and the metadata of their runtime versions into `dir`.
+/// `dir` is created if it does not exist.
+pub async fn export(conn: &mut PgConnection, dir: &Path, from: u32, to: u32) -> Result<Manifest> {
+    std::fs::create_dir_all(dir)?;
+    let genesis_hash = genesis_hash(&mut *conn)
+        .await?
+        .ok_or_else(|| Error::from("the archive has no genesis block"))?;
+
+    let mut writer = FrameWriter::create(&dir.join(METADATA_FILE))?;
+    let mut rows = sqlx::query_as::<_, (i32, Vec<u8>)>(
+        r#"
+        SELECT version, meta FROM metadata
+        WHERE version IN (SELECT DISTINCT spec FROM blocks WHERE block_num BETWEEN $1 AND $2)
+        "#,
+    )
+    .bind(from)
+    .bind(to)
+    .fetch(&mut *conn);
+    while let Some((version, meta)) = rows.try_next().await? {
+        writer.write(&MetadataRow {
+            version: version as u32,
+            meta,
+        })?;
+    }
+    std::mem::drop(rows);
+    let metadata = writer.finish()?;
+
+    let mut writer = FrameWriter::create(&dir.join(BLOCKS_FILE))?;
+    let mut rows =
+        sqlx::query("SELECT * FROM blocks WHERE block_num BETWEEN $1 AND $2 ORDER BY block_num")
+            .bind(from)
+            .bind(to)
+            .fetch(&mut *conn);
+    while let Some(row) = rows.try_next().await? {
+        writer.write(&BlockRow {
+            block: BlockModel::from_row(&row)?,
+            finalized: row.try_get("finalized")?,
+        })?;
+    }
+    std::mem::drop(rows);
+    let blocks = writer.finish()?;
+
+    let mut writer = FrameWriter::create(&dir.join(STORAGE_FILE))?;
+    let mut rows = sqlx::query_as::<_, StorageRow>(
+        r#"
+        SELECT block_num, hash, is_full, key, storage, old_storage FROM storage_with_values
+        WHERE block_num BETWEEN $1 AND $2 ORDER BY block_num
+        "#,
+    )
+    .bind(from)
+    .bind(to)
+    .fetch(&mut *conn);
+    while let Some(storage) = rows.try_next().await? {
+        writer.write(&storage)?;
+    }
+    std::mem::drop(rows);
+    let storage = writer.finish()?;
+
+    let manifest = Manifest {
+        version: EXPORT_VERSION,
+        from,
+        to,
+        genesis_hash,
+        metadata,
+        blocks,
+        storage,
+    };
+    serde_json::to_writer_pretty(File::create(dir.join(MANIFEST))?, &manifest)?;
+    log::info!(
+        "Exported {} blocks, {} storage entries and {} runtime versions to {}",
+        blocks,
+        storage,
+        metadata,
+        dir.display()
+    );
+    Ok(manifest)
+}
+
+/// Import an export from `dir`.
+/// Metadata, blocks and storage are inserted in that order, so that
+/// foreign keys are satisfied.
+pub async fn import<B>(conn: &mut DbConn, dir: &Path) -> Result<Manifest>
+where
+    B: BlockT,
+    NumberFor<B>: Into<u32>,
+{
+    let manifest: Manifest = serde_json::from_reader(File::open(dir.join(MANIFEST))?)?;
+    if manifest.version != EXPORT_VERSION {
+        return Err(Error::from(format!(
+            "unsupported export version {}, expected {}",
+            manifest.version, EXPORT_VERSION
+        )));
+    }
+    if let Some(genesis) = genesis_hash(&mut *conn).await? {
+        if genesis != manifest.genesis_hash {
+            return Err(Error::from(format!(
+                "the export is of the chain with genesis {}, the database of the chain with genesis {}",
+                manifest.genesis_hash, genesis
+            )));
+        }
+    }
+
+    let mut metadata = 0;
+    for row in FrameReader::<MetadataRow>::open(&dir.join(METADATA_FILE))? {
+        let row = row?;
+        Metadata::new(row.version, row.meta).insert(conn).await?;
+        metadata += 1;
+    }
+    expect_frames(METADATA_FILE, metadata, manifest.metadata)?;
+
+    let mut count = 0;
+    let mut blocks = FrameReader::<BlockRow>::open(&dir.join(BLOCKS_FILE))?.peekable();
+    while blocks.peek().is_some() {
+        let chunk = blocks
+            .by_ref()
+            .take(IMPORT_CHUNK)
+            .collect::<Result<Vec<_>>>()?;
+        count += chunk.len() as u64;
+        if let Some(genesis) = chunk.iter().find(|row| row.block.block_num == 0) {
+            if hex::encode(&genesis.block.hash) != manifest.genesis_hash {
+                return Err(Error::from(format!(
+                    "genesis block {} of the export is not genesis {} of the manifest",
+                    hex::encode(&genesis.block.hash),
+                    manifest.genesis_hash
+                )));
+            }
+        }
+        // a batch marks its blocks finalized up to a block number,
+        // so unfinalized blocks are inserted in a batch of their own
+        let (finalized, unfinalized): (Vec<_>, Vec<_>) =
+            chunk.into_iter().partition(|row| row.finalized);
+        if !finalized.is_empty() {
+            let finalized = SqlBlockBuilder::<B>::with_vec(
+                finalized.into_iter().map(|row| row.block).collect(),
+            )?;
+            BatchBlock::new(finalized).insert(conn).await?;
+        }
+        if let Some(first) = unfinalized
+            .iter()
+            .map(|row| row.block.block_num as u32)
+            .min()
+        {
+            let unfinalized = SqlBlockBuilder::<B>::with_vec(
+                unfinalized.into_iter().map(|row| row.block).collect(),
+            )?;
+            BatchBlock::new(unfinalized)
+                .finalized_up_to(first.saturating_sub(1))
+                .insert(conn)
+                .await?;
+        }
+    }
+    expect_frames(BLOCKS_FILE, count, manifest.blocks)?;
+
+    let mut count = 0;
+    let mut storage = FrameReader::<StorageRow>::open(&dir.join(STORAGE_FILE))?.peekable();
+    while storage.peek().is_some() {
+        let chunk = storage
+            .by_ref()
+            .take(IMPORT_CHUNK)
+            .map(|s| {
+                let s = s?;
+                Ok(StorageModel::<B>::new(
+                    Decode::decode(&mut s.hash.as_slice())?,
+                    s.block_num as u32,
+                    s.is_full,
+                    StorageKey(s.key),
+                    s.storage.map(StorageData),
+                )
+                .with_old_data(s.old_storage.map(StorageData)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        count += chunk.len() as u64;
+        chunk.insert(conn).await?;
+    }
+    expect_frames(STORAGE_FILE, count, manifest.storage)?;
+
+    log::info!(
+        "Imported blocks {} to {} from {}",
+        manifest.from,
+        manifest.to,
+        dir.display()
+    );
+    Ok(manifest)
+}
+
+/// Hex-encoded hash of the archived genesis block, if there is one
+async fn genesis_hash(conn: &mut PgConnection) -> Result<Option<String>> {
+    let hash: Option<(Vec<u8>,)> = sqlx::query_as("SELECT hash FROM blocks WHERE block_num = 0")
+        .fetch_optional(conn)
+        .await?;
+    Ok(hash.map(|(h,)| hex::encode(h)))
+}
+
+/// Fail if `file` held another number of frames than the manifest lists
+fn expect_frames(file: &str, read: u64, listed: u64) -> Result<()> {
+    if read != listed {
+        return Err(Error::from(format!(
+            "{} holds {} frames, the manifest lists {}",
+            file, read, listed
+        )));
+    }
+    Ok(())
+}
+
+/// Connect to the database at `pg_url` and export blocks `from..=to` into `dir`
+pub fn export_archive(pg_url: &str, dir: &Path, from: u32, to: u32) -> Result<Manifest> {
+    smol::block_on(async {
+        let mut conn = PgConnection::connect(pg_url).await?;
+        export(&mut conn, dir, from, to).await
+    })
+}
+
+/// Run migrations on the database at `pg_url` and import the export in `dir` into it
+pub fn import_archive<B>(pg_url: &str, dir: &Path) -> Result<Manifest>
+where
+    B: BlockT,
+    NumberFor<B>: Into<u32>,
+{
+    smol::block_on(async {
+        crate::migrations::migrate(pg_url).await?;
+        let pool = sqlx::PgPool::connect(pg_url).await?;
+        let mut conn = pool.acquire().await?;
+        import::<B>(&mut conn, dir).await
+    })
+}
+
+struct FrameWriter {
+    inner: DeflateEncoder<BufWriter<File>>,
+    count: u64,
+}
+
+impl FrameWriter {
+    fn create(path: &Path) -> Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        Ok(Self {
+            inner: DeflateEncoder::new(file, Compression::default()),
+            count: 0,
+        })
+    }
+
+    fn write<T: Serialize>(&mut self, item: &T) -> Result<()> {
+        let bytes = rmp_serde::to_vec(item)?;
+        self.inner.write_all(&(bytes.len() as u32).to_le_bytes())?;
+        self.inner.write_all(&bytes)?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Flush the file, returning the number of frames written
+    fn finish(self) -> Result<u64> {
+        self.inner.finish()?.flush()?;
+        Ok(self.count)
+    }
+}
+
+struct FrameReader<T> {
+    inner: DeflateDecoder<BufReader<File>>,
+    _marker: PhantomData<T>,
+}
+
+impl<T: DeserializeOwned> FrameReader<T> {
+    fn open(path: &Path) -> Result<Self> {
+        Ok(Self {
+            inner: DeflateDecoder::new(BufReader::new(File::open(path)?)),
+            _marker: PhantomData,
+        })
+    }
+
+    /// Read the next frame, `None` if the file ends before it.
+    /// A file that ends inside a frame is truncated, and an error.
+    fn next_frame(&mut self) -> Result<Option<T>> {
+        let mut len = [0u8; 4];
+        let mut read = 0;
+        while read < len.len() {
+            match self.inner.read(&mut len[read..]) {
+                Ok(0) if read == 0 => return Ok(None),
+                Ok(0) => return Err(Error::from("export file ends inside the length of a frame")),
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let mut buf = vec![0u8; u32::from_le_bytes(len) as usize];
+        match self.inner.read_exact(&mut buf) {
+            Ok(()) => Ok(Some(rmp_serde::from_read_ref(&buf)?)),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                Err(Error::from("export file ends inside a frame"))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl<T: DeserializeOwned> Iterator for FrameReader<T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        database::cursors::Stage,
+        harness::{run_archive, TempDatabase, TestChain, Until},
+    };
+    use node_template_runtime::opaque::Block;
+
+    const BLOCKS: u32 = 10;
+
+    fn finality(url: &str) -> Vec<(i32, Vec<u8>, bool)> {
+        smol::block_on(async {
+            let mut conn = PgConnection::connect(url).await.unwrap();
+            sqlx::query_as("SELECT block_num, hash, finalized FROM blocks ORDER BY block_num")
+                .fetch_all(&mut conn)
+                .await
+                .unwrap()
+        })
+    }
+
+    #[test]
+    fn should_roundtrip_frames() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(METADATA_FILE);
+        let mut writer = FrameWriter::create(&path).unwrap();
+        for version in 0..3 {
+            writer
+                .write(&MetadataRow {
+                    version,
+                    meta: vec![version as u8; 100],
+                })
+                .unwrap();
+        }
+        assert_eq!(writer.finish().unwrap(), 3);
+
+        let rows = FrameReader::<MetadataRow>::open(&path)
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[2].version, 2);
+        assert_eq!(rows[2].meta, vec![2u8; 100]);
+    }
+
+    #[test]
+    fn should_reject_truncated_frames() {
+        let dir = tempfile::tempdir().unwrap();
+        let frame = rmp_serde::to_vec(&MetadataRow {
+            version: 0,
+            meta: vec![0u8; 100],
+        })
+        .unwrap();
+        let mut whole = (frame.len() as u32).to_le_bytes().to_vec();
+        whole.extend_from_slice(&frame);
+        // a whole frame followed by part of a length, and a frame missing the end of its row
+        for truncated in [&whole[..2], &whole[..whole.len() - 1]].iter() {
+            let path = dir.path().join(METADATA_FILE);
+            let mut file =
+                DeflateEncoder::new(File::create(&path).unwrap(), Compression::default());
+            file.write_all(&whole).unwrap();
+            file.write_all(truncated).unwrap();
+            file.finish().unwrap();
+
+            let mut reader = FrameReader::<MetadataRow>::open(&path).unwrap();
+            assert!(reader.next().unwrap().is_ok());
+            assert!(reader.next().unwrap().is_err());
+        }
+    }
+
+    #[test]
+    fn should_roundtrip_finality_of_blocks() {
+        crate::initialize();
+        let chain = TestChain::generate(BLOCKS).unwrap();
+        let source = TempDatabase::new().unwrap();
+        run_archive(&chain, &source, Until::Cursor(Stage::Storage, BLOCKS)).unwrap();
+        smol::block_on(async {
+            let mut conn = PgConnection::connect(&source.url).await.unwrap();
+            sqlx::query("UPDATE blocks SET finalized = false WHERE block_num >= $1")
+                .bind(BLOCKS as i32 - 1)
+                .execute(&mut conn)
+                .await
+                .unwrap();
+        });
+        let exported = finality(&source.url);
+        assert_eq!(exported.iter().filter(|(_, _, f)| !f).count(), 2);
+
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = export_archive(&source.url, dir.path(), 0, BLOCKS).unwrap();
+        assert_eq!(manifest.blocks, exported.len() as u64);
+
+        let target = TempDatabase::new().unwrap();
+        assert_eq!(
+            import_archive::<Block>(&target.url, dir.path()).unwrap(),
+            manifest
+        );
+        assert_eq!(finality(&target.url), exported);
+
+        let tampered = Manifest {
+            blocks: manifest.blocks + 1,
+            ..manifest.clone()
+        };
+        serde_json::to_writer(File::create(dir.path().join(MANIFEST)).unwrap(), &tampered).unwrap();
+        assert!(import_archive::<Block>(&target.url, dir.path()).is_err());
+        let tampered = Manifest {
+            genesis_hash: hex::encode([0u8; 32]),
+            ..manifest
+        };
+        serde_json::to_writer(File::create(dir.path().join(MANIFEST)).unwrap(), &tampered).unwrap();
+        assert!(import_archive::<Block>(&target.url, dir.path()).is_err());
+    }
+}
@@ -0,0 +1,394 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! End-to-end test harness.
+//!
+//! Generates a small chain with the node-template runtime into a temporary RocksDB database,
+//! and runs the archive against a temporary Postgres database created next to `DATABASE_URL`.
+//! Every block only sets the timestamp, so the indexed storage is fully predictable.
+//! Tests of a module use the harness from their own `tests` module,
+//! running the archive with `run_archive` until it reaches a cursor or a row count.
+
+use crate::{
+    backend::{self, InMemorySource, ReadOnlyBackend, ReadOnlyDatabase},
+    database::cursors::{self, Stage},
+    error::Result,
+    types::Archive,
+    ArchiveBuilder,
+};
+use codec::{Decode, Encode};
+use node_template_runtime::{
+    opaque::{Block, UncheckedExtrinsic as OpaqueExtrinsic},
+    Call, RuntimeApi, TimestampCall, UncheckedExtrinsic,
+};
+use sc_block_builder::BlockBuilderProvider;
+use sc_client_api::Backend as _;
+use sc_client_db::{DatabaseSettings, DatabaseSettingsSrc, PruningMode};
+use sp_consensus::{BlockImport, BlockImportParams, BlockOrigin, ForkChoiceStrategy};
+use sp_core::twox_128;
+use sp_runtime::{
+    generic::{BlockId, Digest, DigestItem},
+    traits::Header as _,
+    BuildStorage,
+};
+use sp_state_machine::Backend as _;
+use sqlx::{Connection, PgConnection};
+use std::{
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use substrate_test_client::{
+    client::LocalCallExecutor, ClientBlockImportExt, GenesisInit, TestClientBuilder,
+};
+
+pub type Executor = node_template::service::Executor;
+type Backend = sc_client_db::Backend<Block>;
+
+/// milliseconds per slot of the node-template runtime
+pub const SLOT_DURATION: u64 = 6000;
+
+#[derive(Default)]
+struct Genesis;
+
+impl GenesisInit for Genesis {
+    fn genesis_storage(&self) -> sp_core::storage::Storage {
+        node_template::chain_spec::development_config()
+            .expect("Development chain spec is valid")
+            .build_storage()
+            .expect("Genesis storage of the development chain builds")
+    }
+}
+
+/// A chain of `len` blocks on top of genesis, in a temporary RocksDB database
+pub struct TestChain {
+    dir: tempfile::TempDir,
+    pub hashes: Vec<<Block as sp_runtime::traits::Block>::Hash>,
+}
+
+impl TestChain {
+    /// Block `n` is authored in slot `n`, at timestamp `n * SLOT_DURATION`
+    pub fn generate(len: u32) -> Result<Self> {
+        Self::generate_finalized(len, len)
+    }
+
+    /// Like `generate`, with only the blocks up to `finalized` finalized
+    pub fn generate_finalized(len: u32, finalized: u32) -> Result<Self> {
+        Self::build(len, finalized, false)
+    }
+
+    /// Like `generate`, with the header of the last block claiming the state root of its parent.
+    /// The state of the last block is still readable, but executing it results in another root.
+    pub fn generate_with_wrong_root(len: u32) -> Result<Self> {
+        Self::build(len, len, true)
+    }
+
+    fn build(len: u32, finalized: u32, wrong_root: bool) -> Result<Self> {
+        let dir = tempfile::tempdir()?;
+        let settings = DatabaseSettings {
+            state_cache_size: 16 * 1024 * 1024,
+            state_cache_child_ratio: None,
+            pruning: PruningMode::ArchiveAll,
+            source: DatabaseSettingsSrc::RocksDb {
+                path: dir.path().to_path_buf(),
+                cache_size: 16,
+            },
+        };
+        let backend = Arc::new(Backend::new(settings, 0)?);
+        let (mut client, _) = TestClientBuilder::<
+            Block,
+            LocalCallExecutor<Backend, sc_executor::NativeExecutor<Executor>>,
+            Backend,
+            Genesis,
+        >::with_backend(backend)
+        .build_with_native_executor::<RuntimeApi, _>(None);
+
+        let mut hashes = Vec::with_capacity(len as usize);
+        let mut parent_root = Default::default();
+        for n in 1..=len as u64 {
+            let digest = Digest {
+                logs: vec![DigestItem::PreRuntime(*b"aura", n.encode())],
+            };
+            let mut builder = client.new_block(digest)?;
+            let timestamp = UncheckedExtrinsic::new_unsigned(Call::Timestamp(TimestampCall::set(
+                n * SLOT_DURATION,
+            )));
+            builder.push(OpaqueExtrinsic::decode(&mut timestamp.encode().as_slice())?)?;
+            let built = builder.build()?;
+            let mut block = built.block;
+            if wrong_root && n == len as u64 {
+                // import the changes of the block as they are, the client would execute it otherwise
+                block.header.state_root = parent_root;
+                hashes.push(block.header.hash());
+                let mut import = BlockImportParams::new(BlockOrigin::Own, block.header);
+                import.body = Some(block.extrinsics);
+                import.storage_changes = Some(built.storage_changes);
+                import.finalized = n <= finalized as u64;
+                import.fork_choice = Some(ForkChoiceStrategy::LongestChain);
+                client
+                    .import_block(import, Default::default())
+                    .map_err(|e| e.to_string())?;
+                continue;
+            }
+            parent_root = block.header.state_root;
+            hashes.push(block.header.hash());
+            if n <= finalized as u64 {
+                client.import_as_final(BlockOrigin::Own, block)
+            } else {
+                client.import(BlockOrigin::Own, block)
+            }
+            .map_err(|e| e.to_string())?;
+        }
+        Ok(Self { dir, hashes })
+    }
+
+    pub fn path(&self) -> &str {
+        self.dir.path().to_str().expect("Temporary path is UTF-8")
+    }
+
+    /// The blocks of the chain with the state of every block, copied into an `InMemorySource`
+    pub fn in_memory(&self) -> Result<InMemorySource<Block>> {
+        let secondary = tempfile::tempdir()?;
+        let rocks = ReadOnlyBackend::<Block>::new(open_chain(self, secondary.path())?, true);
+        let source = InMemorySource::new();
+        for block in rocks.iter_blocks(|_| true)? {
+            let state = rocks.state_at(BlockId::Hash(block.block.header.hash()))?;
+            source.insert_state(state.pairs());
+            source.insert_block(block);
+        }
+        source.finalize(rocks.source().meta()?.finalized_hash);
+        Ok(source)
+    }
+}
+
+/// A Postgres database that is dropped along with this struct
+pub struct TempDatabase {
+    name: String,
+    pub url: String,
+}
+
+impl TempDatabase {
+    pub fn new() -> Result<Self> {
+        let name = format!(
+            "archive_test_{}_{}",
+            std::process::id(),
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_nanos())
+                .unwrap_or_default()
+        );
+        let base = crate::DATABASE_URL.as_str();
+        let url = match base.rfind('/') {
+            Some(i) => format!("{}/{}", &base[..i], name),
+            None => return Err("DATABASE_URL has no database name".into()),
+        };
+        smol::block_on(async {
+            let mut conn = PgConnection::connect(base).await?;
+            sqlx::query(&format!("CREATE DATABASE {}", name))
+                .execute(&mut conn)
+                .await?;
+            Ok::<_, crate::error::Error>(())
+        })?;
+        Ok(Self { name, url })
+    }
+}
+
+impl Drop for TempDatabase {
+    fn drop(&mut self) {
+        let res = smol::block_on(async {
+            let mut conn = PgConnection::connect(crate::DATABASE_URL.as_str()).await?;
+            sqlx::query(
+                "SELECT pg_terminate_backend(pid) FROM pg_stat_activity WHERE datname = $1",
+            )
+            .bind(&self.name)
+            .execute(&mut conn)
+            .await?;
+            sqlx::query(&format!("DROP DATABASE IF EXISTS {}", self.name))
+                .execute(&mut conn)
+                .await?;
+            Ok::<_, sqlx::Error>(())
+        });
+        if let Err(e) = res {
+            log::warn!("Could not drop test database {}: {}", self.name, e);
+        }
+    }
+}
+
+pub type Builder = ArchiveBuilder<Block, RuntimeApi, Executor>;
+
+/// How long a test waits for the archive to index a chain
+pub const TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Storage key of the timestamp, which every block sets
+pub fn timestamp_key() -> Vec<u8> {
+    let mut key = twox_128(b"Timestamp").to_vec();
+    key.extend_from_slice(&twox_128(b"Now"));
+    key
+}
+
+/// What a test waits for the archive to index
+#[derive(Debug, Clone, Copy)]
+pub enum Until {
+    /// the cursor of a stage reaches a block number
+    Cursor(Stage, u32),
+    /// a `SELECT COUNT(..)` query returns a number
+    Count(&'static str, i64),
+}
+
+impl Until {
+    async fn reached(&self, conn: &mut PgConnection) -> Result<bool> {
+        match *self {
+            Until::Cursor(stage, number) => Ok(cursors::get(conn, stage).await? >= Some(number)),
+            Until::Count(query, count) => {
+                let (n,): (i64,) = sqlx::query_as(query).fetch_one(conn).await?;
+                Ok(n == count)
+            }
+        }
+    }
+
+    /// Poll the database at `url` until this is reached, or `TIMEOUT` elapses
+    pub async fn wait(&self, url: &str) -> Result<()> {
+        let mut conn = PgConnection::connect(url).await?;
+        let start = Instant::now();
+        while start.elapsed() < TIMEOUT {
+            if self.reached(&mut conn).await? {
+                return Ok(());
+            }
+            smol::Timer::new(Duration::from_millis(250)).await;
+        }
+        Err(format!("archive did not reach {:?} in {:?}", self, TIMEOUT).into())
+    }
+}
+
+/// Run the archive over `chain` until `until` is reached
+pub fn run_archive(chain: &TestChain, db: &TempDatabase, until: Until) -> Result<()> {
+    run_archive_with(chain, db, |builder| builder, until)
+}
+
+/// Like `run_archive`, with the archive further configured by `configure`
+pub fn run_archive_with<C>(
+    chain: &TestChain,
+    db: &TempDatabase,
+    configure: C,
+    until: Until,
+) -> Result<()>
+where
+    C: FnOnce(Builder) -> Builder,
+{
+    let mut archive = configure(builder(chain, db)).build()?;
+    archive.drive()?;
+    let res = smol::block_on(until.wait(&db.url));
+    archive.shutdown()?;
+    res
+}
+
+/// An archive over `chain` and `db`, that archives old storage values
+pub fn builder(chain: &TestChain, db: &TempDatabase) -> Builder {
+    Builder {
+        block_workers: Some(2),
+        wasm_pages: Some(256),
+        ..ArchiveBuilder::default()
+    }
+    .chain_data_db(chain.path())
+    .pg_url(db.url.as_str())
+    .old_storage(true)
+}
+
+/// Open the RocksDB database of `chain` as a secondary instance in `secondary`
+pub fn open_chain(chain: &TestChain, secondary: &Path) -> Result<Arc<ReadOnlyDatabase>> {
+    let db = backend::open_database(chain.path(), 16, secondary.to_path_buf())?;
+    Ok(Arc::new(db))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCKS: u32 = 10;
+
+    #[test]
+    fn should_index_synthetic_chain() {
+        crate::initialize();
+        let chain = TestChain::generate(BLOCKS).unwrap();
+        let db = TempDatabase::new().unwrap();
+
+        run_archive(&chain, &db, Until::Cursor(Stage::Storage, BLOCKS)).unwrap();
+
+        smol::block_on(async {
+            let mut conn = PgConnection::connect(&db.url).await.unwrap();
+
+            let blocks: Vec<(i32, Vec<u8>)> = sqlx::query_as(
+                "SELECT block_num, hash FROM blocks WHERE block_num > 0 ORDER BY block_num",
+            )
+            .fetch_all(&mut conn)
+            .await
+            .unwrap();
+            assert_eq!(blocks.len(), BLOCKS as usize);
+            for ((num, hash), expected) in blocks.iter().zip(chain.hashes.iter()) {
+                assert_eq!(hash.as_slice(), expected.as_ref(), "hash of block {}", num);
+            }
+
+            let (spec,): (i32,) = sqlx::query_as("SELECT DISTINCT spec FROM blocks")
+                .fetch_one(&mut conn)
+                .await
+                .unwrap();
+            let (metadata,): (i64,) =
+                sqlx::query_as("SELECT COUNT(*) FROM metadata WHERE version = $1")
+                    .bind(spec)
+                    .fetch_one(&mut conn)
+                    .await
+                    .unwrap();
+            assert_eq!(metadata, 1);
+
+            let (mismatches,): (i64,) =
+                sqlx::query_as("SELECT COUNT(*) FROM state_root_mismatches")
+                    .fetch_one(&mut conn)
+                    .await
+                    .unwrap();
+            assert_eq!(mismatches, 0);
+
+            let storage = cursors::get(&mut conn, Stage::Storage).await.unwrap();
+            assert_eq!(storage, Some(BLOCKS));
+            let blocks = cursors::advance(&mut conn, Stage::Blocks).await.unwrap();
+            assert_eq!(blocks, Some(BLOCKS));
+            let metadata = cursors::get(&mut conn, Stage::Metadata).await.unwrap();
+            assert_eq!(metadata, Some(BLOCKS));
+
+            let queue = crate::database::queue::status(&mut conn).await.unwrap();
+            assert_eq!((queue.tip, queue.backfill), (0, 0));
+
+            let timestamps: Vec<(i32, Option<Vec<u8>>, Option<Vec<u8>>)> = sqlx::query_as(
+                r#"
+                SELECT block_num, storage, old_storage FROM storage
+                WHERE key = $1 AND block_num > 0 ORDER BY block_num
+                "#,
+            )
+            .bind(timestamp_key())
+            .fetch_all(&mut conn)
+            .await
+            .unwrap();
+            for (num, value, old) in timestamps {
+                let num = num as u64;
+                assert_eq!(value, Some((num * SLOT_DURATION).encode()));
+                // genesis does not set the timestamp
+                let expected_old = Some((num - 1) * SLOT_DURATION)
+                    .filter(|t| *t > 0)
+                    .map(|t| t.encode());
+                assert_eq!(old, expected_old, "old timestamp of block {}", num);
+            }
+        });
+    }
+}
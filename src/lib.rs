@@ -20,8 +20,13 @@
 mod actors;
 pub mod archive;
 pub mod backend;
+pub mod block_file;
 mod database;
+pub mod decode;
 mod error;
+pub mod export;
+#[cfg(test)]
+mod harness;
 mod migrations;
 // mod rpc;
 // #[cfg(test)]
@@ -30,13 +35,20 @@ mod sql_block_builder;
 mod tasks;
 mod types;
 mod util;
+pub mod worker;
 
-pub use actors::System;
+pub use actors::{
+    ActorContext, IndexingMode, LatencyMetrics, PendingMetrics, PendingMonitor, System,
+    SystemConfig, TipLatency,
+};
 pub use archive::Builder as ArchiveBuilder;
 pub use database::queries;
+pub use database::queue::QueueStatus;
+pub use database::ClientPool;
 pub use error::Error;
 pub use migrations::MigrationConfig;
 pub use types::Archive;
+pub use worker::Worker;
 
 #[cfg(feature = "logging")]
 pub use util::init_logger;
@@ -169,6 +181,7 @@ mod test {
                 let mut conn = crate::PG_POOL.acquire().await.unwrap();
                 conn.execute(
                     "
+                    TRUNCATE TABLE runtime_code CASCADE;
                     TRUNCATE TABLE metadata CASCADE;
                     TRUNCATE TABLE storage CASCADE;
                     TRUNCATE TABLE blocks CASCADE;
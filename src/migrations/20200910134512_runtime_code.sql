@@ -0,0 +1,7 @@
+-- the raw wasm blob (`:code`) of every runtime version the archive has seen
+CREATE TABLE IF NOT EXISTS runtime_code (
+  code_hash bytea NOT NULL,
+  spec integer NOT NULL REFERENCES metadata(version),
+  code bytea NOT NULL,
+  PRIMARY KEY (code_hash, spec)
+);
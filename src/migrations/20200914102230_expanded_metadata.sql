@@ -0,0 +1,77 @@
+-- metadata of every runtime version, decoded into queryable rows.
+-- storage rows of an item can be found by joining on `metadata_storage.key_prefix`, i.e
+--   SELECT s.* FROM storage s
+--   JOIN blocks b ON b.hash = s.hash
+--   JOIN metadata_storage m ON m.spec = b.spec AND substring(s.key from 1 for 32) = m.key_prefix
+--   WHERE m.pallet = 'System' AND m.name = 'Account';
+CREATE TABLE IF NOT EXISTS metadata_pallets (
+  spec integer NOT NULL REFERENCES metadata(version) ON DELETE CASCADE,
+  name text NOT NULL,
+  pallet_index integer NOT NULL,
+  call_index integer,
+  event_index integer,
+  storage_prefix text,
+  PRIMARY KEY (spec, name)
+);
+
+CREATE TABLE IF NOT EXISTS metadata_calls (
+  spec integer NOT NULL,
+  pallet text NOT NULL,
+  call_index integer NOT NULL,
+  name text NOT NULL,
+  arguments jsonb NOT NULL,
+  documentation text NOT NULL,
+  PRIMARY KEY (spec, pallet, name),
+  FOREIGN KEY (spec, pallet) REFERENCES metadata_pallets(spec, name) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS metadata_events (
+  spec integer NOT NULL,
+  pallet text NOT NULL,
+  event_index integer NOT NULL,
+  name text NOT NULL,
+  arguments text[] NOT NULL,
+  documentation text NOT NULL,
+  PRIMARY KEY (spec, pallet, name),
+  FOREIGN KEY (spec, pallet) REFERENCES metadata_pallets(spec, name) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS metadata_errors (
+  spec integer NOT NULL,
+  pallet text NOT NULL,
+  error_index integer NOT NULL,
+  name text NOT NULL,
+  documentation text NOT NULL,
+  PRIMARY KEY (spec, pallet, name),
+  FOREIGN KEY (spec, pallet) REFERENCES metadata_pallets(spec, name) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS metadata_constants (
+  spec integer NOT NULL,
+  pallet text NOT NULL,
+  name text NOT NULL,
+  ty text NOT NULL,
+  value bytea NOT NULL,
+  documentation text NOT NULL,
+  PRIMARY KEY (spec, pallet, name),
+  FOREIGN KEY (spec, pallet) REFERENCES metadata_pallets(spec, name) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS metadata_storage (
+  spec integer NOT NULL,
+  pallet text NOT NULL,
+  prefix text NOT NULL,
+  name text NOT NULL,
+  modifier text NOT NULL,
+  kind text NOT NULL,
+  hashers text[] NOT NULL,
+  keys text[] NOT NULL,
+  value_ty text NOT NULL,
+  default_value bytea NOT NULL,
+  key_prefix bytea NOT NULL,
+  documentation text NOT NULL,
+  PRIMARY KEY (spec, pallet, name),
+  FOREIGN KEY (spec, pallet) REFERENCES metadata_pallets(spec, name) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS metadata_storage_key_prefix_index ON metadata_storage (key_prefix, spec);
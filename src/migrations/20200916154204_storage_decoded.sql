@@ -0,0 +1,15 @@
+-- storage entries decoded against the metadata of the runtime that wrote them.
+-- Only populated if the archive is configured to decode storage.
+CREATE TABLE IF NOT EXISTS storage_decoded (
+  id SERIAL PRIMARY KEY,
+  block_num int check (block_num >= 0 and block_num < 2147483647) NOT NULL,
+  hash bytea NOT NULL REFERENCES blocks(hash) ON DELETE CASCADE ON UPDATE CASCADE,
+  key bytea NOT NULL,
+  pallet text NOT NULL,
+  item text NOT NULL,
+  key_decoded jsonb,
+  value jsonb
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS storage_decoded_hash_key_index ON storage_decoded (hash, key);
+CREATE INDEX IF NOT EXISTS storage_decoded_item_index ON storage_decoded (pallet, item, block_num);
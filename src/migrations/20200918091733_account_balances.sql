@@ -0,0 +1,17 @@
+-- balance of an account after every block it changed in.
+-- decoded from `System.Account`, or from the legacy `Balances.FreeBalance`,
+-- in which case `account` is the blake2_256 hash of the account id and only `free` is known.
+CREATE TABLE IF NOT EXISTS account_balances (
+  id SERIAL PRIMARY KEY,
+  account bytea NOT NULL,
+  block_num int check (block_num >= 0 and block_num < 2147483647) NOT NULL,
+  hash bytea NOT NULL REFERENCES blocks(hash) ON DELETE CASCADE ON UPDATE CASCADE,
+  free NUMERIC(39) NOT NULL,
+  reserved NUMERIC(39),
+  misc_frozen NUMERIC(39),
+  fee_frozen NUMERIC(39),
+  nonce bigint
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS account_balances_hash_account_index ON account_balances (hash, account);
+CREATE INDEX IF NOT EXISTS account_balances_account_block_num_index ON account_balances (account, block_num DESC);
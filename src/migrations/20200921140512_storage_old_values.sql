@@ -0,0 +1,4 @@
+-- value of the key before the block changed it.
+-- only written when the archive runs with `old_storage` enabled,
+-- in which case NULL means the key did not exist before the block.
+ALTER TABLE storage ADD COLUMN IF NOT EXISTS old_storage bytea;
@@ -0,0 +1,10 @@
+-- cache of merkle proofs of storage values, SCALE-encoded `StorageProof`s
+CREATE TABLE IF NOT EXISTS storage_proofs (
+  id SERIAL PRIMARY KEY,
+  block_num int check (block_num >= 0 and block_num < 2147483647) NOT NULL,
+  hash bytea NOT NULL REFERENCES blocks(hash) ON DELETE CASCADE ON UPDATE CASCADE,
+  key bytea NOT NULL,
+  proof bytea NOT NULL
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS storage_proofs_hash_key_index ON storage_proofs (hash, key);
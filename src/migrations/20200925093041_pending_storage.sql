@@ -0,0 +1,13 @@
+-- storage that could not be buffered in memory until its block was indexed.
+-- rows are moved into `storage` once their block exists
+CREATE TABLE IF NOT EXISTS _pending_storage (
+  id SERIAL PRIMARY KEY,
+  block_num int check (block_num >= 0 and block_num < 2147483647) NOT NULL,
+  hash bytea NOT NULL,
+  is_full boolean NOT NULL,
+  key bytea NOT NULL,
+  storage bytea,
+  old_storage bytea
+);
+
+CREATE INDEX IF NOT EXISTS pending_storage_hash_index ON _pending_storage (hash);
@@ -0,0 +1,7 @@
+-- progress of an initial sync, which drops the indexes of `storage` while ingesting
+-- and rebuilds them in phases afterwards
+CREATE TABLE IF NOT EXISTS _initial_sync (
+  id integer PRIMARY KEY DEFAULT 1 CHECK (id = 1),
+  phase text NOT NULL,
+  updated_at timestamptz NOT NULL DEFAULT now()
+);
@@ -0,0 +1,73 @@
+-- `storage` is partitioned by ranges of `storage_partition_size()` blocks and has 64-bit ids.
+-- Indexes of a partitioned table must contain the partition key,
+-- so `block_num` is part of the primary key and of the unique index used for upserts.
+-- Rows of an existing archive are kept in `_storage_legacy`, and moved into the partitions
+-- by the archive when it starts (see `database::partitions`).
+
+CREATE OR REPLACE FUNCTION storage_partition_size() RETURNS integer
+    LANGUAGE sql IMMUTABLE
+AS 'SELECT 100000';
+
+ALTER TABLE storage RENAME TO _storage_legacy;
+ALTER TABLE _storage_legacy RENAME CONSTRAINT storage_pkey TO _storage_legacy_pkey;
+ALTER TABLE _storage_legacy DROP CONSTRAINT IF EXISTS storage_hash_fkey;
+DROP INDEX IF EXISTS only_unique_hash_key_storage;
+ALTER INDEX IF EXISTS storage_block_num_index RENAME TO _storage_legacy_block_num_index;
+
+CREATE TABLE storage (
+  id BIGSERIAL NOT NULL,
+  block_num int check (block_num >= 0 and block_num < 2147483647) NOT NULL,
+  hash bytea NOT NULL REFERENCES blocks(hash) ON DELETE CASCADE ON UPDATE CASCADE,
+  is_full boolean NOT NULL,
+  key bytea NOT NULL,
+  storage bytea,
+  old_storage bytea,
+  PRIMARY KEY (id, block_num)
+) PARTITION BY RANGE (block_num);
+
+CREATE UNIQUE INDEX only_unique_hash_key_storage ON storage (block_num, hash, key, md5(storage));
+CREATE INDEX storage_block_num_index ON storage (block_num);
+
+-- create the partition holding `num`, if it does not exist yet
+CREATE OR REPLACE FUNCTION create_storage_partition(num integer)
+   RETURNS void
+   LANGUAGE PLPGSQL
+AS $BODY$
+DECLARE
+  first integer := num - num % storage_partition_size();
+  name text := 'storage_' || first;
+BEGIN
+    IF to_regclass(name) IS NULL THEN
+      -- blocks may be inserted concurrently
+      PERFORM pg_advisory_xact_lock(hashtext('create_storage_partition'));
+      EXECUTE format(
+        'CREATE TABLE IF NOT EXISTS %I PARTITION OF storage FOR VALUES FROM (%s) TO (%s)',
+        name, first, first + storage_partition_size()
+      );
+    END IF;
+END;
+$BODY$;
+
+-- partitions are created with the blocks their storage belongs to
+CREATE OR REPLACE FUNCTION create_storage_partitions_fn()
+   RETURNS TRIGGER
+   LANGUAGE PLPGSQL
+AS $BODY$
+BEGIN
+    PERFORM create_storage_partition(first) FROM (
+      SELECT DISTINCT block_num - block_num % storage_partition_size() AS first FROM new_blocks
+    ) ranges;
+    RETURN NULL;
+END;
+$BODY$;
+
+CREATE TRIGGER storage_partitions_trigger
+    AFTER INSERT
+    ON blocks
+    REFERENCING NEW TABLE AS new_blocks
+    FOR EACH STATEMENT
+    EXECUTE PROCEDURE create_storage_partitions_fn();
+
+SELECT create_storage_partition(n::integer) FROM generate_series(
+  0, COALESCE((SELECT max(block_num) FROM blocks), 0), storage_partition_size()
+) AS n;
@@ -0,0 +1,17 @@
+-- optional deduplicated layout of storage, enabled with `ArchiveBuilder::dedup_storage`.
+-- every value is stored once in `storage_values`, keyed by its blake2_256 hash,
+-- and rows of `storage` reference it through `value_hash` with `storage` left NULL.
+CREATE TABLE IF NOT EXISTS storage_values (
+  value_hash bytea PRIMARY KEY,
+  value bytea NOT NULL
+);
+
+ALTER TABLE storage ADD COLUMN IF NOT EXISTS value_hash bytea REFERENCES storage_values(value_hash);
+
+CREATE UNIQUE INDEX IF NOT EXISTS storage_value_hash_unique_index ON storage (block_num, hash, key, value_hash);
+
+-- storage of both layouts, with values resolved
+CREATE OR REPLACE VIEW storage_with_values AS
+SELECT s.id, s.block_num, s.hash, s.is_full, s.key, COALESCE(s.storage, v.value) AS storage, s.old_storage
+FROM storage s
+LEFT JOIN storage_values v ON v.value_hash = s.value_hash;
@@ -0,0 +1,2 @@
+-- state lookups find the latest change of a key at or before a block (see `backend::PgStateBackend`)
+CREATE INDEX IF NOT EXISTS storage_key_block_num_index ON storage (key, block_num);
@@ -0,0 +1,15 @@
+-- blocks whose re-execution resulted in a different state root than the one in their header
+CREATE TABLE IF NOT EXISTS state_root_mismatches (
+  hash bytea PRIMARY KEY REFERENCES blocks(hash) ON DELETE CASCADE ON UPDATE CASCADE,
+  block_num int check (block_num >= 0 and block_num < 2147483647) NOT NULL,
+  spec int NOT NULL,
+  expected_root bytea NOT NULL,
+  computed_root bytea NOT NULL,
+  -- number of storage keys the execution changed
+  changes int NOT NULL,
+  -- whether the storage of the execution was archived anyway
+  archived boolean NOT NULL,
+  detected_at timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS state_root_mismatches_block_num_index ON state_root_mismatches (block_num);
@@ -0,0 +1,11 @@
+-- per stage of the indexing pipeline, the block number up to which every block went through the stage.
+-- Advanced in the same transaction as the inserts of the stage, and used to resume on start.
+CREATE TABLE IF NOT EXISTS indexer_state (
+  stage text PRIMARY KEY,
+  cursor int NOT NULL,
+  updated_at timestamptz NOT NULL DEFAULT now()
+);
+
+-- the genesis block has no storage of its own
+INSERT INTO indexer_state (stage, cursor) VALUES ('blocks', -1), ('storage', 0)
+ON CONFLICT (stage) DO NOTHING;
@@ -0,0 +1,4 @@
+-- Blocks indexed ahead of the finalized block of the node are not finalized,
+-- until finality confirms them or they are rolled back.
+ALTER TABLE blocks ADD COLUMN IF NOT EXISTS finalized boolean NOT NULL DEFAULT true;
+CREATE INDEX IF NOT EXISTS blocks_unfinalized_index ON blocks (block_num) WHERE NOT finalized;
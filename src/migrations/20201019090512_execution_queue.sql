@@ -0,0 +1,10 @@
+-- Blocks waiting to be executed, by priority.
+-- Jobs in `_background_tasks` run in the order they were queued,
+-- so blocks only become jobs a window at a time, blocks near the tip first.
+CREATE TABLE IF NOT EXISTS _execution_queue (
+  block_num int PRIMARY KEY REFERENCES blocks(block_num) ON DELETE CASCADE,
+  -- 0 for blocks near the tip, 1 for a backfill
+  priority smallint NOT NULL,
+  queued_at timestamp NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX IF NOT EXISTS execution_queue_priority_index ON _execution_queue (priority, block_num);
@@ -0,0 +1,8 @@
+-- Claims of standalone workers on the blocks they execute.
+-- A claim holds until `lease_expires`, which the worker pushes back while it runs.
+-- Blocks that failed wait until `lease_expires` before they are claimed again.
+ALTER TABLE _execution_queue ADD COLUMN IF NOT EXISTS leased_by text;
+ALTER TABLE _execution_queue ADD COLUMN IF NOT EXISTS lease_expires timestamptz;
+ALTER TABLE _execution_queue ADD COLUMN IF NOT EXISTS attempts int NOT NULL DEFAULT 0;
+CREATE INDEX IF NOT EXISTS execution_queue_leased_by_index ON _execution_queue (leased_by)
+  WHERE leased_by IS NOT NULL;
@@ -0,0 +1,13 @@
+-- blocks that went through a stage of the indexing pipeline which does not write a row for every block,
+-- like decoding storage or indexing balances. Used to compute the cursors of those stages.
+CREATE TABLE IF NOT EXISTS _stage_blocks (
+  stage text NOT NULL,
+  block_num int NOT NULL,
+  hash bytea NOT NULL REFERENCES blocks(hash) ON DELETE CASCADE ON UPDATE CASCADE,
+  PRIMARY KEY (stage, hash)
+);
+
+CREATE INDEX IF NOT EXISTS stage_blocks_block_num_index ON _stage_blocks (stage, block_num);
+
+INSERT INTO indexer_state (stage, cursor) VALUES ('metadata', -1), ('decoded', 0), ('balances', 0)
+ON CONFLICT (stage) DO NOTHING;
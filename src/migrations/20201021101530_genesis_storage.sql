@@ -0,0 +1,6 @@
+-- the genesis state is archived as the storage of block 0,
+-- so the stages that go through storage start below it.
+-- Archives without it execute the genesis block again on start.
+UPDATE indexer_state SET cursor = -1
+WHERE stage IN ('storage', 'decoded', 'balances')
+AND NOT EXISTS (SELECT 1 FROM storage WHERE block_num = 0);
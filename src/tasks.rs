@@ -19,10 +19,13 @@
 
 use super::{
     actors::StorageAggregator,
-    backend::{ApiAccess, BlockExecutor, ReadOnlyBackend as Backend},
+    backend::{
+        changes_trie, trie_diff, ApiAccess, BlockExecutor, ReadOnlyBackend as Backend,
+        StorageSource,
+    },
 };
-use crate::types::Storage;
-use sc_client_api::backend;
+use crate::types::{StateRootMismatch, Storage};
+use sc_client_api::backend::{self, StateBackend as _};
 use serde::de::DeserializeOwned;
 use sp_api::{ApiExt, ConstructRuntimeApi};
 use sp_block_builder::BlockBuilder as BlockBuilderApi;
@@ -30,21 +33,178 @@ use sp_runtime::{
     generic::BlockId,
     traits::{Block as BlockT, Header, NumberFor},
 };
+use sp_storage::{StorageData, StorageKey};
 use std::marker::PhantomData;
 use std::panic::AssertUnwindSafe;
 use std::sync::Arc;
 use xtra::prelude::*;
 
+/// What the execution of a block needs,
+/// shared by the jobs of the archive and standalone workers (see `crate::worker`)
+pub struct ExecutionEnv<B, R, C>
+where
+    B: BlockT,
+{
+    backend: Arc<Backend<B>>,
+    client: Arc<C>,
+    /// capture the values changed keys had before the block
+    old_storage: bool,
+    storage_source: StorageSource,
+    /// skip storage of blocks whose execution results in the wrong state root
+    strict_state_root: bool,
+    _marker: PhantomData<R>,
+}
+
+/// Storage of an executed block
+pub struct Executed<B: BlockT> {
+    /// `None` for blocks skipped because of a state root mismatch
+    pub storage: Option<Storage<B>>,
+    pub mismatch: Option<StateRootMismatch>,
+}
+
+impl<B, R, C> ExecutionEnv<B, R, C>
+where
+    B: BlockT,
+{
+    pub fn new(
+        backend: Arc<Backend<B>>,
+        client: Arc<C>,
+        old_storage: bool,
+        storage_source: StorageSource,
+        strict_state_root: bool,
+    ) -> Self {
+        Self {
+            backend,
+            client,
+            old_storage,
+            storage_source,
+            strict_state_root,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn backend(&self) -> &Arc<Backend<B>> {
+        &self.backend
+    }
+}
+
+impl<B, RA, Api> ExecutionEnv<B, RA, Api>
+where
+    B: BlockT,
+    NumberFor<B>: Into<u32>,
+    RA: ConstructRuntimeApi<B, Api> + Send + Sync + 'static,
+    RA::RuntimeApi: BlockBuilderApi<B, Error = sp_blockchain::Error>
+        + ApiExt<B, StateBackend = backend::StateBackendFor<Backend<B>, B>>,
+    Api: ApiAccess<B, Backend<B>, RA> + 'static,
+{
+    /// Get the storage changes of `block`, executing it unless they can be derived otherwise.
+    /// The storage of the genesis block is its whole state.
+    pub fn execute(&self, block: B) -> crate::error::Result<Executed<B>> {
+        if *block.header().parent_hash() == Default::default() {
+            return Ok(Executed {
+                storage: Some(self.genesis_storage(block.header())?),
+                mismatch: None,
+            });
+        }
+        let api = self.client.runtime_api();
+
+        log::trace!(
+            "Executing Block: {}:{}, version {}",
+            block.header().hash(),
+            block.header().number(),
+            self.client
+                .runtime_version_at(&BlockId::Hash(block.header().hash()))?
+                .spec_version,
+        );
+        let now = std::time::Instant::now();
+        let expected_root = *block.header().state_root();
+        let header = block.header();
+        let derived = match self.storage_source {
+            StorageSource::Execution => Ok(None),
+            StorageSource::TrieDiff => {
+                trie_diff::block_changes(&self.backend, header, self.old_storage)
+            }
+            StorageSource::ChangesTrie => {
+                changes_trie::block_changes(&self.backend, header, self.old_storage)
+            }
+        };
+        let changes = derived.unwrap_or_else(|e| {
+            log::debug!("Executing block {}: {}", header.hash(), e);
+            None
+        });
+        let block = match changes {
+            Some(changes) => changes,
+            None => BlockExecutor::new(api, &self.backend, block)?
+                .old_storage(self.old_storage)
+                .block_into_storage()?,
+        };
+        log::debug!(
+            "Took {:?} to get the storage changes of a block",
+            now.elapsed()
+        );
+
+        let mut mismatch = None;
+        if let Some(computed_root) = block.computed_root.filter(|root| *root != expected_root) {
+            log::error!(
+                "Execution of block {} resulted in state root {}, but its header has {}",
+                block.block_hash,
+                computed_root,
+                expected_root
+            );
+            let spec = self
+                .client
+                .runtime_version_at(&BlockId::Hash(block.block_hash))?
+                .spec_version;
+            mismatch = Some(StateRootMismatch {
+                block_num: block.block_num.into(),
+                hash: block.block_hash.as_ref().to_vec(),
+                spec,
+                expected_root: expected_root.as_ref().to_vec(),
+                computed_root: computed_root.as_ref().to_vec(),
+                changes: block.storage_changes.len(),
+                archived: !self.strict_state_root,
+            });
+            if self.strict_state_root {
+                return Ok(Executed {
+                    storage: None,
+                    mismatch,
+                });
+            }
+        }
+        Ok(Executed {
+            storage: Some(Storage::from(block)),
+            mismatch,
+        })
+    }
+
+    /// The genesis state, which every key not changed since is read from
+    fn genesis_storage(&self, header: &B::Header) -> crate::error::Result<Storage<B>> {
+        let hash = header.hash();
+        let state = backend::Backend::state_at(&**self.backend, BlockId::Hash(hash))?;
+        let changes = state
+            .pairs()
+            .into_iter()
+            .map(|(k, v)| (StorageKey(k), Some(StorageData(v))))
+            .collect::<Vec<_>>();
+        log::info!("Archiving {} keys of the genesis state", changes.len());
+        let storage = Storage::new(hash, (*header.number()).into(), true, changes);
+        if self.old_storage {
+            let old_values = vec![None; storage.changes().len()];
+            Ok(storage.with_old_values(old_values))
+        } else {
+            Ok(storage)
+        }
+    }
+}
+
 /// The environment passed to each task
 pub struct Environment<B, R, C>
 where
     B: BlockT + Unpin,
     B::Hash: Unpin,
 {
-    backend: Arc<Backend<B>>,
-    client: Arc<C>,
+    exec: ExecutionEnv<B, R, C>,
     storage: Address<StorageAggregator<B>>,
-    _marker: PhantomData<R>,
 }
 
 type Env<B, R, C> = AssertUnwindSafe<Environment<B, R, C>>;
@@ -57,12 +217,19 @@ where
         backend: Arc<Backend<B>>,
         client: Arc<C>,
         storage: Address<StorageAggregator<B>>,
+        old_storage: bool,
+        storage_source: StorageSource,
+        strict_state_root: bool,
     ) -> Self {
         Self {
-            backend,
-            client,
+            exec: ExecutionEnv::new(
+                backend,
+                client,
+                old_storage,
+                storage_source,
+                strict_state_root,
+            ),
             storage,
-            _marker: PhantomData,
         }
     }
 }
@@ -88,25 +255,90 @@ where
         + ApiExt<B, StateBackend = backend::StateBackendFor<Backend<B>, B>>,
     Api: ApiAccess<B, Backend<B>, RA> + 'static,
 {
-    let api = env.client.runtime_api();
-
-    if *block.header().parent_hash() == Default::default() {
-        return Ok(());
+    let executed = env.exec.execute(block)?;
+    if let Some(mismatch) = executed.mismatch {
+        smol::block_on(env.storage.send(mismatch))?;
+    }
+    if let Some(storage) = executed.storage {
+        smol::block_on(env.storage.send(storage))?;
     }
-
-    log::trace!(
-        "Executing Block: {}:{}, version {}",
-        block.header().hash(),
-        block.header().number(),
-        env.client
-            .runtime_version_at(&BlockId::Hash(block.header().hash()))
-            .map_err(|e| format!("{:?}", e))?
-            .spec_version,
-    );
-    let now = std::time::Instant::now();
-    let block = BlockExecutor::new(api, &env.backend, block)?.block_into_storage()?;
-    log::debug!("Took {:?} to execute block", now.elapsed());
-    let storage = Storage::from(block);
-    smol::block_on(env.storage.send(storage))?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::harness::{run_archive_with, TempDatabase, TestChain, Until};
+    use sqlx::{Connection, PgConnection};
+
+    const BLOCKS: u32 = 10;
+
+    #[test]
+    fn should_record_state_root_mismatches() {
+        crate::initialize();
+        let chain = TestChain::generate_with_wrong_root(BLOCKS).unwrap();
+
+        for strict in vec![false, true] {
+            let db = TempDatabase::new().unwrap();
+            // the mismatch, and the blocks with storage: strict mode skips the storage of the last block
+            let archived = i64::from(if strict { BLOCKS } else { BLOCKS + 1 });
+            run_archive_with(
+                &chain,
+                &db,
+                |builder| builder.strict_state_root(strict),
+                Until::Count(
+                    r#"
+                    SELECT (SELECT COUNT(*) FROM state_root_mismatches)
+                        + (SELECT COUNT(DISTINCT block_num) FROM storage WHERE block_num > 0)
+                    "#,
+                    archived,
+                ),
+            )
+            .unwrap();
+
+            smol::block_on(async {
+                let mut conn = PgConnection::connect(&db.url).await.unwrap();
+                let (num, hash, expected, computed, was_archived): (
+                    i32,
+                    Vec<u8>,
+                    Vec<u8>,
+                    Vec<u8>,
+                    bool,
+                ) = sqlx::query_as(
+                    r#"
+                    SELECT block_num, hash, expected_root, computed_root, archived
+                    FROM state_root_mismatches
+                    "#,
+                )
+                .fetch_one(&mut conn)
+                .await
+                .unwrap();
+                assert_eq!(num, BLOCKS as i32);
+                assert_eq!(hash.as_slice(), chain.hashes[BLOCKS as usize - 1].as_ref());
+                assert_ne!(expected, computed);
+                assert_eq!(was_archived, !strict);
+
+                // the header claims the state root of its parent
+                let (parent_root,): (Vec<u8>,) =
+                    sqlx::query_as("SELECT state_root FROM blocks WHERE block_num = $1")
+                        .bind(BLOCKS as i32 - 1)
+                        .fetch_one(&mut conn)
+                        .await
+                        .unwrap();
+                assert_eq!(expected, parent_root);
+
+                let (rows,): (i64,) =
+                    sqlx::query_as("SELECT COUNT(*) FROM storage WHERE block_num = $1")
+                        .bind(BLOCKS as i32)
+                        .fetch_one(&mut conn)
+                        .await
+                        .unwrap();
+                assert_eq!(
+                    rows > 0,
+                    !strict,
+                    "storage of the last block, strict: {}",
+                    strict
+                );
+            });
+        }
+    }
+}
@@ -18,6 +18,7 @@ use crate::error::Result;
 use codec::{Decode, Encode};
 use serde::{Deserialize, Serialize};
 use sp_runtime::{generic::SignedBlock, traits::Block as BlockT};
+use sp_state_machine::StorageProof;
 use sp_storage::{StorageData, StorageKey};
 
 pub trait ThreadPool: Send + Sync {
@@ -47,6 +48,14 @@ where
 
     /// Get a reference to the context the actors are using
     fn context(&self) -> Result<super::actors::ActorContext<B>>;
+
+    /// Get a merkle proof of the value of `key` at block `hash`,
+    /// which can be checked with `backend::verify_storage_proof`.
+    /// Returns `None` if the state of the block is not known.
+    async fn storage_proof(&self, hash: B::Hash, key: &[u8]) -> Result<Option<StorageProof>>;
+
+    /// Blocks waiting to be executed, near the tip and of a backfill, and jobs in the job queue
+    async fn queue_status(&self) -> Result<crate::database::queue::QueueStatus>;
 }
 
 #[derive(Debug)]
@@ -69,6 +78,82 @@ impl Metadata {
     }
 }
 
+/// The raw runtime code (wasm blob) for a runtime version
+#[derive(Debug)]
+pub struct RuntimeCode {
+    code_hash: Vec<u8>,
+    spec: u32,
+    code: Vec<u8>,
+}
+
+impl RuntimeCode {
+    pub fn new(spec: u32, code: Vec<u8>) -> Self {
+        let code_hash = sp_core::hashing::blake2_256(code.as_slice()).to_vec();
+        Self {
+            code_hash,
+            spec,
+            code,
+        }
+    }
+
+    pub fn code_hash(&self) -> &[u8] {
+        self.code_hash.as_slice()
+    }
+
+    pub fn spec(&self) -> u32 {
+        self.spec
+    }
+
+    pub fn code(&self) -> &[u8] {
+        self.code.as_slice()
+    }
+}
+
+/// A merkle proof of the value of a storage key at a block, for the `storage_proofs` cache
+#[derive(Debug, Clone)]
+pub struct CachedStorageProof {
+    hash: Vec<u8>,
+    key: Vec<u8>,
+    proof: Vec<u8>,
+}
+
+impl CachedStorageProof {
+    pub fn new(hash: &[u8], key: &[u8], proof: &StorageProof) -> Self {
+        Self {
+            hash: hash.to_vec(),
+            key: key.to_vec(),
+            proof: proof.encode(),
+        }
+    }
+
+    pub fn hash(&self) -> &[u8] {
+        self.hash.as_slice()
+    }
+
+    pub fn key(&self) -> &[u8] {
+        self.key.as_slice()
+    }
+
+    /// the SCALE-encoded proof
+    pub fn proof(&self) -> &[u8] {
+        self.proof.as_slice()
+    }
+}
+
+/// A block whose execution resulted in a different state root than the one in its header
+#[derive(Debug, Clone)]
+pub struct StateRootMismatch {
+    pub block_num: u32,
+    pub hash: Vec<u8>,
+    pub spec: u32,
+    pub expected_root: Vec<u8>,
+    pub computed_root: Vec<u8>,
+    /// number of storage keys the execution changed
+    pub changes: usize,
+    /// whether the storage of the execution was archived anyway
+    pub archived: bool,
+}
+
 #[derive(Encode, Decode, Debug, Clone)]
 pub struct Block<B: BlockT> {
     pub inner: SignedBlock<B>,
@@ -82,19 +167,35 @@ impl<B: BlockT> Block<B> {
 }
 
 /// NewType for committing many blocks to the database at once
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct BatchBlock<B: BlockT> {
     pub inner: Vec<Block<B>>,
+    /// the finalized block number when the blocks were crawled, `None` if they are all finalized
+    pub finalized: Option<u32>,
 }
 
 impl<B: BlockT> BatchBlock<B> {
     pub fn new(blocks: Vec<Block<B>>) -> Self {
-        Self { inner: blocks }
+        Self {
+            inner: blocks,
+            finalized: None,
+        }
+    }
+
+    /// Insert the blocks above `number` as not finalized
+    pub fn finalized_up_to(mut self, number: u32) -> Self {
+        self.finalized = Some(number);
+        self
     }
 
     pub fn inner(&self) -> &Vec<Block<B>> {
         &self.inner
     }
+
+    /// Whether block number `num` of this batch is finalized
+    pub fn is_finalized(&self, num: u32) -> bool {
+        self.finalized.map(|f| num <= f).unwrap_or(true)
+    }
 }
 
 /// NewType for Storage Data
@@ -104,6 +205,8 @@ pub struct Storage<Block: BlockT> {
     block_num: u32,
     full_storage: bool,
     pub changes: Vec<(StorageKey, Option<StorageData>)>,
+    /// values of the changed keys before this block, in the order of `changes`
+    pub old_values: Option<Vec<Option<StorageData>>>,
 }
 
 impl<Block: BlockT> Storage<Block> {
@@ -118,9 +221,16 @@ impl<Block: BlockT> Storage<Block> {
             hash,
             full_storage,
             changes,
+            old_values: None,
         }
     }
 
+    /// Attach the values the changed keys had before this block
+    pub fn with_old_values(mut self, old_values: Vec<Option<StorageData>>) -> Self {
+        self.old_values = Some(old_values);
+        self
+    }
+
     pub fn is_full(&self) -> bool {
         self.full_storage
     }
@@ -0,0 +1,338 @@
+// Copyright 2018-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Standalone workers executing blocks for an archive, from another process or machine.
+//! A worker opens its own RocksDB secondary and Postgres connections, claims blocks from
+//! `_execution_queue` with a lease it renews while it runs, and writes their storage directly.
+//! Blocks of a worker that stops without releasing them are claimed again once its lease expires.
+//!
+//! Workers archive storage and state root mismatches. The archive decodes storage
+//! and indexes balances of the blocks that workers archived, by reading their storage back
+//! above the cursors of `Stage::Decoded` and `Stage::Balances`.
+//! Build one with `ArchiveBuilder::build_worker`.
+
+use crate::{
+    backend::{ApiAccess, ReadOnlyBackend as Backend},
+    database::{
+        cursors::{self, Stage},
+        initial_sync::{self, Phase},
+        queue, DedupStorage, Insert, PendingStorage, StorageModel, UncheckedStorage,
+    },
+    error::{Error, Result},
+    sql_block_builder::BlockBuilder as SqlBlockBuilder,
+    tasks::{Executed, ExecutionEnv},
+};
+use futures::FutureExt;
+use sc_client_api::backend;
+use sp_api::{ApiExt, ConstructRuntimeApi};
+use sp_block_builder::BlockBuilder as BlockBuilderApi;
+use sp_runtime::traits::{Block as BlockT, Header as _, NumberFor};
+use sqlx::{postgres::PgConnection, Connection, PgPool};
+use std::{panic::AssertUnwindSafe, sync::Arc, time::Duration};
+
+/// How long the claims of a worker hold without being renewed
+const LEASE: Duration = Duration::from_secs(60);
+/// How often a worker looks for blocks while the queue is empty
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Configuration of a standalone worker
+#[derive(Debug, Clone)]
+pub(crate) struct WorkerConfig {
+    /// name of the worker in `_execution_queue.leased_by`, unique among running workers
+    pub id: String,
+    /// number of blocks executed at once
+    pub threads: usize,
+    /// blocks near the tip claimed for every block of a backfill
+    pub tip_weight: u32,
+    /// storage is stored in the deduplicated layout
+    pub dedup_storage: bool,
+}
+
+/// A standalone worker executing queued blocks on its own thread.
+/// Call `shutdown` to stop it and release the blocks it claimed.
+pub struct Worker {
+    id: String,
+    kill_tx: flume::Sender<()>,
+    handle: jod_thread::JoinHandle<Result<()>>,
+}
+
+impl Worker {
+    /// Start executing blocks queued in the database at `pg_url` with `env`
+    pub(crate) fn spawn<B, R, C>(
+        env: ExecutionEnv<B, R, C>,
+        pg_url: &str,
+        config: WorkerConfig,
+    ) -> Self
+    where
+        B: BlockT,
+        NumberFor<B>: Into<u32>,
+        R: ConstructRuntimeApi<B, C> + Send + Sync + 'static,
+        R::RuntimeApi: BlockBuilderApi<B, Error = sp_blockchain::Error>
+            + ApiExt<B, StateBackend = backend::StateBackendFor<Backend<B>, B>>,
+        C: ApiAccess<B, Backend<B>, R> + 'static,
+    {
+        let (kill_tx, kill_rx) = flume::bounded(1);
+        let id = config.id.clone();
+        let pg_url = pg_url.to_string();
+        let handle = jod_thread::spawn(move || {
+            let res = smol::block_on(async move {
+                let pool = PgPool::connect(&pg_url).await?;
+                log::info!("Starting worker `{}`", config.id);
+                {
+                    let heartbeat = heartbeat(&pool, &config.id).fuse();
+                    let run = run(Arc::new(env), &pool, &config, kill_rx).fuse();
+                    futures::pin_mut!(heartbeat, run);
+                    futures::select! {
+                        _ = run => (),
+                        _ = heartbeat => (),
+                    }
+                }
+                let mut conn = pool.acquire().await?;
+                let released = queue::release(&mut *conn, &config.id).await?;
+                log::info!(
+                    "Stopped worker `{}`, released {} blocks",
+                    config.id,
+                    released
+                );
+                Ok(())
+            });
+            if let Err(e) = &res {
+                log::error!("Worker stopped: {}", e);
+            }
+            res
+        });
+        Self {
+            id,
+            kill_tx,
+            handle,
+        }
+    }
+
+    /// The name the worker claims blocks under
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Stop the worker once the blocks it is executing are archived,
+    /// and release the blocks it claimed
+    pub fn shutdown(self) -> Result<()> {
+        let _ = self.kill_tx.send(());
+        self.handle.join()
+    }
+}
+
+/// Execute claimed blocks until a message on `rx`.
+/// Errors of the database are logged and retried, so that the worker does not stop holding claims.
+async fn run<B, R, C>(
+    env: Arc<ExecutionEnv<B, R, C>>,
+    pool: &PgPool,
+    config: &WorkerConfig,
+    mut rx: flume::Receiver<()>,
+) where
+    B: BlockT,
+    NumberFor<B>: Into<u32>,
+    R: ConstructRuntimeApi<B, C> + Send + Sync + 'static,
+    R::RuntimeApi: BlockBuilderApi<B, Error = sp_blockchain::Error>
+        + ApiExt<B, StateBackend = backend::StateBackendFor<Backend<B>, B>>,
+    C: ApiAccess<B, Backend<B>, R> + 'static,
+{
+    loop {
+        let busy = match round(&env, pool, config).await {
+            Ok(busy) => busy,
+            Err(e) => {
+                log::error!("Worker `{}`: {}", config.id, e);
+                false
+            }
+        };
+        if !busy {
+            let idle = smol::Timer::new(POLL_INTERVAL).fuse();
+            futures::pin_mut!(idle);
+            futures::select! {
+                _ = idle => continue,
+                _ = rx.recv_async() => break,
+            }
+        }
+        if rx.try_recv().is_ok() {
+            break;
+        }
+    }
+}
+
+/// Claim blocks, execute them and archive their storage. Returns false if no block was waiting.
+async fn round<B, R, C>(
+    env: &Arc<ExecutionEnv<B, R, C>>,
+    pool: &PgPool,
+    config: &WorkerConfig,
+) -> Result<bool>
+where
+    B: BlockT,
+    NumberFor<B>: Into<u32>,
+    R: ConstructRuntimeApi<B, C> + Send + Sync + 'static,
+    R::RuntimeApi: BlockBuilderApi<B, Error = sp_blockchain::Error>
+        + ApiExt<B, StateBackend = backend::StateBackendFor<Backend<B>, B>>,
+    C: ApiAccess<B, Backend<B>, R> + 'static,
+{
+    // blocks queued by the archive may have been imported after the secondary last caught up
+    let source = env.backend().source();
+    smol::unblock!(source.catch_up())?;
+
+    let mut conn = pool.acquire().await?;
+    let claimed = queue::claim(
+        &mut *conn,
+        &config.id,
+        config.threads,
+        config.tip_weight,
+        LEASE,
+    )
+    .await?;
+    if claimed.is_empty() {
+        return Ok(false);
+    }
+    let nums: Vec<u32> = claimed.iter().map(|b| b.block_num as u32).collect();
+    let blocks = match SqlBlockBuilder::<B>::with_vec(claimed) {
+        Ok(blocks) => blocks,
+        Err(e) => {
+            for num in nums {
+                queue::fail(&mut *conn, &config.id, num).await?;
+            }
+            return Err(e);
+        }
+    };
+    let executions = blocks.into_iter().map(|b| {
+        let env = env.clone();
+        let block = b.inner.block;
+        let num: u32 = (*block.header().number()).into();
+        async move {
+            let executed = smol::unblock!(std::panic::catch_unwind(AssertUnwindSafe(
+                || env.execute(block)
+            ))
+            .unwrap_or_else(|_| Err(Error::from("execution panicked"))));
+            (num, executed)
+        }
+    });
+    for (num, executed) in futures::future::join_all(executions).await {
+        let archived = match executed {
+            Ok(executed) => archive(&mut *conn, config, num, executed).await,
+            Err(e) => Err(e),
+        };
+        match archived {
+            Ok(true) => (),
+            Ok(false) => log::warn!("Claim on block {} expired, not archiving it", num),
+            Err(e) => {
+                log::error!("Worker `{}` failed block {}: {}", config.id, num, e);
+                if let Err(e) = queue::fail(&mut *conn, &config.id, num).await {
+                    log::error!("Could not back off block {}: {}", num, e);
+                }
+            }
+        }
+    }
+    Ok(true)
+}
+
+/// Renew the claims of worker `id` while the worker runs
+async fn heartbeat(pool: &PgPool, id: &str) {
+    loop {
+        smol::Timer::new(LEASE / 3).await;
+        let renewed = match pool.acquire().await {
+            Ok(mut conn) => queue::renew(&mut *conn, id, LEASE).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = renewed {
+            log::warn!("Could not renew the claims of worker `{}`: {}", id, e);
+        }
+    }
+}
+
+/// Archive the storage of block `num` and remove it from the queue, in one transaction.
+/// Returns false if the claim on the block expired.
+async fn archive<B: BlockT>(
+    conn: &mut PgConnection,
+    config: &WorkerConfig,
+    num: u32,
+    executed: Executed<B>,
+) -> Result<bool> {
+    let mut tx = conn.begin().await?;
+    if !queue::complete(&mut tx, &config.id, num).await? {
+        return Ok(false);
+    }
+    // storage inserted during an initial sync depends on the indexes it rebuilt
+    let phase = initial_sync::lock_phase(&mut tx)
+        .await?
+        .unwrap_or(Phase::Done);
+    if let Some(mismatch) = executed.mismatch {
+        mismatch.insert(&mut tx).await?;
+    }
+    if let Some(storage) = executed.storage {
+        let storage = Vec::<StorageModel<B>>::from(storage);
+        if storage.is_empty() {
+            // nothing changed
+        } else if config.dedup_storage {
+            DedupStorage(storage).insert(&mut tx).await?;
+        } else if phase == Phase::Ingesting {
+            UncheckedStorage(storage).insert(&mut tx).await?;
+        } else if !phase.has_unique_index() {
+            PendingStorage(storage).insert(&mut tx).await?;
+        } else {
+            storage.insert(&mut tx).await?;
+        }
+    }
+    // storage ingested without its indexes advances the cursor once they are rebuilt
+    if config.dedup_storage || phase.has_unique_index() {
+        cursors::advance(&mut tx, Stage::Storage).await?;
+    }
+    tx.commit().await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::{self, run_archive_with, TempDatabase, TestChain, Until};
+
+    const BLOCKS: u32 = 10;
+
+    #[test]
+    fn should_execute_blocks_in_standalone_worker() {
+        crate::initialize();
+        let chain = TestChain::generate(BLOCKS).unwrap();
+        let db = TempDatabase::new().unwrap();
+
+        let worker = harness::builder(&chain, &db)
+            .worker_id("test-worker")
+            .build_worker()
+            .unwrap();
+
+        // storage archived by the worker is decoded by the archive
+        let res = run_archive_with(
+            &chain,
+            &db,
+            |builder| builder.local_execution(false).decode_storage(true),
+            Until::Cursor(Stage::Decoded, BLOCKS),
+        );
+        worker.shutdown().unwrap();
+        res.unwrap();
+
+        smol::block_on(async {
+            let mut conn = PgConnection::connect(&db.url).await.unwrap();
+            let status = queue::status(&mut conn).await.unwrap();
+            assert_eq!((status.tip, status.backfill, status.claimed), (0, 0, 0));
+            assert_eq!(status.jobs, 0);
+
+            let storage = cursors::get(&mut conn, Stage::Storage).await.unwrap();
+            assert_eq!(storage, Some(BLOCKS));
+        });
+    }
+}